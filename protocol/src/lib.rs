@@ -6,8 +6,12 @@ use byteorder::{BigEndian, WriteBytesExt};
 
 pub const DHT_EXT: (usize, u8) = (7, 1);
 pub const EXT_PROTO: (usize, u8) = (5, 0x10);
+pub const FAST_EXT: (usize, u8) = (7, 0x04);
 pub const UT_META_ID: u8 = 9;
 pub const UT_PEX_ID: u8 = 11;
+pub const LT_DONTHAVE_ID: u8 = 12;
+pub const LT_TEX_ID: u8 = 13;
+pub const UT_HOLEPUNCH_ID: u8 = 14;
 
 pub trait Bitfield: Clone + From<Vec<u8>> {
     fn bytes(&self) -> usize;
@@ -16,6 +20,28 @@ pub trait Bitfield: Clone + From<Vec<u8>> {
 
 pub trait Buffer: Clone + Deref<Target = [u8]> {}
 
+/// BEP 52 request for a range of hashes in one layer of a file's merkle tree.
+/// Also used to identify the request answered by hashes and hash reject messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HashRequest {
+    pub pieces_root: [u8; 32],
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    pub proof_layers: u32,
+}
+
+impl HashRequest {
+    pub fn encode(&self, mut buf: &mut [u8]) -> io::Result<()> {
+        buf.write_all(&self.pieces_root)?;
+        buf.write_u32::<BigEndian>(self.base_layer)?;
+        buf.write_u32::<BigEndian>(self.index)?;
+        buf.write_u32::<BigEndian>(self.length)?;
+        buf.write_u32::<BigEndian>(self.proof_layers)?;
+        Ok(())
+    }
+}
+
 pub enum Message<BF: Bitfield, Buf: Clone + Deref<Target = [u8]>> {
     // TODO: Consider moving this to the heap,
     // reduces the enum size from 48 bytes to 24,
@@ -50,10 +76,25 @@ pub enum Message<BF: Bitfield, Buf: Clone + Deref<Target = [u8]>> {
         length: u32,
     },
     Port(u16),
+    Suggest(u32),
+    HaveAll,
+    HaveNone,
+    Reject {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    AllowedFast(u32),
     Extension {
         id: u8,
         payload: Vec<u8>,
     },
+    HashRequest(Box<HashRequest>),
+    Hashes {
+        req: Box<HashRequest>,
+        hashes: Vec<u8>,
+    },
+    HashReject(Box<HashRequest>),
 }
 
 impl<BF: Bitfield, Buf: Buffer> fmt::Debug for Message<BF, Buf> {
@@ -91,7 +132,23 @@ impl<BF: Bitfield, Buf: Buffer> fmt::Debug for Message<BF, Buf> {
                 index, begin, length
             ),
             Message::Port(port) => write!(f, "Message::Port({:?})", port),
+            Message::Suggest(p) => write!(f, "Message::Suggest({})", p),
+            Message::HaveAll => write!(f, "Message::HaveAll"),
+            Message::HaveNone => write!(f, "Message::HaveNone"),
+            Message::Reject {
+                index,
+                begin,
+                length,
+            } => write!(
+                f,
+                "Message::Reject {{ idx: {}, begin: {}, len: {} }}",
+                index, begin, length
+            ),
+            Message::AllowedFast(p) => write!(f, "Message::AllowedFast({})", p),
             Message::Extension { id, .. } => write!(f, "Message::Extension {{ id: {} }}", id),
+            Message::HashRequest(ref req) => write!(f, "Message::HashRequest({:?})", req),
+            Message::Hashes { ref req, .. } => write!(f, "Message::Hashes({:?})", req),
+            Message::HashReject(ref req) => write!(f, "Message::HashReject({:?})", req),
         }
     }
 }
@@ -137,10 +194,32 @@ impl<BF: Bitfield, Buf: Buffer> Clone for Message<BF, Buf> {
                 length,
             },
             Message::Port(port) => Message::Port(port),
+            Message::Suggest(p) => Message::Suggest(p),
+            Message::HaveAll => Message::HaveAll,
+            Message::HaveNone => Message::HaveNone,
+            Message::Reject {
+                index,
+                begin,
+                length,
+            } => Message::Reject {
+                index,
+                begin,
+                length,
+            },
+            Message::AllowedFast(p) => Message::AllowedFast(p),
             Message::Extension { id, ref payload } => Message::Extension {
                 id,
                 payload: payload.clone(),
             },
+            Message::HashRequest(ref req) => Message::HashRequest(req.clone()),
+            Message::Hashes {
+                ref req,
+                ref hashes,
+            } => Message::Hashes {
+                req: req.clone(),
+                hashes: hashes.clone(),
+            },
+            Message::HashReject(ref req) => Message::HashReject(req.clone()),
         }
     }
 }
@@ -160,8 +239,12 @@ impl<BF: Bitfield, Buf: Buffer> PartialEq for Message<BF, Buf> {
             | (&Message::Choke, &Message::Choke)
             | (&Message::Unchoke, &Message::Unchoke)
             | (&Message::Interested, &Message::Interested)
-            | (&Message::Uninterested, &Message::Uninterested) => true,
-            (&Message::Have(p), &Message::Have(p_)) => p == p_,
+            | (&Message::Uninterested, &Message::Uninterested)
+            | (&Message::HaveAll, &Message::HaveAll)
+            | (&Message::HaveNone, &Message::HaveNone) => true,
+            (&Message::Have(p), &Message::Have(p_))
+            | (&Message::Suggest(p), &Message::Suggest(p_))
+            | (&Message::AllowedFast(p), &Message::AllowedFast(p_)) => p == p_,
             (&Message::Port(p), &Message::Port(p_)) => p == p_,
             (
                 &Message::Request {
@@ -200,6 +283,18 @@ impl<BF: Bitfield, Buf: Buffer> PartialEq for Message<BF, Buf> {
                     begin: b,
                     length: l,
                 },
+            )
+            | (
+                &Message::Reject {
+                    index,
+                    begin,
+                    length,
+                },
+                &Message::Reject {
+                    index: i,
+                    begin: b,
+                    length: l,
+                },
             ) => index == i && begin == b && length == l,
             (
                 &Message::Extension { id, ref payload },
@@ -208,6 +303,11 @@ impl<BF: Bitfield, Buf: Buffer> PartialEq for Message<BF, Buf> {
                     payload: ref p,
                 },
             ) => id == i && payload == p,
+            (&Message::HashRequest(ref r), &Message::HashRequest(ref r_))
+            | (&Message::HashReject(ref r), &Message::HashReject(ref r_)) => r == r_,
+            (Message::Hashes { req, hashes }, Message::Hashes { req: r, hashes: h }) => {
+                req == r && hashes == h
+            }
             _ => false,
         }
     }
@@ -218,6 +318,7 @@ impl<BF: Bitfield, Buf: Buffer> Message<BF, Buf> {
         let mut rsv = [0u8; 8];
         rsv[DHT_EXT.0] |= DHT_EXT.1;
         rsv[EXT_PROTO.0] |= EXT_PROTO.1;
+        rsv[FAST_EXT.0] |= FAST_EXT.1;
         Message::Handshake {
             rsv,
             hash: *hash,
@@ -251,7 +352,12 @@ impl<BF: Bitfield, Buf: Buffer> Message<BF, Buf> {
 
     pub fn is_special(&self) -> bool {
         match *self {
-            Message::Handshake { .. } | Message::Bitfield(_) | Message::Extension { .. } => true,
+            Message::Handshake { .. }
+            | Message::Bitfield(_)
+            | Message::Extension { .. }
+            | Message::HashRequest(_)
+            | Message::Hashes { .. }
+            | Message::HashReject(_) => true,
             _ => false,
         }
     }
@@ -260,13 +366,20 @@ impl<BF: Bitfield, Buf: Buffer> Message<BF, Buf> {
         match *self {
             Message::Handshake { .. } => 68,
             Message::KeepAlive => 4,
-            Message::Choke | Message::Unchoke | Message::Interested | Message::Uninterested => 5,
+            Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::Uninterested
+            | Message::HaveAll
+            | Message::HaveNone => 5,
             Message::Port(_) => 7,
-            Message::Have(_) => 9,
+            Message::Have(_) | Message::Suggest(_) | Message::AllowedFast(_) => 9,
             Message::Bitfield(ref pf) => 5 + pf.bytes(),
-            Message::Request { .. } | Message::Cancel { .. } => 17,
+            Message::Request { .. } | Message::Cancel { .. } | Message::Reject { .. } => 17,
             Message::Piece { ref data, .. } => 13 + data.len(),
             Message::Extension { ref payload, .. } => 6 + payload.len(),
+            Message::HashRequest(_) | Message::HashReject(_) => 53,
+            Message::Hashes { ref hashes, .. } => 53 + hashes.len(),
         }
     }
 
@@ -354,12 +467,60 @@ impl<BF: Bitfield, Buf: Buffer> Message<BF, Buf> {
                 buf.write_u32::<BigEndian>(begin)?;
                 buf.write_u32::<BigEndian>(length)?;
             }
+            Message::Suggest(piece) => {
+                buf.write_u32::<BigEndian>(5)?;
+                buf.write_u8(0x0D)?;
+                buf.write_u32::<BigEndian>(piece)?;
+            }
+            Message::HaveAll => {
+                buf.write_u32::<BigEndian>(1)?;
+                buf.write_u8(0x0E)?;
+            }
+            Message::HaveNone => {
+                buf.write_u32::<BigEndian>(1)?;
+                buf.write_u8(0x0F)?;
+            }
+            Message::Reject {
+                index,
+                begin,
+                length,
+            } => {
+                buf.write_u32::<BigEndian>(13)?;
+                buf.write_u8(0x10)?;
+                buf.write_u32::<BigEndian>(index)?;
+                buf.write_u32::<BigEndian>(begin)?;
+                buf.write_u32::<BigEndian>(length)?;
+            }
+            Message::AllowedFast(piece) => {
+                buf.write_u32::<BigEndian>(5)?;
+                buf.write_u8(0x11)?;
+                buf.write_u32::<BigEndian>(piece)?;
+            }
             Message::Extension { id, ref payload } => {
                 buf.write_u32::<BigEndian>(2 + payload.len() as u32)?;
                 buf.write_u8(20)?;
                 buf.write_u8(id)?;
                 buf.write_all(payload)?;
             }
+            Message::HashRequest(ref req) => {
+                buf.write_u32::<BigEndian>(49)?;
+                buf.write_u8(21)?;
+                req.encode(buf)?;
+            }
+            Message::Hashes {
+                ref req,
+                ref hashes,
+            } => {
+                buf.write_u32::<BigEndian>(49 + hashes.len() as u32)?;
+                buf.write_u8(22)?;
+                req.encode(&mut buf[..48])?;
+                (&mut buf[48..]).write_all(hashes)?;
+            }
+            Message::HashReject(ref req) => {
+                buf.write_u32::<BigEndian>(49)?;
+                buf.write_u8(23)?;
+                req.encode(buf)?;
+            }
         };
         Ok(())
     }
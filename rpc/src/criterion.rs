@@ -1,7 +1,7 @@
 use std::f32;
 
 use chrono::{DateTime, Utc};
-use regex::{self, Regex};
+use regex::{self, Regex, RegexBuilder};
 
 use crate::resource::ResourceKind;
 
@@ -190,6 +190,32 @@ impl Criterion {
     }
 }
 
+/// Case insensitive match of torrent names and file paths, see SEARCH.
+pub enum Search {
+    Substring(String),
+    Regex(Regex),
+}
+
+impl Search {
+    pub fn new(query: &str, regex: bool) -> Result<Search, regex::Error> {
+        if regex {
+            RegexBuilder::new(query)
+                .case_insensitive(true)
+                .build()
+                .map(Search::Regex)
+        } else {
+            Ok(Search::Substring(query.to_lowercase()))
+        }
+    }
+
+    pub fn matches(&self, s: &str) -> bool {
+        match self {
+            Search::Substring(sub) => s.to_lowercase().contains(sub.as_str()),
+            Search::Regex(re) => re.is_match(s),
+        }
+    }
+}
+
 impl Default for ResourceKind {
     fn default() -> ResourceKind {
         ResourceKind::Torrent
@@ -225,6 +251,21 @@ mod tests {
         assert!(match_like("fo%", "foo"));
     }
 
+    #[test]
+    fn test_search() {
+        let s = Search::new("ubuntu", false).unwrap();
+        assert!(s.matches("Ubuntu 20.04.iso"));
+        assert!(s.matches("isos/UBUNTU"));
+        assert!(!s.matches("debian.iso"));
+        // Only regexes treat special characters as such
+        assert!(!s.matches("ubunt.*"));
+        let s = Search::new(r"\.(mkv|mp4)$", true).unwrap();
+        assert!(s.matches("Show/S01E01.MKV"));
+        assert!(!s.matches("Show/S01E01.mkv.part"));
+        assert!(Search::new("(", true).is_err());
+        assert!(Search::new("(", false).unwrap().matches("a (b)"));
+    }
+
     struct Q;
     impl Queryable for Q {
         fn field(&self, f: &str) -> Option<Field<'_>> {
@@ -51,6 +51,54 @@ pub enum CMessage {
         serial: u64,
         filter_serial: u64,
     },
+    GetEffectiveLimits {
+        serial: u64,
+        id: String,
+    },
+    GetTrackerStats {
+        serial: u64,
+    },
+    GetClients {
+        serial: u64,
+    },
+    /// Lists the notifications which haven't been acknowledged
+    GetNotifications {
+        serial: u64,
+    },
+    /// Dismisses notifications for every client
+    AckNotifications {
+        serial: u64,
+        ids: Vec<u64>,
+    },
+    GetTorrentInfo {
+        serial: u64,
+        id: String,
+        /// Also make the bencoded info dict downloadable
+        #[serde(default = "default_false")]
+        raw: bool,
+    },
+    DisconnectClient {
+        serial: u64,
+        id: u64,
+    },
+    UpdateMatching {
+        serial: u64,
+        #[serde(default)]
+        criteria: Vec<Criterion>,
+        #[serde(default)]
+        resource: Option<CResourceUpdate>,
+        #[serde(default)]
+        paused: Option<bool>,
+    },
+    /// Finds torrents and files whose name or path matches query
+    Search {
+        serial: u64,
+        query: String,
+        #[serde(default = "default_false")]
+        regex: bool,
+        #[serde(default)]
+        limit: Option<u64>,
+    },
 
     // Special messages
     UploadTorrent {
@@ -61,6 +109,11 @@ pub enum CMessage {
         start: bool,
         #[serde(default = "default_false")]
         import: bool,
+        #[serde(default)]
+        category: Option<String>,
+        /// Only parse the torrent and describe it with TORRENT_PREVIEW
+        #[serde(default = "default_false")]
+        dry_run: bool,
     },
     UploadMagnet {
         serial: u64,
@@ -68,6 +121,10 @@ pub enum CMessage {
         path: Option<String>,
         #[serde(default = "default_true")]
         start: bool,
+        #[serde(default)]
+        category: Option<String>,
+        #[serde(default = "default_false")]
+        dry_run: bool,
     },
     UploadFiles {
         serial: u64,
@@ -77,6 +134,9 @@ pub enum CMessage {
     PauseTorrent {
         serial: u64,
         id: String,
+        /// Keep peer connections and tracker listings for a while
+        #[serde(default = "default_false")]
+        soft: bool,
     },
     ResumeTorrent {
         serial: u64,
@@ -86,6 +146,34 @@ pub enum CMessage {
         serial: u64,
         id: String,
     },
+    /// Announces a torrent, or every torrent if no id is given
+    ReannounceTorrent {
+        serial: u64,
+        #[serde(default)]
+        id: Option<String>,
+    },
+    /// Moves the read head of a streaming torrent into a file
+    SetReadHead {
+        serial: u64,
+        id: String,
+        offset: u64,
+    },
+    /// Points a torrent at data which was moved outside of synapse,
+    /// quickly checking it's there
+    RelocateTorrent {
+        serial: u64,
+        id: String,
+        path: String,
+    },
+    /// Requests pieces of a torrent within deadline milliseconds,
+    /// or clears their deadlines if none is given
+    SetPieceDeadline {
+        serial: u64,
+        id: String,
+        pieces: Vec<u32>,
+        #[serde(default)]
+        deadline: Option<u64>,
+    },
     AddTracker {
         serial: u64,
         id: String,
@@ -103,6 +191,41 @@ pub enum CMessage {
     PurgeDns {
         serial: u64,
     },
+    /// Shuts the daemon down cleanly, exiting with RESTART_EXIT_CODE
+    /// rather than 0 if restart is set, for a supervisor to act on
+    Shutdown {
+        serial: u64,
+        #[serde(default)]
+        restart: bool,
+    },
+    /// Makes a manifest of a torrent's files downloadable
+    GetFileManifest {
+        serial: u64,
+        id: String,
+        #[serde(default)]
+        format: ManifestFormat,
+    },
+    /// Recounts the availability of a torrent's pieces, checking the
+    /// picker's bookkeeping, for debugging
+    AuditAvailability {
+        serial: u64,
+        id: String,
+    },
+    /// Makes a JSON dump of a torrent's peers downloadable
+    GetSwarmSnapshot {
+        serial: u64,
+        id: String,
+    },
+    /// Maps pieces of a torrent to the file ranges they hold, and
+    /// files to the pieces holding them
+    GetPieceMap {
+        serial: u64,
+        id: String,
+        #[serde(default)]
+        pieces: Vec<u32>,
+        #[serde(default)]
+        files: Vec<String>,
+    },
 }
 
 /// Server -> client message
@@ -124,6 +247,66 @@ pub enum SMessage<'a> {
         serial: Option<u64>,
         resources: Vec<SResourceUpdate<'a>>,
     },
+    ResourcesUpdated {
+        serial: u64,
+        count: u64,
+    },
+    EffectiveLimits {
+        serial: u64,
+        id: String,
+        throttle_up: Limit,
+        throttle_down: Limit,
+    },
+    TrackerStats {
+        serial: u64,
+        hosts: Vec<TrackerHost>,
+    },
+    Clients {
+        serial: u64,
+        clients: Vec<RpcClient>,
+    },
+    Notifications {
+        serial: u64,
+        notifications: Vec<Notification>,
+    },
+    SearchResults {
+        serial: u64,
+        ids: Vec<String>,
+        /// Whether matches were left out because of the limit
+        truncated: bool,
+    },
+    TorrentPreview {
+        serial: u64,
+        torrent: TorrentPreview,
+    },
+    TorrentInfo {
+        serial: u64,
+        info: TorrentInfo,
+    },
+    AvailabilityAudit {
+        serial: u64,
+        audit: AvailabilityAudit,
+    },
+    PieceMap {
+        serial: u64,
+        map: PieceMap,
+    },
+    FileManifest {
+        serial: u64,
+        id: String,
+        /// ID the manifest is downloaded with
+        download: String,
+        size: u64,
+        expires: DateTime<Utc>,
+    },
+    SwarmSnapshot {
+        serial: u64,
+        id: String,
+        /// ID the snapshot is downloaded with
+        download: String,
+        size: u64,
+        expires: DateTime<Utc>,
+    },
 
     // Special messages
     RpcVersion(Version),
@@ -137,6 +320,13 @@ pub enum SMessage<'a> {
         serial: u64,
         id: String,
     },
+    RequestApplied {
+        serial: u64,
+    },
+    Notification(Notification),
+    NotificationsAcked {
+        ids: Vec<u64>,
+    },
 
     // Error messages
     UnknownResource(Error),
@@ -145,6 +335,205 @@ pub enum SMessage<'a> {
     InvalidRequest(Error),
     PermissionDenied(Error),
     TransferFailed(Error),
+    Conflict(Error),
+}
+
+/// Rate a torrent is actually limited to and the throttle responsible for it.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Limit {
+    /// None if unlimited
+    pub rate: Option<i64>,
+    pub source: LimitSource,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitSource {
+    Torrent,
+    Server,
+    Unlimited,
+}
+
+/// Announce statistics of every tracker on a host, summed over all torrents.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TrackerHost {
+    pub host: String,
+    pub torrents: u64,
+    pub transferred_up: u64,
+    pub transferred_down: u64,
+    pub announces: u64,
+    pub failures: u64,
+    /// Fraction of announces which failed, 0 if none were made
+    pub error_rate: f32,
+    /// Average announce response time in ms
+    pub response_time: Option<u64>,
+}
+
+/// A torrent which would be added by an upload, see `dry_run`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TorrentPreview {
+    pub id: String,
+    pub name: Option<String>,
+    pub private: bool,
+    /// None for magnets, whose metadata isn't known yet
+    pub size: Option<u64>,
+    pub pieces: Option<u64>,
+    pub piece_size: Option<u32>,
+    pub files: Vec<PreviewFile>,
+    pub trackers: Vec<String>,
+    /// Whether the torrent has already been added
+    pub duplicate: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PreviewFile {
+    pub path: String,
+    pub size: u64,
+}
+
+/// Identifying metadata of a torrent, see GET_TORRENT_INFO.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TorrentInfo {
+    pub id: String,
+    /// SHA-1 of the info dict, None for v2 only torrents
+    pub info_hash: Option<String>,
+    /// SHA-256 of the info dict, None for v1 and hybrid torrents
+    pub info_hash_v2: Option<String>,
+    pub piece_size: u32,
+    pub pieces: u64,
+    /// Length of the bencoded info dict
+    pub info_size: u64,
+    /// When the requested info dict stops being downloadable
+    pub raw_expires: Option<DateTime<Utc>>,
+}
+
+/// A transient event, such as a torrent completing, see NOTIFICATION.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Notification {
+    pub id: u64,
+    pub severity: Severity,
+    pub kind: NotificationKind,
+    /// ID of the resource the event concerns
+    pub resource: Option<String>,
+    pub message: String,
+    pub time: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationKind {
+    TorrentCompleted,
+    TrackerError,
+    DiskFull,
+    DiskError,
+    UpdateAvailable,
+}
+
+/// Encoding of a file manifest, see GET_FILE_MANIFEST.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ManifestFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+impl ManifestFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ManifestFormat::Json => "json",
+            ManifestFormat::Csv => "csv",
+        }
+    }
+}
+
+/// Result of recounting piece availability, see AUDIT_AVAILABILITY.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AvailabilityAudit {
+    pub id: String,
+    /// Peers whose bitfields were recounted
+    pub peers: u64,
+    /// Pieces whose count in the picker differs from the recount
+    pub drift: Vec<PieceDrift>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PieceDrift {
+    pub piece: u32,
+    /// Peers the picker counts as having the piece
+    pub counted: u64,
+    /// Peers which actually have it
+    pub actual: u64,
+}
+
+/// Result of GET_PIECE_MAP, in the order of the requested pieces and files.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PieceMap {
+    pub id: String,
+    pub pieces: Vec<PieceFiles>,
+    pub files: Vec<FilePieces>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PieceFiles {
+    pub piece: u32,
+    pub files: Vec<FileSpan>,
+}
+
+/// Range of a file held by a piece.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FileSpan {
+    /// ID of the file resource
+    pub id: String,
+    /// Offset of the range in the file
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FilePieces {
+    pub id: String,
+    /// Pieces holding the start and end of the file, None if it's empty
+    pub first_piece: Option<u32>,
+    pub last_piece: Option<u32>,
+}
+
+/// An RPC client connected to the server.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RpcClient {
+    pub id: u64,
+    pub addr: String,
+    pub connected: DateTime<Utc>,
+    /// User-Agent header sent when the connection was upgraded
+    pub user_agent: Option<String>,
+    pub subscriptions: u64,
+    pub filters: u64,
+    pub messages_in: u64,
+    pub messages_out: u64,
+    /// Recent messages per second received from and sent to the client
+    pub rate_in: f32,
+    pub rate_out: f32,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -207,4 +596,135 @@ mod tests {
             unreachable!();
         }
     }
+
+    #[test]
+    fn test_update_matching_repr() {
+        let data = r#"
+            {
+                "type": "UPDATE_MATCHING",
+                "serial": 3,
+                "criteria": [
+                    { "field": "user_data/label", "op": "==", "value": "linux" }
+                ],
+                "resource": { "path": "/mnt/isos" },
+                "paused": true
+            }
+            "#;
+        let m = serde_json::from_str(data).unwrap();
+        if let CMessage::UpdateMatching {
+            serial: 3,
+            criteria,
+            resource: Some(r),
+            paused: Some(true),
+        } = m
+        {
+            assert_eq!(criteria[0].field, "user_data/label");
+            assert_eq!(r.id, "");
+            assert_eq!(r.path, Some("/mnt/isos".to_owned()));
+        } else {
+            unreachable!();
+        }
+    }
+
+    #[test]
+    fn test_reannounce_repr() {
+        let m = serde_json::from_str(r#"{ "type": "REANNOUNCE_TORRENT", "serial": 1 }"#).unwrap();
+        assert!(matches!(
+            m,
+            CMessage::ReannounceTorrent {
+                serial: 1,
+                id: None
+            }
+        ));
+    }
+
+    #[test]
+    fn test_read_head_repr() {
+        let m = serde_json::from_str(
+            r#"{ "type": "SET_READ_HEAD", "serial": 1, "id": "f", "offset": 1024 }"#,
+        )
+        .unwrap();
+        if let CMessage::SetReadHead { serial, id, offset } = m {
+            assert_eq!((serial, id.as_str(), offset), (1, "f", 1024));
+        } else {
+            unreachable!();
+        }
+    }
+
+    #[test]
+    fn test_relocate_repr() {
+        let m = serde_json::from_str(
+            r#"{ "type": "RELOCATE_TORRENT", "serial": 1, "id": "t", "path": "/mnt/data" }"#,
+        )
+        .unwrap();
+        if let CMessage::RelocateTorrent { serial, id, path } = m {
+            assert_eq!((serial, id.as_str(), path.as_str()), (1, "t", "/mnt/data"));
+        } else {
+            unreachable!();
+        }
+    }
+
+    #[test]
+    fn test_notification_repr() {
+        let n = Notification {
+            id: 1,
+            severity: Severity::Warning,
+            kind: NotificationKind::TrackerError,
+            resource: None,
+            message: "Couldn't contact tracker".to_owned(),
+            time: Utc::now(),
+        };
+        let s = serde_json::to_value(SMessage::Notification(n)).unwrap();
+        assert_eq!(s["type"], "NOTIFICATION");
+        assert_eq!(s["severity"], "warning");
+        assert_eq!(s["kind"], "tracker_error");
+    }
+
+    #[test]
+    fn test_piece_deadline_repr() {
+        let m = serde_json::from_str(
+            r#"{ "type": "SET_PIECE_DEADLINE", "serial": 1, "id": "t", "pieces": [3, 4], "deadline": 500 }"#,
+        )
+        .unwrap();
+        if let CMessage::SetPieceDeadline {
+            pieces, deadline, ..
+        } = m
+        {
+            assert_eq!((pieces, deadline), (vec![3, 4], Some(500)));
+        } else {
+            unreachable!();
+        }
+        let m = serde_json::from_str(
+            r#"{ "type": "SET_PIECE_DEADLINE", "serial": 1, "id": "t", "pieces": [3] }"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            m,
+            CMessage::SetPieceDeadline { deadline: None, .. }
+        ));
+    }
+
+    #[test]
+    fn test_piece_map_repr() {
+        let m = serde_json::from_str(
+            r#"{ "type": "GET_PIECE_MAP", "serial": 1, "id": "t", "pieces": [7] }"#,
+        )
+        .unwrap();
+        if let CMessage::GetPieceMap { pieces, files, .. } = m {
+            assert_eq!(pieces, vec![7]);
+            assert!(files.is_empty());
+        } else {
+            unreachable!();
+        }
+    }
+
+    #[test]
+    fn test_shutdown_repr() {
+        let m = serde_json::from_str(r#"{ "type": "SHUTDOWN", "serial": 1 }"#).unwrap();
+        if let CMessage::Shutdown { restart, .. } = m {
+            assert!(!restart);
+        } else {
+            unreachable!();
+        }
+    }
 }
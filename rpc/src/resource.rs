@@ -63,6 +63,12 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         user_data: json::Value,
     },
+    Revision {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        revision: u64,
+    },
 
     ServerTransfer {
         id: String,
@@ -81,6 +87,42 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         free_space: u64,
     },
+    ServerPeers {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        peers: u64,
+        hosts: u64,
+    },
+    ServerUpdate {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        update_version: Option<String>,
+        update_url: Option<String>,
+    },
+    ServerReadCache {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        read_cache_size: u64,
+        read_cache_hits: u64,
+        read_cache_misses: u64,
+    },
+    ServerPort {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        port: u16,
+    },
+    ServerRpc {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        rpc_port: u16,
+        rpc_local: bool,
+        rpc_tls: bool,
+    },
     ServerToken {
         id: String,
         #[serde(rename = "type")]
@@ -94,6 +136,8 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         error: Option<String>,
         status: Status,
+        check_position: Option<u32>,
+        move_progress: Option<f32>,
     },
     TorrentTransfer {
         id: String,
@@ -112,11 +156,19 @@ pub enum SResourceUpdate<'a> {
         peers: u16,
         availability: f32,
     },
+    TorrentAvailability {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        distributed_copies: f32,
+        piece_availability: Vec<u16>,
+    },
     TorrentPicker {
         id: String,
         #[serde(rename = "type")]
         kind: ResourceKind,
         strategy: Strategy,
+        first_last_pieces: bool,
     },
     TorrentPriority {
         id: String,
@@ -124,12 +176,39 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         priority: u8,
     },
+    TorrentDht {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        dht: bool,
+        dht_peers: u64,
+    },
+    TorrentSuperSeed {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        super_seed: SuperSeed,
+        super_seeding: bool,
+    },
+    TorrentPolicy {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        encryption: Encryption,
+        proxy: Proxy,
+    },
     TorrentPath {
         id: String,
         #[serde(rename = "type")]
         kind: ResourceKind,
         path: String,
     },
+    TorrentCompletePath {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        complete_path: Option<String>,
+    },
     TorrentPieces {
         id: String,
         #[serde(rename = "type")]
@@ -143,6 +222,15 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         last_report: DateTime<Utc>,
         error: Option<String>,
+        announces: u64,
+        failures: u64,
+        response_time: Option<u64>,
+        last_announce: Option<DateTime<Utc>>,
+        next_announce: Option<DateTime<Utc>>,
+        last_error: Option<String>,
+        seeders: Option<u32>,
+        leechers: Option<u32>,
+        downloaded: Option<u32>,
     },
 
     FilePriority {
@@ -157,6 +245,12 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         progress: f32,
     },
+    FileDigest {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        sha256: Option<String>,
+    },
 
     PieceAvailable {
         id: String,
@@ -184,10 +278,24 @@ pub enum SResourceUpdate<'a> {
 #[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct CResourceUpdate {
+    /// Not required when the update applies to every resource matching a criteria
+    #[serde(default)]
     pub id: String,
     pub path: Option<String>,
+    #[serde(deserialize_with = "deserialize_complete_path")]
+    #[serde(default)]
+    pub complete_path: Option<Option<String>>,
     pub priority: Option<u8>,
     pub strategy: Option<Strategy>,
+    pub first_last_pieces: Option<bool>,
+    pub dht: Option<bool>,
+    pub super_seed: Option<SuperSeed>,
+    pub encryption: Option<Encryption>,
+    pub proxy: Option<Proxy>,
+    pub port: Option<u16>,
+    pub rpc_port: Option<u16>,
+    pub rpc_local: Option<bool>,
+    pub rpc_tls: Option<bool>,
     #[serde(deserialize_with = "deserialize_throttle")]
     #[serde(default)]
     pub throttle_up: Option<Option<i64>>,
@@ -195,6 +303,13 @@ pub struct CResourceUpdate {
     #[serde(default)]
     pub throttle_down: Option<Option<i64>>,
     pub user_data: Option<json::Value>,
+    /// New announce URL of a tracker
+    pub url: Option<String>,
+    /// Whether a torrent's data is moved to its new path, rather than
+    /// the torrent being pointed at data already there
+    pub move_data: Option<bool>,
+    /// Only apply the update if the resource is still at this revision
+    pub revision: Option<u64>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -211,7 +326,24 @@ pub struct Server {
     pub ses_transferred_up: u64,
     pub ses_transferred_down: u64,
     pub free_space: u64,
+    /// Open peer connections across all torrents
+    pub peers: u64,
+    /// Distinct remote hosts those connections are to
+    pub hosts: u64,
+    /// Bytes held by the disk read cache, and its hits and misses
+    pub read_cache_size: u64,
+    pub read_cache_hits: u64,
+    pub read_cache_misses: u64,
+    /// Newest release found by the update check, if newer than the server
+    pub update_version: Option<String>,
+    pub update_url: Option<String>,
+    pub port: u16,
+    pub rpc_port: u16,
+    pub rpc_local: bool,
+    pub rpc_tls: bool,
     pub started: DateTime<Utc>,
+    /// Incremented each time a client updates the resource
+    pub revision: u64,
     pub user_data: json::Value,
 }
 
@@ -248,6 +380,41 @@ impl Server {
             SResourceUpdate::ServerSpace { free_space, .. } => {
                 self.free_space = free_space;
             }
+            SResourceUpdate::ServerPeers { peers, hosts, .. } => {
+                self.peers = peers;
+                self.hosts = hosts;
+            }
+            SResourceUpdate::ServerReadCache {
+                read_cache_size,
+                read_cache_hits,
+                read_cache_misses,
+                ..
+            } => {
+                self.read_cache_size = read_cache_size;
+                self.read_cache_hits = read_cache_hits;
+                self.read_cache_misses = read_cache_misses;
+            }
+            SResourceUpdate::ServerUpdate {
+                update_version,
+                update_url,
+                ..
+            } => {
+                self.update_version = update_version;
+                self.update_url = update_url;
+            }
+            SResourceUpdate::ServerPort { port, .. } => {
+                self.port = port;
+            }
+            SResourceUpdate::ServerRpc {
+                rpc_port,
+                rpc_local,
+                rpc_tls,
+                ..
+            } => {
+                self.rpc_port = rpc_port;
+                self.rpc_local = rpc_local;
+                self.rpc_tls = rpc_tls;
+            }
             SResourceUpdate::Rate {
                 rate_up, rate_down, ..
             } => {
@@ -268,14 +435,29 @@ pub struct Torrent {
     pub comment: Option<String>,
     pub private: bool,
     pub path: String,
+    /// Directory the data is moved to once complete, if not the
+    /// configured one
+    pub complete_path: Option<String>,
     pub created: DateTime<Utc>,
     pub modified: DateTime<Utc>,
     pub status: Status,
     pub error: Option<String>,
+    pub check_position: Option<u32>,
+    pub move_progress: Option<f32>,
     pub priority: u8,
     pub progress: f32,
     pub availability: f32,
+    pub distributed_copies: f32,
+    /// Peers having each piece, see the RPC docs for ranges of pieces
+    pub piece_availability: Vec<u16>,
     pub strategy: Strategy,
+    pub first_last_pieces: bool,
+    pub dht: bool,
+    pub dht_peers: u64,
+    pub super_seed: SuperSeed,
+    pub super_seeding: bool,
+    pub encryption: Encryption,
+    pub proxy: Proxy,
     pub rate_up: u64,
     pub rate_down: u64,
     pub throttle_up: Option<i64>,
@@ -290,6 +472,8 @@ pub struct Torrent {
     pub piece_size: Option<u32>,
     pub piece_field: String,
     pub files: Option<u32>,
+    /// Incremented each time a client updates the resource
+    pub revision: u64,
     pub user_data: json::Value,
 }
 
@@ -305,9 +489,17 @@ impl Torrent {
                 self.throttle_up = throttle_up;
                 self.throttle_down = throttle_down;
             }
-            SResourceUpdate::TorrentStatus { error, status, .. } => {
+            SResourceUpdate::TorrentStatus {
+                error,
+                status,
+                check_position,
+                move_progress,
+                ..
+            } => {
                 self.error = error;
                 self.status = status;
+                self.check_position = check_position;
+                self.move_progress = move_progress;
             }
             SResourceUpdate::TorrentTransfer {
                 rate_up,
@@ -331,12 +523,49 @@ impl Torrent {
                 self.peers = peers;
                 self.availability = availability;
             }
-            SResourceUpdate::TorrentPicker { strategy, .. } => {
+            SResourceUpdate::TorrentAvailability {
+                distributed_copies,
+                piece_availability,
+                ..
+            } => {
+                self.distributed_copies = distributed_copies;
+                self.piece_availability = piece_availability;
+            }
+            SResourceUpdate::TorrentPicker {
+                strategy,
+                first_last_pieces,
+                ..
+            } => {
                 self.strategy = strategy;
+                self.first_last_pieces = first_last_pieces;
             }
             SResourceUpdate::TorrentPriority { priority, .. } => {
                 self.priority = priority;
             }
+            SResourceUpdate::TorrentDht { dht, dht_peers, .. } => {
+                self.dht = dht;
+                self.dht_peers = dht_peers;
+            }
+            SResourceUpdate::TorrentSuperSeed {
+                super_seed,
+                super_seeding,
+                ..
+            } => {
+                self.super_seed = super_seed;
+                self.super_seeding = super_seeding;
+            }
+            SResourceUpdate::TorrentPolicy {
+                encryption, proxy, ..
+            } => {
+                self.encryption = encryption;
+                self.proxy = proxy;
+            }
+            SResourceUpdate::TorrentPath { path, .. } => {
+                self.path = path;
+            }
+            SResourceUpdate::TorrentCompletePath { complete_path, .. } => {
+                self.complete_path = complete_path;
+            }
             SResourceUpdate::TorrentPieces { piece_field, .. } => {
                 self.piece_field = piece_field;
             }
@@ -372,6 +601,7 @@ pub enum Status {
 pub enum Strategy {
     Rarest,
     Sequential,
+    Streaming,
 }
 
 impl Strategy {
@@ -379,6 +609,71 @@ impl Strategy {
         match self {
             &Strategy::Rarest => "rarest",
             &Strategy::Sequential => "sequential",
+            &Strategy::Streaming => "streaming",
+        }
+    }
+}
+
+/// Whether a torrent super seeds (BEP 16), automatically engaged
+/// while it appears to be the only seed of its swarm.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[serde(deny_unknown_fields)]
+pub enum SuperSeed {
+    Auto,
+    Enabled,
+    Disabled,
+}
+
+impl SuperSeed {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SuperSeed::Auto => "auto",
+            SuperSeed::Enabled => "enabled",
+            SuperSeed::Disabled => "disabled",
+        }
+    }
+}
+
+/// Encryption (MSE/PE) a torrent requires of its peers, overriding
+/// the server's unless default.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[serde(deny_unknown_fields)]
+pub enum Encryption {
+    Default,
+    Disabled,
+    Enabled,
+    Required,
+}
+
+impl Encryption {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encryption::Default => "default",
+            Encryption::Disabled => "disabled",
+            Encryption::Enabled => "enabled",
+            Encryption::Required => "required",
+        }
+    }
+}
+
+/// Whether a torrent's tracker announces go through the server's proxy.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[serde(deny_unknown_fields)]
+pub enum Proxy {
+    Default,
+    Required,
+    Direct,
+}
+
+impl Proxy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Proxy::Default => "default",
+            Proxy::Required => "required",
+            Proxy::Direct => "direct",
         }
     }
 }
@@ -391,6 +686,8 @@ pub struct Piece {
     pub available: bool,
     pub downloaded: bool,
     pub index: u32,
+    /// Incremented each time a client updates the resource
+    pub revision: u64,
     pub user_data: json::Value,
 }
 
@@ -418,6 +715,10 @@ pub struct File {
     pub availability: f32,
     pub priority: u8,
     pub size: u64,
+    /// Hex encoded SHA-256 digest of the file, if computed
+    pub sha256: Option<String>,
+    /// Incremented each time a client updates the resource
+    pub revision: u64,
     pub user_data: json::Value,
 }
 
@@ -430,6 +731,9 @@ impl File {
             SResourceUpdate::FileProgress { progress, .. } => {
                 self.progress = progress;
             }
+            SResourceUpdate::FileDigest { sha256, .. } => {
+                self.sha256 = sha256;
+            }
             _ => {}
         }
     }
@@ -445,6 +749,8 @@ pub struct Peer {
     pub rate_up: u64,
     pub rate_down: u64,
     pub availability: f32,
+    /// Incremented each time a client updates the resource
+    pub revision: u64,
     pub user_data: json::Value,
 }
 
@@ -474,6 +780,26 @@ pub struct Tracker {
     pub url: Url,
     pub last_report: DateTime<Utc>,
     pub error: Option<String>,
+    /// Number of announces which succeeded
+    pub announces: u64,
+    /// Number of announces which failed or were rejected by the tracker
+    pub failures: u64,
+    /// Average announce response time in ms
+    pub response_time: Option<u64>,
+    /// Whether the tracker was learned from a peer (BEP 28)
+    pub from_peer: bool,
+    /// Time of the last successful announce
+    pub last_announce: Option<DateTime<Utc>>,
+    /// When the next announce is due, if this is the tracker announced to
+    pub next_announce: Option<DateTime<Utc>>,
+    /// Most recent error, kept after later announces succeed
+    pub last_error: Option<String>,
+    /// Swarm counts reported by the last successful announce
+    pub seeders: Option<u32>,
+    pub leechers: Option<u32>,
+    pub downloaded: Option<u32>,
+    /// Incremented each time a client updates the resource
+    pub revision: u64,
     pub user_data: json::Value,
 }
 
@@ -481,10 +807,30 @@ impl Tracker {
     pub fn update(&mut self, update: SResourceUpdate<'_>) {
         match update {
             SResourceUpdate::TrackerStatus {
-                last_report, error, ..
+                last_report,
+                error,
+                announces,
+                failures,
+                response_time,
+                last_announce,
+                next_announce,
+                last_error,
+                seeders,
+                leechers,
+                downloaded,
+                ..
             } => {
                 self.last_report = last_report;
                 self.error = error;
+                self.announces = announces;
+                self.failures = failures;
+                self.response_time = response_time;
+                self.last_announce = last_announce;
+                self.next_announce = next_announce;
+                self.last_error = last_error;
+                self.seeders = seeders;
+                self.leechers = leechers;
+                self.downloaded = downloaded;
             }
             _ => {}
         }
@@ -498,18 +844,30 @@ impl<'a> SResourceUpdate<'a> {
             &SResourceUpdate::Throttle { ref id, .. }
             | &SResourceUpdate::Rate { ref id, .. }
             | &SResourceUpdate::UserData { ref id, .. }
+            | &SResourceUpdate::Revision { ref id, .. }
             | &SResourceUpdate::ServerTransfer { ref id, .. }
             | &SResourceUpdate::ServerToken { ref id, .. }
             | &SResourceUpdate::ServerSpace { ref id, .. }
+            | &SResourceUpdate::ServerPeers { ref id, .. }
+            | &SResourceUpdate::ServerReadCache { ref id, .. }
+            | &SResourceUpdate::ServerUpdate { ref id, .. }
+            | &SResourceUpdate::ServerPort { ref id, .. }
+            | &SResourceUpdate::ServerRpc { ref id, .. }
             | &SResourceUpdate::TorrentStatus { ref id, .. }
             | &SResourceUpdate::TorrentTransfer { ref id, .. }
             | &SResourceUpdate::TorrentPeers { ref id, .. }
+            | &SResourceUpdate::TorrentAvailability { ref id, .. }
             | &SResourceUpdate::TorrentPicker { ref id, .. }
             | &SResourceUpdate::TorrentPriority { ref id, .. }
+            | &SResourceUpdate::TorrentDht { ref id, .. }
+            | &SResourceUpdate::TorrentSuperSeed { ref id, .. }
+            | &SResourceUpdate::TorrentPolicy { ref id, .. }
             | &SResourceUpdate::TorrentPath { ref id, .. }
+            | &SResourceUpdate::TorrentCompletePath { ref id, .. }
             | &SResourceUpdate::TorrentPieces { ref id, .. }
             | &SResourceUpdate::FilePriority { ref id, .. }
             | &SResourceUpdate::FileProgress { ref id, .. }
+            | &SResourceUpdate::FileDigest { ref id, .. }
             | &SResourceUpdate::TrackerStatus { ref id, .. }
             | &SResourceUpdate::PeerAvailability { ref id, .. }
             | &SResourceUpdate::PieceAvailable { ref id, .. }
@@ -611,7 +969,22 @@ impl Resource {
         }
     }
 
+    pub fn revision(&mut self) -> &mut u64 {
+        match self {
+            Resource::Server(r) => &mut r.revision,
+            Resource::Torrent(r) => &mut r.revision,
+            Resource::File(r) => &mut r.revision,
+            Resource::Piece(r) => &mut r.revision,
+            Resource::Peer(r) => &mut r.revision,
+            Resource::Tracker(r) => &mut r.revision,
+        }
+    }
+
     pub fn update(&mut self, update: SResourceUpdate<'_>) {
+        if let SResourceUpdate::Revision { revision, .. } = update {
+            *self.revision() = revision;
+            return;
+        }
         match self {
             &mut Resource::Server(ref mut s) => {
                 s.update(update);
@@ -718,6 +1091,8 @@ impl fmt::Display for Resource {
                 write!(f, "\n")?;
                 write!(f, "  strategy: {:?}", t.strategy)?;
                 write!(f, "\n")?;
+                write!(f, "  dht: {} ({} peers)", t.dht, t.dht_peers)?;
+                write!(f, "\n")?;
                 write!(f, "  upload: {} B/s", t.rate_up)?;
                 write!(f, "\n")?;
                 write!(f, "  download: {} B/s", t.rate_down)?;
@@ -810,6 +1185,20 @@ where
     }
 }
 
+fn deserialize_complete_path<'de, D>(de: D) -> Result<Option<Option<String>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let deser_result = serde::Deserialize::deserialize(de)?;
+    match deser_result {
+        json::Value::Null => Ok(Some(None)),
+        json::Value::String(s) => Ok(Some(Some(s))),
+        _ => Err(serde::de::Error::custom(
+            "Complete path must be string or null",
+        )),
+    }
+}
+
 // TODO: Proc macros to remove this shit
 
 impl Queryable for Resource {
@@ -861,9 +1250,31 @@ impl Queryable for Server {
             "ses_transferred_up" => Some(Field::N(self.ses_transferred_up as i64)),
             "ses_transferred_down" => Some(Field::N(self.ses_transferred_down as i64)),
             "free_space" => Some(Field::N(self.free_space as i64)),
+            "peers" => Some(Field::N(self.peers as i64)),
+            "hosts" => Some(Field::N(self.hosts as i64)),
+            "read_cache_size" => Some(Field::N(self.read_cache_size as i64)),
+            "read_cache_hits" => Some(Field::N(self.read_cache_hits as i64)),
+            "read_cache_misses" => Some(Field::N(self.read_cache_misses as i64)),
+            "update_version" => Some(
+                self.update_version
+                    .as_ref()
+                    .map(|v| Field::S(v.as_str()))
+                    .unwrap_or(FNULL),
+            ),
+            "update_url" => Some(
+                self.update_url
+                    .as_ref()
+                    .map(|v| Field::S(v.as_str()))
+                    .unwrap_or(FNULL),
+            ),
+            "port" => Some(Field::N(i64::from(self.port))),
+            "rpc_port" => Some(Field::N(i64::from(self.rpc_port))),
+            "rpc_local" => Some(Field::B(self.rpc_local)),
+            "rpc_tls" => Some(Field::B(self.rpc_tls)),
 
             "started" => Some(Field::D(self.started)),
 
+            "revision" => Some(Field::N(self.revision as i64)),
             _ if f.starts_with("user_data") => self.user_data.field(&f[9..]),
 
             _ => None,
@@ -882,6 +1293,12 @@ impl Queryable for Torrent {
                     .unwrap_or(FNULL),
             ),
             "private" => Some(Field::B(self.private)),
+            "dht" => Some(Field::B(self.dht)),
+            "first_last_pieces" => Some(Field::B(self.first_last_pieces)),
+            "super_seed" => Some(Field::S(self.super_seed.as_str())),
+            "super_seeding" => Some(Field::B(self.super_seeding)),
+            "encryption" => Some(Field::S(self.encryption.as_str())),
+            "proxy" => Some(Field::S(self.proxy.as_str())),
             "creator" => Some(
                 self.creator
                     .as_ref()
@@ -895,6 +1312,12 @@ impl Queryable for Torrent {
                     .unwrap_or(FNULL),
             ),
             "path" => Some(Field::S(&self.path)),
+            "complete_path" => Some(
+                self.complete_path
+                    .as_ref()
+                    .map(|v| Field::S(v.as_str()))
+                    .unwrap_or(FNULL),
+            ),
             "status" => Some(Field::S(self.status.as_str())),
             "error" => Some(
                 self.error
@@ -902,6 +1325,12 @@ impl Queryable for Torrent {
                     .map(|v| Field::S(v.as_str()))
                     .unwrap_or(FNULL),
             ),
+            "check_position" => Some(
+                self.check_position
+                    .map(|v| Field::N(i64::from(v)))
+                    .unwrap_or(FNULL),
+            ),
+            "move_progress" => Some(self.move_progress.map(Field::F).unwrap_or(FNULL)),
 
             "priority" => Some(Field::N(self.priority as i64)),
             "rate_up" => Some(Field::N(self.rate_up as i64)),
@@ -911,6 +1340,7 @@ impl Queryable for Torrent {
             "transferred_up" => Some(Field::N(self.transferred_up as i64)),
             "transferred_down" => Some(Field::N(self.transferred_down as i64)),
             "peers" => Some(Field::N(self.peers as i64)),
+            "dht_peers" => Some(Field::N(self.dht_peers as i64)),
             "trackers" => Some(Field::N(self.trackers as i64)),
             "tracker_urls" => Some(Field::V(
                 self.tracker_urls.iter().map(|url| Field::S(url)).collect(),
@@ -925,9 +1355,11 @@ impl Queryable for Torrent {
 
             "progress" => Some(Field::F(self.progress)),
             "availability" => Some(Field::F(self.availability)),
+            "distributed_copies" => Some(Field::F(self.distributed_copies)),
 
             "strategy" => Some(Field::S(self.strategy.as_str())),
 
+            "revision" => Some(Field::N(self.revision as i64)),
             _ if f.starts_with("user_data") => self.user_data.field(&f[9..]),
 
             _ if f.starts_with("tracker/") => Some(Field::R(ResourceKind::Tracker)),
@@ -948,6 +1380,7 @@ impl Queryable for Piece {
             "available" => Some(Field::B(self.available)),
             "downloaded" => Some(Field::B(self.downloaded)),
 
+            "revision" => Some(Field::N(self.revision as i64)),
             _ if f.starts_with("user_data") => self.user_data.field(&f[9..]),
 
             _ => None,
@@ -966,6 +1399,14 @@ impl Queryable for File {
 
             "progress" => Some(Field::F(self.progress)),
 
+            "sha256" => Some(
+                self.sha256
+                    .as_ref()
+                    .map(|v| Field::S(v.as_str()))
+                    .unwrap_or(FNULL),
+            ),
+
+            "revision" => Some(Field::N(self.revision as i64)),
             _ if f.starts_with("user_data") => self.user_data.field(&f[9..]),
 
             _ => None,
@@ -987,6 +1428,7 @@ impl Queryable for Peer {
 
             "client_id" => Some(Field::S(&self.client_id)),
 
+            "revision" => Some(Field::N(self.revision as i64)),
             _ if f.starts_with("user_data") => self.user_data.field(&f[9..]),
 
             _ => None,
@@ -1008,7 +1450,27 @@ impl Queryable for Tracker {
             ),
 
             "last_report" => Some(Field::D(self.last_report)),
+            "announces" => Some(Field::N(self.announces as i64)),
+            "failures" => Some(Field::N(self.failures as i64)),
+            "response_time" => Some(
+                self.response_time
+                    .map(|v| Field::N(v as i64))
+                    .unwrap_or(FNULL),
+            ),
+            "from_peer" => Some(Field::B(self.from_peer)),
+            "last_announce" => Some(self.last_announce.map(Field::D).unwrap_or(FNULL)),
+            "next_announce" => Some(self.next_announce.map(Field::D).unwrap_or(FNULL)),
+            "last_error" => Some(
+                self.last_error
+                    .as_ref()
+                    .map(|v| Field::S(v.as_str()))
+                    .unwrap_or(FNULL),
+            ),
+            "seeders" => Some(self.seeders.map(|v| Field::N(v.into())).unwrap_or(FNULL)),
+            "leechers" => Some(self.leechers.map(|v| Field::N(v.into())).unwrap_or(FNULL)),
+            "downloaded" => Some(self.downloaded.map(|v| Field::N(v.into())).unwrap_or(FNULL)),
 
+            "revision" => Some(Field::N(self.revision as i64)),
             _ if f.starts_with("user_data") => self.user_data.field(&f[9..]),
 
             _ => None,
@@ -1070,8 +1532,20 @@ impl Default for Server {
             ses_transferred_up: 0,
             ses_transferred_down: 0,
             free_space: 0,
+            peers: 0,
+            hosts: 0,
+            read_cache_size: 0,
+            read_cache_hits: 0,
+            read_cache_misses: 0,
+            update_version: None,
+            update_url: None,
+            port: 0,
+            rpc_port: 0,
+            rpc_local: true,
+            rpc_tls: false,
             download_token: "".to_owned(),
             started: Utc::now(),
+            revision: 0,
             user_data: json::Value::Null,
         }
     }
@@ -1086,14 +1560,26 @@ impl Default for Torrent {
             creator: None,
             private: false,
             path: "".to_owned(),
+            complete_path: None,
             created: Utc::now(),
             modified: Utc::now(),
             status: Default::default(),
             error: None,
+            check_position: None,
+            move_progress: None,
             priority: 0,
             progress: 0.,
             availability: 0.,
+            distributed_copies: 0.,
+            piece_availability: vec![],
             strategy: Strategy::Rarest,
+            first_last_pieces: false,
+            dht: true,
+            dht_peers: 0,
+            super_seed: SuperSeed::Auto,
+            super_seeding: false,
+            encryption: Encryption::Default,
+            proxy: Proxy::Default,
             rate_up: 0,
             rate_down: 0,
             throttle_up: None,
@@ -1108,6 +1594,7 @@ impl Default for Torrent {
             piece_size: None,
             piece_field: "".to_owned(),
             files: None,
+            revision: 0,
             user_data: json::Value::Null,
         }
     }
@@ -1121,6 +1608,17 @@ impl Default for Tracker {
             url: Url::parse("http://my.tracker/announce").unwrap(),
             last_report: Utc::now(),
             error: None,
+            announces: 0,
+            failures: 0,
+            response_time: None,
+            from_peer: false,
+            last_announce: None,
+            next_announce: None,
+            last_error: None,
+            seeders: None,
+            leechers: None,
+            downloaded: None,
+            revision: 0,
             user_data: json::Value::Null,
         }
     }
@@ -3,7 +3,7 @@ extern crate serde_derive;
 
 pub mod torrent {
     pub use self::current::Session;
-    pub use self::ver_fa1b6f as current;
+    pub use self::ver_d81c4f as current;
 
     #[derive(Serialize, Deserialize, Clone)]
     pub struct Bitfield {
@@ -12,8 +12,10 @@ pub mod torrent {
     }
 
     pub fn load(data: &[u8]) -> Option<Session> {
-        if let Ok(m) = bincode::deserialize::<ver_fa1b6f::Session>(data) {
+        if let Ok(m) = bincode::deserialize::<ver_d81c4f::Session>(data) {
             Some(m)
+        } else if let Ok(m) = bincode::deserialize::<ver_fa1b6f::Session>(data) {
+            Some(m.migrate())
         } else if let Ok(m) = bincode::deserialize::<ver_6e27af::Session>(data) {
             Some(m.migrate())
         } else if let Ok(m) = bincode::deserialize::<ver_249b1b::Session>(data) {
@@ -33,7 +35,7 @@ pub mod torrent {
         }
     }
 
-    pub mod ver_fa1b6f {
+    pub mod ver_d81c4f {
         use super::Bitfield;
 
         use chrono::{DateTime, Utc};
@@ -54,6 +56,34 @@ pub mod torrent {
             pub throttle_ul: Option<i64>,
             pub throttle_dl: Option<i64>,
             pub trackers: Vec<String>,
+            pub web_seeds: Vec<String>,
+            pub http_seeds: Vec<String>,
+            pub dht: bool,
+            pub completed: Option<DateTime<Utc>>,
+            /// Completed blocks of partially downloaded pieces
+            pub partial: Vec<(u32, Bitfield)>,
+            /// BEP 12 tier of each tracker
+            pub tracker_tiers: Vec<u32>,
+            /// Key sent in announces, kept across restarts
+            pub announce_key: Option<u32>,
+            /// Super seeding setting, None if automatic
+            pub super_seed: Option<bool>,
+            /// How pieces are picked
+            pub strategy: Strategy,
+            /// Whether the first and last pieces of files are picked first
+            pub first_last: bool,
+            /// SHA-256 digests of the files, computed once complete
+            pub file_digests: Vec<Option<[u8; 32]>>,
+            /// Directory to move the data to once complete, overriding
+            /// the configured one
+            pub complete_path: Option<String>,
+            /// Encryption required of peers, None to use the config
+            pub encryption: Option<Encryption>,
+            /// Whether announces must (or mustn't) go through the proxy,
+            /// None to use it if configured
+            pub proxy: Option<bool>,
+            /// SHA-256 infohash of v2 and hybrid torrents
+            pub info_hash_v2: Option<[u8; 32]>,
         }
 
         #[derive(Clone, Serialize, Deserialize)]
@@ -70,12 +100,14 @@ pub mod torrent {
             pub private: bool,
             pub be_name: Option<Vec<u8>>,
             pub piece_idx: Vec<(usize, u64)>,
+            pub meta_version: u8,
         }
 
         #[derive(Serialize, Deserialize, Clone, Debug)]
         pub struct File {
             pub path: PathBuf,
             pub length: u64,
+            pub pieces_root: Option<[u8; 32]>,
         }
 
         #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -86,6 +118,23 @@ pub mod torrent {
             pub state: StatusState,
         }
 
+        #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+        pub enum Strategy {
+            Rarest,
+            Sequential,
+            /// Rarest first after the pieces from the read head on
+            Streaming {
+                head: u32,
+            },
+        }
+
+        #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+        pub enum Encryption {
+            Disabled,
+            Enabled,
+            Required,
+        }
+
         #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
         pub enum StatusState {
             Magnet,
@@ -96,6 +145,114 @@ pub mod torrent {
         }
     }
 
+    pub mod ver_fa1b6f {
+        pub use self::next::{Status, StatusState};
+        pub use super::ver_d81c4f as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        use std::path::PathBuf;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+        }
+
+        #[derive(Clone, Serialize, Deserialize)]
+        pub struct Info {
+            pub name: String,
+            pub announce: Option<String>,
+            pub creator: Option<String>,
+            pub comment: Option<String>,
+            pub piece_len: u32,
+            pub total_len: u64,
+            pub hashes: Vec<Vec<u8>>,
+            pub hash: [u8; 20],
+            pub files: Vec<File>,
+            pub private: bool,
+            pub be_name: Option<Vec<u8>>,
+            pub piece_idx: Vec<(usize, u64)>,
+        }
+
+        #[derive(Serialize, Deserialize, Clone, Debug)]
+        pub struct File {
+            pub path: PathBuf,
+            pub length: u64,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: next::Info {
+                        name: self.info.name,
+                        announce: self.info.announce,
+                        creator: self.info.creator,
+                        comment: self.info.comment,
+                        piece_len: self.info.piece_len,
+                        total_len: self.info.total_len,
+                        hashes: self.info.hashes,
+                        hash: self.info.hash,
+                        files: self
+                            .info
+                            .files
+                            .into_iter()
+                            .map(|f| next::File {
+                                path: f.path,
+                                length: f.length,
+                                pieces_root: None,
+                            })
+                            .collect(),
+                        private: self.info.private,
+                        be_name: self.info.be_name,
+                        piece_idx: self.info.piece_idx,
+                        meta_version: 1,
+                    },
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    web_seeds: Vec::new(),
+                    http_seeds: Vec::new(),
+                    dht: true,
+                    // Dated by the data's mtime once loaded
+                    completed: None,
+                    partial: Vec::new(),
+                    tracker_tiers: Vec::new(),
+                    announce_key: None,
+                    super_seed: None,
+                    strategy: next::Strategy::Rarest,
+                    first_last: false,
+                    file_digests: Vec::new(),
+                    complete_path: None,
+                    encryption: None,
+                    proxy: None,
+                    info_hash_v2: None,
+                }
+                .migrate()
+            }
+        }
+    }
+
     pub mod ver_6e27af {
         pub use self::next::{File, Status, StatusState};
         pub use super::ver_fa1b6f as next;
@@ -1,13 +1,16 @@
 use std::env;
 use std::process;
+use std::time::Duration;
 
 use getopts::Options;
 
+use crate::bench::{self, Benchmark};
 use crate::log;
 
 pub struct Args {
     pub config: Option<String>,
     pub level: Option<log::LogLevel>,
+    pub benchmark: Option<Benchmark>,
 }
 
 pub fn args() -> Args {
@@ -16,6 +19,18 @@ pub fn args() -> Args {
     opts.optflag("h", "help", "Show help message.");
     opts.optflag("d", "debug", "Enable debug logging.");
     opts.optopt("c", "config", "Use config file.", "FILE");
+    opts.optopt(
+        "b",
+        "benchmark",
+        "Seed the torrent, which must be added and complete, to a local leecher and report throughput.",
+        "TORRENT",
+    );
+    opts.optopt(
+        "",
+        "benchmark-secs",
+        "Duration of the benchmark, 30 seconds by default.",
+        "SECS",
+    );
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(f) => {
@@ -31,6 +46,7 @@ pub fn args() -> Args {
     let mut args = Args {
         config: None,
         level: None,
+        benchmark: None,
     };
 
     if matches.opt_present("d") {
@@ -41,6 +57,21 @@ pub fn args() -> Args {
         args.config = Some(cfg);
     }
 
+    if let Some(torrent) = matches.opt_str("b") {
+        let secs = match matches.opt_str("benchmark-secs").map(|s| s.parse()) {
+            None => bench::DEFAULT_SECS,
+            Some(Ok(secs)) => secs,
+            Some(Err(_)) => {
+                println!("Invalid benchmark duration");
+                usage(1, opts);
+            }
+        };
+        args.benchmark = Some(Benchmark {
+            torrent,
+            duration: Duration::from_secs(secs),
+        });
+    }
+
     args
 }
 
@@ -0,0 +1,370 @@
+//! Seeding benchmark, in which an in-process leecher downloads a
+//! complete torrent from the daemon over loopback, so connections,
+//! the throttler and disk reads are all exercised as for real peers.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::bencode;
+use crate::torrent::{Info, Message};
+use crate::util::{random_string, FHashMap};
+use crate::{PORT, SHUTDOWN};
+
+/// Default duration of a benchmark
+pub const DEFAULT_SECS: u64 = 30;
+/// Block requests kept outstanding
+const QUEUE_LEN: usize = 64;
+const BLOCK_LEN: u32 = 16_384;
+/// Largest frame accepted, other than bitfields
+const MAX_FRAME: usize = 2 * BLOCK_LEN as usize;
+/// Time allowed for the daemon to answer before giving up
+const TIMEOUT: Duration = Duration::from_secs(15);
+
+pub struct Benchmark {
+    /// Path of the torrent file, which must be added to the daemon
+    /// and complete
+    pub torrent: String,
+    pub duration: Duration,
+}
+
+struct Report {
+    elapsed: Duration,
+    bytes: u64,
+    pieces: u64,
+    /// Pieces which failed their hash check
+    failed: u64,
+    /// CPU ticks used by each thread name, in user and system mode
+    cpu: BTreeMap<String, (u64, u64)>,
+}
+
+/// Runs the benchmark in its own thread, shutting down once done.
+pub fn start(bench: Benchmark) -> thread::JoinHandle<()> {
+    thread::Builder::new()
+        .name("bench".to_string())
+        .spawn(move || {
+            match run(&bench) {
+                Ok(report) => report.print(),
+                Err(e) => error!("Benchmark failed: {}", e),
+            }
+            SHUTDOWN.store(true, atomic::Ordering::SeqCst);
+        })
+        .unwrap()
+}
+
+fn run(bench: &Benchmark) -> io::Result<Report> {
+    let info = load_info(&bench.torrent)?;
+    info!("Benchmarking {} for {:?}", info.name, bench.duration);
+    let mut peer = Leecher::connect(&info)?;
+    peer.wait_unchoke()?;
+    let mut blocks = Blocks::new(&info, &peer.have);
+    if blocks.pieces.is_empty() {
+        return Err(invalid("the daemon has no pieces of the torrent"));
+    }
+
+    let start = Instant::now();
+    let cpu_start = thread_cpu();
+    let mut queued = 0;
+    let mut partial: FHashMap<u32, Vec<u8>> = FHashMap::default();
+    let (mut bytes, mut pieces, mut failed) = (0, 0, 0);
+    while start.elapsed() < bench.duration {
+        while queued < QUEUE_LEN && !peer.choked {
+            let (index, begin, length) = blocks.next(&info);
+            peer.send(Message::request(index, begin, length))?;
+            queued += 1;
+        }
+        match peer.read()? {
+            Frame::Piece { index, begin } => {
+                queued -= 1;
+                let data = &peer.buf[9..];
+                if index >= info.pieces()
+                    || begin as usize + data.len() > info.piece_len(index) as usize
+                {
+                    return Err(invalid("block outside of the torrent"));
+                }
+                bytes += data.len() as u64;
+                let piece = partial
+                    .entry(index)
+                    .or_insert_with(|| vec![0; info.piece_len(index) as usize]);
+                piece[begin as usize..begin as usize + data.len()].copy_from_slice(data);
+                if begin as usize + data.len() == piece.len() {
+                    if info.piece_valid(index, piece) {
+                        pieces += 1;
+                    } else {
+                        failed += 1;
+                    }
+                    partial.remove(&index);
+                }
+            }
+            Frame::Reject => queued -= 1,
+            // Requests are dropped on choke, and made again once unchoked
+            Frame::Choke => {
+                queued = 0;
+                partial.clear();
+                peer.wait_unchoke()?;
+            }
+            _ => {}
+        }
+    }
+    let elapsed = start.elapsed();
+    peer.conn.shutdown(Shutdown::Both).ok();
+
+    let mut cpu = thread_cpu();
+    for (name, (user, sys)) in &mut cpu {
+        if let Some(&(u, s)) = cpu_start.get(name) {
+            *user = user.saturating_sub(u);
+            *sys = sys.saturating_sub(s);
+        }
+    }
+    Ok(Report {
+        elapsed,
+        bytes,
+        pieces,
+        failed,
+        cpu,
+    })
+}
+
+fn load_info(path: &str) -> io::Result<Info> {
+    let data = fs::read(path)?;
+    let b = bencode::decode_buf(&data).map_err(|_| invalid("invalid torrent file"))?;
+    Info::from_bencode(b).map_err(invalid)
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_owned())
+}
+
+enum Frame {
+    Choke,
+    Unchoke,
+    Have(u32),
+    Bitfield,
+    HaveAll,
+    Piece { index: u32, begin: u32 },
+    Reject,
+    Other,
+}
+
+/// A minimal blocking peer, which only downloads.
+struct Leecher {
+    conn: TcpStream,
+    reader: BufReader<TcpStream>,
+    /// Payload of the last frame read
+    buf: Vec<u8>,
+    have: Vec<bool>,
+    choked: bool,
+}
+
+impl Leecher {
+    fn connect(info: &Info) -> io::Result<Leecher> {
+        let port = PORT.load(atomic::Ordering::Relaxed);
+        let conn = TcpStream::connect(("localhost", port))?;
+        conn.set_read_timeout(Some(TIMEOUT))?;
+        conn.set_nodelay(true)?;
+        let mut peer = Leecher {
+            reader: BufReader::with_capacity(1024 * 1024, conn.try_clone()?),
+            conn,
+            buf: Vec::new(),
+            have: vec![false; info.pieces() as usize],
+            choked: true,
+        };
+
+        let mut id = [0u8; 20];
+        id.copy_from_slice(format!("-SYBNCH-{}", random_string(12)).as_bytes());
+        peer.send(Message::handshake(&id, &info.hash))?;
+        let mut hs = [0u8; 68];
+        peer.reader
+            .read_exact(&mut hs)
+            .map_err(|_| invalid("the daemon closed the connection, is the torrent added?"))?;
+        if hs[28..48] != info.hash {
+            return Err(invalid("handshake for another torrent"));
+        }
+        peer.send(Message::Interested)?;
+        Ok(peer)
+    }
+
+    fn send(&mut self, msg: Message) -> io::Result<()> {
+        let mut buf = vec![0u8; msg.len()];
+        msg.encode(&mut buf)?;
+        self.conn.write_all(&buf)
+    }
+
+    fn wait_unchoke(&mut self) -> io::Result<()> {
+        loop {
+            match self.read()? {
+                Frame::Unchoke => return Ok(()),
+                Frame::Have(idx) if (idx as usize) < self.have.len() => {
+                    self.have[idx as usize] = true
+                }
+                Frame::HaveAll => self.have.iter_mut().for_each(|h| *h = true),
+                Frame::Bitfield => {
+                    for (i, h) in self.have.iter_mut().enumerate() {
+                        *h = self.buf.get(1 + i / 8).copied().unwrap_or(0) & (0x80 >> (i % 8)) != 0;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Reads a frame into buf, with its message ID first.
+    fn read(&mut self) -> io::Result<Frame> {
+        let mut len = [0u8; 4];
+        self.reader.read_exact(&mut len)?;
+        let len = BigEndian::read_u32(&len) as usize;
+        if len > MAX_FRAME.max(self.have.len() / 8 + 2) {
+            return Err(invalid("oversized message"));
+        }
+        self.buf.resize(len, 0);
+        self.reader.read_exact(&mut self.buf)?;
+        let u32_at = |b: &[u8], i: usize| b.get(i..i + 4).map(BigEndian::read_u32);
+        let frame = match self.buf.first() {
+            Some(0) => Frame::Choke,
+            Some(1) => Frame::Unchoke,
+            Some(4) => u32_at(&self.buf, 1).map_or(Frame::Other, Frame::Have),
+            Some(5) => Frame::Bitfield,
+            Some(7) if len >= 9 => Frame::Piece {
+                index: u32_at(&self.buf, 1).unwrap(),
+                begin: u32_at(&self.buf, 5).unwrap(),
+            },
+            Some(14) => Frame::HaveAll,
+            Some(16) => Frame::Reject,
+            _ => Frame::Other,
+        };
+        match frame {
+            Frame::Choke => self.choked = true,
+            Frame::Unchoke => self.choked = false,
+            _ => {}
+        }
+        Ok(frame)
+    }
+}
+
+/// Cycles through the blocks of every piece the daemon has, so the
+/// benchmark can run for longer than a single download would take.
+struct Blocks {
+    pieces: Vec<u32>,
+    pos: usize,
+    begin: u32,
+}
+
+impl Blocks {
+    fn new(info: &Info, have: &[bool]) -> Blocks {
+        let pieces = (0..info.pieces())
+            .filter(|&i| have[i as usize] && info.piece_len(i) > 0)
+            .collect();
+        Blocks {
+            pieces,
+            pos: 0,
+            begin: 0,
+        }
+    }
+
+    /// Returns the index, offset and length of the next block request.
+    fn next(&mut self, info: &Info) -> (u32, u32, u32) {
+        let index = self.pieces[self.pos];
+        let begin = self.begin;
+        let length = BLOCK_LEN.min(info.piece_len(index) - begin);
+        self.begin += length;
+        if self.begin == info.piece_len(index) {
+            self.begin = 0;
+            self.pos = (self.pos + 1) % self.pieces.len();
+        }
+        (index, begin, length)
+    }
+}
+
+/// CPU ticks used so far by the threads of this process, summed by
+/// thread name. Empty where /proc isn't available.
+fn thread_cpu() -> BTreeMap<String, (u64, u64)> {
+    let mut cpu = BTreeMap::new();
+    let tasks = match fs::read_dir("/proc/self/task") {
+        Ok(t) => t,
+        Err(_) => return cpu,
+    };
+    for task in tasks.filter_map(|t| t.ok()) {
+        let stat = fs::read_to_string(task.path().join("stat")).unwrap_or_default();
+        if let Some((name, user, sys)) = parse_stat(&stat) {
+            let entry = cpu.entry(name).or_insert((0, 0));
+            entry.0 += user;
+            entry.1 += sys;
+        }
+    }
+    cpu
+}
+
+/// Parses the name, user and system time of a /proc stat line.
+fn parse_stat(stat: &str) -> Option<(String, u64, u64)> {
+    // The name is in parentheses and may itself contain them
+    let start = stat.find('(')?;
+    let end = stat.rfind(')')?;
+    let name = stat.get(start + 1..end)?.to_owned();
+    // utime and stime are the 14th and 15th fields, the name the 2nd
+    let mut fields = stat[end + 1..].split_whitespace().skip(11);
+    let user = fields.next()?.parse().ok()?;
+    let sys = fields.next()?.parse().ok()?;
+    Some((name, user, sys))
+}
+
+impl Report {
+    fn print(&self) {
+        let secs = self.elapsed.as_secs_f64();
+        let mib = self.bytes as f64 / (1024. * 1024.);
+        println!("Benchmark ran for {:.1}s", secs);
+        println!(
+            "  {:.1} MiB downloaded, {:.1} MiB/s, {:.1} pieces/s",
+            mib,
+            mib / secs,
+            self.pieces as f64 / secs
+        );
+        if self.failed != 0 {
+            println!("  {} pieces failed their hash check", self.failed);
+        }
+        if self.cpu.is_empty() {
+            return;
+        }
+        let tick = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as f64;
+        println!("  CPU time by thread (user, system, share of wall time):");
+        for (name, &(user, sys)) in &self.cpu {
+            let (user, sys) = (user as f64 / tick, sys as f64 / tick);
+            println!(
+                "    {:<12} {:>7.2}s {:>7.2}s {:>6.1}%",
+                name,
+                user,
+                sys,
+                100. * (user + sys) / secs
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_stat() {
+        let stat = "4242 (disk (1)) S 1 4242 4242 0 -1 4194624 530 0 0 0 17 5 0 0 20 0 7 0";
+        assert_eq!(parse_stat(stat), Some(("disk (1)".to_owned(), 17, 5)));
+        assert_eq!(parse_stat("4242 (rpc) S 1"), None);
+    }
+
+    #[test]
+    fn test_blocks() {
+        let mut info = Info::with_pieces_scale(3, 2);
+        info.total_len -= 100;
+        let mut blocks = Blocks::new(&info, &[true, false, true]);
+        assert_eq!(blocks.next(&info), (0, 0, BLOCK_LEN));
+        assert_eq!(blocks.next(&info), (0, BLOCK_LEN, BLOCK_LEN));
+        assert_eq!(blocks.next(&info), (2, 0, BLOCK_LEN));
+        assert_eq!(blocks.next(&info), (2, BLOCK_LEN, BLOCK_LEN - 100));
+        assert_eq!(blocks.next(&info), (0, 0, BLOCK_LEN));
+    }
+}
@@ -1,3 +1,4 @@
+use std::cmp;
 use std::ops::{Deref, DerefMut};
 use std::sync::atomic;
 
@@ -5,36 +6,50 @@ use crate::protocol;
 
 const MAX_BUFS: usize = 4096;
 pub const BUF_SIZE: usize = 16_384;
+/// Most of the pool the disk write buffer may hold, leaving the rest
+/// for receiving blocks from peers
+pub const MAX_WRITE_BUFFER: usize = MAX_BUFS * BUF_SIZE / 4;
 static BUF_COUNT: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
 
 #[derive(Clone)]
 pub struct Buffer {
-    data: Box<[u8; BUF_SIZE]>,
+    data: Box<[u8]>,
 }
 
 impl Buffer {
     pub fn get() -> Option<Buffer> {
-        if BUF_COUNT.load(atomic::Ordering::Acquire) >= MAX_BUFS && !cfg!(test) {
+        Buffer::with_len(BUF_SIZE)
+    }
+
+    /// Gets a buffer of len bytes, which counts as several
+    /// buffers towards the limit if larger than BUF_SIZE.
+    pub fn with_len(len: usize) -> Option<Buffer> {
+        let units = Buffer::units(len);
+        if BUF_COUNT.load(atomic::Ordering::Acquire) + units > MAX_BUFS && !cfg!(test) {
             return None;
         }
-        BUF_COUNT.fetch_add(1, atomic::Ordering::AcqRel);
+        BUF_COUNT.fetch_add(units, atomic::Ordering::AcqRel);
         Some(Buffer {
-            data: Box::new([0; BUF_SIZE]),
+            data: vec![0; len].into_boxed_slice(),
         })
     }
+
+    fn units(len: usize) -> usize {
+        cmp::max(len.div_ceil(BUF_SIZE), 1)
+    }
 }
 
 impl Deref for Buffer {
     type Target = [u8];
 
     fn deref(&self) -> &Self::Target {
-        &*self.data
+        &self.data
     }
 }
 
 impl DerefMut for Buffer {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut *self.data
+        &mut self.data
     }
 }
 
@@ -42,6 +57,6 @@ impl protocol::Buffer for Buffer {}
 
 impl Drop for Buffer {
     fn drop(&mut self) {
-        BUF_COUNT.fetch_sub(1, atomic::Ordering::AcqRel);
+        BUF_COUNT.fetch_sub(Buffer::units(self.data.len()), atomic::Ordering::AcqRel);
     }
 }
@@ -1,8 +1,10 @@
+use std::collections::HashMap;
 use std::io::Read;
-use std::net::{SocketAddr, ToSocketAddrs};
-use std::{fs, process};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::{cmp, fs, process};
 
 use crate::args;
+use crate::buffers::MAX_WRITE_BUFFER;
 
 error_chain! {
     errors {
@@ -23,6 +25,10 @@ error_chain! {
     }
 }
 
+/// Largest keepalive probe interval and count Linux accepts
+const MAX_KEEPALIVE_INTERVAL: u64 = 32_767;
+const MAX_KEEPALIVE_COUNT: u32 = 127;
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub port: u16,
@@ -33,6 +39,7 @@ pub struct Config {
     pub disk: DiskConfig,
     pub net: NetConfig,
     pub peer: PeerConfig,
+    pub update: UpdateConfig,
 }
 
 #[derive(Debug, Clone)]
@@ -59,6 +66,8 @@ pub struct ConfigFile {
     pub net: NetConfig,
     #[serde(default)]
     pub peer: PeerConfig,
+    #[serde(default)]
+    pub update: UpdateConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -75,12 +84,28 @@ pub struct RpcConfig {
     pub ssl_cert: String,
     #[serde(default = "default_ssl")]
     pub ssl_key: String,
+    #[serde(default = "default_max_frame_size")]
+    pub max_frame_size: u64,
+    #[serde(default = "default_max_message_size")]
+    pub max_message_size: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TrkConfig {
     #[serde(default = "default_trk_port")]
     pub port: u16,
+    #[serde(default = "default_verify_tls")]
+    pub verify_tls: bool,
+    #[serde(default = "default_ca_bundle")]
+    pub ca_bundle: Option<String>,
+    #[serde(default = "default_announce_ip")]
+    pub announce_ip: Option<IpAddr>,
+    #[serde(default = "default_trk_proxy")]
+    pub proxy: Option<String>,
+    #[serde(default = "default_ip_check_url")]
+    pub ip_check_url: Option<String>,
+    #[serde(default = "default_ip_check_interval")]
+    pub ip_check_interval: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -99,6 +124,105 @@ pub struct DiskConfig {
     pub directory: String,
     #[serde(default = "default_validate")]
     pub validate: bool,
+    #[serde(default = "default_parallel_checks")]
+    pub parallel_checks: usize,
+    #[serde(default = "default_hash_threads")]
+    pub hash_threads: usize,
+    #[serde(default = "default_deferred_check")]
+    pub deferred_check: bool,
+    #[serde(default = "default_intent_log")]
+    pub intent_log: bool,
+    #[serde(default = "default_paranoid")]
+    pub paranoid: bool,
+    #[serde(default = "default_readonly_complete")]
+    pub readonly_complete: bool,
+    #[serde(
+        default = "default_readonly_mask",
+        deserialize_with = "deserialize_mode"
+    )]
+    pub readonly_mask: u32,
+    #[serde(default = "default_mode", deserialize_with = "deserialize_opt_mode")]
+    pub file_mode: Option<u32>,
+    #[serde(default = "default_mode", deserialize_with = "deserialize_opt_mode")]
+    pub dir_mode: Option<u32>,
+    #[serde(default = "default_owner")]
+    pub owner: Option<String>,
+    #[serde(default = "default_owner")]
+    pub group: Option<String>,
+    #[serde(default = "default_roots")]
+    pub roots: Vec<String>,
+    #[serde(default = "default_placement")]
+    pub placement: Placement,
+    #[serde(default = "default_categories")]
+    pub categories: HashMap<String, String>,
+    #[serde(default = "default_migration")]
+    pub migration: Option<Migration>,
+    #[serde(default = "default_complete_directory")]
+    pub complete_directory: Option<String>,
+    #[serde(default = "default_move_rate")]
+    pub move_rate: Option<u64>,
+    #[serde(default = "default_preallocate")]
+    pub preallocate: Preallocate,
+    #[serde(default = "default_read_cache")]
+    pub read_cache: usize,
+    #[serde(default = "default_file_digests")]
+    pub file_digests: bool,
+    #[serde(default = "default_write_buffer")]
+    pub write_buffer: usize,
+    #[serde(default = "default_backend")]
+    pub backend: Backend,
+    #[serde(default = "default_watch_interval")]
+    pub watch_interval: u64,
+}
+
+/// Moves completed torrents from one download root to another.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Migration {
+    /// Days since completion before a torrent is moved
+    pub after: u32,
+    pub from: String,
+    pub to: String,
+    /// Maximum number of torrents being moved at once
+    #[serde(default = "default_max_moves")]
+    pub max_moves: usize,
+}
+
+/// How the download directory of torrents added without a path is chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Placement {
+    /// Always use the default directory
+    Directory,
+    /// The root with the most free space
+    FreeSpace,
+    /// Each root in turn
+    RoundRobin,
+    /// The root configured for the torrent's category
+    Category,
+}
+
+/// What is done to the files of torrents once they're added. Unless
+/// they are allocated in full, files stay sparse when written to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Preallocate {
+    /// Files are allocated in full
+    Full,
+    /// Files are created at their full length without being allocated
+    Sparse,
+    /// Files are only created once written to
+    None,
+}
+
+/// How the data of torrents is read and written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// With a read or write call per range
+    Pread,
+    /// Through shared memory maps of the files, falling back to
+    /// write calls for files which aren't fully allocated
+    Mmap,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -109,12 +233,100 @@ pub struct NetConfig {
     pub max_open_sockets: usize,
     #[serde(default = "default_max_announces")]
     pub max_open_announces: usize,
+    #[serde(default = "default_max_accept_rate")]
+    pub max_accept_rate: u32,
+    #[serde(default = "default_max_pending_per_ip")]
+    pub max_pending_per_ip: usize,
+    #[serde(default = "default_ip_stack")]
+    pub ip_stack: IpStack,
+}
+
+/// IP versions the peer and RPC listeners accept connections over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpStack {
+    Ipv4,
+    Dual,
+    Ipv6,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PeerConfig {
     #[serde(default = "default_prune_timeout")]
     pub prune_timeout: u64,
+    #[serde(default = "default_nodelay")]
+    pub nodelay: bool,
+    #[serde(default = "default_sock_buffer")]
+    pub send_buffer: usize,
+    #[serde(default = "default_sock_buffer")]
+    pub recv_buffer: usize,
+    #[serde(default = "default_keepalive_idle")]
+    pub keepalive_idle: u64,
+    #[serde(default = "default_keepalive_interval")]
+    pub keepalive_interval: u64,
+    #[serde(default = "default_keepalive_count")]
+    pub keepalive_count: u32,
+    #[serde(default = "default_encryption")]
+    pub encryption: Encryption,
+    #[serde(default = "default_id_prefix")]
+    pub id_prefix: String,
+    #[serde(default = "default_user_agent")]
+    pub user_agent: String,
+    #[serde(default = "default_max_request")]
+    pub max_request: u32,
+    #[serde(default = "default_reserved_unchokes")]
+    pub reserved_unchokes: f32,
+    #[serde(default = "default_auto_super_seed")]
+    pub auto_super_seed: bool,
+    #[serde(default = "default_random_first_pieces")]
+    pub random_first_pieces: u32,
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+    #[serde(default = "default_max_upload_queue")]
+    pub max_upload_queue: usize,
+    #[serde(default = "default_max_upload_share")]
+    pub max_upload_share: u8,
+    #[serde(default = "default_choke_strategy")]
+    pub choke_strategy: ChokeStrategy,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateConfig {
+    #[serde(default = "default_update_check")]
+    pub check: bool,
+    #[serde(default = "default_update_url")]
+    pub url: String,
+    #[serde(default = "default_update_channel")]
+    pub channel: Channel,
+    #[serde(default = "default_update_interval")]
+    pub interval: u64,
+}
+
+/// Releases considered by the update check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    Stable,
+    /// Pre-releases too
+    Beta,
+}
+
+/// How the upload slots of a torrent are handed out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChokeStrategy {
+    TitForTat,
+    FastestFirst,
+    FairShare,
+}
+
+/// Whether MSE/PE encrypted handshakes are accepted from incoming peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encryption {
+    Disabled,
+    Enabled,
+    Required,
 }
 
 impl ConfigFile {
@@ -141,6 +353,24 @@ impl ConfigFile {
                         error!("Config max_dl must not be 0");
                         process::exit(1);
                     }
+                    if cfg.peer.keepalive_idle != 0
+                        && !(1..=MAX_KEEPALIVE_INTERVAL).contains(&cfg.peer.keepalive_interval)
+                    {
+                        error!(
+                            "Config keepalive_interval must be between 1 and {}",
+                            MAX_KEEPALIVE_INTERVAL
+                        );
+                        process::exit(1);
+                    }
+                    if cfg.peer.keepalive_idle != 0
+                        && !(1..=MAX_KEEPALIVE_COUNT).contains(&cfg.peer.keepalive_count)
+                    {
+                        error!(
+                            "Config keepalive_count must be between 1 and {}",
+                            MAX_KEEPALIVE_COUNT
+                        );
+                        process::exit(1);
+                    }
                     if !cfg!(debug_assertions) && !cfg.disk.validate {
                         error!("validation skipping can only be used in development, overriding!");
                         cfg.disk.validate = true;
@@ -187,6 +417,22 @@ impl Config {
         };
         file.disk.session = shellexpand::tilde(&file.disk.session).into();
         file.disk.directory = shellexpand::tilde(&file.disk.directory).into();
+        for root in file
+            .disk
+            .roots
+            .iter_mut()
+            .chain(file.disk.categories.values_mut())
+        {
+            *root = shellexpand::tilde(root).into();
+        }
+        if let Some(ref mut d) = file.disk.complete_directory {
+            *d = shellexpand::tilde(d).into();
+        }
+        if let Some(ref mut m) = file.disk.migration {
+            m.from = shellexpand::tilde(&m.from).into();
+            m.to = shellexpand::tilde(&m.to).into();
+        }
+        file.disk.write_buffer = cmp::min(file.disk.write_buffer, MAX_WRITE_BUFFER);
         Config {
             port: file.port,
             max_dl: file.max_dl,
@@ -195,6 +441,7 @@ impl Config {
             disk: file.disk,
             net: file.net,
             peer: file.peer,
+            update: file.update,
             dht,
         }
     }
@@ -209,6 +456,24 @@ fn default_max_dl() -> u32 {
 fn default_trk_port() -> u16 {
     16_362
 }
+fn default_verify_tls() -> bool {
+    true
+}
+fn default_ca_bundle() -> Option<String> {
+    None
+}
+fn default_announce_ip() -> Option<IpAddr> {
+    None
+}
+fn default_trk_proxy() -> Option<String> {
+    None
+}
+fn default_ip_check_url() -> Option<String> {
+    None
+}
+fn default_ip_check_interval() -> u64 {
+    300
+}
 fn default_dht_port() -> u16 {
     16_309
 }
@@ -227,6 +492,12 @@ fn default_password() -> String {
 fn default_ssl() -> String {
     "".to_owned()
 }
+fn default_max_frame_size() -> u64 {
+    5 * 1000 * 1000
+}
+fn default_max_message_size() -> u64 {
+    5 * 1000 * 1000
+}
 fn default_bootstrap_node() -> Option<String> {
     None
 }
@@ -241,6 +512,72 @@ fn default_directory_dir() -> String {
 fn default_validate() -> bool {
     true
 }
+fn default_parallel_checks() -> usize {
+    1
+}
+fn default_hash_threads() -> usize {
+    2
+}
+fn default_deferred_check() -> bool {
+    false
+}
+fn default_intent_log() -> bool {
+    true
+}
+fn default_paranoid() -> bool {
+    false
+}
+fn default_readonly_complete() -> bool {
+    false
+}
+fn default_readonly_mask() -> u32 {
+    0o222
+}
+fn default_mode() -> Option<u32> {
+    None
+}
+fn default_owner() -> Option<String> {
+    None
+}
+fn default_roots() -> Vec<String> {
+    Vec::new()
+}
+fn default_placement() -> Placement {
+    Placement::Directory
+}
+fn default_categories() -> HashMap<String, String> {
+    HashMap::new()
+}
+fn default_migration() -> Option<Migration> {
+    None
+}
+fn default_complete_directory() -> Option<String> {
+    None
+}
+fn default_move_rate() -> Option<u64> {
+    None
+}
+fn default_preallocate() -> Preallocate {
+    Preallocate::None
+}
+fn default_read_cache() -> usize {
+    16 * 1024 * 1024
+}
+fn default_file_digests() -> bool {
+    false
+}
+fn default_write_buffer() -> usize {
+    16 * 1024 * 1024
+}
+fn default_backend() -> Backend {
+    Backend::Pread
+}
+fn default_watch_interval() -> u64 {
+    60
+}
+fn default_max_moves() -> usize {
+    1
+}
 fn default_max_files() -> usize {
     500
 }
@@ -250,9 +587,117 @@ fn default_max_sockets() -> usize {
 fn default_max_announces() -> usize {
     50
 }
+fn default_max_accept_rate() -> u32 {
+    5
+}
+fn default_ip_stack() -> IpStack {
+    IpStack::Dual
+}
+
+fn default_max_pending_per_ip() -> usize {
+    5
+}
 fn default_prune_timeout() -> u64 {
     15
 }
+fn default_nodelay() -> bool {
+    true
+}
+fn default_sock_buffer() -> usize {
+    0
+}
+fn default_keepalive_idle() -> u64 {
+    600
+}
+fn default_keepalive_interval() -> u64 {
+    60
+}
+fn default_keepalive_count() -> u32 {
+    4
+}
+
+fn default_encryption() -> Encryption {
+    Encryption::Enabled
+}
+
+fn default_id_prefix() -> String {
+    "-SY0010-".to_owned()
+}
+
+fn default_user_agent() -> String {
+    concat!("synapse/", env!("CARGO_PKG_VERSION")).to_owned()
+}
+
+fn default_reserved_unchokes() -> f32 {
+    0.2
+}
+fn default_auto_super_seed() -> bool {
+    true
+}
+fn default_random_first_pieces() -> u32 {
+    4
+}
+fn default_request_timeout() -> u64 {
+    10
+}
+fn default_max_upload_queue() -> usize {
+    600
+}
+fn default_max_upload_share() -> u8 {
+    0
+}
+fn default_choke_strategy() -> ChokeStrategy {
+    ChokeStrategy::TitForTat
+}
+fn default_max_request() -> u32 {
+    16_384
+}
+fn default_update_check() -> bool {
+    false
+}
+fn default_update_url() -> String {
+    "https://api.github.com/repos/Luminarys/synapse/releases".to_owned()
+}
+fn default_update_channel() -> Channel {
+    Channel::Stable
+}
+fn default_update_interval() -> u64 {
+    86_400
+}
+
+/// A file mode, either as an octal string such as "0222" or a plain number.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Mode {
+    Octal(String),
+    Num(u32),
+}
+
+impl Mode {
+    fn parse<E: serde::de::Error>(self) -> std::result::Result<u32, E> {
+        match self {
+            Mode::Octal(s) => u32::from_str_radix(s.trim_start_matches("0o"), 8)
+                .map_err(|_| E::custom("file mode must be an octal string")),
+            Mode::Num(n) => Ok(n),
+        }
+    }
+}
+
+fn deserialize_mode<'de, D>(de: D) -> std::result::Result<u32, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    <Mode as serde::Deserialize>::deserialize(de)?.parse()
+}
+
+fn deserialize_opt_mode<'de, D>(de: D) -> std::result::Result<Option<u32>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    <Option<Mode> as serde::Deserialize>::deserialize(de)?
+        .map(Mode::parse)
+        .transpose()
+}
 
 impl Default for Config {
     fn default() -> Self {
@@ -265,6 +710,7 @@ impl Default for Config {
             net: Default::default(),
             dht: Default::default(),
             peer: Default::default(),
+            update: Default::default(),
         }
     }
 }
@@ -278,6 +724,8 @@ impl Default for RpcConfig {
             password: default_password(),
             ssl_cert: default_ssl(),
             ssl_key: default_ssl(),
+            max_frame_size: default_max_frame_size(),
+            max_message_size: default_max_message_size(),
         }
     }
 }
@@ -286,6 +734,12 @@ impl Default for TrkConfig {
     fn default() -> TrkConfig {
         TrkConfig {
             port: default_trk_port(),
+            verify_tls: default_verify_tls(),
+            ca_bundle: default_ca_bundle(),
+            announce_ip: default_announce_ip(),
+            proxy: default_trk_proxy(),
+            ip_check_url: default_ip_check_url(),
+            ip_check_interval: default_ip_check_interval(),
         }
     }
 }
@@ -314,6 +768,29 @@ impl Default for DiskConfig {
             session: default_session_dir(),
             directory: default_directory_dir(),
             validate: default_validate(),
+            parallel_checks: default_parallel_checks(),
+            hash_threads: default_hash_threads(),
+            deferred_check: default_deferred_check(),
+            intent_log: default_intent_log(),
+            paranoid: default_paranoid(),
+            readonly_complete: default_readonly_complete(),
+            readonly_mask: default_readonly_mask(),
+            file_mode: default_mode(),
+            dir_mode: default_mode(),
+            owner: default_owner(),
+            group: default_owner(),
+            roots: default_roots(),
+            placement: default_placement(),
+            categories: default_categories(),
+            migration: default_migration(),
+            complete_directory: default_complete_directory(),
+            move_rate: default_move_rate(),
+            preallocate: default_preallocate(),
+            read_cache: default_read_cache(),
+            file_digests: default_file_digests(),
+            write_buffer: default_write_buffer(),
+            backend: default_backend(),
+            watch_interval: default_watch_interval(),
         }
     }
 }
@@ -324,6 +801,9 @@ impl Default for NetConfig {
             max_open_files: default_max_files(),
             max_open_sockets: default_max_sockets(),
             max_open_announces: default_max_announces(),
+            max_accept_rate: default_max_accept_rate(),
+            max_pending_per_ip: default_max_pending_per_ip(),
+            ip_stack: default_ip_stack(),
         }
     }
 }
@@ -332,6 +812,34 @@ impl Default for PeerConfig {
     fn default() -> PeerConfig {
         PeerConfig {
             prune_timeout: default_prune_timeout(),
+            nodelay: default_nodelay(),
+            send_buffer: default_sock_buffer(),
+            recv_buffer: default_sock_buffer(),
+            keepalive_idle: default_keepalive_idle(),
+            keepalive_interval: default_keepalive_interval(),
+            keepalive_count: default_keepalive_count(),
+            encryption: default_encryption(),
+            id_prefix: default_id_prefix(),
+            user_agent: default_user_agent(),
+            max_request: default_max_request(),
+            reserved_unchokes: default_reserved_unchokes(),
+            auto_super_seed: default_auto_super_seed(),
+            random_first_pieces: default_random_first_pieces(),
+            request_timeout: default_request_timeout(),
+            max_upload_queue: default_max_upload_queue(),
+            max_upload_share: default_max_upload_share(),
+            choke_strategy: default_choke_strategy(),
+        }
+    }
+}
+
+impl Default for UpdateConfig {
+    fn default() -> UpdateConfig {
+        UpdateConfig {
+            check: default_update_check(),
+            url: default_update_url(),
+            channel: default_update_channel(),
+            interval: default_update_interval(),
         }
     }
 }
@@ -1,5 +1,5 @@
 use std::cell::RefCell;
-use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
+use std::net::{IpAddr, TcpListener};
 use std::rc::Rc;
 use std::{io, time};
 
@@ -7,9 +7,9 @@ use amy::{self, ChannelError};
 
 use crate::control::cio::{self, Error, ErrorKind, Result, ResultExt};
 use crate::torrent::peer::reader::RRes;
-use crate::util::UHashMap;
+use crate::util::{FHashMap, UHashMap};
 use crate::CONFIG;
-use crate::{disk, rpc, torrent, tracker};
+use crate::{disk, rpc, socket, torrent, tracker};
 
 const POLL_INT_MS: usize = 1000;
 const PRUNE_GOAL: usize = 50;
@@ -34,6 +34,7 @@ struct ACIOData {
     poll: amy::Poller,
     reg: amy::Registrar,
     peers: UHashMap<torrent::PeerConn>,
+    hosts: Hosts,
     events: Vec<cio::Event>,
     chans: ACChans,
     crashed: bool,
@@ -43,9 +44,7 @@ struct ACIOData {
 
 impl ACIO {
     pub fn new(poll: amy::Poller, reg: amy::Registrar, chans: ACChans) -> io::Result<ACIO> {
-        let ip = Ipv4Addr::new(0, 0, 0, 0);
-        let port = CONFIG.port;
-        let listener = TcpListener::bind(SocketAddrV4::new(ip, port))?;
+        let listener = socket::listener(CONFIG.port, false)?;
         listener.set_nonblocking(true)?;
         let lid = reg.register(&listener, amy::Event::Both)?;
 
@@ -56,6 +55,7 @@ impl ACIO {
             listener,
             lid,
             peers: UHashMap::default(),
+            hosts: Hosts::default(),
             events: Vec::new(),
             crashed: false,
         };
@@ -203,7 +203,8 @@ impl cio::CIO for ACIO {
     }
 
     fn add_peer(&mut self, mut peer: torrent::PeerConn) -> Result<cio::PID> {
-        if self.data.borrow().peers.len() > CONFIG.net.max_open_sockets {
+        let ip = peer.sock().addr().ip();
+        if self.data.borrow().over_limit() {
             let mut pruned = Vec::new();
             for (id, peer) in &self.data.borrow().peers {
                 if peer.last_action().elapsed()
@@ -234,7 +235,9 @@ impl cio::CIO for ACIO {
         if let Some(t) = peer.sock_mut().throttle.as_mut() {
             t.id = id
         }
-        self.data.borrow_mut().peers.insert(id, peer);
+        let mut d = self.data.borrow_mut();
+        d.hosts.add(ip);
+        d.peers.insert(id, peer);
         Ok(id)
     }
 
@@ -254,6 +257,11 @@ impl cio::CIO for ACIO {
         self.data.borrow_mut().remove_peer(peer);
     }
 
+    fn peer_counts(&self) -> (usize, usize) {
+        let d = self.data.borrow();
+        (d.peers.len(), d.hosts.len())
+    }
+
     fn flush_peers(&mut self, peers: Vec<cio::PID>) {
         let mut events = Vec::new();
         let mut d = self.data.borrow_mut();
@@ -329,6 +337,23 @@ impl cio::CIO for ACIO {
             .chain_err(|| ErrorKind::IO)
     }
 
+    fn rebind(&mut self, port: u16) -> Result<()> {
+        let mut d = self.data.borrow_mut();
+        let d = &mut *d;
+        let listener = socket::listener(port, false).chain_err(|| ErrorKind::IO)?;
+        listener.set_nonblocking(true).chain_err(|| ErrorKind::IO)?;
+        let lid = d
+            .reg
+            .register(&listener, amy::Event::Both)
+            .chain_err(|| ErrorKind::IO)?;
+        if let Err(e) = d.reg.deregister(&d.listener) {
+            error!("Failed to deregister old listener: {}", e);
+        }
+        d.listener = listener;
+        d.lid = lid;
+        Ok(())
+    }
+
     fn new_handle(&self) -> Self {
         ACIO {
             data: self.data.clone(),
@@ -337,8 +362,15 @@ impl cio::CIO for ACIO {
 }
 
 impl ACIOData {
+    /// Whether another connection would exceed the socket limit. Every
+    /// connection uses an fd, so those to shared hosts count too.
+    fn over_limit(&self) -> bool {
+        self.peers.len() >= CONFIG.net.max_open_sockets
+    }
+
     fn remove_peer(&mut self, pid: cio::PID) {
         if let Some(p) = self.peers.remove(&pid) {
+            self.hosts.remove(p.sock().addr().ip());
             if let Err(e) = self.reg.deregister(p.sock()) {
                 error!("Failed to deregister sock: {:?}", e);
             }
@@ -349,3 +381,50 @@ impl ACIOData {
         }
     }
 }
+
+/// Number of open connections to each remote host. A host we share
+/// several torrents with has a connection for each of them, but is
+/// only reported once in the server's host count.
+#[derive(Default)]
+struct Hosts(FHashMap<IpAddr, usize>);
+
+impl Hosts {
+    fn add(&mut self, ip: IpAddr) {
+        *self.0.entry(ip).or_insert(0) += 1;
+    }
+
+    fn remove(&mut self, ip: IpAddr) {
+        if let Some(c) = self.0.get_mut(&ip) {
+            *c -= 1;
+            if *c == 0 {
+                self.0.remove(&ip);
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hosts() {
+        let mut h = Hosts::default();
+        let (a, b): (IpAddr, IpAddr) = ("1.2.3.4".parse().unwrap(), "::1".parse().unwrap());
+        h.add(a);
+        h.add(a);
+        h.add(b);
+        assert_eq!(h.len(), 2);
+        h.remove(a);
+        assert_eq!(h.0.get(&a), Some(&1));
+        h.remove(a);
+        assert_eq!(h.0.get(&a), None);
+        // Removing an unknown host is harmless
+        h.remove(a);
+        assert_eq!(h.len(), 1);
+    }
+}
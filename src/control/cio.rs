@@ -57,6 +57,10 @@ pub trait CIO {
     /// for this to occur before internally removing the peer.
     fn remove_peer(&self, peer: PID);
 
+    /// Returns the number of open peer connections and of
+    /// distinct hosts they are to
+    fn peer_counts(&self) -> (usize, usize);
+
     /// Flushes events on the given vec of peers
     fn flush_peers(&mut self, peers: Vec<PID>);
 
@@ -75,6 +79,9 @@ pub trait CIO {
     /// Sets a timer in milliseconds
     fn set_timer(&mut self, interval: usize) -> Result<TID>;
 
+    /// Replaces the peer listener with one bound to port
+    fn rebind(&mut self, port: u16) -> Result<()>;
+
     /// Creates a copy of the IO object, which has the same underlying data
     fn new_handle(&self) -> Self;
 }
@@ -83,7 +90,7 @@ pub trait CIO {
 pub mod test {
     use super::{Event, Result, CIO, PID, TID};
     use crate::{disk, rpc, torrent, tracker};
-    use std::collections::HashMap;
+    use std::collections::{HashMap, HashSet};
     use std::sync::{Arc, Mutex};
 
     pub struct TCIO {
@@ -118,6 +125,21 @@ pub mod test {
                 data: Arc::new(Mutex::new(d)),
             }
         }
+
+        /// Messages sent to peers so far.
+        pub fn peer_msgs(&self) -> Vec<(PID, torrent::Message)> {
+            self.data.lock().unwrap().peer_msgs.clone()
+        }
+
+        /// Takes the messages sent over RPC so far.
+        pub fn take_rpc_msgs(&self) -> Vec<rpc::CtlMessage> {
+            std::mem::take(&mut self.data.lock().unwrap().rpc_msgs)
+        }
+
+        /// Takes the requests sent to the disk worker so far.
+        pub fn take_disk_msgs(&self) -> Vec<disk::Request> {
+            std::mem::take(&mut self.data.lock().unwrap().disk_msgs)
+        }
     }
 
     impl CIO for TCIO {
@@ -153,6 +175,12 @@ pub mod test {
             d.peers.remove(&peer);
         }
 
+        fn peer_counts(&self) -> (usize, usize) {
+            let d = self.data.lock().unwrap();
+            let hosts: HashSet<_> = d.peers.values().map(|p| p.sock().addr().ip()).collect();
+            (d.peers.len(), hosts.len())
+        }
+
         fn flush_peers(&mut self, mut peers: Vec<PID>) {
             let mut d = self.data.lock().unwrap();
             d.flushed_peers.extend(peers.drain(..));
@@ -188,6 +216,10 @@ pub mod test {
             Ok(timer)
         }
 
+        fn rebind(&mut self, _: u16) -> Result<()> {
+            Ok(())
+        }
+
         fn new_handle(&self) -> Self {
             TCIO {
                 data: self.data.clone(),
@@ -1,10 +1,14 @@
 use std::collections::HashSet;
 use std::net::SocketAddr;
+use std::path::Path;
 use std::time;
 
+use chrono::{Duration, Utc};
+
 use crate::control::cio;
 use crate::torrent::Torrent;
 use crate::util::UHashMap;
+use crate::CONFIG;
 
 pub trait Job<T: cio::CIO> {
     fn update(&mut self, torrents: &mut UHashMap<Torrent<T>>);
@@ -30,6 +34,16 @@ impl<T: cio::CIO> Job<T> for UnchokeUpdate {
     }
 }
 
+pub struct Checkpoint;
+
+impl<T: cio::CIO> Job<T> for Checkpoint {
+    fn update(&mut self, torrents: &mut UHashMap<Torrent<T>>) {
+        for (_, torrent) in torrents.iter_mut() {
+            torrent.checkpoint();
+        }
+    }
+}
+
 pub struct SessionUpdate;
 
 impl<T: cio::CIO> Job<T> for SessionUpdate {
@@ -106,12 +120,17 @@ impl<T: cio::CIO> Job<T> for PEXUpdate {
             }
 
             let (added, removed) = {
-                let peers: HashSet<_> = torrent.peers().values().map(|p| p.addr()).collect();
+                let peers: HashSet<_> = torrent.peers().values().map(|p| p.listen_addr()).collect();
                 let prev = self.peers.get_mut(id).unwrap();
                 let mut add: Vec<_> = peers.difference(prev).cloned().collect();
                 let mut rem: Vec<_> = prev.difference(&peers).cloned().collect();
                 add.truncate(50);
                 rem.truncate(50 - add.len());
+                // Peers left out are sent in the next update
+                prev.extend(add.iter().cloned());
+                for addr in &rem {
+                    prev.remove(addr);
+                }
                 (add, rem)
             };
             torrent.update_pex(&added, &removed);
@@ -119,3 +138,50 @@ impl<T: cio::CIO> Job<T> for PEXUpdate {
         self.peers.retain(|id, _| torrents.contains_key(id));
     }
 }
+
+/// Pauses torrents whose files were removed or truncated by something
+/// other than synapse.
+pub struct FileWatch;
+
+impl<T: cio::CIO> Job<T> for FileWatch {
+    fn update(&mut self, torrents: &mut UHashMap<Torrent<T>>) {
+        for torrent in torrents.values_mut() {
+            torrent.watch_files();
+        }
+    }
+}
+
+/// Moves torrents which have been complete for long enough between the
+/// roots given by the migration config, a limited number at a time.
+pub struct MigrationUpdate;
+
+impl<T: cio::CIO> Job<T> for MigrationUpdate {
+    fn update(&mut self, torrents: &mut UHashMap<Torrent<T>>) {
+        let m = match CONFIG.disk.migration {
+            Some(ref m) => m,
+            None => return,
+        };
+        let cutoff = Utc::now() - Duration::days(i64::from(m.after));
+        let mut moving = torrents.values().filter(|t| t.moving()).count();
+        for torrent in torrents.values_mut() {
+            if moving >= m.max_moves {
+                break;
+            }
+            let due = torrent.completed_at().map(|c| c <= cutoff).unwrap_or(false);
+            // Errored torrents are skipped so a failing move isn't retried forever
+            if due
+                && !torrent.moving()
+                && torrent.status().error.is_none()
+                && Path::new(torrent.location()) == Path::new(&m.from)
+            {
+                match torrent.set_path(m.to.clone()) {
+                    Ok(()) => {
+                        info!("Migrating torrent {} to {}", torrent.rpc_id(), m.to);
+                        moving += 1;
+                    }
+                    Err(e) => debug!("Not migrating torrent {} yet: {}", torrent.rpc_id(), e),
+                }
+            }
+        }
+    }
+}
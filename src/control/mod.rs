@@ -1,18 +1,20 @@
 use std::io::Read;
-use std::net::TcpStream;
+use std::net::{IpAddr, TcpStream};
 use std::path::PathBuf;
-use std::sync::atomic;
+use std::sync::{atomic, Arc};
 use std::{fs, io, mem, process, time};
 
 use chrono::Utc;
 
+use crate::rpc::proto::message;
+use crate::socket::mse;
 use crate::throttle::Throttler;
-use crate::torrent::{self, peer, Torrent};
+use crate::torrent::{self, peer, policy, Torrent};
+use crate::util::limit::AcceptLimiter;
 use crate::util::{
     self, hash_to_id, id_to_hash, io_err, io_err_val, random_string, FHashSet, MHashMap, UHashMap,
-    UHashSet,
 };
-use crate::{disk, rpc, stat, tracker, CONFIG, DL_TOKEN, SHUTDOWN};
+use crate::{disk, log, rpc, stat, tracker, CONFIG, DL_TOKEN, PORT, RESTART, SHUTDOWN};
 
 pub mod acio;
 pub mod cio;
@@ -24,14 +26,20 @@ const TRK_JOB_SECS: u64 = 60;
 const UNCHK_JOB_SECS: u64 = 15;
 /// Session serialization job interval
 const SES_JOB_SECS: u64 = 60;
+/// Interval to sync written data, trimming intent logs
+const CHECKPOINT_JOB_SECS: u64 = 60 * 5;
 /// Interval to update RPC of transfer stats
 const TX_JOB_MS: u64 = 500;
 /// Interval to check space on disk
 const SPACE_JOB_SECS: u64 = 10;
+/// Interval to update RPC of read cache stats
+const CACHE_JOB_SECS: u64 = 5;
 /// Interval to send PEX updates
 const PEX_JOB_SECS: u64 = 60 * 5;
 /// Interval to enqueue new torrents
 const ENQUEUE_JOB_SECS: u64 = 5;
+/// Interval to check for torrents to migrate between roots
+const MIGRATE_JOB_SECS: u64 = 60;
 
 /// Interval to requery all jobs and execute if needed
 const JOB_INT_MS: usize = 500;
@@ -46,8 +54,13 @@ pub struct Control<T: cio::CIO> {
     torrents: UHashMap<Torrent<T>>,
     queue: Queue,
     peers: UHashMap<usize>,
-    incoming: UHashSet,
+    /// Incoming connections which haven't yet handshaked, and their source
+    incoming: UHashMap<IpAddr>,
+    limiter: AcceptLimiter,
     hash_idx: MHashMap<[u8; 20], usize>,
+    /// MSE lookup table for the torrents in hash_idx, shared with
+    /// incoming connections
+    skeys: Arc<mse::SKeys>,
     data: ServerData,
     db: amy::Sender<disk::Request>,
 }
@@ -63,6 +76,11 @@ struct ServerData {
     session_dl: u64,
     #[serde(skip)]
     free_space: u64,
+    #[serde(skip)]
+    peer_counts: (usize, usize),
+    /// Read cache size, hits and misses
+    #[serde(skip)]
+    read_cache: (u64, u64, u64),
     throttle_ul: Option<i64>,
     throttle_dl: Option<i64>,
 }
@@ -95,7 +113,8 @@ impl<T: cio::CIO> Control<T> {
     ) -> io::Result<Control<T>> {
         let torrents = UHashMap::default();
         let peers = UHashMap::default();
-        let incoming = UHashSet::default();
+        let incoming = UHashMap::default();
+        let limiter = AcceptLimiter::new(CONFIG.net.max_accept_rate, CONFIG.net.max_pending_per_ip);
         let hash_idx = MHashMap::default();
         let mut jobs = JobManager::new();
 
@@ -105,6 +124,10 @@ impl<T: cio::CIO> Control<T> {
             time::Duration::from_secs(UNCHK_JOB_SECS),
         );
         jobs.add_job(job::SessionUpdate, time::Duration::from_secs(SES_JOB_SECS));
+        jobs.add_job(
+            job::Checkpoint,
+            time::Duration::from_secs(CHECKPOINT_JOB_SECS),
+        );
         jobs.add_job(
             job::TorrentTxUpdate::new(),
             time::Duration::from_millis(TX_JOB_MS),
@@ -113,8 +136,19 @@ impl<T: cio::CIO> Control<T> {
             job::PEXUpdate::new(),
             time::Duration::from_secs(PEX_JOB_SECS),
         );
+        jobs.add_job(
+            job::MigrationUpdate,
+            time::Duration::from_secs(MIGRATE_JOB_SECS),
+        );
+        if CONFIG.disk.watch_interval != 0 {
+            jobs.add_job(
+                job::FileWatch,
+                time::Duration::from_secs(CONFIG.disk.watch_interval),
+            );
+        }
 
         jobs.add_cjob(SpaceUpdate, time::Duration::from_secs(SPACE_JOB_SECS));
+        jobs.add_cjob(CacheUpdate, time::Duration::from_secs(CACHE_JOB_SECS));
         jobs.add_cjob(EnqueueUpdate, time::Duration::from_secs(ENQUEUE_JOB_SECS));
         jobs.add_cjob(SerializeUpdate, time::Duration::from_secs(SES_JOB_SECS));
         let job_timer = cio
@@ -129,7 +163,9 @@ impl<T: cio::CIO> Control<T> {
             torrents,
             peers,
             incoming,
+            limiter,
             hash_idx,
+            skeys: Arc::new(mse::SKeys::default()),
             stat: stat::EMA::new(),
             data: Default::default(),
             db,
@@ -158,6 +194,9 @@ impl<T: cio::CIO> Control<T> {
                 break;
             }
         }
+        for torrent in self.torrents.values_mut() {
+            torrent.checkpoint();
+        }
         self.serialize();
     }
 
@@ -225,6 +264,7 @@ impl<T: cio::CIO> Control<T> {
         if let Some(t) = Torrent::deserialize(tid, &data, throttle, self.cio.new_handle()) {
             trace!("Succesfully parsed torrent file {:?}", dir.path());
             self.hash_idx.insert(t.info().hash, tid);
+            Arc::make_mut(&mut self.skeys).insert(t.info().hash);
             self.tid_cnt += 1;
             if t.status().leeching() {
                 self.queue.add(tid, t.priority());
@@ -277,6 +317,7 @@ impl<T: cio::CIO> Control<T> {
                 } else if t == self.job_timer {
                     self.update_jobs();
                     self.update_rpc_tx();
+                    log::flush_repeats();
                 } else {
                     error!("unknown timer id {} reported", t);
                 }
@@ -289,13 +330,13 @@ impl<T: cio::CIO> Control<T> {
     }
 
     fn handle_trk_ev(&mut self, tr: tracker::Response) {
-        let (id, peers) = match tr {
+        let (id, peers, source) = match tr {
             tracker::Response::Tracker { tid, url, resp } => {
                 debug!("Handling tracker response for {:?}", url);
                 if let Some(torrent) = self.torrents.get_mut(&tid) {
                     torrent.set_tracker_response(url.as_ref(), &resp);
                     if let Ok(r) = resp {
-                        (tid, r.peers)
+                        (tid, r.peers, torrent::Source::Tracker)
                     } else {
                         return;
                     }
@@ -303,15 +344,50 @@ impl<T: cio::CIO> Control<T> {
                     return;
                 }
             }
-            tracker::Response::DHT { tid, peers } | tracker::Response::PEX { tid, peers } => {
-                (tid, peers)
+            tracker::Response::DHT { tid, peers } => match self.torrents.get_mut(&tid) {
+                // Lookups started before the DHT was disabled may still complete
+                Some(torrent) if torrent.uses_dht() => {
+                    torrent.dht_peers_found(peers.len());
+                    (tid, peers, torrent::Source::Dht)
+                }
+                _ => return,
+            },
+            tracker::Response::PEX { tid, peers } => match self.torrents.get(&tid) {
+                // Private torrents only get peers from their trackers
+                Some(torrent) if !torrent.info().private => (tid, peers, torrent::Source::Pex),
+                _ => return,
+            },
+            tracker::Response::WebSeed {
+                tid,
+                seed,
+                piece,
+                begin,
+                resp,
+            } => {
+                if let Some(torrent) = self.torrents.get_mut(&tid) {
+                    torrent.handle_webseed(seed, piece, begin, resp);
+                }
+                return;
+            }
+            tracker::Response::ExternalIp(ip) => {
+                info!("External IP changed to {}, announcing torrents again", ip);
+                for torrent in self.torrents.values_mut() {
+                    torrent.external_ip_changed();
+                }
+                return;
+            }
+            tracker::Response::UpdateAvailable { version, url } => {
+                self.update_available(version, url);
+                return;
             }
+            // Handled by the tracker thread
+            tracker::Response::IpCheck(_) | tracker::Response::UpdateCheck(_) => return,
         };
         for ip in &peers {
             trace!("Adding peer({:?})!", ip);
             if let Ok(peer) = peer::PeerConn::new_outgoing(ip) {
                 trace!("Added peer({:?})!", ip);
-                self.add_peer(id, peer);
+                self.add_peer(id, peer, source);
             }
         }
     }
@@ -330,16 +406,33 @@ impl<T: cio::CIO> Control<T> {
                 self.data.free_space = space;
                 self.update_rpc_space();
             }
+        } else if let disk::Response::CacheStats { size, hits, misses } = resp {
+            if (size, hits, misses) != self.data.read_cache {
+                self.data.read_cache = (size, hits, misses);
+                self.update_rpc_cache();
+            }
         } else if let Some(torrent) = self.torrents.get_mut(&resp.tid()) {
             torrent.handle_disk_resp(resp);
         }
     }
 
     fn handle_incoming_conn(&mut self, conn: TcpStream) {
-        match peer::PeerConn::new_incoming(conn) {
+        let ip = match conn.peer_addr() {
+            Ok(addr) => addr.ip(),
+            Err(_) => return,
+        };
+        let pending = self.incoming.values().filter(|&&i| i == ip).count();
+        if let Err(e) = self.limiter.allow(ip, pending) {
+            info!("Dropping incoming peer connection from {}: {}", ip, e);
+            return;
+        }
+        // Torrents may override the configured encryption, so only what
+        // none of them accept is refused before the handshake is known
+        let encryption = policy::incoming(self.torrents.values().map(|t| t.policy().encryption()));
+        match peer::PeerConn::new_incoming(conn, &self.skeys, encryption) {
             Ok(pconn) => match self.cio.add_peer(pconn) {
                 Ok(pid) => {
-                    self.incoming.insert(pid);
+                    self.incoming.insert(pid, ip);
                 }
                 Err(e) => {
                     error!("Failed to add peer connection: {:?}", e);
@@ -389,7 +482,7 @@ impl<T: cio::CIO> Control<T> {
                     torrent.update_rpc_peers();
                 }
             }
-        } else if self.incoming.remove(&pid) {
+        } else if self.incoming.remove(&pid).is_some() {
             if self.inc_handshake(pid, ev).is_err() {
                 self.cio.remove_peer(pid);
             }
@@ -434,6 +527,7 @@ impl<T: cio::CIO> Control<T> {
             import,
         );
         self.hash_idx.insert(t.info().hash, tid);
+        Arc::make_mut(&mut self.skeys).insert(t.info().hash);
         self.tid_cnt += 1;
         self.queue.add(tid, t.priority());
         self.torrents.insert(tid, t);
@@ -444,18 +538,58 @@ impl<T: cio::CIO> Control<T> {
     fn handle_rpc_ev(&mut self, req: rpc::Message) -> bool {
         debug!("Handling rpc reqest!");
         match req {
-            rpc::Message::UpdateTorrent(u) => {
+            rpc::Message::UpdateTorrent {
+                update: u,
+                client,
+                serial,
+            } => {
                 let hash_idx = &self.hash_idx;
                 let torrents = &mut self.torrents;
+                let reason = format!("Torrent {} does not exist", u.id);
                 let res = id_to_hash(&u.id)
                     .and_then(|d| hash_idx.get(d.as_ref()))
                     .and_then(|i| torrents.get_mut(i));
-                if let Some(t) = res {
+                let res = if let Some(t) = res {
                     let old_pri = t.priority();
                     t.rpc_update(u);
                     let new_pri = t.priority();
                     self.queue.modify_pri(t.id(), new_pri, old_pri);
+                    Ok(())
+                } else {
+                    Err(reason)
+                };
+                self.ack_rpc(client, serial, res);
+            }
+            rpc::Message::UpdateTorrents {
+                ids,
+                update,
+                paused,
+                client,
+                serial,
+            } => {
+                for id in ids {
+                    let hash_idx = &self.hash_idx;
+                    let torrents = &mut self.torrents;
+                    let res = id_to_hash(&id)
+                        .and_then(|d| hash_idx.get(d.as_ref()))
+                        .and_then(|i| torrents.get_mut(i));
+                    if let Some(t) = res {
+                        let old_pri = t.priority();
+                        t.rpc_update(rpc::resource::CResourceUpdate {
+                            id,
+                            ..update.clone()
+                        });
+                        let new_pri = t.priority();
+                        self.queue.modify_pri(t.id(), new_pri, old_pri);
+                        match paused {
+                            Some(true) => t.pause(),
+                            Some(false) => t.resume(),
+                            None => {}
+                        }
+                    }
                 }
+                // Torrents removed since matching are simply skipped
+                self.ack_rpc(client, serial, Ok(()));
             }
             rpc::Message::Torrent {
                 info,
@@ -469,15 +603,68 @@ impl<T: cio::CIO> Control<T> {
                 id,
                 torrent_id,
                 priority,
+                client,
+                serial,
             } => {
                 let hash_idx = &self.hash_idx;
                 let torrents = &mut self.torrents;
+                let reason = format!("Torrent {} does not exist", torrent_id);
                 let res = id_to_hash(&torrent_id)
                     .and_then(|d| hash_idx.get(d.as_ref()))
-                    .and_then(|i| torrents.get_mut(i));
-                if let Some(t) = res {
-                    t.rpc_update_file(id, priority);
-                }
+                    .and_then(|i| torrents.get_mut(i))
+                    .map(|t| t.rpc_update_file(id, priority))
+                    .ok_or(reason);
+                self.ack_rpc(client, serial, res);
+            }
+            rpc::Message::SetReadHead {
+                id,
+                torrent_id,
+                offset,
+                client,
+                serial,
+            } => {
+                let hash_idx = &self.hash_idx;
+                let torrents = &mut self.torrents;
+                let reason = format!("Torrent {} does not exist", torrent_id);
+                let res = id_to_hash(&torrent_id)
+                    .and_then(|d| hash_idx.get(d.as_ref()))
+                    .and_then(|i| torrents.get_mut(i))
+                    .ok_or(reason)
+                    .and_then(|t| t.set_read_head(&id, offset));
+                self.ack_rpc(client, serial, res);
+            }
+            rpc::Message::Relocate {
+                id,
+                path,
+                client,
+                serial,
+            } => {
+                let hash_idx = &self.hash_idx;
+                let torrents = &mut self.torrents;
+                let reason = format!("Torrent {} does not exist", id);
+                let res = id_to_hash(&id)
+                    .and_then(|d| hash_idx.get(d.as_ref()))
+                    .and_then(|i| torrents.get_mut(i))
+                    .ok_or(reason)
+                    .and_then(|t| t.relocate(path));
+                self.ack_rpc(client, serial, res);
+            }
+            rpc::Message::SetPieceDeadline {
+                id,
+                pieces,
+                deadline,
+                client,
+                serial,
+            } => {
+                let hash_idx = &self.hash_idx;
+                let torrents = &mut self.torrents;
+                let reason = format!("Torrent {} does not exist", id);
+                let res = id_to_hash(&id)
+                    .and_then(|d| hash_idx.get(d.as_ref()))
+                    .and_then(|i| torrents.get_mut(i))
+                    .ok_or(reason)
+                    .and_then(|t| t.set_piece_deadline(&pieces, deadline));
+                self.ack_rpc(client, serial, res);
             }
             rpc::Message::AddPeer {
                 id,
@@ -543,7 +730,14 @@ impl<T: cio::CIO> Control<T> {
                 id,
                 throttle_up,
                 throttle_down,
+                port,
+                client,
+                serial,
             } => {
+                let res = match port {
+                    Some(port) => self.set_port(id.clone(), port),
+                    None => Ok(()),
+                };
                 let tu = throttle_up.unwrap_or_else(|| self.throttler.ul_rate());
                 let td = throttle_down.unwrap_or_else(|| self.throttler.dl_rate());
                 self.throttler.set_ul_rate(tu);
@@ -558,6 +752,7 @@ impl<T: cio::CIO> Control<T> {
                         throttle_down: td,
                     },
                 ]));
+                self.ack_rpc(client, serial, res);
             }
             rpc::Message::RemoveTorrent {
                 id,
@@ -566,11 +761,15 @@ impl<T: cio::CIO> Control<T> {
                 artifacts,
             } => {
                 let hash_idx = &mut self.hash_idx;
+                let skeys = &mut self.skeys;
                 let torrents = &mut self.torrents;
                 let cio = &mut self.cio;
                 let reason = format!("Torrent {} does not exist", id);
                 id_to_hash(&id)
-                    .and_then(|d| hash_idx.remove(d.as_ref()))
+                    .and_then(|d| {
+                        Arc::make_mut(skeys).remove(&d);
+                        hash_idx.remove(d.as_ref())
+                    })
                     .and_then(|i| torrents.remove(&i))
                     .map(|mut t| t.delete(artifacts))
                     .map(|_| cio.msg_rpc(rpc::CtlMessage::ClientRemoved { id, client, serial }))
@@ -582,37 +781,57 @@ impl<T: cio::CIO> Control<T> {
                         })
                     });
             }
-            rpc::Message::Pause(id) => {
+            rpc::Message::Pause {
+                id,
+                soft,
+                client,
+                serial,
+            } => {
                 let hash_idx = &mut self.hash_idx;
                 let torrents = &mut self.torrents;
-                if let Some(t) = id_to_hash(&id)
+                let reason = format!("Torrent {} does not exist", id);
+                let res = id_to_hash(&id)
                     .and_then(|d| hash_idx.get(d.as_ref()))
                     .and_then(|i| torrents.get_mut(i))
-                {
-                    t.pause()
-                }
+                    .map(|t| if soft { t.soft_pause() } else { t.pause() })
+                    .ok_or(reason);
+                self.ack_rpc(client, serial, res);
             }
-            rpc::Message::Resume(id) => {
+            rpc::Message::Resume { id, client, serial } => {
                 let hash_idx = &mut self.hash_idx;
                 let torrents = &mut self.torrents;
-                if let Some(t) = id_to_hash(&id)
+                let reason = format!("Torrent {} does not exist", id);
+                let res = id_to_hash(&id)
                     .and_then(|d| hash_idx.get(d.as_ref()))
                     .and_then(|i| torrents.get_mut(i))
-                {
-                    t.resume();
-                }
+                    .map(|t| t.resume())
+                    .ok_or(reason);
+                self.ack_rpc(client, serial, res);
             }
-            rpc::Message::Validate(ids) => {
+            rpc::Message::Validate {
+                ids,
+                client,
+                serial,
+            } => {
                 let hash_idx = &mut self.hash_idx;
                 let torrents = &mut self.torrents;
+                let mut missing = Vec::new();
                 for id in ids {
                     if let Some(t) = id_to_hash(&id)
                         .and_then(|d| hash_idx.get(d.as_ref()))
                         .and_then(|i| torrents.get_mut(i))
                     {
                         t.validate();
+                    } else {
+                        missing.push(id);
                     }
                 }
+                let res = if missing.is_empty() {
+                    Ok(())
+                } else {
+                    Err(format!("Torrents {} do not exist", missing.join(", ")))
+                };
+                self.ack_rpc(client, serial, res);
             }
             rpc::Message::RemovePeer {
                 id,
@@ -660,27 +879,206 @@ impl<T: cio::CIO> Control<T> {
                         })
                     });
             }
-            rpc::Message::UpdateTracker { id, torrent_id } => {
+            rpc::Message::GetTorrentInfo {
+                id,
+                raw,
+                client,
+                serial,
+            } => {
+                let res = self
+                    .with_torrent(&id, |t| t.info_dict(raw))
+                    .map(|(info, raw)| rpc::CtlMessage::TorrentInfo {
+                        info,
+                        raw,
+                        client,
+                        serial,
+                    });
+                self.reply_rpc(client, serial, res);
+            }
+            rpc::Message::GetFileManifest {
+                id,
+                format,
+                client,
+                serial,
+            } => {
+                let res = self
+                    .with_torrent(&id, |t| t.file_manifest(format))
+                    .map(|data| rpc::CtlMessage::FileManifest {
+                        id,
+                        format,
+                        data,
+                        client,
+                        serial,
+                    });
+                self.reply_rpc(client, serial, res);
+            }
+            rpc::Message::AuditAvailability { id, client, serial } => {
+                let res = self
+                    .with_torrent(&id, |t| t.audit_availability())
+                    .map(|audit| rpc::CtlMessage::AvailabilityAudit {
+                        audit,
+                        client,
+                        serial,
+                    });
+                self.reply_rpc(client, serial, res);
+            }
+            rpc::Message::GetSwarmSnapshot { id, client, serial } => {
+                let res = self
+                    .with_torrent(&id, |t| Ok(t.swarm_snapshot()))
+                    .map(|data| rpc::CtlMessage::SwarmSnapshot {
+                        data,
+                        id,
+                        client,
+                        serial,
+                    });
+                self.reply_rpc(client, serial, res);
+            }
+            rpc::Message::GetPieceMap {
+                id,
+                pieces,
+                files,
+                client,
+                serial,
+            } => {
+                let res = self
+                    .with_torrent(&id, |t| t.piece_map(&pieces, &files))
+                    .map(|map| rpc::CtlMessage::PieceMap {
+                        map,
+                        client,
+                        serial,
+                    });
+                self.reply_rpc(client, serial, res);
+            }
+            rpc::Message::ReplaceTracker {
+                id,
+                torrent_id,
+                url,
+                client,
+                serial,
+            } => {
                 let hash_idx = &self.hash_idx;
                 let torrents = &mut self.torrents;
-                if let Some(t) = id_to_hash(&torrent_id)
+                let msg = match id_to_hash(&torrent_id)
                     .and_then(|d| hash_idx.get(d.as_ref()))
                     .and_then(|i| torrents.get_mut(i))
+                    .ok_or_else(|| format!("Torrent {} does not exist", torrent_id))
+                    .and_then(|t| t.replace_tracker(&id, url))
                 {
-                    t.update_tracker_req(&id);
-                }
+                    Ok(id) => rpc::CtlMessage::Uploaded { id, client, serial },
+                    Err(reason) => rpc::CtlMessage::Error {
+                        client,
+                        serial,
+                        reason,
+                    },
+                };
+                self.cio.msg_rpc(msg);
+            }
+            rpc::Message::UpdateTracker {
+                id,
+                torrent_id,
+                client,
+                serial,
+            } => {
+                let hash_idx = &self.hash_idx;
+                let torrents = &mut self.torrents;
+                let reason = format!("Torrent {} does not exist", torrent_id);
+                let res = id_to_hash(&torrent_id)
+                    .and_then(|d| hash_idx.get(d.as_ref()))
+                    .and_then(|i| torrents.get_mut(i))
+                    .map(|t| t.update_tracker_req(&id))
+                    .ok_or(reason);
+                self.ack_rpc(client, serial, res);
             }
-            rpc::Message::PurgeDNS => {
+            rpc::Message::Reannounce { id, client, serial } => {
+                let res = match id {
+                    Some(id) => {
+                        let hash_idx = &self.hash_idx;
+                        let torrents = &mut self.torrents;
+                        let reason = format!("Torrent {} does not exist", id);
+                        id_to_hash(&id)
+                            .and_then(|d| hash_idx.get(d.as_ref()))
+                            .and_then(|i| torrents.get_mut(i))
+                            .ok_or(reason)
+                            .and_then(|t| t.reannounce())
+                    }
+                    None => {
+                        // Torrents which can't be reannounced right now are skipped
+                        for t in self.torrents.values_mut() {
+                            t.reannounce().ok();
+                        }
+                        Ok(())
+                    }
+                };
+                self.ack_rpc(client, serial, res);
+            }
+            rpc::Message::PurgeDNS { client, serial } => {
                 self.cio.msg_trk(tracker::Request::PurgeDNS);
+                self.ack_rpc(client, serial, Ok(()));
+            }
+            rpc::Message::Shutdown {
+                restart,
+                client,
+                serial,
+            } => {
+                if restart {
+                    info!("Restart requested over RPC, shutting down");
+                } else {
+                    info!("Shutdown requested over RPC");
+                }
+                RESTART.store(restart, atomic::Ordering::SeqCst);
+                SHUTDOWN.store(true, atomic::Ordering::SeqCst);
+                // Acked before the RPC thread is told to stop, so it's
+                // sent first. Session data is written and stopped
+                // announces made as the control thread exits.
+                self.ack_rpc(client, serial, Ok(()));
+                return true;
             }
         }
         false
     }
 
+    /// Tells the client whether its request was applied.
+    /// Runs f on the torrent with the given RPC id, failing if there's
+    /// no such torrent.
+    fn with_torrent<R, F>(&mut self, id: &str, f: F) -> Result<R, String>
+    where
+        F: FnOnce(&mut Torrent<T>) -> Result<R, String>,
+    {
+        let hash_idx = &self.hash_idx;
+        let torrents = &mut self.torrents;
+        id_to_hash(id)
+            .and_then(|d| hash_idx.get(d.as_ref()))
+            .and_then(|i| torrents.get_mut(i))
+            .ok_or_else(|| format!("Torrent {} does not exist", id))
+            .and_then(f)
+    }
+
+    /// Sends the reply to an RPC request, or the error it failed with.
+    fn reply_rpc(&mut self, client: usize, serial: u64, res: Result<rpc::CtlMessage, String>) {
+        let msg = res.unwrap_or_else(|reason| rpc::CtlMessage::Error {
+            client,
+            serial,
+            reason,
+        });
+        self.cio.msg_rpc(msg);
+    }
+
+    fn ack_rpc(&mut self, client: usize, serial: u64, res: Result<(), String>) {
+        let msg = match res {
+            Ok(()) => rpc::CtlMessage::Applied { client, serial },
+            Err(reason) => rpc::CtlMessage::Error {
+                client,
+                serial,
+                reason,
+            },
+        };
+        self.cio.msg_rpc(msg);
+    }
+
     fn add_peer_rpc(&mut self, id: usize, peer: peer::PeerConn) -> Option<String> {
         trace!("Adding peer to torrent {:?}!", id);
         if let Some(torrent) = self.torrents.get_mut(&id) {
-            if let Some(pid) = torrent.add_peer(peer) {
+            if let Some(pid) = torrent.add_peer(peer, torrent::Source::Rpc) {
                 self.peers.insert(pid, id);
                 return Some(util::peer_rpc_id(&torrent.info().hash, pid as u64));
             }
@@ -688,14 +1086,14 @@ impl<T: cio::CIO> Control<T> {
         None
     }
 
-    fn add_peer(&mut self, id: usize, peer: peer::PeerConn) {
+    fn add_peer(&mut self, id: usize, peer: peer::PeerConn, source: torrent::Source) {
         trace!("Adding peer to torrent {:?}!", id);
         if let Some(torrent) = self.torrents.get_mut(&id) {
             if !self.queue.active_dl.contains(&id) && !torrent.status().completed() {
                 self.queue.add(id, torrent.priority());
                 return;
             }
-            if let Some(pid) = torrent.add_peer(peer) {
+            if let Some(pid) = torrent.add_peer(peer, source) {
                 self.peers.insert(pid, id);
             }
         }
@@ -716,12 +1114,45 @@ impl<T: cio::CIO> Control<T> {
             }
             if let Some(pid) = torrent.add_inc_peer(pid, cid, rsv) {
                 self.peers.insert(pid, id);
+                // Reads were held after the handshake, resume them and
+                // process anything which arrived in the meantime.
+                if let Some(Err(e)) = self.cio.get_peer(pid, |p| p.accept()) {
+                    debug!("Failed to accept peer: {}", e);
+                }
+                self.cio.flush_peers(vec![pid]);
                 return Ok(());
             }
         }
         Err(())
     }
 
+    fn set_port(&mut self, id: String, port: u16) -> Result<(), String> {
+        if port == PORT.load(atomic::Ordering::Relaxed) {
+            return Ok(());
+        }
+        // The OS would pick a random port, which peers have no way of learning
+        if port == 0 {
+            return Err("Port 0 is not a valid peer port".to_owned());
+        }
+        if let Err(e) = self.cio.rebind(port) {
+            error!("Failed to listen for peers on port {}: {}", port, e);
+            return Err(format!("Failed to listen for peers on port {}", port));
+        }
+        info!("Listening for peers on port {}", port);
+        PORT.store(port, atomic::Ordering::Relaxed);
+        for torrent in self.torrents.values_mut() {
+            torrent.port_changed();
+        }
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerPort {
+                id,
+                kind: rpc::resource::ResourceKind::Server,
+                port,
+            },
+        ]));
+        Ok(())
+    }
+
     fn update_rpc_space(&mut self) {
         self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
             rpc::resource::SResourceUpdate::ServerSpace {
@@ -732,6 +1163,36 @@ impl<T: cio::CIO> Control<T> {
         ]));
     }
 
+    fn update_available(&mut self, version: String, url: String) {
+        self.cio.msg_rpc(rpc::CtlMessage::Notify {
+            severity: message::Severity::Info,
+            kind: message::NotificationKind::UpdateAvailable,
+            resource: Some(self.data.id.clone()),
+            message: format!("synapse {} is available at {}", version, url),
+        });
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerUpdate {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                update_version: Some(version),
+                update_url: Some(url),
+            },
+        ]));
+    }
+
+    fn update_rpc_cache(&mut self) {
+        let (size, hits, misses) = self.data.read_cache;
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerReadCache {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                read_cache_size: size,
+                read_cache_hits: hits,
+                read_cache_misses: misses,
+            },
+        ]));
+    }
+
     fn update_rpc_tx(&mut self) {
         self.stat.tick();
         if self.stat.active() {
@@ -749,6 +1210,18 @@ impl<T: cio::CIO> Control<T> {
                 },
             ]));
         }
+        let counts = self.cio.peer_counts();
+        if counts != self.data.peer_counts {
+            self.data.peer_counts = counts;
+            self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+                rpc::resource::SResourceUpdate::ServerPeers {
+                    id: self.data.id.clone(),
+                    kind: rpc::resource::ResourceKind::Server,
+                    peers: counts.0 as u64,
+                    hosts: counts.1 as u64,
+                },
+            ]));
+        }
     }
 
     fn send_rpc_info(&mut self) {
@@ -763,6 +1236,10 @@ impl<T: cio::CIO> Control<T> {
             ses_transferred_up: self.data.session_ul,
             ses_transferred_down: self.data.session_dl,
             free_space: self.data.free_space,
+            port: PORT.load(atomic::Ordering::Relaxed),
+            rpc_port: CONFIG.rpc.port,
+            rpc_local: CONFIG.rpc.local,
+            rpc_tls: !(CONFIG.rpc.ssl_cert.is_empty() && CONFIG.rpc.ssl_key.is_empty()),
             started: Utc::now(),
             download_token: DL_TOKEN.clone(),
             ..Default::default()
@@ -790,6 +1267,8 @@ impl ServerData {
             session_ul: 0,
             session_dl: 0,
             free_space: 0,
+            peer_counts: (0, 0),
+            read_cache: (0, 0, 0),
             throttle_ul: Some(-1),
             throttle_dl: Some(-1),
         }
@@ -895,6 +1374,14 @@ impl<T: cio::CIO> CJob<T> for SpaceUpdate {
     }
 }
 
+pub struct CacheUpdate;
+
+impl<T: cio::CIO> CJob<T> for CacheUpdate {
+    fn update(&mut self, control: &mut Control<T>) {
+        control.cio.msg_disk(disk::Request::CacheStats);
+    }
+}
+
 pub struct EnqueueUpdate;
 
 impl<T: cio::CIO> CJob<T> for EnqueueUpdate {
@@ -1,8 +1,13 @@
+use std::collections::BTreeMap;
 use std::ffi::OsString;
-use std::{fs, io, mem, path};
+use std::{fs, io, mem, path, time};
 
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::PermissionsExt;
 
+use super::Location;
+use crate::buffers::Buffer;
+use crate::config::{Backend, Preallocate};
 use crate::util::{native, MHashMap};
 use crate::CONFIG;
 
@@ -16,13 +21,78 @@ pub struct BufCache {
 
 pub struct FileCache {
     files: MHashMap<path::PathBuf, Entry>,
+    ownership: Ownership,
+    /// Files made read-only and the mode they had before
+    readonly: MHashMap<path::PathBuf, u32>,
+    /// Whether files are accessed through memory maps
+    mmap: bool,
+}
+
+/// Blocks recently read for upload, so that blocks requested by
+/// several peers are only read from disk once. The least recently
+/// used blocks are evicted once the cache is full.
+pub struct ReadCache {
+    /// Blocks by torrent and piece, then offset and length
+    pieces: MHashMap<(usize, u32), MHashMap<(u32, u32), CachedBlock>>,
+    /// Keys of the blocks, by when they were last used
+    lru: BTreeMap<u64, BlockKey>,
+    tick: u64,
+    capacity: usize,
+    size: usize,
+    hits: u64,
+    misses: u64,
+}
+
+/// Torrent, piece, offset and length of a block
+type BlockKey = (usize, u32, u32, u32);
+
+/// Downloaded blocks held back until their piece is complete, so that
+/// contiguous blocks are written with one call rather than a call per
+/// block. The fullest pieces are written out once the buffer is full.
+pub struct WriteBuffer {
+    pieces: MHashMap<(usize, u32), PendingPiece>,
+    capacity: usize,
+    size: usize,
+    /// Scratch space for joining blocks
+    buf: Vec<u8>,
+    path: path::PathBuf,
+    /// Offsets of the blocks written out since the last take_flushed,
+    /// by torrent and piece
+    flushed: Vec<(usize, u32, Vec<u32>)>,
+}
+
+struct PendingPiece {
+    path: Option<String>,
+    blocks: Vec<(Buffer, Vec<Location>)>,
+    begins: Vec<u32>,
+    size: usize,
+    modified: time::Instant,
+}
+
+struct CachedBlock {
+    data: Box<[u8]>,
+    used: u64,
+}
+
+/// Permissions and ownership given to created files and directories
+#[derive(Clone)]
+pub struct Ownership {
+    pub(super) file_mode: Option<u32>,
+    pub(super) dir_mode: Option<u32>,
+    pub(super) uid: Option<u32>,
+    pub(super) gid: Option<u32>,
 }
 
 pub struct Entry {
     used: bool,
     alloc_failed: bool,
     sparse: bool,
+    writable: bool,
+    /// Whether the file has been extended to its full length, which
+    /// isn't done for files first opened to be read
+    sized: bool,
     file: fs::File,
+    map: Option<native::Mmap>,
 }
 
 pub struct TempPB<'a> {
@@ -92,21 +162,325 @@ impl BufCache {
     }
 }
 
+impl ReadCache {
+    pub fn new(capacity: usize) -> ReadCache {
+        ReadCache {
+            pieces: MHashMap::default(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            capacity,
+            size: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Copies the block into buf if it's cached.
+    pub fn get(&mut self, key: BlockKey, buf: &mut [u8]) -> bool {
+        let (tid, piece, begin, len) = key;
+        let block = self
+            .pieces
+            .get_mut(&(tid, piece))
+            .and_then(|p| p.get_mut(&(begin, len)));
+        match block {
+            Some(block) => {
+                buf.copy_from_slice(&block.data);
+                self.lru.remove(&block.used);
+                self.tick += 1;
+                block.used = self.tick;
+                self.lru.insert(self.tick, key);
+                self.hits += 1;
+                true
+            }
+            None => {
+                self.misses += 1;
+                false
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: BlockKey, data: &[u8]) {
+        if data.len() > self.capacity {
+            return;
+        }
+        let (tid, piece, begin, len) = key;
+        self.remove_block(key);
+        while self.size + data.len() > self.capacity {
+            let oldest = match self.lru.keys().next() {
+                Some(&used) => self.lru[&used],
+                None => break,
+            };
+            self.remove_block(oldest);
+        }
+        self.tick += 1;
+        self.size += data.len();
+        self.lru.insert(self.tick, key);
+        self.pieces.entry((tid, piece)).or_default().insert(
+            (begin, len),
+            CachedBlock {
+                data: data.into(),
+                used: self.tick,
+            },
+        );
+    }
+
+    fn remove_block(&mut self, (tid, piece, begin, len): BlockKey) {
+        if let Some(blocks) = self.pieces.get_mut(&(tid, piece)) {
+            if let Some(block) = blocks.remove(&(begin, len)) {
+                self.lru.remove(&block.used);
+                self.size -= block.data.len();
+            }
+            if blocks.is_empty() {
+                self.pieces.remove(&(tid, piece));
+            }
+        }
+    }
+
+    /// Drops the cached blocks of a piece whose data may have changed.
+    pub fn remove_piece(&mut self, tid: usize, piece: u32) {
+        if let Some(blocks) = self.pieces.remove(&(tid, piece)) {
+            for block in blocks.values() {
+                self.lru.remove(&block.used);
+                self.size -= block.data.len();
+            }
+        }
+    }
+
+    /// Drops the cached blocks of a torrent.
+    pub fn remove_torrent(&mut self, tid: usize) {
+        let pieces: Vec<_> = self
+            .pieces
+            .keys()
+            .filter(|&&(t, _)| t == tid)
+            .cloned()
+            .collect();
+        for (tid, piece) in pieces {
+            self.remove_piece(tid, piece);
+        }
+    }
+
+    /// Bytes cached, and the number of cache hits and misses so far.
+    pub fn stats(&self) -> (u64, u64, u64) {
+        (self.size as u64, self.hits, self.misses)
+    }
+}
+
+impl WriteBuffer {
+    pub fn new(capacity: usize) -> WriteBuffer {
+        WriteBuffer {
+            pieces: MHashMap::default(),
+            capacity,
+            size: 0,
+            buf: Vec::new(),
+            path: path::PathBuf::new(),
+            flushed: Vec::new(),
+        }
+    }
+
+    pub fn insert(
+        &mut self,
+        tid: usize,
+        piece: u32,
+        begin: u32,
+        path: Option<String>,
+        data: Buffer,
+        locations: Vec<Location>,
+    ) {
+        let len = locations.iter().map(|l| l.end - l.start).sum::<usize>();
+        let pending = self
+            .pieces
+            .entry((tid, piece))
+            .or_insert_with(|| PendingPiece {
+                path: None,
+                blocks: Vec::new(),
+                begins: Vec::new(),
+                size: 0,
+                modified: time::Instant::now(),
+            });
+        pending.path = path;
+        pending.blocks.push((data, locations));
+        pending.begins.push(begin);
+        pending.size += len;
+        pending.modified = time::Instant::now();
+        self.size += len;
+    }
+
+    /// Writes out the blocks of a piece.
+    pub fn flush_piece(&mut self, fc: &mut FileCache, tid: usize, piece: u32) -> io::Result<()> {
+        let pending = match self.pieces.remove(&(tid, piece)) {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        self.size -= pending.size;
+        let mut segments: Vec<_> = pending
+            .blocks
+            .iter()
+            .flat_map(|(data, locs)| locs.iter().map(move |l| (l, &data[l.start..l.end])))
+            .collect();
+        segments.sort_by_key(|&(l, _)| (l.file, l.offset));
+
+        let dir = pending.path.as_ref().unwrap_or(&CONFIG.disk.directory);
+        let mut i = 0;
+        while i < segments.len() {
+            let (loc, data) = segments[i];
+            let mut end = loc.offset + data.len() as u64;
+            let mut j = i + 1;
+            while j < segments.len()
+                && segments[j].0.file == loc.file
+                && segments[j].0.offset == end
+            {
+                end += segments[j].1.len() as u64;
+                j += 1;
+            }
+            let chunk = if j == i + 1 {
+                data
+            } else {
+                self.buf.clear();
+                for &(_, data) in &segments[i..j] {
+                    self.buf.extend_from_slice(data);
+                }
+                &self.buf[..]
+            };
+            self.path.clear();
+            self.path.push(dir);
+            self.path.push(loc.path());
+            fc.write_file_range(
+                &self.path,
+                if loc.allocate && CONFIG.disk.preallocate == Preallocate::Full {
+                    Ok(loc.file_len)
+                } else {
+                    Err(loc.file_len)
+                },
+                loc.offset,
+                chunk,
+            )?;
+            if end == loc.file_len {
+                fc.flush_file(&self.path);
+            }
+            i = j;
+        }
+        self.flushed.push((tid, piece, pending.begins));
+        Ok(())
+    }
+
+    /// Returns the blocks written out since this was last called, so
+    /// torrents know which of them are safe to resume from.
+    pub fn take_flushed(&mut self) -> Vec<(usize, u32, Vec<u32>)> {
+        mem::take(&mut self.flushed)
+    }
+
+    /// Writes out the blocks of a torrent.
+    pub fn flush_torrent(&mut self, fc: &mut FileCache, tid: usize) -> io::Result<()> {
+        for piece in self.torrent_pieces(tid) {
+            self.flush_piece(fc, tid, piece)?;
+        }
+        Ok(())
+    }
+
+    /// Drops the blocks of a torrent whose files are being deleted.
+    pub fn discard_torrent(&mut self, tid: usize) {
+        for piece in self.torrent_pieces(tid) {
+            if let Some(pending) = self.pieces.remove(&(tid, piece)) {
+                self.size -= pending.size;
+            }
+        }
+    }
+
+    fn torrent_pieces(&self, tid: usize) -> Vec<u32> {
+        self.pieces
+            .keys()
+            .filter(|&&(t, _)| t == tid)
+            .map(|&(_, piece)| piece)
+            .collect()
+    }
+
+    /// Writes out the fullest pieces until the buffer is within its
+    /// capacity, returning the torrents whose writes failed.
+    pub fn evict(&mut self, fc: &mut FileCache) -> Vec<(usize, io::Error)> {
+        let mut errors = Vec::new();
+        while self.size > self.capacity {
+            let (tid, piece) = match self.pieces.iter().max_by_key(|(_, p)| p.size) {
+                Some((&key, _)) => key,
+                None => break,
+            };
+            if let Err(e) = self.flush_piece(fc, tid, piece) {
+                errors.push((tid, e));
+            }
+        }
+        errors
+    }
+
+    /// Writes out pieces which haven't been written to for age, e.g.
+    /// because their peers went away.
+    pub fn flush_idle(
+        &mut self,
+        fc: &mut FileCache,
+        age: time::Duration,
+    ) -> Vec<(usize, io::Error)> {
+        let idle: Vec<_> = self
+            .pieces
+            .iter()
+            .filter(|(_, p)| p.modified.elapsed() >= age)
+            .map(|(&key, _)| key)
+            .collect();
+        let mut errors = Vec::new();
+        for (tid, piece) in idle {
+            if let Err(e) = self.flush_piece(fc, tid, piece) {
+                errors.push((tid, e));
+            }
+        }
+        errors
+    }
+}
+
 impl FileCache {
     pub fn new() -> FileCache {
         FileCache {
             files: MHashMap::default(),
+            ownership: Ownership::from_config(),
+            readonly: MHashMap::default(),
+            mmap: CONFIG.disk.backend == Backend::Mmap,
         }
     }
 
+    /// Mode and owner given to the files and directories created.
+    pub fn ownership(&self) -> &Ownership {
+        &self.ownership
+    }
+
     pub fn read_file_range(
         &mut self,
         path: &path::Path,
         offset: u64,
         buf: &mut [u8],
     ) -> io::Result<()> {
-        self.ensure_exists(path, Err(0))?;
+        self.ensure_exists(path, Err(0), false)?;
         let entry = self.files.get_mut(path).unwrap();
+        let end = offset + buf.len() as u64;
+        if let Some(map) = entry.mapped(self.mmap, end, false) {
+            if let Err(e) = map.read(offset as usize, buf) {
+                entry.map = None;
+                return Err(e);
+            }
+            return Ok(());
+        }
+        entry.file.seek(SeekFrom::Start(offset))?;
+        entry.file.read_exact(buf)?;
+        Ok(())
+    }
+
+    /// Reads a range from the disk rather than the page cache, where possible.
+    pub fn read_file_range_uncached(
+        &mut self,
+        path: &path::Path,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> io::Result<()> {
+        self.ensure_exists(path, Err(0), false)?;
+        let entry = self.files.get_mut(path).unwrap();
+        // Pages still mapped can't be dropped from the cache
+        entry.map = None;
+        native::drop_cache(&entry.file, offset, buf.len() as u64)?;
         entry.file.seek(SeekFrom::Start(offset))?;
         entry.file.read_exact(buf)?;
         Ok(())
@@ -119,22 +493,99 @@ impl FileCache {
         offset: u64,
         buf: &[u8],
     ) -> io::Result<()> {
-        self.ensure_exists(path, size)?;
+        self.ensure_exists(path, size, true)?;
         let entry = self.files.get_mut(path).unwrap();
+        if !entry.writable {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file is read-only",
+            ));
+        }
+        let end = offset + buf.len() as u64;
+        if let Some(map) = entry.mapped(self.mmap, end, true) {
+            if let Err(e) = map.write(offset as usize, buf) {
+                entry.map = None;
+                return Err(e);
+            }
+            return Ok(());
+        }
         entry.file.seek(SeekFrom::Start(offset))?;
         entry.file.write_all(&buf)?;
         Ok(())
     }
 
+    /// Writes a range of a mapped file out to disk. Ranges written
+    /// with write calls are left to the page cache.
+    pub fn sync_range(&mut self, path: &path::Path, offset: u64, len: u64) -> io::Result<()> {
+        match self.files.get(path).and_then(|e| e.map.as_ref()) {
+            Some(map) if map.writable() && offset + len <= map.len() as u64 => {
+                map.sync(offset as usize, len as usize)
+            }
+            _ => Ok(()),
+        }
+    }
+
     pub fn remove_file(&mut self, path: &path::Path) {
         self.files.remove(path);
+        self.readonly.remove(path);
     }
 
     pub fn flush_file(&mut self, path: &path::Path) {
         self.files.get_mut(path).map(|e| e.file.sync_all().ok());
     }
 
-    fn ensure_exists(&mut self, path: &path::Path, len: Result<u64, u64>) -> io::Result<()> {
+    /// Writes a file's data out to disk, including writes made through
+    /// handles which have since been closed.
+    pub fn sync_file(&mut self, path: &path::Path) -> io::Result<()> {
+        match self.files.get(path) {
+            Some(entry) => entry.file.sync_all(),
+            None => match fs::File::open(path) {
+                Ok(f) => f.sync_all(),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(e),
+            },
+        }
+    }
+
+    /// Reopens a file read-only, removing the bits of mask from its permissions.
+    pub fn make_readonly(&mut self, path: &path::Path, mask: u32) -> io::Result<()> {
+        if let Some(entry) = self.files.remove(path) {
+            entry.file.sync_all()?;
+        }
+        let mut perms = fs::metadata(path)?.permissions();
+        let mode = perms.mode();
+        // If the file was already made read-only by a previous run,
+        // the best we can do later on is give write access back to its owner.
+        let orig = if mode & mask == 0 { mode | 0o200 } else { mode };
+        self.readonly.entry(path.to_path_buf()).or_insert(orig);
+        perms.set_mode(mode & !mask);
+        fs::set_permissions(path, perms)
+    }
+
+    /// Creates a file at its full length, also allocating it if full.
+    pub fn allocate_file(&mut self, path: &path::Path, len: u64, full: bool) -> io::Result<()> {
+        let size = if full { Ok(len) } else { Err(len) };
+        self.ensure_exists(path, size, true)
+    }
+
+    /// Restores the permissions of a read-only file so it can be written again.
+    pub fn make_writable(&mut self, path: &path::Path) -> io::Result<()> {
+        self.files.remove(path);
+        let mut perms = fs::metadata(path)?.permissions();
+        let mode = self
+            .readonly
+            .remove(path)
+            .unwrap_or_else(|| perms.mode() | 0o200);
+        perms.set_mode(mode);
+        fs::set_permissions(path, perms)
+    }
+
+    fn ensure_exists(
+        &mut self,
+        path: &path::Path,
+        len: Result<u64, u64>,
+        write: bool,
+    ) -> io::Result<()> {
         let len_val = if let Ok(v) = len {
             v
         } else {
@@ -153,22 +604,52 @@ impl FileCache {
                     }
                 }
                 if let Some(f) = removal {
-                    self.remove_file(&f);
+                    self.files.remove(&f);
                 }
             }
 
-            fs::create_dir_all(path.parent().unwrap())?;
-            let file = fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .read(true)
-                .open(path)?;
+            let (file, writable) = if self.readonly.contains_key(path) {
+                (fs::File::open(path)?, false)
+            } else {
+                // Reads of files which don't exist yet fail rather than
+                // creating them, so that skipped files never show up
+                if write {
+                    self.ownership.create_dirs(path.parent().unwrap())?;
+                }
+                let created = !path.exists();
+                let res = fs::OpenOptions::new()
+                    .write(true)
+                    .create(write)
+                    .read(true)
+                    .open(path);
+                match res {
+                    Ok(f) => {
+                        if created {
+                            self.ownership.apply(path, self.ownership.file_mode);
+                        }
+                        (f, true)
+                    }
+                    // Files made read-only by a previous run can still be read
+                    Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied && !write => {
+                        (fs::File::open(path)?, false)
+                    }
+                    Err(e) => return Err(e),
+                }
+            };
 
-            let alloc_failed = if len.is_ok() && file.metadata()?.len() != len.ok().unwrap() {
+            let alloc_failed = if !writable {
+                false
+            } else if len.is_ok()
+                && (file.metadata()?.len() != len_val || native::is_sparse(&file)?)
+            {
+                // Files which already have their length may still be sparse
                 let res = !native::fallocate(&file, len.unwrap())?;
                 debug!("Attempted to fallocate {:?}: success {}!", path, !res);
                 res
             } else {
+                // Extending files leaves the space in between unallocated,
+                // so writing pieces far into a file doesn't fill in the
+                // ones before them.
                 if len_val != 0 {
                     file.set_len(len_val)?;
                 }
@@ -183,15 +664,25 @@ impl FileCache {
                     file,
                     used: true,
                     sparse,
+                    writable,
                     alloc_failed,
+                    sized: len_val != 0,
+                    map: None,
                 },
             );
-        } else if len.is_ok() {
+        } else if len.is_err() {
+            let entry = self.files.get_mut(path).unwrap();
+            if write && entry.writable && !entry.sized && len_val != 0 {
+                entry.file.set_len(len_val)?;
+                entry.sized = true;
+            }
+        } else {
             let entry = self.files.get_mut(path).unwrap();
-            if entry.sparse && !entry.alloc_failed {
+            if entry.writable && (entry.sparse || !entry.sized) && !entry.alloc_failed {
                 debug!("Attempting delayed falloc!");
                 let file = fs::OpenOptions::new().write(true).read(true).open(path)?;
                 entry.alloc_failed = !native::fallocate(&file, len_val)?;
+                entry.sized = true;
                 if !entry.alloc_failed {
                     entry.sparse = false;
                 }
@@ -201,6 +692,84 @@ impl FileCache {
     }
 }
 
+impl Entry {
+    /// Maps the file if it holds the range up to end. Writes are only
+    /// mapped to fully allocated files, since running out of space
+    /// while writing to a map raises SIGBUS.
+    fn mapped(&mut self, mmap: bool, end: u64, write: bool) -> Option<&mut native::Mmap> {
+        if !mmap || end == 0 || (write && self.sparse) {
+            return None;
+        }
+        if !matches!(self.map, Some(ref m) if m.len() as u64 >= end) {
+            self.map = None;
+            let len = self.file.metadata().ok()?.len();
+            if len < end {
+                return None;
+            }
+            match native::Mmap::map(&self.file, len, self.writable) {
+                Ok(map) => self.map = Some(map),
+                Err(e) => {
+                    debug!("Failed to map file: {}", e);
+                    return None;
+                }
+            }
+        }
+        self.map.as_mut()
+    }
+}
+
+impl Ownership {
+    fn from_config() -> Ownership {
+        let lookup = |name: &Option<String>, resolve: fn(&str) -> io::Result<u32>| {
+            name.as_ref().and_then(|n| match resolve(n) {
+                Ok(id) => Some(id),
+                Err(e) => {
+                    error!("Failed to look up owner {}: {}", n, e);
+                    None
+                }
+            })
+        };
+        Ownership {
+            file_mode: CONFIG.disk.file_mode,
+            dir_mode: CONFIG.disk.dir_mode,
+            uid: lookup(&CONFIG.disk.owner, native::user_id),
+            gid: lookup(&CONFIG.disk.group, native::group_id),
+        }
+    }
+
+    /// Sets the mode and owner of a newly created path. Failures are only
+    /// logged, since the data is still usable.
+    pub fn apply(&self, path: &path::Path, mode: Option<u32>) {
+        if let Some(mode) = mode {
+            if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(mode)) {
+                error!("Failed to set mode of {:?}: {}", path, e);
+            }
+        }
+        if self.uid.is_some() || self.gid.is_some() {
+            if let Err(e) = native::chown(path, self.uid, self.gid) {
+                error!("Failed to set owner of {:?}: {}", path, e);
+            }
+        }
+    }
+
+    /// Creates dir and any missing parents, applying the
+    /// directory mode and owner to those created.
+    fn create_dirs(&self, dir: &path::Path) -> io::Result<()> {
+        if dir.is_dir() {
+            return Ok(());
+        }
+        let missing: Vec<_> = dir
+            .ancestors()
+            .take_while(|d| !d.as_os_str().is_empty() && !d.exists())
+            .collect();
+        fs::create_dir_all(dir)?;
+        for d in missing.iter().rev() {
+            self.apply(d, self.dir_mode);
+        }
+        Ok(())
+    }
+}
+
 impl Drop for FileCache {
     fn drop(&mut self) {
         for (_, entry) in self.files.drain() {
@@ -223,4 +792,239 @@ mod tests {
         assert_eq!(buf.get(30).len(), 30);
         assert_eq!(buf.get(10).len(), 10);
     }
+
+    #[test]
+    fn test_readonly() {
+        let path = std::env::temp_dir().join(format!("synapse-ro-{}", std::process::id()));
+        let mut fc = FileCache::new();
+        fc.write_file_range(&path, Err(4), 0, b"abcd").unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+
+        fc.make_readonly(&path, 0o222).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode(),
+            mode & !0o222
+        );
+        assert!(fc.write_file_range(&path, Err(4), 0, b"efgh").is_err());
+        let mut buf = [0u8; 4];
+        fc.read_file_range(&path, 0, &mut buf).unwrap();
+        assert_eq!(&buf, b"abcd");
+
+        fc.make_writable(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode(), mode);
+        fc.write_file_range(&path, Err(4), 0, b"efgh").unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_uncached() {
+        let path = std::env::temp_dir().join(format!("synapse-uc-{}", std::process::id()));
+        let mut fc = FileCache::new();
+        fc.write_file_range(&path, Err(8), 0, b"abcdefgh").unwrap();
+        let mut buf = [0u8; 4];
+        fc.read_file_range_uncached(&path, 2, &mut buf).unwrap();
+        assert_eq!(&buf, b"cdef");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_allocate_file() {
+        let path = std::env::temp_dir().join(format!("synapse-alloc-{}", std::process::id()));
+        let mut fc = FileCache::new();
+        fc.allocate_file(&path, 1 << 20, false).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 1 << 20);
+        assert!(native::is_sparse(&fs::File::open(&path).unwrap()).unwrap());
+
+        // Filesystems without fallocate leave the file sparse
+        fc.allocate_file(&path, 1 << 20, true).unwrap();
+        if !fc.files[&path].alloc_failed {
+            assert!(!native::is_sparse(&fs::File::open(&path).unwrap()).unwrap());
+        }
+        fs::remove_file(&path).unwrap();
+
+        // Sparse files left at their full length are allocated when first opened
+        let mut fc = FileCache::new();
+        fc.allocate_file(&path, 1 << 20, false).unwrap();
+        let mut fc = FileCache::new();
+        fc.allocate_file(&path, 1 << 20, true).unwrap();
+        if !fc.files[&path].alloc_failed {
+            assert!(!fc.files[&path].sparse);
+            assert!(!native::is_sparse(&fs::File::open(&path).unwrap()).unwrap());
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_sparse() {
+        let path = std::env::temp_dir().join(format!("synapse-sparse-{}", std::process::id()));
+        let mut fc = FileCache::new();
+        let mut buf = [1u8; 4];
+        assert!(fc.read_file_range(&path, 0, &mut buf).is_err());
+        assert!(!path.exists());
+
+        // Only the last piece is written
+        fc.write_file_range(&path, Err(1 << 20), (1 << 20) - 4, b"abcd")
+            .unwrap();
+        let file = fs::File::open(&path).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 1 << 20);
+        assert!(native::is_sparse(&file).unwrap());
+        fc.read_file_range(&path, 0, &mut buf).unwrap();
+        assert_eq!(buf, [0; 4]);
+        fs::remove_file(&path).unwrap();
+
+        // Files first opened to be read are extended once written
+        let mut fc = FileCache::new();
+        fs::write(&path, b"abcd").unwrap();
+        fc.read_file_range(&path, 0, &mut buf).unwrap();
+        fc.write_file_range(&path, Err(1 << 20), 1 << 19, b"efgh")
+            .unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 1 << 20);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_cache() {
+        let mut rc = ReadCache::new(8);
+        let mut buf = [0u8; 4];
+        assert!(!rc.get((0, 0, 0, 4), &mut buf));
+        rc.insert((0, 0, 0, 4), b"abcd");
+        rc.insert((0, 1, 0, 4), b"efgh");
+        assert!(rc.get((0, 0, 0, 4), &mut buf));
+        assert_eq!(&buf, b"abcd");
+
+        // The least recently used block is evicted
+        rc.insert((1, 0, 0, 4), b"ijkl");
+        assert!(!rc.get((0, 1, 0, 4), &mut buf));
+        assert!(rc.get((0, 0, 0, 4), &mut buf));
+        assert_eq!(rc.stats(), (8, 2, 2));
+
+        rc.remove_torrent(0);
+        assert!(!rc.get((0, 0, 0, 4), &mut buf));
+        rc.remove_piece(1, 0);
+        assert_eq!(rc.stats().0, 0);
+        assert!(rc.lru.is_empty());
+
+        // Blocks larger than the cache aren't kept
+        rc.insert((0, 0, 0, 16), &[0; 16]);
+        assert_eq!(rc.stats().0, 0);
+    }
+
+    #[test]
+    fn test_ownership() {
+        let base = std::env::temp_dir().join(format!("synapse-own-{}", std::process::id()));
+        let o = Ownership {
+            file_mode: Some(0o640),
+            dir_mode: Some(0o750),
+            uid: None,
+            gid: None,
+        };
+        let dir = base.join("a/b");
+        o.create_dirs(&dir).unwrap();
+        let mode = |p: &path::Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&base), 0o750);
+        assert_eq!(mode(&dir), 0o750);
+
+        let file = dir.join("f");
+        fs::File::create(&file).unwrap();
+        o.apply(&file, o.file_mode);
+        assert_eq!(mode(&file), 0o640);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_write_buffer() {
+        use std::sync::Arc;
+
+        let dir = std::env::temp_dir().join(format!("synapse-wb-{}", std::process::id()));
+        let mut info = crate::torrent::Info::with_pieces(2);
+        info.files[0].path = "f".into();
+        let info = Arc::new(info);
+        let block = |piece: u64, begin: u64, fill: u8| {
+            let mut data = Buffer::with_len(8).unwrap();
+            data.iter_mut().for_each(|b| *b = fill);
+            let loc = Location::new(0, 32_768, piece * 16_384 + begin, 0, 8, info.clone(), false);
+            (data, vec![loc])
+        };
+        let path = Some(dir.to_string_lossy().into_owned());
+        let mut fc = FileCache::new();
+        let mut wb = WriteBuffer::new(16);
+        for (piece, begin, fill) in [(0, 8, 2), (0, 0, 1), (1, 0, 3)] {
+            let (data, locs) = block(piece, begin, fill);
+            wb.insert(0, piece as u32, begin as u32, path.clone(), data, locs);
+        }
+        assert_eq!(wb.size, 24);
+        assert!(!dir.join("f").exists());
+
+        // Blocks of a piece are joined, whatever order they came in
+        wb.flush_piece(&mut fc, 0, 0).unwrap();
+        let mut buf = [0u8; 16];
+        fc.read_file_range(&dir.join("f"), 0, &mut buf).unwrap();
+        assert_eq!(buf, [[1u8; 8], [2u8; 8]].concat()[..]);
+        assert_eq!(wb.take_flushed(), vec![(0, 0, vec![8, 0])]);
+
+        // The fullest piece is written out once over capacity
+        let (data, locs) = block(0, 0, 4);
+        wb.insert(0, 0, 0, path.clone(), data, locs);
+        let (data, locs) = block(1, 8, 5);
+        wb.insert(0, 1, 8, path.clone(), data, locs);
+        assert!(wb.evict(&mut fc).is_empty());
+        assert_eq!(wb.size, 8);
+        assert_eq!(wb.torrent_pieces(0), vec![0]);
+        fc.read_file_range(&dir.join("f"), 16_384, &mut buf)
+            .unwrap();
+        assert_eq!(buf, [[3u8; 8], [5u8; 8]].concat()[..]);
+        assert_eq!(wb.take_flushed(), vec![(0, 1, vec![0, 8])]);
+
+        // Discarded blocks are never reported as written
+        wb.discard_torrent(0);
+        assert_eq!(wb.size, 0);
+        assert!(wb.take_flushed().is_empty());
+        fc.read_file_range(&dir.join("f"), 0, &mut buf).unwrap();
+        assert_eq!(buf[0], 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_mmap() {
+        let path = std::env::temp_dir().join(format!("synapse-mmap-{}", std::process::id()));
+        let mut fc = FileCache::new();
+        fc.mmap = true;
+        let mut buf = [0u8; 4];
+
+        // Sparse files are only written with write calls
+        fc.write_file_range(&path, Err(1 << 16), 8, b"abcd")
+            .unwrap();
+        assert!(fc.files[&path].map.is_none());
+        fc.read_file_range(&path, 8, &mut buf).unwrap();
+        assert_eq!(&buf, b"abcd");
+        assert!(fc.files[&path].map.is_some());
+        fs::remove_file(&path).unwrap();
+
+        let mut fc = FileCache::new();
+        fc.mmap = true;
+        fc.write_file_range(&path, Ok(1 << 16), 1 << 15, b"efgh")
+            .unwrap();
+        assert!(fc.files[&path].map.is_some());
+        fc.sync_range(&path, 1 << 15, 4).unwrap();
+        assert_eq!(&fs::read(&path).unwrap()[1 << 15..(1 << 15) + 4], b"efgh");
+        // Uncached reads have the pages unmapped so they can be dropped
+        fc.read_file_range_uncached(&path, 1 << 15, &mut buf)
+            .unwrap();
+        assert_eq!(&buf, b"efgh");
+        assert!(fc.files[&path].map.is_none());
+        // Ranges past the end of the file aren't mapped
+        assert!(fc.read_file_range(&path, (1 << 16) - 2, &mut buf).is_err());
+        // Accesses to pages cut off by someone else truncating the file fail
+        fc.read_file_range(&path, 1 << 15, &mut buf).unwrap();
+        assert!(fc.files[&path].map.is_some());
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(1 << 14)
+            .unwrap();
+        assert!(fc.read_file_range(&path, 1 << 15, &mut buf).is_err());
+        assert!(fc.files[&path].map.is_none());
+        fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,355 @@
+use std::cmp;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use sha1::{Digest, Sha1};
+
+use super::{FileCache, Ownership};
+use crate::util::{io_err, native};
+use crate::CONFIG;
+
+/// Size of the chunks files are copied and checked in
+const CHUNK_LEN: u64 = 1024 * 128;
+
+/// A move across filesystems, which copies the data a chunk at a time
+/// so that other disk jobs keep running meanwhile. Each file is read
+/// back after being copied and compared against the hash of the data
+/// read from the source, and only then is the source removed. Files
+/// already at the destination are never overwritten, so that aborting
+/// only removes what the copy itself created.
+pub struct Copy {
+    /// Files and directories created at the destination, in order
+    created: Vec<PathBuf>,
+    /// Source, destination, length and source modification time of files
+    files: Vec<(PathBuf, PathBuf, u64, Option<SystemTime>)>,
+    /// Source directories, in the order they can be removed
+    dirs: Vec<PathBuf>,
+    file: usize,
+    offset: u64,
+    verifying: bool,
+    /// Whether a file is being copied again after having changed
+    rewound: bool,
+    src: Option<File>,
+    dst: Option<File>,
+    hash: Sha1,
+    digest: [u8; 20],
+    copied: u64,
+    verified: u64,
+    total: u64,
+    started: Instant,
+    /// When a throttled copy may continue
+    resume: Option<Instant>,
+    /// Mode and owner of created files and directories, as for downloads
+    ownership: Ownership,
+    buf: Vec<u8>,
+}
+
+impl Copy {
+    /// Prepares copying from to to, creating the destination directories.
+    pub fn new(from: &Path, to: &Path, ownership: Ownership) -> io::Result<Copy> {
+        let mut copy = Copy {
+            created: Vec::new(),
+            files: Vec::new(),
+            dirs: Vec::new(),
+            file: 0,
+            offset: 0,
+            verifying: false,
+            rewound: false,
+            src: None,
+            dst: None,
+            hash: Sha1::new(),
+            digest: [0; 20],
+            copied: 0,
+            verified: 0,
+            total: 0,
+            started: Instant::now(),
+            resume: None,
+            ownership,
+            buf: vec![0; CHUNK_LEN as usize],
+        };
+        if let Err(e) = copy.walk(from, to) {
+            copy.abort();
+            return Err(e);
+        }
+        Ok(copy)
+    }
+
+    fn walk(&mut self, src: &Path, dst: &Path) -> io::Result<()> {
+        let meta = fs::metadata(src)?;
+        if meta.is_dir() {
+            self.create_dirs(dst)?;
+            for entry in fs::read_dir(src)? {
+                let name = entry?.file_name();
+                self.walk(&src.join(&name), &dst.join(&name))?;
+            }
+            self.dirs.push(src.to_owned());
+        } else {
+            if fs::symlink_metadata(dst).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{:?} already exists", dst),
+                ));
+            }
+            if let Some(parent) = dst.parent() {
+                self.create_dirs(parent)?;
+            }
+            self.total += meta.len();
+            self.files
+                .push((src.to_owned(), dst.to_owned(), meta.len(), None));
+        }
+        Ok(())
+    }
+
+    /// Creates dir and whichever of its parents are missing.
+    fn create_dirs(&mut self, dir: &Path) -> io::Result<()> {
+        let missing: Vec<_> = dir
+            .ancestors()
+            .take_while(|d| !d.as_os_str().is_empty() && !d.exists())
+            .map(Path::to_owned)
+            .collect();
+        for d in missing.into_iter().rev() {
+            fs::create_dir(&d)?;
+            self.ownership.apply(&d, self.ownership.dir_mode);
+            self.created.push(d);
+        }
+        Ok(())
+    }
+
+    /// Copies and checks data for up to slice, returning whether
+    /// everything has been copied. Throttled copies stop early, see
+    /// resume_at.
+    pub fn step(&mut self, slice: Duration) -> io::Result<bool> {
+        self.resume = None;
+        let start = Instant::now();
+        while start.elapsed() < slice {
+            if self.file == self.files.len() && !self.rewind() {
+                return Ok(true);
+            }
+            if self.verifying {
+                self.verify_chunk()?;
+            } else if self.throttle() {
+                self.copy_chunk()?;
+            } else {
+                break;
+            }
+        }
+        Ok(false)
+    }
+
+    fn copy_chunk(&mut self) -> io::Result<()> {
+        let (ref src, ref dst, len, ref mut modified) = self.files[self.file];
+        if self.src.is_none() {
+            let f = File::open(src)?;
+            *modified = f.metadata()?.modified().ok();
+            self.src = Some(f);
+            // Files copied again after changing are already ours
+            let created = !dst.exists();
+            self.dst = Some(File::create(dst)?);
+            if created {
+                self.ownership.apply(dst, self.ownership.file_mode);
+                self.created.push(dst.clone());
+            }
+        }
+        let n = cmp::min(CHUNK_LEN, len - self.offset);
+        let buf = &mut self.buf[..n as usize];
+        self.src.as_mut().unwrap().read_exact(buf)?;
+        self.dst.as_mut().unwrap().write_all(buf)?;
+        self.hash.update(&buf[..]);
+        self.offset += n;
+        self.copied += n;
+        if self.offset == len {
+            // Evict the copy from the page cache so the check reads the disk
+            native::drop_cache(self.dst.as_ref().unwrap(), 0, len)?;
+            self.digest = self.hash.finalize_reset().into();
+            self.src = None;
+            self.dst = None;
+            self.offset = 0;
+            self.verifying = true;
+        }
+        Ok(())
+    }
+
+    fn verify_chunk(&mut self) -> io::Result<()> {
+        let (_, ref dst, len, _) = self.files[self.file];
+        if self.dst.is_none() {
+            let f = File::open(dst)?;
+            if f.metadata()?.len() != len {
+                return io_err("Copied file has the wrong size!");
+            }
+            self.dst = Some(f);
+        }
+        let n = cmp::min(CHUNK_LEN, len - self.offset);
+        let buf = &mut self.buf[..n as usize];
+        self.dst.as_mut().unwrap().read_exact(buf)?;
+        self.hash.update(&buf[..]);
+        self.offset += n;
+        self.verified += n;
+        if self.offset == len {
+            if self.hash.finalize_reset()[..] != self.digest {
+                return io_err("Copied file doesn't match the original!");
+            }
+            self.dst = None;
+            self.offset = 0;
+            self.verifying = false;
+            // Files after a copied again one are already done
+            self.file = if self.rewound {
+                self.files.len()
+            } else {
+                self.file + 1
+            };
+        }
+        Ok(())
+    }
+
+    /// Goes back to a file modified since it was copied, returning
+    /// false if there is none.
+    fn rewind(&mut self) -> bool {
+        let changed = self.files.iter().position(|(src, _, _, modified)| {
+            fs::metadata(src).and_then(|m| m.modified()).ok() != *modified
+        });
+        let i = match changed {
+            Some(i) => i,
+            None => return false,
+        };
+        debug!(
+            "{:?} changed while being moved, copying it again",
+            self.files[i].0
+        );
+        let len = fs::metadata(&self.files[i].0).map(|m| m.len()).unwrap_or(0);
+        let old = self.files[i].2;
+        self.files[i].2 = len;
+        self.total = self.total - old + len;
+        self.copied -= old;
+        self.verified -= old;
+        self.file = i;
+        self.rewound = true;
+        true
+    }
+
+    /// Returns whether more may be copied under the configured rate,
+    /// noting when it may continue otherwise.
+    fn throttle(&mut self) -> bool {
+        let rate = match CONFIG.disk.move_rate {
+            Some(r) if r > 0 => r as f64,
+            _ => return true,
+        };
+        let allowed = rate * self.started.elapsed().as_secs_f64();
+        if (self.copied as f64) < allowed {
+            return true;
+        }
+        let wait = Duration::from_secs_f64((self.copied as f64 - allowed) / rate);
+        self.resume = Some(Instant::now() + wait);
+        false
+    }
+
+    /// When the copy may continue, if the last step stopped as it ran
+    /// over the configured rate.
+    pub fn resume_at(&self) -> Option<Instant> {
+        self.resume
+    }
+
+    /// Fraction of the data which has been copied and checked.
+    pub fn progress(&self) -> f32 {
+        if self.total == 0 {
+            return 0.;
+        }
+        (self.copied + self.verified) as f32 / (2 * self.total) as f32
+    }
+
+    /// Removes the source once everything has been copied.
+    pub fn finish(self, fc: &mut FileCache) {
+        for (src, _, _, _) in &self.files {
+            fc.remove_file(src);
+            if let Err(e) = fs::remove_file(src) {
+                error!("Failed to remove moved file {:?}: {}", src, e);
+            }
+        }
+        for dir in &self.dirs {
+            if let Err(e) = fs::remove_dir(dir) {
+                error!("Failed to remove moved directory {:?}: {}", dir, e);
+            }
+        }
+    }
+
+    /// Removes whatever has been copied so far. Directories are only
+    /// removed once empty, so anything else put in them is kept.
+    pub fn abort(&self) {
+        for path in self.created.iter().rev() {
+            let res = if path.is_dir() {
+                fs::remove_dir(path)
+            } else {
+                fs::remove_file(path)
+            };
+            if let Err(e) = res {
+                debug!("Failed to clean up {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn own() -> Ownership {
+        Ownership {
+            file_mode: Some(0o640),
+            dir_mode: Some(0o750),
+            uid: None,
+            gid: None,
+        }
+    }
+
+    #[test]
+    fn test_copy() {
+        let base = std::env::temp_dir().join(format!("synapse-mv-{}", std::process::id()));
+        let (from, to) = (base.join("from"), base.join("to"));
+        fs::create_dir_all(from.join("a/b")).unwrap();
+        let data: Vec<u8> = (0..CHUNK_LEN * 2 + 5).map(|i| i as u8).collect();
+        fs::write(from.join("a/b/large"), &data).unwrap();
+        fs::write(from.join("a/empty"), b"").unwrap();
+
+        let mut copy = Copy::new(&from.join("a"), &to.join("a"), own()).unwrap();
+        assert_eq!(copy.total, data.len() as u64);
+        assert_eq!(copy.progress(), 0.);
+        while !copy.step(Duration::from_millis(1)).unwrap() {}
+        assert_eq!(copy.progress(), 1.);
+        copy.finish(&mut FileCache::new());
+
+        assert_eq!(fs::read(to.join("a/b/large")).unwrap(), data);
+        assert_eq!(fs::read(to.join("a/empty")).unwrap(), b"");
+        let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&to), 0o750);
+        assert_eq!(mode(&to.join("a/b")), 0o750);
+        assert_eq!(mode(&to.join("a/b/large")), 0o640);
+        assert!(!from.join("a").exists());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_abort() {
+        let base = std::env::temp_dir().join(format!("synapse-abort-{}", std::process::id()));
+        let (from, to) = (base.join("from"), base.join("to"));
+        fs::create_dir_all(from.join("a/b")).unwrap();
+        fs::create_dir_all(to.join("a")).unwrap();
+        fs::write(from.join("a/b/new"), b"new").unwrap();
+        fs::write(to.join("a/kept"), b"kept").unwrap();
+
+        let mut copy = Copy::new(&from.join("a"), &to.join("a"), own()).unwrap();
+        copy.step(Duration::from_millis(1)).unwrap();
+        copy.abort();
+        assert!(!to.join("a/b").exists());
+        assert_eq!(fs::read(to.join("a/kept")).unwrap(), b"kept");
+        assert!(from.join("a/b/new").exists());
+
+        // Files already at the destination aren't overwritten
+        fs::write(from.join("a/kept"), b"other").unwrap();
+        assert!(Copy::new(&from.join("a"), &to.join("a"), own()).is_err());
+        assert_eq!(fs::read(to.join("a/kept")).unwrap(), b"kept");
+        assert!(!to.join("a/b").exists());
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
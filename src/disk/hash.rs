@@ -0,0 +1,293 @@
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+use std::{io, thread, time};
+
+use super::{Response, JOB_TIME_SLICE};
+use crate::torrent::Info;
+
+/// Pieces of a check queued with the workers at once, per worker
+const QUEUED_PER_WORKER: usize = 4;
+
+/// Hash checks whole torrents on a pool of worker threads. Workers read
+/// pieces through their own file handles, so checks proceed in parallel
+/// and the disk thread remains free for other jobs, only handing out
+/// pieces and collecting results.
+pub struct HashPool {
+    tasks: Option<mpsc::Sender<Task>>,
+    results: amy::Receiver<Checked>,
+    workers: Vec<thread::JoinHandle<()>>,
+    checks: Vec<Check>,
+}
+
+struct Task {
+    tid: usize,
+    generation: u64,
+    info: Arc<Info>,
+    root: PathBuf,
+    piece: u32,
+}
+
+#[derive(Debug)]
+struct Checked {
+    tid: usize,
+    generation: u64,
+    piece: u32,
+    valid: bool,
+}
+
+/// A torrent being checked
+struct Check {
+    tid: usize,
+    /// Tells apart checks of the same torrent
+    generation: u64,
+    info: Arc<Info>,
+    root: PathBuf,
+    /// Pieces to check, all of them if None
+    pieces: Option<Vec<u32>>,
+    /// Position of the next piece to queue
+    next: u32,
+    checked: u32,
+    invalid: Vec<u32>,
+    /// When progress was last reported
+    updated: time::Instant,
+}
+
+impl HashPool {
+    pub fn new(reg: &mut amy::Registrar, threads: usize) -> io::Result<HashPool> {
+        let (tx, rx) = mpsc::channel();
+        let (rtx, results) = reg.channel()?;
+        let rx = Arc::new(Mutex::new(rx));
+        let workers = (0..threads)
+            .map(|i| {
+                let (rx, rtx) = (rx.clone(), rtx.clone());
+                thread::Builder::new()
+                    .name(format!("hash-{}", i))
+                    .spawn(move || work(&rx, &rtx))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(HashPool {
+            tasks: Some(tx),
+            results,
+            workers,
+            checks: Vec::new(),
+        })
+    }
+
+    /// Number of torrents being checked
+    pub fn running(&self) -> usize {
+        self.checks.len()
+    }
+
+    /// Starts checking the pieces of a torrent whose files are stored
+    /// under root, or all of them if pieces is None.
+    pub fn check(
+        &mut self,
+        tid: usize,
+        generation: u64,
+        info: Arc<Info>,
+        root: PathBuf,
+        pieces: Option<Vec<u32>>,
+    ) {
+        self.checks.push(Check {
+            tid,
+            generation,
+            info,
+            root,
+            pieces,
+            next: 0,
+            checked: 0,
+            invalid: Vec::new(),
+            updated: time::Instant::now(),
+        });
+        let window = self.window();
+        let check = self.checks.last_mut().unwrap();
+        queue(self.tasks.as_ref().unwrap(), check, window);
+    }
+
+    /// Collects checked pieces, returning progress updates and the
+    /// responses of completed checks.
+    pub fn poll(&mut self) -> Vec<Response> {
+        let mut resps = Vec::new();
+        while let Ok(c) = self.results.try_recv() {
+            if let Some(check) = self
+                .checks
+                .iter_mut()
+                .find(|ch| (ch.tid, ch.generation) == (c.tid, c.generation))
+            {
+                check.checked += 1;
+                if !c.valid {
+                    check.invalid.push(c.piece);
+                }
+            }
+        }
+        let window = self.window();
+        let tasks = self.tasks.as_ref().unwrap();
+        let slice = time::Duration::from_millis(JOB_TIME_SLICE);
+        self.checks.retain_mut(|check| {
+            let pieces = check.total();
+            if check.checked == pieces {
+                let mut invalid = std::mem::take(&mut check.invalid);
+                invalid.sort_unstable();
+                resps.push(Response::validation_complete(
+                    check.tid,
+                    check.generation,
+                    invalid,
+                ));
+                return false;
+            }
+            queue(tasks, check, window);
+            if check.updated.elapsed() >= slice {
+                check.updated = time::Instant::now();
+                resps.push(Response::ValidationUpdate {
+                    tid: check.tid,
+                    generation: check.generation,
+                    percent: check.checked as f32 / pieces as f32,
+                });
+            }
+            true
+        });
+        resps
+    }
+
+    fn window(&self) -> u32 {
+        (self.workers.len() * QUEUED_PER_WORKER) as u32
+    }
+}
+
+impl Check {
+    fn total(&self) -> u32 {
+        self.pieces
+            .as_ref()
+            .map_or(self.info.pieces(), |p| p.len() as u32)
+    }
+
+    fn piece(&self, pos: u32) -> u32 {
+        self.pieces.as_ref().map_or(pos, |p| p[pos as usize])
+    }
+}
+
+/// Queues pieces of check until window of them are outstanding.
+fn queue(tasks: &mpsc::Sender<Task>, check: &mut Check, window: u32) {
+    while check.next < check.total() && check.next - check.checked < window {
+        let task = Task {
+            tid: check.tid,
+            generation: check.generation,
+            info: check.info.clone(),
+            root: check.root.clone(),
+            piece: check.piece(check.next),
+        };
+        if tasks.send(task).is_err() {
+            return;
+        }
+        check.next += 1;
+    }
+}
+
+fn work(tasks: &Mutex<mpsc::Receiver<Task>>, results: &amy::Sender<Checked>) {
+    let mut buf = Vec::new();
+    let mut open = None;
+    loop {
+        let task = match tasks.lock().unwrap().recv() {
+            Ok(t) => t,
+            Err(_) => return,
+        };
+        let valid = check_piece(&task, &mut buf, &mut open).unwrap_or(false);
+        let res = Checked {
+            tid: task.tid,
+            generation: task.generation,
+            piece: task.piece,
+            valid,
+        };
+        if results.send(res).is_err() {
+            return;
+        }
+    }
+}
+
+/// Reads and hashes a piece, keeping the last file read open since
+/// pieces are mostly handed out in order.
+fn check_piece(
+    task: &Task,
+    buf: &mut Vec<u8>,
+    open: &mut Option<(PathBuf, File)>,
+) -> io::Result<bool> {
+    let len = task.info.piece_len(task.piece) as usize;
+    buf.resize(len, 0);
+    for loc in Info::piece_disk_locs(&task.info, task.piece) {
+        let path = task.root.join(loc.path());
+        if !matches!(open, Some((ref p, _)) if *p == path) {
+            let file = File::open(&path)?;
+            *open = Some((path, file));
+        }
+        let (_, file) = open.as_ref().unwrap();
+        file.read_exact_at(&mut buf[loc.start..loc.end], loc.offset)?;
+    }
+    Ok(task.info.piece_valid(task.piece, &buf[..len]))
+}
+
+impl Drop for HashPool {
+    fn drop(&mut self) {
+        // Workers stop once the queue is closed and drained
+        self.tasks.take();
+        for worker in self.workers.drain(..) {
+            worker.join().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::sha1_hash;
+
+    #[test]
+    fn test_check() {
+        let dir = std::env::temp_dir().join(format!("synapse-pool-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut info = Info::with_pieces(20);
+        let data: Vec<u8> = (0..info.total_len).map(|i| (i % 251) as u8).collect();
+        let pl = info.piece_len as usize;
+        info.files[0].path = PathBuf::from("a");
+        info.hashes = data.chunks(pl).map(|p| sha1_hash(p).to_vec()).collect();
+        info.piece_idx =
+            Info::generate_piece_idx(info.hashes.len(), info.piece_len as u64, &info.files);
+        let mut stored = data.clone();
+        stored[3 * pl] ^= 1;
+        stored[17 * pl + 10] ^= 1;
+        // The last piece is missing
+        stored.truncate(19 * pl + 1);
+        std::fs::write(dir.join("a"), &stored).unwrap();
+
+        let poll = amy::Poller::new().unwrap();
+        let mut pool = HashPool::new(&mut poll.get_registrar(), 3).unwrap();
+        let info = Arc::new(info);
+        let complete = |pool: &mut HashPool| loop {
+            let resp = pool.poll().into_iter().find_map(|r| match r {
+                Response::ValidationComplete {
+                    tid: 1,
+                    generation,
+                    invalid,
+                } => Some((generation, invalid)),
+                _ => None,
+            });
+            if let Some(invalid) = resp {
+                break invalid;
+            }
+            thread::sleep(time::Duration::from_millis(1));
+        };
+        pool.check(1, 0, info.clone(), dir.clone(), None);
+        assert_eq!(pool.running(), 1);
+        assert_eq!(complete(&mut pool), (0, vec![3, 17, 19]));
+        assert_eq!(pool.running(), 0);
+
+        // Checks of the same torrent don't mix up their pieces
+        pool.check(1, 1, info.clone(), dir.clone(), Some(vec![2, 3, 18]));
+        pool.check(1, 2, info, dir.clone(), None);
+        let mut done = vec![complete(&mut pool), complete(&mut pool)];
+        done.sort();
+        assert_eq!(done, vec![(1, vec![3]), (2, vec![3, 17, 19])]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,82 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::util::hash_to_id;
+use crate::CONFIG;
+
+/// Path of the intent log of a torrent. The log lists pieces which
+/// were written to since the torrent's files were last synced, as big
+/// endian u32s, so that only those have to be rechecked after an
+/// unclean shutdown.
+pub fn path(hash: &[u8; 20]) -> PathBuf {
+    let mut p = PathBuf::from(&CONFIG.disk.session);
+    p.push(hash_to_id(hash) + ".intent");
+    p
+}
+
+/// Pieces of a torrent which may not have been written out completely,
+/// empty if it was shut down cleanly.
+pub fn suspect_pieces(hash: &[u8; 20]) -> Vec<u32> {
+    load(&path(hash)).unwrap_or_default()
+}
+
+fn load(path: &Path) -> io::Result<Vec<u32>> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    let mut pieces: Vec<_> = data
+        .chunks_exact(4)
+        .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]))
+        .collect();
+    pieces.sort_unstable();
+    pieces.dedup();
+    Ok(pieces)
+}
+
+/// Logs pieces, returning once the entries are on disk so that they're
+/// written before any of the pieces' data.
+pub fn append(path: &Path, pieces: &[u32]) -> io::Result<()> {
+    let mut data = Vec::with_capacity(pieces.len() * 4);
+    for piece in pieces {
+        data.extend_from_slice(&piece.to_be_bytes());
+    }
+    let mut f = OpenOptions::new().append(true).create(true).open(path)?;
+    f.write_all(&data)?;
+    f.sync_data()
+}
+
+/// Replaces the log with pieces, removing it if there are none.
+pub fn rewrite(path: &Path, pieces: &[u32]) -> io::Result<()> {
+    if pieces.is_empty() {
+        return match fs::remove_file(path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        };
+    }
+    let temp = path.with_extension("intent.temp");
+    let mut f = File::create(&temp)?;
+    for piece in pieces {
+        f.write_all(&piece.to_be_bytes())?;
+    }
+    f.sync_data()?;
+    fs::rename(temp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log() {
+        let p = std::env::temp_dir().join(format!("synapse-{}.intent", std::process::id()));
+        rewrite(&p, &[]).unwrap();
+        assert!(load(&p).is_err());
+        append(&p, &[7]).unwrap();
+        append(&p, &[2, 7]).unwrap();
+        assert_eq!(load(&p).unwrap(), vec![2, 7]);
+        rewrite(&p, &[5]).unwrap();
+        assert_eq!(load(&p).unwrap(), vec![5]);
+        rewrite(&p, &[]).unwrap();
+        assert!(!p.exists());
+    }
+}
@@ -5,16 +5,20 @@ use std::{cmp, fmt, fs, path, time};
 
 use fs2;
 use http_range::HttpRange;
-use sha1::{Digest, Sha1};
+use sha2::{Digest, Sha256};
 use sstream::SStream;
 
-use super::{BufCache, FileCache, JOB_TIME_SLICE};
+use super::copy::Copy;
+use super::intent;
+use super::{BufCache, FileCache, ReadCache, WriteBuffer, JOB_TIME_SLICE};
 use crate::buffers::Buffer;
 use crate::torrent::{Info, LocIter};
-use crate::util::{hash_to_id, io_err};
+use crate::util::hash_to_id;
 use crate::CONFIG;
 
 static MP_BOUNDARY: &str = "qxyllcqgNchqyob";
+/// Bytes of a file hashed per read
+const HASH_CHUNK: u64 = 1024 * 1024;
 const EXDEV: i32 = 18;
 
 pub struct Location {
@@ -35,6 +39,8 @@ pub struct Location {
 pub enum Request {
     Write {
         tid: usize,
+        piece: u32,
+        begin: u32,
         data: Buffer,
         locations: LocIter,
         path: Option<String>,
@@ -50,6 +56,20 @@ pub enum Request {
         data: Vec<u8>,
         hash: [u8; 20],
     },
+    /// Logs a piece as being written, ahead of its data
+    Intent {
+        hash: [u8; 20],
+        piece: u32,
+    },
+    /// Syncs the files a torrent wrote to, leaving only the pieces
+    /// still being written in its intent log
+    Checkpoint {
+        tid: usize,
+        hash: [u8; 20],
+        files: Vec<PathBuf>,
+        path: Option<String>,
+        pending: Vec<u32>,
+    },
     Delete {
         tid: usize,
         hash: [u8; 20],
@@ -63,10 +83,22 @@ pub enum Request {
         to: String,
         target: String,
     },
+    /// A move across filesystems in progress
+    Copy {
+        tid: usize,
+        from: String,
+        to: String,
+        copy: Box<Copy>,
+    },
     Validate {
         tid: usize,
+        /// Echoed back so superseded checks can be told apart
+        generation: u64,
         info: Arc<Info>,
         path: Option<String>,
+        priority: u8,
+        /// Pieces to check, all of them if None
+        pieces: Option<Vec<u32>>,
         idx: u32,
         invalid: Vec<u32>,
     },
@@ -80,6 +112,18 @@ pub enum Request {
         data: Vec<u8>,
         path: PathBuf,
     },
+    ReadOnly {
+        files: Vec<PathBuf>,
+        path: Option<String>,
+        readonly: bool,
+    },
+    /// Creates files at their length, also allocating them if full
+    Allocate {
+        tid: usize,
+        files: Vec<(PathBuf, u64)>,
+        path: Option<String>,
+        full: bool,
+    },
     Download {
         client: SStream,
         ranges: Vec<HttpRange>,
@@ -89,19 +133,98 @@ pub enum Request {
         buf: Vec<u8>,
         buf_idx: usize,
     },
+    /// Computes the SHA-256 digests of a torrent's files
+    HashFiles {
+        tid: usize,
+        /// Echoed back so superseded responses can be told apart
+        generation: u64,
+        path: Option<String>,
+        /// Index, path and length of the files left to hash, the
+        /// one being hashed last
+        files: Vec<(usize, PathBuf, u64)>,
+        offset: u64,
+        hasher: Box<Sha256>,
+        digests: Vec<(usize, [u8; 32])>,
+    },
+    /// Checks that a torrent's files are still on disk
+    Watch {
+        tid: usize,
+        path: Option<String>,
+        /// Path and length of the files left to check, each of which
+        /// holds at least that many bytes of downloaded data
+        files: Vec<(PathBuf, u64)>,
+        /// Files found missing, or shorter than expected along with
+        /// their length
+        missing: Vec<(PathBuf, Option<u64>)>,
+    },
     FreeSpace,
+    /// Asks for the read cache's usage
+    CacheStats,
     Ping,
     Shutdown,
 }
 
 pub enum Response {
-    Read { context: Ctx, data: Buffer },
-    ValidationComplete { tid: usize, invalid: Vec<u32> },
-    PieceValidated { tid: usize, piece: u32, valid: bool },
-    ValidationUpdate { tid: usize, percent: f32 },
-    Moved { tid: usize, path: String },
+    Read {
+        context: Ctx,
+        data: Buffer,
+    },
+    ValidationComplete {
+        tid: usize,
+        generation: u64,
+        invalid: Vec<u32>,
+    },
+    PieceValidated {
+        tid: usize,
+        piece: u32,
+        valid: bool,
+    },
+    ValidationUpdate {
+        tid: usize,
+        generation: u64,
+        percent: f32,
+    },
+    ValidationQueued {
+        tid: usize,
+        position: u32,
+    },
+    Moved {
+        tid: usize,
+        from: String,
+        path: String,
+    },
+    MoveUpdate {
+        tid: usize,
+        percent: f32,
+    },
+    /// Digests of the files which could be read, by file index
+    FilesHashed {
+        tid: usize,
+        generation: u64,
+        digests: Vec<(usize, [u8; 32])>,
+    },
+    /// Files of a torrent which were removed or truncated underneath it,
+    /// with their length if still there
+    FilesMissing {
+        tid: usize,
+        files: Vec<(PathBuf, Option<u64>)>,
+    },
+    /// Blocks of a piece which made it to disk, by offset
+    Written {
+        tid: usize,
+        piece: u32,
+        blocks: Vec<u32>,
+    },
     FreeSpace(u64),
-    Error { tid: usize, err: io::Error },
+    CacheStats {
+        size: u64,
+        hits: u64,
+        misses: u64,
+    },
+    Error {
+        tid: usize,
+        err: io::Error,
+    },
 }
 
 pub struct Ctx {
@@ -120,9 +243,18 @@ pub enum JobRes {
 }
 
 impl Request {
-    pub fn write(tid: usize, data: Buffer, locations: LocIter, path: Option<String>) -> Request {
+    pub fn write(
+        tid: usize,
+        piece: u32,
+        begin: u32,
+        data: Buffer,
+        locations: LocIter,
+        path: Option<String>,
+    ) -> Request {
         Request::Write {
             tid,
+            piece,
+            begin,
             data,
             locations,
             path,
@@ -142,11 +274,61 @@ impl Request {
         Request::Serialize { tid, data, hash }
     }
 
-    pub fn validate(tid: usize, info: Arc<Info>, path: Option<String>) -> Request {
+    pub fn intent(hash: [u8; 20], piece: u32) -> Request {
+        Request::Intent { hash, piece }
+    }
+
+    pub fn checkpoint(
+        tid: usize,
+        hash: [u8; 20],
+        files: Vec<PathBuf>,
+        path: Option<String>,
+        pending: Vec<u32>,
+    ) -> Request {
+        Request::Checkpoint {
+            tid,
+            hash,
+            files,
+            path,
+            pending,
+        }
+    }
+
+    pub fn validate(
+        tid: usize,
+        generation: u64,
+        info: Arc<Info>,
+        path: Option<String>,
+        priority: u8,
+    ) -> Request {
         Request::Validate {
             tid,
+            generation,
             info,
             path,
+            priority,
+            pieces: None,
+            idx: 0,
+            invalid: Vec::new(),
+        }
+    }
+
+    /// Checks only the given pieces of a torrent.
+    pub fn validate_pieces(
+        tid: usize,
+        generation: u64,
+        info: Arc<Info>,
+        path: Option<String>,
+        priority: u8,
+        pieces: Vec<u32>,
+    ) -> Request {
+        Request::Validate {
+            tid,
+            generation,
+            info,
+            path,
+            priority,
+            pieces: Some(pieces),
             idx: 0,
             invalid: Vec::new(),
         }
@@ -182,6 +364,56 @@ impl Request {
         }
     }
 
+    pub fn readonly(files: Vec<PathBuf>, path: Option<String>, readonly: bool) -> Request {
+        Request::ReadOnly {
+            files,
+            path,
+            readonly,
+        }
+    }
+
+    pub fn allocate(
+        tid: usize,
+        files: Vec<(PathBuf, u64)>,
+        path: Option<String>,
+        full: bool,
+    ) -> Request {
+        Request::Allocate {
+            tid,
+            files,
+            path,
+            full,
+        }
+    }
+
+    pub fn watch(tid: usize, mut files: Vec<(PathBuf, u64)>, path: Option<String>) -> Request {
+        files.reverse();
+        Request::Watch {
+            tid,
+            path,
+            files,
+            missing: Vec::new(),
+        }
+    }
+
+    pub fn hash_files(
+        tid: usize,
+        generation: u64,
+        mut files: Vec<(usize, PathBuf, u64)>,
+        path: Option<String>,
+    ) -> Request {
+        files.reverse();
+        Request::HashFiles {
+            tid,
+            generation,
+            path,
+            files,
+            offset: 0,
+            hasher: Box::new(Sha256::new()),
+            digests: Vec::new(),
+        }
+    }
+
     pub fn download(
         client: SStream,
         mut ranges: Vec<HttpRange>,
@@ -266,6 +498,29 @@ impl Request {
         }
     }
 
+    /// Serves data held in memory rather than a file.
+    pub fn download_data(client: SStream, name: &str, data: Vec<u8>) -> Request {
+        let http_lines = [
+            "HTTP/1.1 200 OK".to_owned(),
+            format!("Content-Length: {}", data.len()),
+            "Content-Type: application/octet-stream".to_owned(),
+            format!("Content-Disposition: attachment; filename=\"{}\"", name),
+            "Connection: Close".to_owned(),
+            "\r\n".to_owned(),
+        ];
+        let mut buf = http_lines.join("\r\n").into_bytes();
+        buf.extend_from_slice(&data);
+        Request::Download {
+            client,
+            multipart: false,
+            ranges: vec![],
+            file_path: name.to_owned(),
+            file_len: data.len() as u64,
+            buf,
+            buf_idx: 0,
+        }
+    }
+
     pub fn shutdown() -> Request {
         Request::Shutdown
     }
@@ -277,7 +532,22 @@ impl Request {
         }
     }
 
-    pub fn execute(self, fc: &mut FileCache, bc: &mut BufCache) -> io::Result<JobRes> {
+    /// Order in which queued validations are started: higher
+    /// priority torrents first, then the smallest.
+    pub fn check_order(&self) -> (cmp::Reverse<u8>, u64) {
+        match self {
+            Request::Validate { priority, info, .. } => (cmp::Reverse(*priority), info.total_len),
+            _ => (cmp::Reverse(0), 0),
+        }
+    }
+
+    pub fn execute(
+        self,
+        fc: &mut FileCache,
+        bc: &mut BufCache,
+        rc: &mut ReadCache,
+        wb: &mut WriteBuffer,
+    ) -> io::Result<JobRes> {
         let sd = &CONFIG.disk.session;
         let dd = &CONFIG.disk.directory;
         let (mut tb, mut tpb, mut tpb2) = bc.data();
@@ -287,6 +557,116 @@ impl Request {
                 let free_space = fs2::available_space(dd.as_str())?;
                 return Ok(JobRes::Resp(Response::FreeSpace(free_space)));
             }
+            Request::HashFiles {
+                tid,
+                generation,
+                path,
+                mut files,
+                mut offset,
+                mut hasher,
+                mut digests,
+            } => {
+                wb.flush_torrent(fc, tid)?;
+                let start = time::Instant::now();
+                while start.elapsed() < time::Duration::from_millis(JOB_TIME_SLICE) {
+                    let (idx, file, len) = match files.last() {
+                        Some(f) => f,
+                        None => break,
+                    };
+                    let pb = tpb.get(path.as_ref().unwrap_or(dd));
+                    pb.push(file);
+                    let n = cmp::min(HASH_CHUNK, len - offset) as usize;
+                    let buf = tb.get(n);
+                    // Empty files needn't exist on disk
+                    let res = if n == 0 {
+                        Ok(())
+                    } else {
+                        fc.read_file_range(pb, offset, buf)
+                    };
+                    if let Err(e) = res {
+                        // Files which can't be read are left without a digest
+                        error!("Failed to hash {:?}: {}", pb, e);
+                        files.pop();
+                        offset = 0;
+                        hasher.reset();
+                        continue;
+                    }
+                    hasher.update(&buf);
+                    offset += n as u64;
+                    if offset == *len {
+                        digests.push((*idx, hasher.finalize_reset().into()));
+                        files.pop();
+                        offset = 0;
+                    }
+                }
+                if files.is_empty() {
+                    return Ok(JobRes::Resp(Response::FilesHashed {
+                        tid,
+                        generation,
+                        digests,
+                    }));
+                }
+                return Ok(JobRes::Paused(Request::HashFiles {
+                    tid,
+                    generation,
+                    path,
+                    files,
+                    offset,
+                    hasher,
+                    digests,
+                }));
+            }
+            Request::Watch {
+                tid,
+                path,
+                mut files,
+                mut missing,
+            } => {
+                let start = time::Instant::now();
+                while start.elapsed() < time::Duration::from_millis(JOB_TIME_SLICE) {
+                    let (file, len) = match files.pop() {
+                        Some(f) => f,
+                        None => break,
+                    };
+                    let pb = tpb.get(path.as_ref().unwrap_or(dd));
+                    pb.push(&file);
+                    match fs::metadata(&pb) {
+                        Ok(ref m) if m.len() >= len => {}
+                        Ok(m) => missing.push((file, Some(m.len()))),
+                        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                            missing.push((file, None))
+                        }
+                        // Left to fail on access, with the error to show for it
+                        Err(e) => debug!("Failed to stat {:?}: {}", pb, e),
+                    }
+                }
+                if !files.is_empty() {
+                    return Ok(JobRes::Paused(Request::Watch {
+                        tid,
+                        path,
+                        files,
+                        missing,
+                    }));
+                }
+                if missing.is_empty() {
+                    return Ok(JobRes::Done);
+                }
+                // Cached handles would keep serving the removed files
+                rc.remove_torrent(tid);
+                for (file, _) in &missing {
+                    let pb = tpb.get(path.as_ref().unwrap_or(dd));
+                    pb.push(file);
+                    fc.remove_file(pb);
+                }
+                return Ok(JobRes::Resp(Response::FilesMissing {
+                    tid,
+                    files: missing,
+                }));
+            }
+            Request::CacheStats => {
+                let (size, hits, misses) = rc.stats();
+                return Ok(JobRes::Resp(Response::CacheStats { size, hits, misses }));
+            }
             Request::WriteFile { path, data } => {
                 let p = tpb.get(path.iter());
                 p.set_extension("temp");
@@ -309,29 +689,44 @@ impl Request {
                 }
             }
             Request::Write {
+                tid,
+                piece,
+                begin,
                 data,
                 locations,
                 path,
-                ..
             } => {
-                for loc in locations {
+                // Written out by the disk thread once the piece is
+                // validated or the buffer is full
+                wb.insert(tid, piece, begin, path, data, locations.collect());
+            }
+            Request::ReadOnly {
+                files,
+                path,
+                readonly,
+            } => {
+                for file in &files {
                     let pb = tpb.get(path.as_ref().unwrap_or(dd));
-                    pb.push(loc.path());
-                    fc.write_file_range(
-                        &pb,
-                        if loc.allocate {
-                            Ok(loc.file_len)
-                        } else {
-                            Err(loc.file_len)
-                        },
-                        loc.offset,
-                        &data[loc.start..loc.end],
-                    )?;
-                    if loc.end - loc.start != 16_384 {
-                        fc.flush_file(&pb);
+                    pb.push(file);
+                    let res = if readonly {
+                        fc.make_readonly(pb, CONFIG.disk.readonly_mask)
+                    } else {
+                        fc.make_writable(pb)
+                    };
+                    if let Err(e) = res {
+                        error!("Failed to change permissions of {:?}: {}", pb, e);
                     }
                 }
             }
+            Request::Allocate {
+                files, path, full, ..
+            } => {
+                for (file, len) in &files {
+                    let pb = tpb.get(path.as_ref().unwrap_or(dd));
+                    pb.push(file);
+                    fc.allocate_file(pb, *len, full)?;
+                }
+            }
             Request::Read {
                 context,
                 mut data,
@@ -339,10 +734,16 @@ impl Request {
                 path,
                 ..
             } => {
-                for loc in locations {
-                    let pb = tpb.get(path.as_ref().unwrap_or(dd));
-                    pb.push(loc.path());
-                    fc.read_file_range(&pb, loc.offset, &mut data[loc.start..loc.end])?;
+                let key = (context.tid, context.idx, context.begin, context.length);
+                let len = context.length as usize;
+                if !rc.get(key, &mut data[..len]) {
+                    wb.flush_piece(fc, context.tid, context.idx)?;
+                    for loc in locations {
+                        let pb = tpb.get(path.as_ref().unwrap_or(dd));
+                        pb.push(loc.path());
+                        fc.read_file_range(&pb, loc.offset, &mut data[loc.start..loc.end])?;
+                    }
+                    rc.insert(key, &data[..len]);
                 }
                 return Ok(JobRes::Resp(Response::read(context, data)));
             }
@@ -352,33 +753,83 @@ impl Request {
                 to,
                 target,
             } => {
+                rc.remove_torrent(tid);
+                wb.flush_torrent(fc, tid)?;
                 let fp = tpb.get(&from);
                 let tp = tpb2.get(&to);
                 fp.push(target.clone());
                 tp.push(target);
+                // Files are only created once written to, so there may
+                // be nothing to move yet
+                if !fp.exists() {
+                    return Ok(JobRes::Resp(Response::moved(tid, from, to)));
+                }
                 match fs::rename(&fp, &tp) {
                     Ok(_) => {}
-                    // Cross filesystem move, try to copy then delete
+                    // Cross filesystem move, copy the data over bit by bit
                     Err(ref e) if e.raw_os_error() == Some(EXDEV) => {
-                        match fs_extra::dir::copy(&fp, &tp, &fs_extra::dir::CopyOptions::new()) {
-                            Ok(_) => {
-                                fs::remove_dir_all(&fp)?;
-                            }
-                            Err(e) => {
-                                fs::remove_dir_all(&tp)?;
-                                error!("FS copy failed: {:?}", e);
-                                return io_err("Failed to copy directory across filesystems!");
-                            }
-                        }
+                        debug!("Copying {:?} to {:?} across filesystems", fp, tp);
+                        let copy = Box::new(Copy::new(fp, tp, fc.ownership().clone())?);
+                        return Ok(JobRes::Update(
+                            Request::Copy {
+                                tid,
+                                from,
+                                to,
+                                copy,
+                            },
+                            Response::MoveUpdate { tid, percent: 0. },
+                        ));
                     }
                     Err(e) => {
                         error!("FS rename failed: {:?}", e);
                         return Err(e);
                     }
                 }
-                return Ok(JobRes::Resp(Response::moved(tid, to)));
+                return Ok(JobRes::Resp(Response::moved(tid, from, to)));
+            }
+            Request::Copy {
+                tid,
+                from,
+                to,
+                mut copy,
+            } => {
+                wb.flush_torrent(fc, tid)?;
+                match copy.step(time::Duration::from_millis(JOB_TIME_SLICE)) {
+                    Ok(true) => {
+                        copy.finish(fc);
+                        return Ok(JobRes::Resp(Response::moved(tid, from, to)));
+                    }
+                    Ok(false) if copy.resume_at().is_some() => {
+                        return Ok(JobRes::Paused(Request::Copy {
+                            tid,
+                            from,
+                            to,
+                            copy,
+                        }));
+                    }
+                    Ok(false) => {
+                        let percent = copy.progress();
+                        return Ok(JobRes::Update(
+                            Request::Copy {
+                                tid,
+                                from,
+                                to,
+                                copy,
+                            },
+                            Response::MoveUpdate { tid, percent },
+                        ));
+                    }
+                    Err(e) => {
+                        error!("FS copy failed: {:?}", e);
+                        copy.abort();
+                        return Err(e);
+                    }
+                }
             }
-            Request::Serialize { data, hash, .. } => {
+            Request::Serialize { tid, data, hash } => {
+                // Partially downloaded blocks listed by the session have
+                // to be on disk before it is
+                wb.flush_torrent(fc, tid)?;
                 let temp = tpb.get(sd);
                 temp.push(hash_to_id(&hash) + ".temp");
                 let mut f = fs::OpenOptions::new()
@@ -390,19 +841,45 @@ impl Request {
                 actual.push(hash_to_id(&hash));
                 fs::rename(temp, actual)?;
             }
+            Request::Intent { hash, piece } => {
+                if let Err(e) = intent::append(&intent::path(&hash), &[piece]) {
+                    error!("Failed to log write of piece {}: {}", piece, e);
+                }
+            }
+            Request::Checkpoint {
+                tid,
+                hash,
+                files,
+                path,
+                pending,
+            } => {
+                wb.flush_torrent(fc, tid)?;
+                for file in &files {
+                    let pb = tpb.get(path.as_ref().unwrap_or(dd));
+                    pb.push(file);
+                    fc.sync_file(pb)?;
+                }
+                if let Err(e) = intent::rewrite(&intent::path(&hash), &pending) {
+                    error!("Failed to update intent log: {}", e);
+                }
+            }
             Request::Delete {
+                tid,
                 hash,
                 files,
                 path,
                 artifacts,
-                ..
             } => {
+                rc.remove_torrent(tid);
+                wb.discard_torrent(tid);
                 {
                     let spb = tpb.get(sd);
                     spb.push(hash_to_id(&hash));
                     fs::remove_file(&spb).ok();
                     spb.set_extension("torrent");
                     fs::remove_file(&spb).ok();
+                    spb.set_extension("intent");
+                    fs::remove_file(&spb).ok();
                 }
 
                 for file in &files {
@@ -430,39 +907,53 @@ impl Request {
                 path,
                 piece,
             } => {
+                rc.remove_piece(tid, piece);
+                wb.flush_piece(fc, tid, piece)?;
                 let buf = tb.get(info.piece_len as usize);
-                let mut ctx = Sha1::new();
+                let mut valid = true;
                 let locs = Info::piece_disk_locs(&info, piece);
                 for loc in locs {
                     let pb = tpb.get(path.as_ref().unwrap_or(dd));
                     pb.push(loc.path());
-                    fc.read_file_range(&pb, loc.offset, &mut buf[loc.start..loc.end])
-                        .map(|_| ctx.update(&buf[loc.start..loc.end]))
-                        .ok();
+                    let data = &mut buf[loc.start..loc.end];
+                    let res = if CONFIG.disk.paranoid {
+                        fc.read_file_range_uncached(pb, loc.offset, data)
+                    } else {
+                        fc.read_file_range(pb, loc.offset, data)
+                    };
+                    valid &= res.is_ok();
                 }
-                let digest = ctx.finalize();
-                return Ok(JobRes::Resp(Response::PieceValidated {
-                    tid,
-                    piece,
-                    valid: digest[..] == info.hashes[piece as usize][..],
-                }));
+                let len = info.piece_len(piece) as usize;
+                let valid = valid && info.piece_valid(piece, &buf[..len]);
+                if valid {
+                    for loc in Info::piece_disk_locs(&info, piece) {
+                        let pb = tpb.get(path.as_ref().unwrap_or(dd));
+                        pb.push(loc.path());
+                        fc.sync_range(pb, loc.offset, (loc.end - loc.start) as u64)?;
+                    }
+                }
+                return Ok(JobRes::Resp(Response::PieceValidated { tid, piece, valid }));
             }
             Request::Validate {
                 tid,
+                generation,
                 info,
                 path,
+                priority,
+                pieces,
                 mut idx,
                 mut invalid,
             } => {
+                rc.remove_torrent(tid);
+                wb.flush_torrent(fc, tid)?;
                 let buf = tb.get(info.piece_len as usize);
                 let start = time::Instant::now();
+                let total = pieces.as_ref().map_or(info.pieces(), |p| p.len() as u32);
 
-                while idx < info.pieces()
-                    && start.elapsed() < time::Duration::from_millis(JOB_TIME_SLICE)
-                {
+                while idx < total && start.elapsed() < time::Duration::from_millis(JOB_TIME_SLICE) {
+                    let piece = pieces.as_ref().map_or(idx, |p| p[idx as usize]);
                     let mut valid = true;
-                    let mut ctx = Sha1::new();
-                    let locs = Info::piece_disk_locs(&info, idx);
+                    let locs = Info::piece_disk_locs(&info, piece);
                     for loc in locs {
                         if !valid {
                             break;
@@ -471,31 +962,35 @@ impl Request {
                         pb.push(loc.path());
                         valid &= fc
                             .read_file_range(&pb, loc.offset, &mut buf[loc.start..loc.end])
-                            .map(|_| ctx.update(&buf[loc.start..loc.end]))
                             .is_ok();
                     }
-                    let digest = ctx.finalize();
-                    if !valid || digest[..] != info.hashes[idx as usize][..] {
-                        invalid.push(idx);
+                    let len = info.piece_len(piece) as usize;
+                    if !valid || !info.piece_valid(piece, &buf[..len]) {
+                        invalid.push(piece);
                     }
 
                     idx += 1;
                 }
-                if idx == info.pieces() {
-                    return Ok(JobRes::Resp(Response::validation_complete(tid, invalid)));
+                if idx == total {
+                    return Ok(JobRes::Resp(Response::validation_complete(
+                        tid, generation, invalid,
+                    )));
                 } else {
-                    let pieces = info.pieces();
                     return Ok(JobRes::Update(
                         Request::Validate {
                             tid,
+                            generation,
                             info,
                             path,
+                            priority,
+                            pieces,
                             idx,
                             invalid,
                         },
                         Response::ValidationUpdate {
                             tid,
-                            percent: idx as f32 / pieces as f32,
+                            generation,
+                            percent: idx as f32 / total as f32,
                         },
                     ));
                 }
@@ -597,16 +1092,50 @@ impl Request {
         match *self {
             Request::Read { ref context, .. } => Some(context.tid),
             Request::Serialize { tid, .. }
+            | Request::Checkpoint { tid, .. }
             | Request::Validate { tid, .. }
             | Request::ValidatePiece { tid, .. }
             | Request::Delete { tid, .. }
             | Request::Move { tid, .. }
+            | Request::Copy { tid, .. }
+            | Request::Allocate { tid, .. }
+            | Request::HashFiles { tid, .. }
+            | Request::Watch { tid, .. }
             | Request::Write { tid, .. } => Some(tid),
             Request::WriteFile { .. }
+            | Request::Intent { .. }
+            | Request::ReadOnly { .. }
             | Request::Download { .. }
             | Request::Shutdown
             | Request::Ping
-            | Request::FreeSpace => None,
+            | Request::FreeSpace
+            | Request::CacheStats => None,
+        }
+    }
+
+    /// When a paused job may run again, if it's waiting for something
+    /// rather than just yielding to other jobs.
+    pub fn resume_at(&self) -> Option<time::Instant> {
+        match *self {
+            Request::Copy { ref copy, .. } => copy.resume_at(),
+            _ => None,
+        }
+    }
+
+    /// Directory the torrent's files are looked up in, for requests
+    /// which access them.
+    pub fn root_mut(&mut self) -> Option<&mut Option<String>> {
+        match *self {
+            Request::Write { ref mut path, .. }
+            | Request::Read { ref mut path, .. }
+            | Request::Checkpoint { ref mut path, .. }
+            | Request::Delete { ref mut path, .. }
+            | Request::Validate { ref mut path, .. }
+            | Request::ValidatePiece { ref mut path, .. }
+            | Request::Allocate { ref mut path, .. }
+            | Request::HashFiles { ref mut path, .. }
+            | Request::Watch { ref mut path, .. } => Some(path),
+            _ => None,
         }
     }
 }
@@ -662,12 +1191,16 @@ impl Response {
         Response::Error { tid, err }
     }
 
-    pub fn moved(tid: usize, path: String) -> Response {
-        Response::Moved { tid, path }
+    pub fn moved(tid: usize, from: String, path: String) -> Response {
+        Response::Moved { tid, from, path }
     }
 
-    pub fn validation_complete(tid: usize, invalid: Vec<u32>) -> Response {
-        Response::ValidationComplete { tid, invalid }
+    pub fn validation_complete(tid: usize, generation: u64, invalid: Vec<u32>) -> Response {
+        Response::ValidationComplete {
+            tid,
+            generation,
+            invalid,
+        }
     }
 
     pub fn tid(&self) -> usize {
@@ -675,10 +1208,15 @@ impl Response {
             Response::Read { ref context, .. } => context.tid,
             Response::ValidationComplete { tid, .. }
             | Response::Moved { tid, .. }
+            | Response::MoveUpdate { tid, .. }
             | Response::ValidationUpdate { tid, .. }
+            | Response::ValidationQueued { tid, .. }
             | Response::PieceValidated { tid, .. }
+            | Response::FilesHashed { tid, .. }
+            | Response::FilesMissing { tid, .. }
+            | Response::Written { tid, .. }
             | Response::Error { tid, .. } => tid,
-            Response::FreeSpace(_) => unreachable!(),
+            Response::FreeSpace(_) | Response::CacheStats { .. } => unreachable!(),
         }
     }
 }
@@ -700,3 +1238,80 @@ impl Ctx {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_files() {
+        let dir = std::env::temp_dir().join(format!("synapse-hash-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a"), vec![7u8; HASH_CHUNK as usize + 10]).unwrap();
+        fs::write(dir.join("b"), b"abc").unwrap();
+        let files = vec![
+            (0, PathBuf::from("a"), HASH_CHUNK + 10),
+            (1, PathBuf::from("missing"), 10),
+            (2, PathBuf::from("b"), 3),
+            (3, PathBuf::from("empty"), 0),
+        ];
+        let mut req = Request::hash_files(0, 0, files, Some(dir.to_string_lossy().into_owned()));
+        let (mut fc, mut bc, mut rc) = (FileCache::new(), BufCache::new(), ReadCache::new(0));
+        let mut wb = WriteBuffer::new(0);
+        let digests = loop {
+            match req.execute(&mut fc, &mut bc, &mut rc, &mut wb).unwrap() {
+                JobRes::Paused(r) => req = r,
+                JobRes::Resp(Response::FilesHashed { digests, .. }) => break digests,
+                _ => unreachable!(),
+            }
+        };
+        fs::remove_dir_all(&dir).unwrap();
+
+        let a: [u8; 32] = Sha256::digest(&vec![7u8; HASH_CHUNK as usize + 10]).into();
+        assert_eq!(digests[0], (0, a));
+        let (idx, b) = digests[1];
+        assert_eq!(idx, 2);
+        assert_eq!(
+            crate::util::to_hex(&b),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            crate::util::to_hex(&digests[2].1),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(digests.len(), 3);
+    }
+
+    #[test]
+    fn test_watch() {
+        let dir = std::env::temp_dir().join(format!("synapse-watch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a"), b"abcd").unwrap();
+        fs::write(dir.join("b"), b"ab").unwrap();
+        let files = vec![
+            (PathBuf::from("a"), 4),
+            (PathBuf::from("b"), 3),
+            (PathBuf::from("c"), 1),
+        ];
+        let mut req = Request::watch(0, files, Some(dir.to_string_lossy().into_owned()));
+        let (mut fc, mut bc, mut rc) = (FileCache::new(), BufCache::new(), ReadCache::new(0));
+        let mut wb = WriteBuffer::new(0);
+        let missing = loop {
+            match req.execute(&mut fc, &mut bc, &mut rc, &mut wb).unwrap() {
+                JobRes::Paused(r) => req = r,
+                JobRes::Resp(Response::FilesMissing { files, .. }) => break files,
+                _ => unreachable!(),
+            }
+        };
+        assert_eq!(
+            missing,
+            vec![(PathBuf::from("b"), Some(2)), (PathBuf::from("c"), None)]
+        );
+
+        let files = vec![(PathBuf::from("a"), 3)];
+        let req = Request::watch(0, files, Some(dir.to_string_lossy().into_owned()));
+        let res = req.execute(&mut fc, &mut bc, &mut rc, &mut wb).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(res, JobRes::Done));
+    }
+}
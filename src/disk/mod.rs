@@ -1,20 +1,31 @@
 mod cache;
+mod copy;
+mod hash;
+mod intent;
 mod job;
+mod placement;
 
+pub use self::intent::suspect_pieces;
 pub use self::job::Ctx;
 pub use self::job::Location;
 pub use self::job::Request;
 pub use self::job::Response;
+pub use self::placement::Roots;
 
 use std::collections::VecDeque;
-use std::{fs, io, thread};
+use std::path::PathBuf;
+use std::{cmp, fs, io, thread, time};
 
-use self::cache::{BufCache, FileCache};
+use self::cache::{BufCache, FileCache, Ownership, ReadCache, WriteBuffer};
+use self::hash::HashPool;
 use self::job::JobRes;
+use crate::util::{FHashMap, UHashMap};
 use crate::{handle, CONFIG};
 
 const POLL_INT_MS: usize = 1000;
 const JOB_TIME_SLICE: u64 = 150;
+/// Seconds after which blocks of an unfinished piece are written out
+const WRITE_IDLE_SECS: u64 = 5;
 
 pub struct Disk {
     poll: amy::Poller,
@@ -23,7 +34,22 @@ pub struct Disk {
     files: FileCache,
     active: VecDeque<Request>,
     sequential: VecDeque<Request>,
+    /// Whether positions in the sequential queue have changed
+    queue_dirty: bool,
     bufs: BufCache,
+    reads: ReadCache,
+    writes: WriteBuffer,
+    /// Checks whole torrents, unless done by the disk thread itself
+    hashes: Option<HashPool>,
+    /// Where torrents' data was last moved from and to, so that requests
+    /// issued before the torrent learned of it find the files
+    moved: UHashMap<(String, String)>,
+    /// Paused jobs which may only run again from the given time, such
+    /// as throttled moves
+    waiting: Vec<(time::Instant, Request)>,
+    /// Pieces to add to torrents' intent logs, appended together once
+    /// all pending requests are read so each log is synced only once
+    intents: FHashMap<[u8; 20], Vec<u32>>,
 }
 
 impl Disk {
@@ -32,14 +58,27 @@ impl Disk {
         ch: handle::Handle<Request, Response>,
         jobs: amy::Receiver<Request>,
     ) -> Disk {
+        let hashes = match CONFIG.disk.hash_threads {
+            0 => None,
+            n => HashPool::new(&mut poll.get_registrar(), n)
+                .map_err(|e| error!("Failed to start hash threads: {}", e))
+                .ok(),
+        };
         Disk {
             poll,
             ch,
             jobs,
             files: FileCache::new(),
             bufs: BufCache::new(),
+            reads: ReadCache::new(CONFIG.disk.read_cache),
+            writes: WriteBuffer::new(CONFIG.disk.write_buffer),
+            hashes,
             active: VecDeque::new(),
             sequential: VecDeque::new(),
+            queue_dirty: false,
+            moved: UHashMap::default(),
+            waiting: Vec::new(),
+            intents: FHashMap::default(),
         }
     }
 
@@ -48,7 +87,7 @@ impl Disk {
         fs::create_dir_all(sd).unwrap();
 
         loop {
-            match self.poll.wait(POLL_INT_MS) {
+            match self.poll.wait(self.poll_timeout()) {
                 Ok(_) => {
                     if self.handle_events() {
                         break;
@@ -58,36 +97,210 @@ impl Disk {
                     error!("Failed to poll for events: {}", e);
                 }
             }
+            self.resume_waiting();
             if !self.active.is_empty() && self.handle_active() {
                 break;
             }
+            let idle = time::Duration::from_secs(WRITE_IDLE_SECS);
+            let errors = self.writes.flush_idle(&mut self.files, idle);
+            self.report_writes(errors);
         }
 
         // Try to finish up remaining jobs
         for job in self.active.drain(..) {
             if job.concurrent() {
-                job.execute(&mut self.files, &mut self.bufs).ok();
+                job.execute(
+                    &mut self.files,
+                    &mut self.bufs,
+                    &mut self.reads,
+                    &mut self.writes,
+                )
+                .ok();
             }
         }
+        for (_, e) in self
+            .writes
+            .flush_idle(&mut self.files, time::Duration::from_secs(0))
+        {
+            error!("Failed to write buffered blocks: {}", e);
+        }
+    }
+
+    /// Time until the next waiting job is due, at most POLL_INT_MS.
+    fn poll_timeout(&self) -> usize {
+        let now = time::Instant::now();
+        self.waiting
+            .iter()
+            .map(|(at, _)| at.saturating_duration_since(now).as_millis() as usize)
+            .fold(POLL_INT_MS, cmp::min)
+    }
+
+    fn resume_waiting(&mut self) {
+        let now = time::Instant::now();
+        let mut i = 0;
+        while i < self.waiting.len() {
+            if self.waiting[i].0 <= now {
+                let (_, req) = self.waiting.swap_remove(i);
+                self.active.push_back(req);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Passes on failed writes, and which blocks were written out so
+    /// torrents only save those in their resume data.
+    fn report_writes(&mut self, errors: Vec<(usize, io::Error)>) {
+        for (tid, e) in errors {
+            self.ch.send(Response::error(tid, e)).ok();
+        }
+        for (tid, piece, blocks) in self.writes.take_flushed() {
+            self.ch.send(Response::Written { tid, piece, blocks }).ok();
+        }
     }
 
     fn enqueue_req(&mut self, req: Request) {
-        if req.concurrent() || self.active.iter().find(|r| !r.concurrent()).is_none() {
+        let running = self.active.iter().filter(|r| !r.concurrent()).count()
+            + self.hashes.as_ref().map(HashPool::running).unwrap_or(0);
+        if req.concurrent() {
             self.active.push_back(req);
+        } else if running < CONFIG.disk.parallel_checks.max(1) {
+            self.start_check(req);
+        } else {
+            let order = req.check_order();
+            let pos = self
+                .sequential
+                .iter()
+                .position(|r| r.check_order() > order)
+                .unwrap_or(self.sequential.len());
+            self.sequential.insert(pos, req);
+            self.queue_dirty = true;
+        }
+    }
+
+    /// Runs a check, on the hash threads if there are any.
+    fn start_check(&mut self, mut req: Request) {
+        self.redirect(&mut req);
+        let pool = match self.hashes {
+            Some(ref mut pool) => pool,
+            None => return self.active.push_back(req),
+        };
+        if let Request::Validate {
+            tid,
+            generation,
+            info,
+            path,
+            pieces,
+            ..
+        } = req
+        {
+            // The workers only see what's on disk
+            self.reads.remove_torrent(tid);
+            if let Err(e) = self.writes.flush_torrent(&mut self.files, tid) {
+                self.ch.send(Response::error(tid, e)).ok();
+                return;
+            }
+            let root = PathBuf::from(path.as_ref().unwrap_or(&CONFIG.disk.directory));
+            pool.check(tid, generation, info, root, pieces);
+        } else {
+            self.active.push_back(req);
+        }
+    }
+
+    /// Points a request still using the directory its torrent's data was
+    /// moved out of at the new one. Once the torrent issues requests for
+    /// the new directory itself the move is forgotten.
+    fn redirect(&mut self, req: &mut Request) {
+        let tid = match req.tid() {
+            Some(tid) => tid,
+            None => return,
+        };
+        let (from, to) = match self.moved.get(&tid) {
+            Some(m) => m,
+            None => return,
+        };
+        let root = match req.root_mut() {
+            Some(root) => root,
+            None => return,
+        };
+        if root.as_ref().unwrap_or(&CONFIG.disk.directory) == from {
+            *root = Some(to.clone());
         } else {
-            self.sequential.push_back(req);
+            self.moved.remove(&tid);
+        }
+    }
+
+    /// Starts the next queued check once one has finished.
+    fn next_check(&mut self) {
+        if let Some(r) = self.sequential.pop_front() {
+            self.start_check(r);
+            self.queue_dirty = true;
+            self.announce_queue();
+        }
+    }
+
+    /// Passes on the progress of checks done by the hash threads.
+    fn poll_checks(&mut self) {
+        let resps = match self.hashes {
+            Some(ref mut pool) => pool.poll(),
+            None => return,
+        };
+        for r in resps {
+            let done = matches!(r, Response::ValidationComplete { .. });
+            self.ch.send(r).ok();
+            if done {
+                self.next_check();
+            }
+        }
+    }
+
+    fn log_intents(&mut self) {
+        for (hash, pieces) in self.intents.drain() {
+            if let Err(e) = intent::append(&intent::path(&hash), &pieces) {
+                error!("Failed to log write of {} pieces: {}", pieces.len(), e);
+            }
+        }
+    }
+
+    /// Tells torrents waiting to be checked their current position.
+    fn announce_queue(&mut self) {
+        if !self.queue_dirty {
+            return;
+        }
+        self.queue_dirty = false;
+        for (i, req) in self.sequential.iter().enumerate() {
+            if let Some(tid) = req.tid() {
+                let position = i as u32 + 1;
+                self.ch
+                    .send(Response::ValidationQueued { tid, position })
+                    .ok();
+            }
         }
     }
 
     fn handle_active(&mut self) -> bool {
         let mut rotate = 1;
-        while let Some(j) = self.active.pop_front() {
+        while let Some(mut j) = self.active.pop_front() {
+            self.redirect(&mut j);
             let tid = j.tid();
             let seq = !j.concurrent();
             let mut done = false;
-            match j.execute(&mut self.files, &mut self.bufs) {
+            match j.execute(
+                &mut self.files,
+                &mut self.bufs,
+                &mut self.reads,
+                &mut self.writes,
+            ) {
                 Ok(JobRes::Resp(r)) => {
                     done = true;
+                    if let Response::Moved {
+                        tid,
+                        ref from,
+                        ref path,
+                    } = r
+                    {
+                        self.moved.insert(tid, (from.clone(), path.clone()));
+                    }
                     self.ch.send(r).ok();
                 }
                 Ok(JobRes::Update(s, r)) => {
@@ -99,7 +312,9 @@ impl Disk {
                     }
                 }
                 Ok(JobRes::Paused(s)) => {
-                    if rotate % 3 == 0 {
+                    if let Some(at) = s.resume_at() {
+                        self.waiting.push((at, s));
+                    } else if rotate % 3 == 0 {
                         self.active.push_back(s);
                     } else {
                         self.active.push_front(s);
@@ -117,10 +332,10 @@ impl Disk {
                     }
                 }
             }
+            let errors = self.writes.evict(&mut self.files);
+            self.report_writes(errors);
             if done && seq {
-                if let Some(r) = self.sequential.pop_front() {
-                    self.active.push_back(r);
-                }
+                self.next_check();
             }
             match self.poll.wait(0) {
                 Ok(_) => {
@@ -141,8 +356,12 @@ impl Disk {
         loop {
             match self.ch.recv() {
                 Ok(Request::Shutdown) => {
+                    self.log_intents();
                     return true;
                 }
+                Ok(Request::Intent { hash, piece }) => {
+                    self.intents.entry(hash).or_default().push(piece);
+                }
                 Ok(mut r) => {
                     trace!("Handling disk job!");
                     let tid = r.tid();
@@ -162,6 +381,10 @@ impl Disk {
             }
             self.enqueue_req(r);
         }
+        // Log before any of the writes read above can run
+        self.log_intents();
+        self.poll_checks();
+        self.announce_queue();
         false
     }
 }
@@ -0,0 +1,44 @@
+use crate::config::Placement;
+use crate::CONFIG;
+
+/// Chooses the download directory of torrents added without one,
+/// according to the configured placement policy.
+pub struct Roots {
+    next: usize,
+}
+
+impl Roots {
+    pub fn new() -> Roots {
+        Roots { next: 0 }
+    }
+
+    /// Returns the directory to use, or None if the torrent
+    /// should just use the default directory.
+    pub fn choose(&mut self, category: Option<&str>) -> Option<String> {
+        let roots = Roots::all();
+        match CONFIG.disk.placement {
+            Placement::Directory => None,
+            Placement::Category => category.and_then(|c| CONFIG.disk.categories.get(c).cloned()),
+            Placement::RoundRobin => {
+                let root = roots[self.next % roots.len()].to_owned();
+                self.next = self.next.wrapping_add(1);
+                Some(root)
+            }
+            Placement::FreeSpace => {
+                let space = |r: &&str| fs2::available_space(r).unwrap_or(0);
+                roots.into_iter().max_by_key(space).map(str::to_owned)
+            }
+        }
+    }
+
+    /// The default directory followed by every extra root.
+    fn all() -> Vec<&'static str> {
+        let mut roots = vec![CONFIG.disk.directory.as_str()];
+        for root in &CONFIG.disk.roots {
+            if !roots.contains(&root.as_str()) {
+                roots.push(root);
+            }
+        }
+        roots
+    }
+}
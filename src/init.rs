@@ -4,8 +4,8 @@ use std::{io, process, thread};
 use ctrlc;
 
 use crate::control::acio;
-use crate::{args, control, disk, log, rpc, throttle, tracker};
-use crate::{CONFIG, SHUTDOWN, THROT_TOKS};
+use crate::{args, bench, control, disk, log, rpc, throttle, tracker};
+use crate::{CONFIG, PORT, SHUTDOWN, THROT_TOKS};
 
 pub fn init(args: args::Args) -> Result<(), ()> {
     if let Some(level) = args.level {
@@ -19,7 +19,7 @@ pub fn init(args: args::Args) -> Result<(), ()> {
     info!("Initializing");
 
     // Since the config is lazy loaded, dereference now to check it.
-    CONFIG.port;
+    PORT.store(CONFIG.port, atomic::Ordering::Relaxed);
 
     if let Err(e) = init_signals() {
         error!("Failed to initialize signal handlers: {}", e);
@@ -28,9 +28,12 @@ pub fn init(args: args::Args) -> Result<(), ()> {
     Ok(())
 }
 
-pub fn run() -> Result<(), ()> {
+pub fn run(bench: Option<bench::Benchmark>) -> Result<(), ()> {
     match init_threads() {
-        Ok(threads) => {
+        Ok(mut threads) => {
+            if let Some(b) = bench {
+                threads.push(bench::start(b));
+            }
             for thread in threads {
                 if thread.join().is_err() {
                     error!("Unclean shutdown detected, terminating");
@@ -1,4 +1,16 @@
-use std::fmt;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use std::{fmt, mem};
+
+use chrono::Local;
+
+/// Seconds for which repeats of an error or info message are counted
+/// rather than written, after which their number is logged
+const REPEAT_SECS: u64 = 30;
+/// Most distinct messages whose repeats are counted at once
+const MAX_TRACKED: usize = 1024;
 
 #[derive(PartialEq, PartialOrd)]
 pub enum LogLevel {
@@ -27,6 +39,70 @@ pub fn log_init(level: LogLevel) {
     }
 }
 
+lazy_static! {
+    static ref REPEATS: Mutex<Repeats> = Mutex::new(Repeats::default());
+}
+
+/// Recently logged messages, and how often they were repeated since.
+#[derive(Default)]
+struct Repeats {
+    recent: HashMap<String, (Instant, u32)>,
+}
+
+impl Repeats {
+    /// Records that msg is being logged, returning whether it should
+    /// be written rather than counted as a repeat.
+    fn record(&mut self, msg: &str, now: Instant) -> bool {
+        if let Some((_, count)) = self.recent.get_mut(msg) {
+            *count += 1;
+            return false;
+        }
+        if self.recent.len() < MAX_TRACKED {
+            self.recent.insert(msg.to_owned(), (now, 0));
+        }
+        true
+    }
+
+    /// Forgets the messages logged more than REPEAT_SECS ago, returning
+    /// those which were repeated along with how often.
+    fn expire(&mut self, now: Instant) -> Vec<(String, u32)> {
+        let window = Duration::from_secs(REPEAT_SECS);
+        let (expired, recent) = mem::take(&mut self.recent)
+            .into_iter()
+            .partition(|(_, (first, _))| now.duration_since(*first) >= window);
+        self.recent = recent;
+        expired
+            .into_iter()
+            .filter(|(_, (_, count))| *count > 0)
+            .map(|(msg, (_, count))| (msg, count))
+            .collect()
+    }
+}
+
+/// Writes a message logged at location to stderr. Repeats of error and
+/// info messages are collapsed into one line giving their number.
+pub fn write(level: LogLevel, location: &str, line: u32, args: fmt::Arguments<'_>) {
+    let msg = format!("[{}:{}] {}: {}", location, line, level, args);
+    let write = level > LogLevel::Info || REPEATS.lock().unwrap().record(&msg, Instant::now());
+    if write {
+        write_line(&msg);
+    }
+}
+
+/// Logs how often the messages counted by write were repeated, once
+/// they've been for REPEAT_SECS.
+pub fn flush_repeats() {
+    let expired = REPEATS.lock().unwrap().expire(Instant::now());
+    for (msg, count) in expired {
+        write_line(&format!("{} (repeated {} times)", msg, count));
+    }
+}
+
+fn write_line(msg: &str) {
+    let line = format!("{} {}\n", Local::now().format("%x %X"), msg);
+    std::io::stderr().lock().write_all(line.as_bytes()).ok();
+}
+
 #[macro_export]
 macro_rules! trace(
     ($fmt:expr) => {
@@ -74,40 +150,37 @@ macro_rules! error(
 #[macro_export]
 macro_rules! log(
     ($level:expr, $fmt:expr) => {
-        {
-            #[allow(unused_imports)]
-            use std::io::Write;
-            use chrono::Local;
-            if unsafe { $level <= $crate::log::LEVEL } {
-                let mut msg = Vec::with_capacity(25);
-                let time = Local::now();
-                write!(&mut msg, "{} [{}:{}] {}: ",
-                       time.format("%x %X"), module_path!(), line!(), $level).ok();
-                write!(&mut msg, $fmt).ok();
-                write!(&mut msg, "\n").ok();
-                let stderr = std::io::stderr();
-                let mut handle = stderr.lock();
-                handle.write_all(&msg).ok();
-            }
+        if unsafe { $level <= $crate::log::LEVEL } {
+            $crate::log::write($level, module_path!(), line!(), format_args!($fmt))
         }
     };
 
     ($level:expr, $fmt:expr, $($arg:tt)*) => {
-        {
-            #[allow(unused_imports)]
-            use std::io::Write;
-            use chrono::Local;
-            if unsafe { $level <= $crate::log::LEVEL } {
-                let mut msg = Vec::with_capacity(25);
-                let time = Local::now();
-                write!(&mut msg, "{} [{}:{}] {}: ",
-                       time.format("%x %X"), module_path!(), line!(), $level).ok();
-                write!(&mut msg, $fmt, $($arg)*).ok();
-                write!(&mut msg, "\n").ok();
-                let stderr = std::io::stderr();
-                let mut handle = stderr.lock();
-                handle.write_all(&msg).ok();
-            }
+        if unsafe { $level <= $crate::log::LEVEL } {
+            $crate::log::write($level, module_path!(), line!(), format_args!($fmt, $($arg)*))
         }
     };
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeats() {
+        let mut r = Repeats::default();
+        let start = Instant::now();
+        assert!(r.record("a", start));
+        assert!(r.record("b", start));
+        assert!(!r.record("a", start));
+        assert!(!r.record("a", start + Duration::from_secs(1)));
+        assert!(r.expire(start + Duration::from_secs(1)).is_empty());
+
+        let later = start + Duration::from_secs(REPEAT_SECS);
+        assert!(r.record("c", later));
+        // Messages which weren't repeated are forgotten silently
+        assert_eq!(r.expire(later), vec![("a".to_owned(), 2)]);
+        assert!(r.record("a", later));
+        assert_eq!(r.recent.len(), 2);
+    }
+}
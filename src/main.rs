@@ -31,6 +31,7 @@ mod log;
 #[macro_use]
 mod util;
 mod args;
+mod bench;
 mod buffers;
 mod config;
 mod control;
@@ -49,6 +50,10 @@ use std::sync::atomic;
 
 pub use crate::protocol::DHT_EXT;
 pub use crate::protocol::EXT_PROTO;
+pub use crate::protocol::FAST_EXT;
+pub use crate::protocol::LT_DONTHAVE_ID;
+pub use crate::protocol::LT_TEX_ID;
+pub use crate::protocol::UT_HOLEPUNCH_ID;
 pub use crate::protocol::UT_META_ID;
 pub use crate::protocol::UT_PEX_ID;
 
@@ -56,6 +61,14 @@ pub use crate::protocol::UT_PEX_ID;
 pub const THROT_TOKS: usize = 2 * 1024 * 1024;
 
 pub static SHUTDOWN: atomic::AtomicBool = atomic::AtomicBool::new(false);
+/// Set along with SHUTDOWN when a restart was requested over RPC
+pub static RESTART: atomic::AtomicBool = atomic::AtomicBool::new(false);
+/// Exit code after a restart request, for supervisors to restart on,
+/// e.g. with systemd's RestartForceExitStatus. This is EX_TEMPFAIL.
+pub const RESTART_EXIT_CODE: i32 = 75;
+/// Port peers connect to, which starts as the configured port and
+/// can be changed over RPC
+pub static PORT: atomic::AtomicU16 = atomic::AtomicU16::new(0);
 
 lazy_static! {
     pub static ref CONFIG: config::Config = config::Config::load();
@@ -63,8 +76,9 @@ lazy_static! {
         use rand::Rng;
 
         let mut pid = [0u8; 20];
-        let prefix = b"-SY0010-";
-        pid[..prefix.len()].clone_from_slice(&prefix[..]);
+        let prefix = CONFIG.peer.id_prefix.as_bytes();
+        let prefix = &prefix[..prefix.len().min(pid.len())];
+        pid[..prefix.len()].clone_from_slice(prefix);
 
         let mut rng = rand::thread_rng();
         for p in pid.iter_mut().skip(prefix.len()) {
@@ -76,7 +90,8 @@ lazy_static! {
 }
 
 fn main() {
-    let args = args::args();
+    let mut args = args::args();
+    let bench = args.benchmark.take();
     match init::init(args) {
         Ok(()) => {}
         Err(()) => {
@@ -85,7 +100,8 @@ fn main() {
         }
     }
     info!("Initialized, starting!");
-    match init::run() {
+    match init::run(bench) {
+        Ok(()) if RESTART.load(atomic::Ordering::SeqCst) => process::exit(RESTART_EXIT_CODE),
         Ok(()) => process::exit(0),
         Err(()) => process::exit(1),
     }
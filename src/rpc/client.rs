@@ -1,11 +1,13 @@
 use std::io::{self, Write};
+use std::net::SocketAddr;
 use std::{mem, result, str, time};
 
+use chrono::{DateTime, Utc};
 use sstream::SStream;
 use url::Url;
 
-use super::proto::message::{SMessage, Version};
-use super::proto::ws::{Frame, Message, Opcode};
+use super::proto::message::{RpcClient, SMessage, Version};
+use super::proto::ws::{Frame, FrameTooLong, Message, Opcode, CLOSE_TOO_BIG};
 use super::reader::Reader;
 use super::writer::Writer;
 use super::{ErrorKind, Result, ResultExt};
@@ -19,11 +21,29 @@ pub struct Client {
     w: Writer,
     buf: FragBuf,
     last_action: time::Instant,
+    stats: Stats,
+}
+
+/// Connection details and message counts reported to admins
+/// through GET_CLIENTS.
+struct Stats {
+    addr: SocketAddr,
+    connected: DateTime<Utc>,
+    user_agent: Option<String>,
+    received: u64,
+    sent: u64,
+    last_tick: time::Instant,
+    last_received: u64,
+    last_sent: u64,
+    rate_in: f32,
+    rate_out: f32,
 }
 
 pub struct Incoming {
     pub conn: SStream,
+    pub addr: SocketAddr,
     key: Option<String>,
+    user_agent: Option<String>,
     buf: [u8; 1024],
     pos: usize,
     last_action: time::Instant,
@@ -50,7 +70,10 @@ impl Client {
         self.last_action = time::Instant::now();
         loop {
             match self.read_frame()? {
-                Ok(f) => return Ok(Some(f)),
+                Ok(f) => {
+                    self.stats.received += 1;
+                    return Ok(Some(f));
+                }
                 Err(true) => return Ok(None),
                 Err(false) => continue,
             }
@@ -58,9 +81,17 @@ impl Client {
     }
 
     fn read_frame(&mut self) -> Result<result::Result<Frame, bool>> {
-        let m = match self.r.read(&mut self.conn).chain_err(|| ErrorKind::IO)? {
-            Some(m) => m,
-            None => return Ok(Err(true)),
+        let m = match self.r.read(&mut self.conn) {
+            Ok(Some(m)) => m,
+            Ok(None) => return Ok(Err(true)),
+            Err(ref e) if FrameTooLong::is(e) => {
+                self.send_msg(Message::close_status(CLOSE_TOO_BIG)).ok();
+                return Err(ErrorKind::BadPayload("Frame too long!").into());
+            }
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                return Err(e).chain_err(|| ErrorKind::BadPayload("Invalid frame!"));
+            }
+            Err(e) => return Err(e).chain_err(|| ErrorKind::IO),
         };
         if m.opcode().is_control() && m.len > 125 {
             return Err(ErrorKind::BadPayload("Control frame too long!").into());
@@ -80,6 +111,10 @@ impl Client {
                 return Err(ErrorKind::Complete.into());
             }
             Opcode::Text | Opcode::Binary | Opcode::Continuation => {
+                if self.buf.len() + m.data.len() as u64 > CONFIG.rpc.max_message_size {
+                    self.send_msg(Message::close_status(CLOSE_TOO_BIG)).ok();
+                    return Err(ErrorKind::BadPayload("Message too long!").into());
+                }
                 if let Some(f) = self.buf.process(m)? {
                     #[cfg(feature = "autobahn")]
                     self.send(f)?;
@@ -103,6 +138,7 @@ impl Client {
     }
 
     pub fn send(&mut self, f: Frame) -> Result<()> {
+        self.stats.sent += 1;
         self.send_msg(f.into())
     }
 
@@ -118,6 +154,36 @@ impl Client {
         self.last_action.elapsed().as_secs() > CONN_PING
             && self.send_msg(Message::ping(vec![0xDE, 0xAD])).is_err()
     }
+
+    /// Updates the message rates from the counts since the last tick.
+    pub fn tick(&mut self) {
+        let s = &mut self.stats;
+        let dur = s.last_tick.elapsed();
+        let secs = dur.as_secs() as f32 + dur.subsec_millis() as f32 / 1000.;
+        if secs > 0. {
+            s.rate_in = (s.received - s.last_received) as f32 / secs;
+            s.rate_out = (s.sent - s.last_sent) as f32 / secs;
+        }
+        s.last_tick = time::Instant::now();
+        s.last_received = s.received;
+        s.last_sent = s.sent;
+    }
+
+    pub fn info(&self, id: usize, subscriptions: u64, filters: u64) -> RpcClient {
+        let s = &self.stats;
+        RpcClient {
+            id: id as u64,
+            addr: s.addr.to_string(),
+            connected: s.connected,
+            user_agent: s.user_agent.clone(),
+            subscriptions,
+            filters,
+            messages_in: s.received,
+            messages_out: s.sent,
+            rate_in: s.rate_in,
+            rate_out: s.rate_out,
+        }
+    }
 }
 
 impl Into<SStream> for Client {
@@ -142,11 +208,23 @@ impl Into<Client> for Incoming {
         self.conn.write(data.as_bytes()).ok();
 
         let mut c = Client {
-            r: Reader::new(),
+            r: Reader::new(CONFIG.rpc.max_frame_size),
             w: Writer::new(),
             buf: FragBuf::None,
             conn: self.conn,
             last_action: time::Instant::now(),
+            stats: Stats {
+                addr: self.addr,
+                connected: Utc::now(),
+                user_agent: self.user_agent,
+                received: 0,
+                sent: 0,
+                last_tick: time::Instant::now(),
+                last_received: 0,
+                last_sent: 0,
+                rate_in: 0.,
+                rate_out: 0.,
+            },
         };
 
         c.send(Frame::Text(
@@ -164,13 +242,15 @@ impl Into<SStream> for Incoming {
 }
 
 impl Incoming {
-    pub fn new(conn: SStream) -> Incoming {
+    pub fn new(conn: SStream, addr: SocketAddr) -> Incoming {
         Incoming {
             conn,
+            addr,
             buf: [0; 1024],
             pos: 0,
             last_action: time::Instant::now(),
             key: None,
+            user_agent: None,
         }
     }
 
@@ -219,6 +299,12 @@ impl Incoming {
                 match validate_upgrade(&req) {
                     Ok(k) => {
                         self.key = Some(k);
+                        self.user_agent = req
+                            .headers
+                            .iter()
+                            .find(|header| header.name.to_lowercase() == "user-agent")
+                            .and_then(|header| str::from_utf8(header.value).ok())
+                            .map(str::to_owned);
                         return Ok(Some(IncomingStatus::Upgrade));
                     }
                     Err(true) => {
@@ -246,6 +332,14 @@ impl Incoming {
 }
 
 impl FragBuf {
+    /// Length of the fragments received so far.
+    fn len(&self) -> u64 {
+        match *self {
+            FragBuf::None => 0,
+            FragBuf::Text(ref b) | FragBuf::Binary(ref b) => b.len() as u64,
+        }
+    }
+
     fn process(&mut self, msg: Message) -> Result<Option<Frame>> {
         let fin = msg.fin();
         let s = mem::replace(self, FragBuf::None);
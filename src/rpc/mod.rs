@@ -7,7 +7,7 @@ mod transfer;
 mod writer;
 
 use std::io::Write;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener};
+use std::net::{SocketAddr, TcpListener};
 use std::sync::Arc;
 use std::{fs, io, result, str, thread};
 
@@ -18,15 +18,17 @@ use url::Url;
 
 use self::client::{Client, Incoming, IncomingStatus};
 pub use self::errors::{Error, ErrorKind, Result, ResultExt};
-use self::processor::{Processor, TransferKind};
-use self::proto::message::{self, SMessage};
+use self::processor::{Processor, Rebind, TransferKind};
+use self::proto::message::{self, CMessage, SMessage};
 pub use self::proto::resource;
 use self::proto::ws;
-use self::transfer::{TransferResult, Transfers};
+use self::transfer::{TorrentUpload, TransferResult, Transfers};
 use crate::bencode;
 use crate::disk;
 use crate::handle;
+use crate::socket;
 use crate::torrent;
+use crate::util::limit::AcceptLimiter;
 use crate::util::UHashMap;
 use crate::CONFIG;
 
@@ -100,22 +102,85 @@ pub enum CtlMessage {
         client: usize,
         serial: u64,
     },
+    /// The request has been applied without error
+    Applied {
+        client: usize,
+        serial: u64,
+    },
+    /// Answer to GET_TORRENT_INFO, with the info dict if requested
+    TorrentInfo {
+        info: message::TorrentInfo,
+        raw: Option<Vec<u8>>,
+        client: usize,
+        serial: u64,
+    },
+    /// Transient event sent to every client
+    Notify {
+        severity: message::Severity,
+        kind: message::NotificationKind,
+        resource: Option<String>,
+        message: String,
+    },
+    /// Answer to GET_FILE_MANIFEST
+    FileManifest {
+        id: String,
+        format: message::ManifestFormat,
+        data: Vec<u8>,
+        client: usize,
+        serial: u64,
+    },
+    /// Answer to GET_SWARM_SNAPSHOT
+    SwarmSnapshot {
+        id: String,
+        data: Vec<u8>,
+        client: usize,
+        serial: u64,
+    },
+    /// Answer to AUDIT_AVAILABILITY
+    AvailabilityAudit {
+        audit: message::AvailabilityAudit,
+        client: usize,
+        serial: u64,
+    },
+    /// Answer to GET_PIECE_MAP
+    PieceMap {
+        map: message::PieceMap,
+        client: usize,
+        serial: u64,
+    },
     Ping,
     Shutdown,
 }
 
 #[derive(Debug)]
 pub enum Message {
-    UpdateTorrent(resource::CResourceUpdate),
+    UpdateTorrent {
+        update: resource::CResourceUpdate,
+        client: usize,
+        serial: u64,
+    },
+    /// Applies an update, ignoring its id, to each of the torrents
+    UpdateTorrents {
+        ids: Vec<String>,
+        update: resource::CResourceUpdate,
+        paused: Option<bool>,
+        client: usize,
+        serial: u64,
+    },
     UpdateServer {
         id: String,
         throttle_up: Option<Option<i64>>,
         throttle_down: Option<Option<i64>>,
+        port: Option<u16>,
+        client: usize,
+        serial: u64,
     },
     UpdateFile {
         id: String,
         torrent_id: String,
         priority: u8,
+        client: usize,
+        serial: u64,
     },
     RemoveTorrent {
         id: String,
@@ -123,9 +188,22 @@ pub enum Message {
         serial: u64,
         artifacts: bool,
     },
-    Pause(String),
-    Resume(String),
-    Validate(Vec<String>),
+    Pause {
+        id: String,
+        soft: bool,
+        client: usize,
+        serial: u64,
+    },
+    Resume {
+        id: String,
+        client: usize,
+        serial: u64,
+    },
+    Validate {
+        ids: Vec<String>,
+        client: usize,
+        serial: u64,
+    },
     AddPeer {
         id: String,
         client: usize,
@@ -147,6 +225,38 @@ pub enum Message {
     UpdateTracker {
         id: String,
         torrent_id: String,
+        client: usize,
+        serial: u64,
+    },
+    /// Reannounces the torrent, or all of them if None
+    Reannounce {
+        id: Option<String>,
+        client: usize,
+        serial: u64,
+    },
+    /// Moves the read head of the file's torrent
+    SetReadHead {
+        id: String,
+        torrent_id: String,
+        offset: u64,
+        client: usize,
+        serial: u64,
+    },
+    /// Points the torrent at data moved elsewhere
+    Relocate {
+        id: String,
+        path: String,
+        client: usize,
+        serial: u64,
+    },
+    /// Sets or clears the deadline by which the torrent's pieces are
+    /// ordered ahead of others
+    SetPieceDeadline {
+        id: String,
+        pieces: Vec<u32>,
+        deadline: Option<u64>,
+        client: usize,
+        serial: u64,
     },
     RemoveTracker {
         id: String,
@@ -154,6 +264,43 @@ pub enum Message {
         client: usize,
         serial: u64,
     },
+    GetTorrentInfo {
+        id: String,
+        raw: bool,
+        client: usize,
+        serial: u64,
+    },
+    GetFileManifest {
+        id: String,
+        format: message::ManifestFormat,
+        client: usize,
+        serial: u64,
+    },
+    AuditAvailability {
+        id: String,
+        client: usize,
+        serial: u64,
+    },
+    GetSwarmSnapshot {
+        id: String,
+        client: usize,
+        serial: u64,
+    },
+    GetPieceMap {
+        id: String,
+        pieces: Vec<u32>,
+        files: Vec<String>,
+        client: usize,
+        serial: u64,
+    },
+    /// Replaces the URL of a tracker, which changes its ID
+    ReplaceTracker {
+        id: String,
+        torrent_id: String,
+        url: Url,
+        client: usize,
+        serial: u64,
+    },
     Torrent {
         info: torrent::Info,
         client: usize,
@@ -162,7 +309,15 @@ pub enum Message {
         start: bool,
         import: bool,
     },
-    PurgeDNS,
+    PurgeDNS {
+        client: usize,
+        serial: u64,
+    },
+    Shutdown {
+        restart: bool,
+        client: usize,
+        serial: u64,
+    },
 }
 
 pub struct RPC {
@@ -170,6 +325,9 @@ pub struct RPC {
     reg: amy::Registrar,
     ch: handle::Handle<CtlMessage, Message>,
     listener: TcpListener,
+    /// Port and whether the listener is bound to localhost only
+    port: u16,
+    local: bool,
     config: Option<Arc<rustls::ServerConfig>>,
     lid: usize,
     cleanup: usize,
@@ -177,6 +335,7 @@ pub struct RPC {
     transfers: Transfers,
     clients: UHashMap<Client>,
     incoming: UHashMap<Incoming>,
+    limiter: AcceptLimiter,
     disk: amy::Sender<disk::Request>,
 }
 
@@ -211,6 +370,22 @@ fn load_private_key(filename: &str) -> io::Result<rustls::PrivateKey> {
     }
 }
 
+/// Loads the configured TLS certificate and key, if any.
+fn tls_config() -> io::Result<Option<Arc<rustls::ServerConfig>>> {
+    match (CONFIG.rpc.ssl_cert.as_str(), CONFIG.rpc.ssl_key.as_str()) {
+        ("", "") => Ok(None),
+        (cert_file, key_file) => {
+            let mut config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+            let certs = load_certs(cert_file)?;
+            let key = load_private_key(key_file)?;
+            config
+                .set_single_cert(certs, key)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            Ok(Some(Arc::new(config)))
+        }
+    }
+}
+
 impl RPC {
     pub fn start(
         creg: &mut amy::Registrar,
@@ -221,34 +396,18 @@ impl RPC {
         let cleanup = reg.set_interval(CLEANUP_INT_MS)?;
         let (ch, dh) = handle::Handle::new(creg, &mut reg)?;
 
-        let ip = if CONFIG.rpc.local {
-            Ipv4Addr::new(127, 0, 0, 1)
-        } else {
-            Ipv4Addr::new(0, 0, 0, 0)
-        };
-        let port = CONFIG.rpc.port;
-        let listener = TcpListener::bind(SocketAddrV4::new(ip, port))?;
+        let listener = socket::listener(CONFIG.rpc.port, CONFIG.rpc.local)?;
         listener.set_nonblocking(true)?;
         let lid = reg.register(&listener, amy::Event::Both)?;
 
         let disk = db.clone();
 
-        let config = match (CONFIG.rpc.ssl_cert.as_str(), CONFIG.rpc.ssl_key.as_str()) {
-            ("", "") => {
-                info!("RPC SSL parameters not specified, using insecure connections!");
-                None
-            }
-            (cert_file, key_file) => {
-                let mut config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
-                let certs = load_certs(cert_file)?;
-                let key = load_private_key(key_file)?;
-                config
-                    .set_single_cert(certs, key)
-                    .expect("Invalid ssl_cert and ssl_key");
-                info!("SSL initialized!");
-                Some(Arc::new(config))
-            }
-        };
+        let config = tls_config()?;
+        if config.is_some() {
+            info!("SSL initialized!");
+        } else {
+            info!("RPC SSL parameters not specified, using insecure connections!");
+        }
 
         let th = dh.run("rpc", move |ch| {
             RPC {
@@ -257,10 +416,16 @@ impl RPC {
                 poll,
                 reg,
                 listener,
+                port: CONFIG.rpc.port,
+                local: CONFIG.rpc.local,
                 lid,
                 cleanup,
                 clients: UHashMap::default(),
                 incoming: UHashMap::default(),
+                limiter: AcceptLimiter::new(
+                    CONFIG.net.max_accept_rate,
+                    CONFIG.net.max_pending_per_ip,
+                ),
                 processor: Processor::new(db),
                 transfers: Transfers::new(),
                 config,
@@ -294,6 +459,13 @@ impl RPC {
                     _ => self.handle_conn(not),
                 }
             }
+            if let Some(rebind) = self.processor.take_rebind() {
+                self.rebind(rebind);
+            }
+            let revisions = self.processor.take_revisions();
+            if !revisions.is_empty() {
+                self.dispatch_ctl(CtlMessage::Update(revisions));
+            }
         }
     }
 
@@ -302,33 +474,111 @@ impl RPC {
             match m {
                 CtlMessage::Ping => continue,
                 CtlMessage::Shutdown => return true,
-                m => {
-                    let msgs: Vec<_> = {
-                        self.processor
-                            .handle_ctl(m)
-                            .into_iter()
-                            .map(|(c, m)| (c, serde_json::to_string(&m).unwrap()))
-                            .collect()
-                    };
-                    for (c, m) in msgs {
-                        let res = match self.clients.get_mut(&c) {
-                            Some(client) => client.send(ws::Frame::Text(m)),
-                            None => {
-                                debug!("Processor referenced a nonexistent client!");
-                                Ok(())
-                            }
-                        };
-                        if res.is_err() {
-                            let client = self.clients.remove(&c).unwrap();
-                            self.remove_client(c, client);
-                        }
-                    }
+                CtlMessage::Notify {
+                    severity,
+                    kind,
+                    resource,
+                    message,
+                } => {
+                    let n = self.processor.notify(severity, kind, resource, message);
+                    self.broadcast(&SMessage::Notification(n));
                 }
+                m => self.dispatch_ctl(m),
             }
         }
         false
     }
 
+    fn dispatch_ctl(&mut self, m: CtlMessage) {
+        let msgs: Vec<_> = {
+            self.processor
+                .handle_ctl(m)
+                .into_iter()
+                .map(|(c, m)| (c, serde_json::to_string(&m).unwrap()))
+                .collect()
+        };
+        for (c, m) in msgs {
+            self.send_client(c, m);
+        }
+    }
+
+    /// Sends a message to every connected client.
+    fn broadcast(&mut self, msg: &SMessage<'_>) {
+        let m = serde_json::to_string(msg).unwrap();
+        let clients: Vec<_> = self.clients.keys().cloned().collect();
+        for c in clients {
+            self.send_client(c, m.clone());
+        }
+    }
+
+    fn send_client(&mut self, c: usize, m: String) {
+        let res = match self.clients.get_mut(&c) {
+            Some(client) => client.send(ws::Frame::Text(m)),
+            None => {
+                debug!("Processor referenced a nonexistent client!");
+                Ok(())
+            }
+        };
+        if res.is_err() {
+            let client = self.clients.remove(&c).unwrap();
+            self.remove_client(c, client);
+        }
+    }
+
+    /// Moves the listener to a new address or changes whether new
+    /// connections use TLS. Existing clients stay connected.
+    fn rebind(&mut self, rebind: Rebind) {
+        if let Err(e) = self.try_rebind(&rebind) {
+            let msg = SMessage::InvalidRequest(message::Error {
+                serial: Some(rebind.serial),
+                reason: format!("Failed to rebind RPC listener: {}", e),
+            });
+            self.send_client(rebind.client, serde_json::to_string(&msg).unwrap());
+            return;
+        }
+        if let Some(id) = self.processor.server_id() {
+            self.dispatch_ctl(CtlMessage::Update(vec![
+                resource::SResourceUpdate::ServerRpc {
+                    id,
+                    kind: resource::ResourceKind::Server,
+                    rpc_port: self.port,
+                    rpc_local: self.local,
+                    rpc_tls: self.config.is_some(),
+                },
+            ]));
+        }
+        // The rest of the update is only applied once the listener moved,
+        // so a failed rebind leaves the server untouched.
+        self.ch.send(rebind.update).unwrap();
+    }
+
+    fn try_rebind(&mut self, rebind: &Rebind) -> io::Result<()> {
+        let port = rebind.port.unwrap_or(self.port);
+        let local = rebind.local.unwrap_or(self.local);
+        let config = match rebind.tls {
+            Some(true) if self.config.is_none() => Some(tls_config()?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "ssl_cert and ssl_key are not set")
+            })?),
+            Some(false) => None,
+            _ => self.config.clone(),
+        };
+        if port != self.port || local != self.local {
+            let listener = socket::listener(port, local)?;
+            listener.set_nonblocking(true)?;
+            let lid = self.reg.register(&listener, amy::Event::Both)?;
+            if let Err(e) = self.reg.deregister(&self.listener) {
+                error!("Failed to deregister old RPC listener: {}", e);
+            }
+            info!("RPC now listening on port {}", port);
+            self.listener = listener;
+            self.lid = lid;
+            self.port = port;
+            self.local = local;
+        }
+        self.config = config;
+        Ok(())
+    }
+
     fn handle_transfer(&mut self, id: usize) {
         match self.transfers.ready(id) {
             TransferResult::Incomplete => {}
@@ -340,6 +590,7 @@ impl RPC {
                 serial,
                 start,
                 import,
+                dry_run,
             } => {
                 debug!("Got torrent via HTTP transfer!");
                 if self.reg.deregister(&conn).is_err() {
@@ -348,6 +599,10 @@ impl RPC {
                 }
                 match bencode::decode_buf(&data) {
                     Ok(b) => match torrent::info::Info::from_bencode(b) {
+                        Ok(i) if dry_run => {
+                            let msg = self.processor.preview(serial, &i);
+                            self.send_client(client, serde_json::to_string(&msg).unwrap());
+                        }
                         Ok(i) => {
                             if self
                                 .ch
@@ -424,6 +679,15 @@ impl RPC {
             match self.listener.accept() {
                 Ok((conn, ip)) => {
                     debug!("Accepted new connection from {:?}!", ip);
+                    let pending = self
+                        .incoming
+                        .values()
+                        .filter(|i| i.addr.ip() == ip.ip())
+                        .count();
+                    if let Err(e) = self.limiter.allow(ip.ip(), pending) {
+                        info!("Dropping incoming RPC connection from {}: {}", ip, e);
+                        continue;
+                    }
                     let id = self.reg.register(&conn, amy::Event::Both);
                     let conn = if let Some(ref config) = self.config {
                         SStream::from_ssl(conn, config)
@@ -431,7 +695,7 @@ impl RPC {
                         SStream::from_plain(conn)
                     };
                     if let (Ok(id), Ok(conn)) = (id, conn) {
-                        self.incoming.insert(id, Incoming::new(conn));
+                        self.incoming.insert(id, Incoming::new(conn, ip));
                     }
                 }
                 Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
@@ -465,20 +729,20 @@ impl RPC {
                                 size,
                                 start,
                                 import,
+                                dry_run,
                             },
                         )) => {
                             debug!("Torrent transfer initiated");
-                            self.transfers.add_torrent(
-                                id,
+                            let up = TorrentUpload {
                                 client,
                                 serial,
-                                i.into(),
-                                data,
                                 path,
                                 size,
                                 start,
                                 import,
-                            );
+                                dry_run,
+                            };
+                            self.transfers.add_torrent(id, i.into(), data, up);
                             // Since a succesful result means the buffer hasn't been flushed,
                             // immediatly attempt to handle the transfer as if it was ready
                             self.handle_transfer(id);
@@ -495,7 +759,12 @@ impl RPC {
                 Ok(IncomingStatus::DL { id, range }) => {
                     debug!("Attempting DL of {}", id);
                     let mut conn: SStream = i.into();
-                    if let Some((path, size)) = self.processor.get_dl(&id) {
+                    if let Some((name, data)) = self.processor.get_generated(&id) {
+                        debug!("Initiating DL of generated {}", name);
+                        self.disk
+                            .send(disk::Request::download_data(conn, &name, data))
+                            .ok();
+                    } else if let Some((path, size)) = self.processor.get_dl(&id) {
                         if size == 0 {
                             conn.write(&EMPTY_HTTP_RESP).ok();
                             return;
@@ -562,6 +831,58 @@ impl RPC {
 
     fn process_frame(&mut self, id: usize, c: &mut Client, data: &str) -> result::Result<(), ()> {
         match serde_json::from_str(data) {
+            Ok(CMessage::GetClients { serial }) => {
+                let mut clients: Vec<_> = self
+                    .clients
+                    .iter()
+                    .map(|(&cid, client)| (cid, client))
+                    .chain(Some((id, &*c)))
+                    .map(|(cid, client)| {
+                        let (subs, filters) = self.processor.subscriptions(cid);
+                        client.info(cid, subs, filters)
+                    })
+                    .collect();
+                clients.sort_by_key(|client| client.id);
+                let msg = SMessage::Clients { serial, clients };
+                if c.send(ws::Frame::Text(serde_json::to_string(&msg).unwrap()))
+                    .is_err()
+                {
+                    return Err(());
+                }
+            }
+            Ok(CMessage::AckNotifications { ids, .. }) => {
+                // Other clients dismiss the notifications too
+                let msg = SMessage::NotificationsAcked {
+                    ids: self.processor.ack_notifications(&ids),
+                };
+                self.broadcast(&msg);
+                if c.send(ws::Frame::Text(serde_json::to_string(&msg).unwrap()))
+                    .is_err()
+                {
+                    return Err(());
+                }
+            }
+            Ok(CMessage::DisconnectClient { serial, id: target }) => {
+                let target = target as usize;
+                if target == id {
+                    info!("client {} disconnected itself", id);
+                    return Err(());
+                }
+                if let Some(client) = self.clients.remove(&target) {
+                    info!("client {} disconnected by client {}", target, id);
+                    self.remove_client(target, client);
+                } else {
+                    let msg = SMessage::UnknownResource(message::Error {
+                        serial: Some(serial),
+                        reason: format!("Unknown client {}", target),
+                    });
+                    if c.send(ws::Frame::Text(serde_json::to_string(&msg).unwrap()))
+                        .is_err()
+                    {
+                        return Err(());
+                    }
+                }
+            }
             Ok(m) => {
                 let (msgs, rm) = self.processor.handle_client(id, m);
                 if let Some(m) = rm {
@@ -616,6 +937,7 @@ impl RPC {
         self.processor.remove_expired_tokens();
         let processor = &mut self.processor;
         self.clients.retain(|id, client| {
+            client.tick();
             let res = client.timed_out();
             if res {
                 info!("client {} timed out", id);
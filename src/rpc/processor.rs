@@ -1,5 +1,5 @@
 use std::borrow::Cow;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fs::OpenOptions;
 use std::io::Read;
 use std::mem;
@@ -11,12 +11,16 @@ use serde_json as json;
 use url::Url;
 
 use super::proto::criterion::{self, Criterion, Operation};
-use super::proto::message::{CMessage, Error, SMessage};
+use super::proto::message::{
+    CMessage, Error, Notification, NotificationKind, PreviewFile, SMessage, Severity,
+    TorrentPreview, TrackerHost,
+};
 use super::proto::resource::{merge_json, Resource, ResourceKind, SResourceUpdate};
 use super::{CtlMessage, Message};
 use crate::disk;
+use crate::throttle;
 use crate::torrent::info::Info;
-use crate::util::{random_string, FHashMap, FHashSet, MHashSet, SHashMap};
+use crate::util::{hash_to_id, random_string, FHashMap, FHashSet, MHashSet, SHashMap};
 use crate::CONFIG;
 
 const USER_DATA_FILE: &str = "rpc_user_data";
@@ -38,6 +42,27 @@ pub struct Processor {
     tokens: SHashMap<BearerToken>,
     db: amy::Sender<disk::Request>,
     user_data: SHashMap<json::Value>,
+    roots: disk::Roots,
+    rebind: Option<Rebind>,
+    /// Revision changes to send to every subscriber
+    revisions: Vec<SResourceUpdate<'static>>,
+    /// Generated data, such as info dicts and file manifests, along
+    /// with its file name, downloadable by ID until it expires
+    generated: SHashMap<(String, Vec<u8>, DateTime<Utc>)>,
+    /// Notifications which haven't been acknowledged, oldest first
+    notifications: VecDeque<Notification>,
+    next_notification: u64,
+}
+
+/// Requested change to the RPC listener, applied by the RPC thread.
+pub struct Rebind {
+    pub client: usize,
+    pub serial: u64,
+    pub port: Option<u16>,
+    pub local: Option<bool>,
+    pub tls: Option<bool>,
+    /// Server update to forward to control once rebound
+    pub update: Message,
 }
 
 struct Filter {
@@ -59,6 +84,7 @@ pub enum TransferKind {
         path: Option<String>,
         start: bool,
         import: bool,
+        dry_run: bool,
     },
     UploadFiles {
         size: u64,
@@ -67,6 +93,12 @@ pub enum TransferKind {
 }
 
 const EXPIRATION_DUR: i64 = 120;
+/// Most unacknowledged notifications kept, older ones are dropped
+const MAX_NOTIFICATIONS: usize = 100;
+
+/// Stats of a tracker host, the torrents counted towards it
+/// and the total time and number of its timed responses.
+type HostTally<'a> = (TrackerHost, FHashSet<&'a str>, (u64, u64));
 
 impl Processor {
     pub fn new(db: amy::Sender<disk::Request>) -> Processor {
@@ -115,11 +147,64 @@ impl Processor {
             kinds: vec![MHashSet::default(); 6],
             db,
             user_data,
+            roots: disk::Roots::new(),
+            rebind: None,
+            revisions: Vec::new(),
+            generated: SHashMap::default(),
+            notifications: VecDeque::new(),
+            next_notification: 1,
         }
     }
 
     pub fn remove_expired_tokens(&mut self) {
-        self.tokens.retain(|_, tok| tok.expiration > Utc::now())
+        let now = Utc::now();
+        self.tokens.retain(|_, tok| tok.expiration > now);
+        self.generated
+            .retain(|_, (_, _, expiration)| *expiration > now);
+    }
+
+    /// Records a notification, returning it so it can be sent to clients.
+    pub fn notify(
+        &mut self,
+        severity: Severity,
+        kind: NotificationKind,
+        resource: Option<String>,
+        message: String,
+    ) -> Notification {
+        let n = Notification {
+            id: self.next_notification,
+            severity,
+            kind,
+            resource,
+            message,
+            time: Utc::now(),
+        };
+        self.next_notification += 1;
+        if self.notifications.len() == MAX_NOTIFICATIONS {
+            self.notifications.pop_front();
+        }
+        self.notifications.push_back(n.clone());
+        n
+    }
+
+    /// Removes acknowledged notifications, returning the IDs of
+    /// those which were pending.
+    pub fn ack_notifications(&mut self, ids: &[u64]) -> Vec<u64> {
+        let mut acked = Vec::new();
+        self.notifications.retain(|n| {
+            let ack = ids.contains(&n.id);
+            if ack {
+                acked.push(n.id);
+            }
+            !ack
+        });
+        acked
+    }
+
+    pub fn get_generated(&self, id: &str) -> Option<(String, Vec<u8>)> {
+        self.generated
+            .get(id)
+            .map(|(name, data, _)| (name.clone(), data.clone()))
     }
 
     pub fn get_dl(&self, id: &str) -> Option<(String, u64)> {
@@ -206,6 +291,22 @@ impl Processor {
                 serial,
                 mut resource,
             } => {
+                if let Some(res) = self.resources.get_mut(&resource.id) {
+                    let revision = *res.revision();
+                    if matches!(resource.revision, Some(r) if r != revision) {
+                        resp.push(SMessage::Conflict(Error {
+                            serial: Some(serial),
+                            reason: format!("resource {} is at revision {}", resource.id, revision),
+                        }));
+                        return (resp, rmsg);
+                    }
+                    *res.revision() += 1;
+                    self.revisions.push(SResourceUpdate::Revision {
+                        id: resource.id.clone(),
+                        kind: res.kind(),
+                        revision: revision + 1,
+                    });
+                }
                 let udo = mem::replace(&mut resource.user_data, None);
                 if let Some(user_data) = udo.clone() {
                     let mut modified = false;
@@ -231,7 +332,11 @@ impl Processor {
 
                 match self.resources.get(&resource.id) {
                     Some(&Resource::Torrent(_)) => {
-                        rmsg = Some(Message::UpdateTorrent(resource));
+                        rmsg = Some(Message::UpdateTorrent {
+                            update: resource,
+                            client,
+                            serial,
+                        });
                     }
                     Some(&Resource::File(ref f)) => {
                         // TODO: Validate other fields(make sure they're not present)
@@ -240,17 +345,56 @@ impl Processor {
                                 id: resource.id,
                                 torrent_id: f.torrent_id.to_owned(),
                                 priority: p,
+                                client,
+                                serial,
                             });
+                        } else {
+                            resp.push(SMessage::RequestApplied { serial });
                         }
                     }
                     Some(&Resource::Server(_)) => {
-                        rmsg = Some(Message::UpdateServer {
+                        let update = Message::UpdateServer {
                             id: resource.id,
                             throttle_up: resource.throttle_up,
                             throttle_down: resource.throttle_down,
-                        });
+                            port: resource.port,
+                            client,
+                            serial,
+                        };
+                        if resource.rpc_port.is_some()
+                            || resource.rpc_local.is_some()
+                            || resource.rpc_tls.is_some()
+                        {
+                            self.rebind = Some(Rebind {
+                                client,
+                                serial,
+                                port: resource.rpc_port,
+                                local: resource.rpc_local,
+                                tls: resource.rpc_tls,
+                                update,
+                            });
+                        } else {
+                            rmsg = Some(update);
+                        }
                     }
-                    Some(_) => {}
+                    Some(Resource::Tracker(t)) => match resource.url.map(|u| Url::parse(&u)) {
+                        Some(Ok(url)) => {
+                            rmsg = Some(Message::ReplaceTracker {
+                                id: resource.id,
+                                torrent_id: t.torrent_id.to_owned(),
+                                url,
+                                client,
+                                serial,
+                            });
+                        }
+                        Some(Err(e)) => resp.push(SMessage::InvalidRequest(Error {
+                            serial: Some(serial),
+                            reason: format!("Invalid tracker URI: {}", e),
+                        })),
+                        None => resp.push(SMessage::RequestApplied { serial }),
+                    },
+                    // Only user data can be changed on other resources
+                    Some(_) => resp.push(SMessage::RequestApplied { serial }),
                     None => {
                         resp.push(SMessage::UnknownResource(Error {
                             serial: Some(serial),
@@ -370,9 +514,248 @@ impl Processor {
             CMessage::FilterUnsubscribe { filter_serial, .. } => {
                 self.filter_subs.remove(&(client, filter_serial));
             }
+            CMessage::GetEffectiveLimits { serial, id } => match self.resources.get(&id) {
+                Some(Resource::Torrent(t)) => {
+                    let server = self.kinds[ResourceKind::Server as usize]
+                        .iter()
+                        .next()
+                        .and_then(|sid| self.resources.get(sid))
+                        .map(Resource::as_server);
+                    let (su, sd) = server
+                        .map(|s| (s.throttle_up, s.throttle_down))
+                        .unwrap_or((None, None));
+                    resp.push(SMessage::EffectiveLimits {
+                        serial,
+                        id: id.clone(),
+                        throttle_up: throttle::effective_limit(t.throttle_up, su),
+                        throttle_down: throttle::effective_limit(t.throttle_down, sd),
+                    });
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "GET_EFFECTIVE_LIMITS not used with torrent".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
+            CMessage::GetTorrentInfo { serial, id, raw } => match self.resources.get(&id) {
+                Some(Resource::Torrent(_)) => {
+                    rmsg = Some(Message::GetTorrentInfo {
+                        id,
+                        raw,
+                        client,
+                        serial,
+                    });
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "GET_TORRENT_INFO not used with torrent".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
+            CMessage::Search {
+                serial,
+                query,
+                regex,
+                limit,
+            } => match criterion::Search::new(&query, regex) {
+                Ok(search) => {
+                    let resources = &self.resources;
+                    let matches = |kind: ResourceKind| {
+                        let mut ids: Vec<_> = self.kinds[kind as usize]
+                            .iter()
+                            .filter(|id| match resources.get(*id) {
+                                Some(Resource::Torrent(t)) => {
+                                    t.name.as_ref().is_some_and(|n| search.matches(n))
+                                }
+                                Some(Resource::File(f)) => search.matches(&f.path),
+                                _ => false,
+                            })
+                            .cloned()
+                            .collect();
+                        ids.sort_unstable();
+                        ids
+                    };
+                    // Torrents come first, so they aren't crowded out by their files
+                    let mut ids = matches(ResourceKind::Torrent);
+                    ids.extend(matches(ResourceKind::File));
+                    let limit = limit.map_or(ids.len(), |l| l as usize);
+                    let truncated = ids.len() > limit;
+                    ids.truncate(limit);
+                    resp.push(SMessage::SearchResults {
+                        serial,
+                        ids,
+                        truncated,
+                    });
+                }
+                Err(e) => resp.push(SMessage::InvalidRequest(Error {
+                    serial: Some(serial),
+                    reason: format!("Invalid regex: {}", e),
+                })),
+            },
+            CMessage::GetFileManifest { serial, id, format } => match self.resources.get(&id) {
+                Some(Resource::Torrent(_)) => {
+                    rmsg = Some(Message::GetFileManifest {
+                        id,
+                        format,
+                        client,
+                        serial,
+                    });
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "GET_FILE_MANIFEST not used with torrent".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
+            CMessage::AuditAvailability { serial, id } => match self.resources.get(&id) {
+                Some(Resource::Torrent(_)) => {
+                    rmsg = Some(Message::AuditAvailability { id, client, serial });
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "AUDIT_AVAILABILITY not used with torrent".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
+            CMessage::GetSwarmSnapshot { serial, id } => match self.resources.get(&id) {
+                Some(Resource::Torrent(_)) => {
+                    rmsg = Some(Message::GetSwarmSnapshot { id, client, serial });
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "GET_SWARM_SNAPSHOT not used with torrent".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
+            CMessage::GetPieceMap {
+                serial,
+                id,
+                pieces,
+                files,
+            } => match self.resources.get(&id) {
+                Some(Resource::Torrent(_)) => {
+                    rmsg = Some(Message::GetPieceMap {
+                        id,
+                        pieces,
+                        files,
+                        client,
+                        serial,
+                    });
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "GET_PIECE_MAP not used with torrent".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
+            CMessage::GetNotifications { serial } => {
+                resp.push(SMessage::Notifications {
+                    serial,
+                    notifications: self.notifications.iter().cloned().collect(),
+                });
+            }
+            CMessage::GetTrackerStats { serial } => {
+                resp.push(SMessage::TrackerStats {
+                    serial,
+                    hosts: self.tracker_stats(),
+                });
+            }
+            CMessage::UpdateMatching {
+                serial,
+                criteria,
+                resource,
+                paused,
+            } => {
+                let f = Filter {
+                    criteria,
+                    kind: ResourceKind::Torrent,
+                };
+                let ids: Vec<_> = self.kinds[ResourceKind::Torrent as usize]
+                    .iter()
+                    .filter(|id| {
+                        let r = self.resources.get(*id).unwrap();
+                        f.matches(r, &self.torrent_idx, &self.kinds, &self.resources)
+                    })
+                    .cloned()
+                    .collect();
+
+                for id in &ids {
+                    let res = self.resources.get_mut(id).unwrap();
+                    *res.revision() += 1;
+                    self.revisions.push(SResourceUpdate::Revision {
+                        id: id.clone(),
+                        kind: ResourceKind::Torrent,
+                        revision: *res.revision(),
+                    });
+                }
+
+                let mut update = resource.unwrap_or_default();
+                if let Some(ref user_data) = update.user_data {
+                    let mut resources = Vec::with_capacity(ids.len());
+                    for id in &ids {
+                        let res = self.resources.get_mut(id).unwrap();
+                        merge_json(res.user_data(), &mut user_data.clone());
+                        self.user_data.insert(id.clone(), res.user_data().clone());
+                        resources.push(SResourceUpdate::UserData {
+                            id: id.clone(),
+                            kind: ResourceKind::Torrent,
+                            user_data: user_data.clone(),
+                        });
+                    }
+                    if !ids.is_empty() {
+                        self.serialize();
+                        resp.push(SMessage::UpdateResources {
+                            serial: Some(serial),
+                            resources,
+                        });
+                    }
+                }
+                update.id = String::new();
+
+                resp.push(SMessage::ResourcesUpdated {
+                    serial,
+                    count: ids.len() as u64,
+                });
+                if !ids.is_empty() {
+                    rmsg = Some(Message::UpdateTorrents {
+                        ids,
+                        update,
+                        paused,
+                        client,
+                        serial,
+                    });
+                } else {
+                    resp.push(SMessage::RequestApplied { serial });
+                }
+            }
 
-            CMessage::PauseTorrent { serial, id } => match self.resources.get(&id) {
-                Some(&Resource::Torrent(_)) => rmsg = Some(Message::Pause(id)),
+            CMessage::PauseTorrent { serial, id, soft } => match self.resources.get(&id) {
+                Some(&Resource::Torrent(_)) => {
+                    rmsg = Some(Message::Pause {
+                        id,
+                        soft,
+                        client,
+                        serial,
+                    })
+                }
                 Some(_) => resp.push(SMessage::InvalidResource(Error {
                     serial: Some(serial),
                     reason: "Only torrents can be paused".to_owned(),
@@ -383,7 +766,7 @@ impl Processor {
                 })),
             },
             CMessage::ResumeTorrent { serial, id } => match self.resources.get(&id) {
-                Some(&Resource::Torrent(_)) => rmsg = Some(Message::Resume(id)),
+                Some(&Resource::Torrent(_)) => rmsg = Some(Message::Resume { id, client, serial }),
                 Some(_) => resp.push(SMessage::InvalidResource(Error {
                     serial: Some(serial),
                     reason: "Only torrents can be resumed".to_owned(),
@@ -446,6 +829,8 @@ impl Processor {
                     rmsg = Some(Message::UpdateTracker {
                         id,
                         torrent_id: t.torrent_id.clone(),
+                        client,
+                        serial,
                     })
                 }
                 Some(_) => resp.push(SMessage::InvalidResource(Error {
@@ -457,6 +842,86 @@ impl Processor {
                     reason: format!("Unknown resource {}", id),
                 })),
             },
+            CMessage::ReannounceTorrent { serial, id } => {
+                match id.as_ref().map(|id| self.resources.get(id)) {
+                    None | Some(Some(&Resource::Torrent(_))) => {
+                        rmsg = Some(Message::Reannounce { id, client, serial })
+                    }
+                    Some(Some(_)) => resp.push(SMessage::InvalidResource(Error {
+                        serial: Some(serial),
+                        reason: "Only torrents can be reannounced".to_owned(),
+                    })),
+                    Some(None) => resp.push(SMessage::UnknownResource(Error {
+                        serial: Some(serial),
+                        reason: format!("Unknown resource {}", id.unwrap()),
+                    })),
+                }
+            }
+            CMessage::SetReadHead { serial, id, offset } => match self.resources.get(&id) {
+                Some(Resource::File(f)) => {
+                    rmsg = Some(Message::SetReadHead {
+                        torrent_id: f.torrent_id.to_owned(),
+                        id,
+                        offset,
+                        client,
+                        serial,
+                    })
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "Only files have a read head".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
+            CMessage::RelocateTorrent { serial, id, path } => match self.resources.get(&id) {
+                Some(&Resource::Torrent(_)) => {
+                    rmsg = Some(Message::Relocate {
+                        id,
+                        path,
+                        client,
+                        serial,
+                    })
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "RELOCATE_TORRENT not used with torrent".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
+            CMessage::SetPieceDeadline {
+                serial,
+                id,
+                pieces,
+                deadline,
+            } => match self.resources.get(&id) {
+                Some(&Resource::Torrent(_)) => {
+                    rmsg = Some(Message::SetPieceDeadline {
+                        id,
+                        pieces,
+                        deadline,
+                        client,
+                        serial,
+                    })
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "SET_PIECE_DEADLINE not used with torrent".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
+            CMessage::ValidateResources { serial, ids } if ids.is_empty() => {
+                // Nothing to check, so nothing the control thread would answer for
+                resp.push(SMessage::RequestApplied { serial });
+            }
             CMessage::ValidateResources { serial, mut ids } => {
                 ids.retain(|id| match self.resources.get(id) {
                     Some(&Resource::Torrent(_)) => true,
@@ -475,7 +940,13 @@ impl Processor {
                         false
                     }
                 });
-                rmsg = Some(Message::Validate(ids));
+                if !ids.is_empty() {
+                    rmsg = Some(Message::Validate {
+                        ids,
+                        client,
+                        serial,
+                    });
+                }
             }
             CMessage::UploadTorrent {
                 serial,
@@ -483,7 +954,15 @@ impl Processor {
                 path,
                 start,
                 import,
+                category,
+                dry_run,
             } => {
+                // Imported data is expected to already be in the default directory
+                let path = if import || dry_run {
+                    path
+                } else {
+                    path.or_else(|| self.roots.choose(category.as_deref()))
+                };
                 resp.push(self.new_transfer(
                     client,
                     serial,
@@ -492,6 +971,7 @@ impl Processor {
                         path,
                         start,
                         import,
+                        dry_run,
                     },
                 ));
             }
@@ -500,11 +980,14 @@ impl Processor {
                 uri,
                 path,
                 start,
+                category,
+                dry_run,
             } => match Info::from_magnet(&uri) {
+                Ok(info) if dry_run => resp.push(self.preview(serial, &info)),
                 Ok(info) => {
                     rmsg = Some(Message::Torrent {
                         info,
-                        path,
+                        path: path.or_else(|| self.roots.choose(category.as_deref())),
                         start,
                         import: false,
                         client,
@@ -525,13 +1008,98 @@ impl Processor {
                     TransferKind::UploadFiles { size, path },
                 ));
             }
-            CMessage::PurgeDns { .. } => {
-                rmsg = Some(Message::PurgeDNS);
+            CMessage::PurgeDns { serial } => {
+                rmsg = Some(Message::PurgeDNS { client, serial });
             }
+            CMessage::Shutdown { serial, restart } => {
+                // Without a password anyone who can reach the port could
+                // stop the daemon, so only allow that on localhost
+                if !CONFIG.rpc.auth && !CONFIG.rpc.local {
+                    resp.push(SMessage::PermissionDenied(Error {
+                        serial: Some(serial),
+                        reason: "SHUTDOWN requires rpc.auth unless RPC is local".to_owned(),
+                    }));
+                } else {
+                    rmsg = Some(Message::Shutdown {
+                        restart,
+                        client,
+                        serial,
+                    });
+                }
+            }
+            // The RPC server owns the connections and answers these itself
+            CMessage::GetClients { .. }
+            | CMessage::DisconnectClient { .. }
+            | CMessage::AckNotifications { .. } => {}
         }
         (resp, rmsg)
     }
 
+    /// Describes the torrent info would add, without adding it.
+    pub fn preview(&self, serial: u64, info: &Info) -> SMessage<'static> {
+        let id = hash_to_id(&info.hash[..]);
+        let complete = info.complete();
+        let trackers = if info.url_list.is_empty() {
+            info.announce
+                .iter()
+                .map(|url| url.as_str().to_owned())
+                .collect()
+        } else {
+            info.url_list
+                .iter()
+                .flatten()
+                .map(|url| url.as_str().to_owned())
+                .collect()
+        };
+        SMessage::TorrentPreview {
+            serial,
+            torrent: TorrentPreview {
+                duplicate: self.resources.contains_key(&id),
+                id,
+                name: if info.name.is_empty() {
+                    None
+                } else {
+                    Some(info.name.clone())
+                },
+                private: info.private,
+                size: if complete { Some(info.total_len) } else { None },
+                pieces: if complete {
+                    Some(u64::from(info.pieces()))
+                } else {
+                    None
+                },
+                piece_size: if complete { Some(info.piece_len) } else { None },
+                files: info
+                    .files
+                    .iter()
+                    .map(|f| PreviewFile {
+                        path: f.path.to_string_lossy().into_owned(),
+                        size: f.length,
+                    })
+                    .collect(),
+                trackers,
+            },
+        }
+    }
+
+    pub fn take_rebind(&mut self) -> Option<Rebind> {
+        self.rebind.take()
+    }
+
+    /// Revision changes of resources which still exist.
+    pub fn take_revisions(&mut self) -> Vec<SResourceUpdate<'static>> {
+        let mut revisions = mem::take(&mut self.revisions);
+        revisions.retain(|u| self.resources.contains_key(u.id()));
+        revisions
+    }
+
+    pub fn server_id(&self) -> Option<String> {
+        self.kinds[ResourceKind::Server as usize]
+            .iter()
+            .next()
+            .cloned()
+    }
+
     pub fn handle_ctl(&mut self, msg: CtlMessage) -> Vec<(usize, SMessage<'_>)> {
         let mut msgs = Vec::new();
         match msg {
@@ -678,12 +1246,103 @@ impl Processor {
             CtlMessage::Pending { id, serial, client } => {
                 msgs.push((client, SMessage::ResourcePending { serial, id }));
             }
+            CtlMessage::Applied { serial, client } => {
+                msgs.push((client, SMessage::RequestApplied { serial }));
+            }
+            CtlMessage::TorrentInfo {
+                mut info,
+                raw,
+                client,
+                serial,
+            } => {
+                if let Some(data) = raw {
+                    let expiration = Utc::now() + Duration::seconds(EXPIRATION_DUR);
+                    info.raw_expires = Some(expiration);
+                    let name = format!("{}.info", info.id);
+                    self.generated
+                        .insert(info.id.clone(), (name, data, expiration));
+                }
+                msgs.push((client, SMessage::TorrentInfo { serial, info }));
+            }
+            CtlMessage::FileManifest {
+                id,
+                format,
+                data,
+                client,
+                serial,
+            } => {
+                let expires = Utc::now() + Duration::seconds(EXPIRATION_DUR);
+                let download = format!("{}.{}", id, format.extension());
+                let size = data.len() as u64;
+                self.generated
+                    .insert(download.clone(), (download.clone(), data, expires));
+                msgs.push((
+                    client,
+                    SMessage::FileManifest {
+                        serial,
+                        id,
+                        download,
+                        size,
+                        expires,
+                    },
+                ));
+            }
+            CtlMessage::SwarmSnapshot {
+                id,
+                data,
+                client,
+                serial,
+            } => {
+                let expires = Utc::now() + Duration::seconds(EXPIRATION_DUR);
+                let download = format!("{}.swarm.json", id);
+                let size = data.len() as u64;
+                self.generated
+                    .insert(download.clone(), (download.clone(), data, expires));
+                msgs.push((
+                    client,
+                    SMessage::SwarmSnapshot {
+                        serial,
+                        id,
+                        download,
+                        size,
+                        expires,
+                    },
+                ));
+            }
+            CtlMessage::AvailabilityAudit {
+                audit,
+                client,
+                serial,
+            } => {
+                msgs.push((client, SMessage::AvailabilityAudit { serial, audit }));
+            }
+            CtlMessage::PieceMap {
+                map,
+                client,
+                serial,
+            } => {
+                msgs.push((client, SMessage::PieceMap { serial, map }));
+            }
+            CtlMessage::Notify { .. } => {
+                unreachable!("notifications must be handled before rpc processor")
+            }
             CtlMessage::Ping => unreachable!("ping must be handled before rpc processor"),
             CtlMessage::Shutdown => unreachable!("shutdown must be handled before rpc processor"),
         }
         msgs
     }
 
+    /// Returns the number of resources and filters client is subscribed to.
+    pub fn subscriptions(&self, client: usize) -> (u64, u64) {
+        let subs = self.subs.values().filter(|s| s.contains(&client)).count();
+        let filters = self
+            .filter_subs
+            .keys()
+            .filter(|&&(c, _)| c == client)
+            .count();
+        (subs as u64, filters as u64)
+    }
+
     pub fn remove_client(&mut self, client: usize) {
         for (_, sub) in self.subs.iter_mut() {
             sub.remove(&client);
@@ -718,6 +1377,55 @@ impl Processor {
         matched
     }
 
+    /// Sums up the announce statistics of trackers by host, attributing the
+    /// transfer of every torrent to each host it announces to.
+    fn tracker_stats(&self) -> Vec<TrackerHost> {
+        let mut hosts: BTreeMap<&str, HostTally<'_>> = BTreeMap::new();
+        for id in self.kinds[ResourceKind::Tracker as usize].iter() {
+            let t = self.resources[id].as_tracker();
+            let host = t.url.host_str().unwrap_or_else(|| t.url.as_str());
+            let (stats, torrents, timing) = hosts.entry(host).or_insert_with(|| {
+                let stats = TrackerHost {
+                    host: host.to_owned(),
+                    torrents: 0,
+                    transferred_up: 0,
+                    transferred_down: 0,
+                    announces: 0,
+                    failures: 0,
+                    error_rate: 0.,
+                    response_time: None,
+                };
+                (stats, FHashSet::default(), (0, 0))
+            });
+            stats.announces += t.announces;
+            stats.failures += t.failures;
+            if let Some(ms) = t.response_time {
+                let responses = t.announces + t.failures;
+                timing.0 += ms * responses;
+                timing.1 += responses;
+            }
+            if !torrents.insert(&t.torrent_id) {
+                continue;
+            }
+            if let Some(Resource::Torrent(torrent)) = self.resources.get(&t.torrent_id) {
+                stats.torrents += 1;
+                stats.transferred_up += torrent.transferred_up;
+                stats.transferred_down += torrent.transferred_down;
+            }
+        }
+        hosts
+            .into_iter()
+            .map(|(_, (mut stats, _, (response_ms, timed)))| {
+                let total = stats.announces + stats.failures;
+                if total != 0 {
+                    stats.error_rate = stats.failures as f32 / total as f32;
+                }
+                stats.response_time = response_ms.checked_div(timed);
+                stats
+            })
+            .collect()
+    }
+
     fn new_transfer(&mut self, client: usize, serial: u64, kind: TransferKind) -> SMessage<'_> {
         let expiration = Utc::now() + Duration::seconds(EXPIRATION_DUR);
         let tok = random_string(15);
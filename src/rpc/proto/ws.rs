@@ -1,9 +1,27 @@
 use byteorder::{BigEndian, ByteOrder};
-use std::io;
+use std::{error, fmt, io};
 
-// Since we never do large transfers of WS itself this should be
-// reasonable
-const MAX_MSG_BYTES: u64 = 5 * 1000 * 1000;
+/// Close status of connections sending messages larger than allowed
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+/// Source of the InvalidInput error returned for frames longer than
+/// allowed, telling them apart from other invalid input.
+#[derive(Debug)]
+pub struct FrameTooLong;
+
+impl FrameTooLong {
+    pub fn is(e: &io::Error) -> bool {
+        e.get_ref().is_some_and(|e| e.is::<FrameTooLong>())
+    }
+}
+
+impl fmt::Display for FrameTooLong {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "frame too long")
+    }
+}
+
+impl error::Error for FrameTooLong {}
 
 #[derive(Debug)]
 pub enum Frame {
@@ -48,6 +66,15 @@ impl Message {
         }
     }
 
+    pub fn close_status(status: u16) -> Message {
+        Message {
+            header: 0x80 | Opcode::Close.code(),
+            len: 2,
+            mask: None,
+            data: status.to_be_bytes().to_vec(),
+        }
+    }
+
     pub fn text(s: String) -> Message {
         let d = s.into_bytes();
         Message {
@@ -85,9 +112,11 @@ impl Message {
         }
     }
 
-    pub fn allocate(&mut self) -> io::Result<()> {
-        if self.len > MAX_MSG_BYTES {
-            return Err(io::ErrorKind::InvalidInput.into());
+    /// Sizes the payload buffer to the frame's length, failing with
+    /// InvalidInput from FrameTooLong if it's longer than max.
+    pub fn allocate(&mut self, max: u64) -> io::Result<()> {
+        if self.len > max {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, FrameTooLong));
         }
         self.data.resize(self.len as usize, 0u8);
         Ok(())
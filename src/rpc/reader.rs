@@ -7,6 +7,9 @@ pub struct Reader {
     msg: Message,
     pos: usize,
     state: State,
+    /// Longest frame payload accepted, longer ones fail reading with
+    /// InvalidInput from FrameTooLong before anything is allocated for them
+    max_len: u64,
 }
 
 #[derive(Copy, Clone)]
@@ -19,11 +22,12 @@ enum State {
 }
 
 impl Reader {
-    pub fn new() -> Reader {
+    pub fn new(max_len: u64) -> Reader {
         Reader {
             msg: Message::new(),
             pos: 0,
             state: State::Header,
+            max_len,
         }
     }
 
@@ -73,7 +77,7 @@ impl Reader {
                             if self.msg.masked() {
                                 self.state = State::MaskingKey;
                             } else {
-                                self.msg.allocate()?;
+                                self.msg.allocate(self.max_len)?;
                                 self.state = State::Payload(l as usize);
                             }
                         }
@@ -96,7 +100,7 @@ impl Reader {
                     if self.msg.masked() {
                         self.state = State::MaskingKey;
                     } else {
-                        self.msg.allocate()?;
+                        self.msg.allocate(self.max_len)?;
                         self.state = State::Payload(self.msg.len as usize);
                     }
 
@@ -108,7 +112,7 @@ impl Reader {
                     mask.copy_from_slice(&self.msg.data[start..end]);
                     self.msg.mask = Some(mask);
                     self.state = State::Payload(self.msg.len as usize);
-                    self.msg.allocate()?;
+                    self.msg.allocate(self.max_len)?;
 
                     self.pos = 0;
                 }
@@ -140,3 +144,32 @@ impl State {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::proto::ws::FrameTooLong;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_max_len() {
+        // Masked text frames with 16 bit lengths
+        let frame = |len: u16| {
+            let mut f = vec![0x81, 0x80 | 126];
+            f.extend_from_slice(&len.to_be_bytes());
+            f.extend_from_slice(&[0; 4]);
+            f.resize(f.len() + len as usize, b'a');
+            f
+        };
+        let mut r = Reader::new(1000);
+        let m = r.read(&mut Cursor::new(frame(1000))).unwrap().unwrap();
+        assert_eq!(m.data.len(), 1000);
+
+        // Only the header is needed to reject a frame
+        let mut r = Reader::new(1000);
+        let err = r.read(&mut Cursor::new(&frame(1001)[..8])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(FrameTooLong::is(&err));
+        assert!(!FrameTooLong::is(&io::ErrorKind::InvalidInput.into()));
+    }
+}
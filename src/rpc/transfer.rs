@@ -17,6 +17,7 @@ pub enum TransferResult {
         conn: SStream,
         start: bool,
         import: bool,
+        dry_run: bool,
         data: Vec<u8>,
         path: Option<String>,
         client: usize,
@@ -30,6 +31,18 @@ pub enum TransferResult {
     Incomplete,
 }
 
+/// A torrent upload a client was given a transfer token for
+pub struct TorrentUpload {
+    pub client: usize,
+    pub serial: u64,
+    pub path: Option<String>,
+    /// Length of the torrent file
+    pub size: u64,
+    pub start: bool,
+    pub import: bool,
+    pub dry_run: bool,
+}
+
 struct TorrentTx {
     conn: SStream,
     client: usize,
@@ -38,6 +51,7 @@ struct TorrentTx {
     buf: Vec<u8>,
     start: bool,
     import: bool,
+    dry_run: bool,
     path: Option<String>,
     last_action: time::Instant,
 }
@@ -51,33 +65,23 @@ impl Transfers {
         }
     }
 
-    pub fn add_torrent(
-        &mut self,
-        id: usize,
-        client: usize,
-        serial: u64,
-        conn: SStream,
-        mut data: Vec<u8>,
-        path: Option<String>,
-        size: u64,
-        start: bool,
-        import: bool,
-    ) {
+    pub fn add_torrent(&mut self, id: usize, conn: SStream, mut data: Vec<u8>, up: TorrentUpload) {
         let pos = data.len();
         // Given that this requires an authenticated connection
         // we can safely assume this won't be abused
-        data.resize(size as usize, 0u8);
+        data.resize(up.size as usize, 0u8);
         self.torrents.insert(
             id,
             TorrentTx {
-                client,
-                serial,
+                client: up.client,
+                serial: up.serial,
                 conn,
                 pos,
                 buf: data,
-                path,
-                start,
-                import,
+                path: up.path,
+                start: up.start,
+                import: up.import,
+                dry_run: up.dry_run,
                 last_action: time::Instant::now(),
             },
         );
@@ -103,6 +107,7 @@ impl Transfers {
                     serial: tx.serial,
                     start: tx.start,
                     import: tx.import,
+                    dry_run: tx.dry_run,
                 }
             }
             Some(Ok(false)) => TransferResult::Incomplete,
@@ -0,0 +1,332 @@
+use std::convert::TryFrom;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+use std::time::Duration;
+
+use net2::{TcpBuilder, TcpStreamExt};
+
+use crate::config::IpStack;
+use crate::throttle::Throttle;
+use crate::util::native;
+use crate::CONFIG;
+
+pub mod mse;
+
+/// Wrapper type over Mio sockets, allowing for use of UDP/TCP, encryption,
+/// rate limiting, etc.
+pub struct Socket {
+    conn: TcpStream,
+    addr: SocketAddr,
+    pub throttle: Option<Throttle>,
+    /// Encryption handshake of an incoming connection which is in progress
+    handshake: Option<Box<mse::Handshake>>,
+    cipher: Option<Box<mse::Cipher>>,
+    /// Data received during the handshake which hasn't been read yet
+    pending: Vec<u8>,
+    /// Encrypted data which has been accepted but not yet sent
+    out: Vec<u8>,
+}
+
+const EINPROGRESS: i32 = 115;
+
+impl Socket {
+    pub fn new(addr: &SocketAddr) -> io::Result<Socket> {
+        let sock = (match *addr {
+            SocketAddr::V4(..) => TcpBuilder::new_v4(),
+            SocketAddr::V6(..) => TcpBuilder::new_v6(),
+        })?;
+        let conn = sock.to_tcp_stream()?;
+        conn.set_nonblocking(true)?;
+        // Buffer sizes need to be set prior to connecting for window scaling
+        tune(&conn)?;
+        if let Err(e) = conn.connect(addr) {
+            // OSX gives the AddrNotAvailable error sometimes
+            if Some(EINPROGRESS) != e.raw_os_error() && e.kind() != ErrorKind::AddrNotAvailable {
+                return Err(e);
+            }
+        }
+        Ok(Socket {
+            conn,
+            throttle: None,
+            addr: *addr,
+            handshake: None,
+            cipher: None,
+            pending: Vec::new(),
+            out: Vec::new(),
+        })
+    }
+
+    #[cfg(test)]
+    pub fn empty() -> Socket {
+        let conn = TcpBuilder::new_v4().unwrap().to_tcp_stream().unwrap();
+        Socket {
+            conn,
+            throttle: None,
+            addr: "127.0.0.1:0".parse().unwrap(),
+            handshake: None,
+            cipher: None,
+            pending: Vec::new(),
+            out: Vec::new(),
+        }
+    }
+
+    /// Returns whether the connection is MSE encrypted
+    pub fn encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Wraps an accepted connection. The configured TCP options are not
+    /// applied until `tune` is called.
+    pub fn from_stream(conn: TcpStream) -> io::Result<Socket> {
+        conn.set_nonblocking(true)?;
+        let addr = conn.peer_addr()?;
+        Ok(Socket {
+            conn,
+            throttle: None,
+            addr,
+            handshake: None,
+            cipher: None,
+            pending: Vec::new(),
+            out: Vec::new(),
+        })
+    }
+
+    pub fn tune(&self) -> io::Result<()> {
+        tune(&self.conn)
+    }
+
+    /// Accepts either a plaintext or an MSE encrypted handshake for one of
+    /// the torrents in skeys before any data is read from the connection.
+    pub fn expect_handshake(&mut self, skeys: Arc<mse::SKeys>, required: bool) {
+        self.handshake = Some(Box::new(mse::Handshake::new(skeys, required)));
+    }
+
+    /// Sends any buffered encrypted data, returning an error if some
+    /// of it remains.
+    pub fn flush_out(&mut self) -> io::Result<()> {
+        while !self.out.is_empty() {
+            let out = std::mem::take(&mut self.out);
+            let res = self.write_raw(&out);
+            self.out = out;
+            match res {
+                Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, "")),
+                Ok(amnt) => {
+                    self.out.drain(..amnt);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn advance_handshake(&mut self) -> io::Result<()> {
+        let mut hs = self.handshake.take().unwrap();
+        match hs.advance(&mut self.conn, &mut self.out)? {
+            Some(est) => {
+                self.pending = est.initial;
+                self.cipher = est.cipher;
+            }
+            None => self.handshake = Some(hs),
+        }
+        match self.flush_out() {
+            Err(ref e) if e.kind() != ErrorKind::WouldBlock => {
+                return Err(io::Error::new(e.kind(), "Failed to send handshake"));
+            }
+            _ => {}
+        }
+        if self.handshake.is_some() {
+            return Err(io::Error::new(ErrorKind::WouldBlock, ""));
+        }
+        Ok(())
+    }
+
+    fn read_raw(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Don't bother rate limiting small requests
+        if buf.len() < 20 {
+            return self.conn.read(buf);
+        }
+        if let Some(ref mut t) = self.throttle {
+            match t.get_bytes_dl(buf.len()) {
+                Ok(()) => match self.conn.read(buf) {
+                    Ok(amnt) => {
+                        t.restore_bytes_dl(buf.len() - amnt);
+                        Ok(amnt)
+                    }
+                    Err(e) => {
+                        t.restore_bytes_dl(buf.len());
+                        Err(e)
+                    }
+                },
+                Err(()) => Err(io::Error::new(ErrorKind::WouldBlock, "")),
+            }
+        } else {
+            self.conn.read(buf)
+        }
+    }
+
+    fn write_raw(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() < 20 {
+            return self.conn.write(buf);
+        }
+        if let Some(ref mut t) = self.throttle {
+            match t.get_bytes_ul(buf.len()) {
+                Ok(()) => match self.conn.write(buf) {
+                    Ok(amnt) => {
+                        t.restore_bytes_ul(buf.len() - amnt);
+                        Ok(amnt)
+                    }
+                    Err(e) => {
+                        t.restore_bytes_ul(buf.len());
+                        Err(e)
+                    }
+                },
+                Err(()) => Err(io::Error::new(ErrorKind::WouldBlock, "")),
+            }
+        } else {
+            self.conn.write(buf)
+        }
+    }
+
+    fn write_vectored_raw(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        let len = bufs.iter().map(|b| b.len()).sum();
+        if len < 20 {
+            return self.conn.write_vectored(bufs);
+        }
+        if let Some(ref mut t) = self.throttle {
+            match t.get_bytes_ul(len) {
+                Ok(()) => match self.conn.write_vectored(bufs) {
+                    Ok(amnt) => {
+                        t.restore_bytes_ul(len - amnt);
+                        Ok(amnt)
+                    }
+                    Err(e) => {
+                        t.restore_bytes_ul(len);
+                        Err(e)
+                    }
+                },
+                Err(()) => Err(io::Error::new(ErrorKind::WouldBlock, "")),
+            }
+        } else {
+            self.conn.write_vectored(bufs)
+        }
+    }
+
+    /// Encrypts and buffers all of bufs, sending as much as possible.
+    fn write_encrypted(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        self.flush_out()?;
+        let cipher = self.cipher.as_mut().unwrap();
+        for buf in bufs {
+            let start = self.out.len();
+            self.out.extend_from_slice(buf);
+            cipher.encrypt(&mut self.out[start..]);
+        }
+        match self.flush_out() {
+            Err(ref e) if e.kind() != ErrorKind::WouldBlock => {
+                return Err(io::Error::new(e.kind(), "Failed to send data"));
+            }
+            _ => {}
+        }
+        Ok(bufs.iter().map(|b| b.len()).sum())
+    }
+}
+
+/// Binds a listener on port over the configured IP versions, either on
+/// all interfaces or only loopback if local is set.
+pub fn listener(port: u16, local: bool) -> io::Result<TcpListener> {
+    let (v4, v6) = if local {
+        (Ipv4Addr::LOCALHOST, Ipv6Addr::LOCALHOST)
+    } else {
+        (Ipv4Addr::UNSPECIFIED, Ipv6Addr::UNSPECIFIED)
+    };
+    match CONFIG.net.ip_stack {
+        IpStack::Ipv4 => TcpListener::bind((v4, port)),
+        IpStack::Ipv6 => bind_v6(v6, port, true),
+        // IPv4 connections can't reach the v6 loopback address
+        IpStack::Dual if local => TcpListener::bind((v4, port)),
+        IpStack::Dual => bind_v6(v6, port, false).or_else(|e| {
+            info!("Dual stack listening unavailable, using IPv4: {}", e);
+            TcpListener::bind((v4, port))
+        }),
+    }
+}
+
+fn bind_v6(ip: Ipv6Addr, port: u16, only_v6: bool) -> io::Result<TcpListener> {
+    let sock = TcpBuilder::new_v6()?;
+    sock.only_v6(only_v6)?;
+    sock.reuse_address(true)?;
+    sock.bind((ip, port))?;
+    sock.listen(128)
+}
+
+/// Applies the configured TCP options to a peer connection.
+fn tune(conn: &TcpStream) -> io::Result<()> {
+    let cfg = &CONFIG.peer;
+    TcpStreamExt::set_nodelay(conn, cfg.nodelay)?;
+    if cfg.send_buffer != 0 {
+        conn.set_send_buffer_size(cfg.send_buffer)?;
+    }
+    if cfg.recv_buffer != 0 {
+        conn.set_recv_buffer_size(cfg.recv_buffer)?;
+    }
+    if cfg.keepalive_idle != 0 {
+        TcpStreamExt::set_keepalive(conn, Some(Duration::from_secs(cfg.keepalive_idle)))?;
+        let interval = u32::try_from(cfg.keepalive_interval).unwrap_or(u32::MAX);
+        native::set_keepalive_params(conn, interval, cfg.keepalive_count)?;
+    }
+    Ok(())
+}
+
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.conn.as_raw_fd()
+    }
+}
+
+impl io::Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.handshake.is_some() {
+            self.advance_handshake()?;
+        }
+        if !self.pending.is_empty() {
+            let amnt = buf.len().min(self.pending.len());
+            buf[..amnt].copy_from_slice(&self.pending[..amnt]);
+            self.pending.drain(..amnt);
+            return Ok(amnt);
+        }
+        let amnt = self.read_raw(buf)?;
+        if let Some(ref mut c) = self.cipher {
+            c.decrypt(&mut buf[..amnt]);
+        }
+        Ok(amnt)
+    }
+}
+
+impl io::Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_vectored(&[io::IoSlice::new(buf)])
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        if self.handshake.is_some() {
+            return Err(io::Error::new(ErrorKind::WouldBlock, ""));
+        }
+        if self.cipher.is_some() {
+            return self.write_encrypted(bufs);
+        }
+        match bufs {
+            [buf] => self.write_raw(buf),
+            _ => self.write_vectored_raw(bufs),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_out()?;
+        self.conn.flush()
+    }
+}
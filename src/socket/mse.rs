@@ -0,0 +1,495 @@
+//! Responder side of the Message Stream Encryption (MSE/PE) handshake.
+//!
+//! The initiator identifies the torrent it wants through HASH('req2', SKEY)
+//! rather than the infohash itself, so incoming handshakes are matched
+//! against a table of these hashes for every torrent we have.
+
+use std::io::{self, ErrorKind, Read};
+use std::sync::Arc;
+
+use num_bigint::BigUint;
+use rand::{self, Rng};
+use sha1::{Digest, Sha1};
+
+use crate::util::{io_err_val, MHashMap};
+
+/// 768 bit safe prime used for the DH exchange
+const PRIME: &[u8] = b"FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const KEY_LEN: usize = 96;
+const MAX_PAD: usize = 512;
+const VC: [u8; 8] = [0; 8];
+const CRYPTO_PLAIN: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+/// Largest amount of handshake data buffered before giving up
+const MAX_HANDSHAKE: usize = 4096;
+const PROTOCOL: &[u8] = b"\x13BitTorrent protocol";
+
+lazy_static! {
+    static ref P: BigUint = BigUint::parse_bytes(PRIME, 16).unwrap();
+}
+
+/// Infohashes of our torrents, keyed by HASH('req2', infohash).
+#[derive(Clone, Default)]
+pub struct SKeys {
+    keys: MHashMap<[u8; 20], [u8; 20]>,
+}
+
+impl SKeys {
+    pub fn insert(&mut self, hash: [u8; 20]) {
+        self.keys.insert(hash_of(&[b"req2", &hash]), hash);
+    }
+
+    pub fn remove(&mut self, hash: &[u8; 20]) {
+        self.keys.remove(&hash_of(&[b"req2", hash]));
+    }
+
+    pub fn find(&self, req2: &[u8; 20]) -> Option<&[u8; 20]> {
+        self.keys.get(req2)
+    }
+}
+
+/// RC4 keystream, applied in place.
+struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Rc4 {
+        let mut s = [0u8; 256];
+        for (i, b) in s.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        Rc4 { s, i: 0, j: 0 }
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for b in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k = self.s[self.i as usize].wrapping_add(self.s[self.j as usize]);
+            *b ^= self.s[k as usize];
+        }
+    }
+}
+
+/// Stream ciphers of an established RC4 connection.
+pub struct Cipher {
+    dec: Rc4,
+    enc: Rc4,
+}
+
+impl Cipher {
+    /// Creates the responder's ciphers, MSE discards the first 1024
+    /// bytes of each keystream.
+    fn new(s: &[u8], skey: &[u8; 20]) -> Cipher {
+        let mut dec = Rc4::new(&hash_of(&[b"keyA", s, skey]));
+        let mut enc = Rc4::new(&hash_of(&[b"keyB", s, skey]));
+        dec.apply(&mut [0u8; 1024]);
+        enc.apply(&mut [0u8; 1024]);
+        Cipher { dec, enc }
+    }
+
+    pub fn decrypt(&mut self, data: &mut [u8]) {
+        self.dec.apply(data);
+    }
+
+    pub fn encrypt(&mut self, data: &mut [u8]) {
+        self.enc.apply(data);
+    }
+}
+
+/// Result of a completed handshake.
+pub struct Established {
+    /// Decrypted peer protocol data which arrived with the handshake
+    pub initial: Vec<u8>,
+    /// None if the connection continues in plaintext
+    pub cipher: Option<Box<Cipher>>,
+}
+
+enum State {
+    /// Waiting for either a plaintext handshake or the initiator's key
+    Start,
+    /// Scanning PadA for HASH('req1', S)
+    Sync {
+        s: [u8; KEY_LEN],
+    },
+    /// Reading HASH('req2', SKEY) xor HASH('req3', S)
+    SKey {
+        s: [u8; KEY_LEN],
+    },
+    /// Reading VC, crypto_provide and len(PadC)
+    Provide {
+        cipher: Box<Cipher>,
+    },
+    /// Reading PadC and len(IA)
+    PadC {
+        cipher: Box<Cipher>,
+        provide: u32,
+        len: usize,
+    },
+    /// Reading IA
+    Initial {
+        cipher: Box<Cipher>,
+        provide: u32,
+        len: usize,
+    },
+    Failed,
+}
+
+enum Progress {
+    NeedData,
+    Advanced,
+    Done(Established),
+}
+
+/// Handshake of an incoming connection which may or may not be encrypted.
+pub struct Handshake {
+    skeys: Arc<SKeys>,
+    required: bool,
+    state: State,
+    /// Received data which hasn't been processed yet
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Handshake {
+    pub fn new(skeys: Arc<SKeys>, required: bool) -> Handshake {
+        Handshake {
+            skeys,
+            required,
+            state: State::Start,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Reads from conn until the handshake is complete or the read blocks,
+    /// appending any data which should be sent to the peer to out.
+    pub fn advance<R: Read>(
+        &mut self,
+        conn: &mut R,
+        out: &mut Vec<u8>,
+    ) -> io::Result<Option<Established>> {
+        let mut chunk = [0u8; 1024];
+        loop {
+            match self.process(out)? {
+                Progress::Done(e) => return Ok(Some(e)),
+                Progress::Advanced => continue,
+                Progress::NeedData => {}
+            }
+            if self.buf.len() >= MAX_HANDSHAKE {
+                return Err(io_err_val("Encryption handshake too long"));
+            }
+            match conn.read(&mut chunk) {
+                Ok(0) => return Err(io_err_val("EOF")),
+                Ok(amnt) => self.buf.extend_from_slice(&chunk[..amnt]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn process(&mut self, out: &mut Vec<u8>) -> io::Result<Progress> {
+        let avail = self.buf.len() - self.pos;
+        match std::mem::replace(&mut self.state, State::Failed) {
+            State::Start => {
+                if avail < PROTOCOL.len() {
+                    self.state = State::Start;
+                    return Ok(Progress::NeedData);
+                }
+                if &self.buf[..PROTOCOL.len()] == PROTOCOL {
+                    if self.required {
+                        return Err(io_err_val("Plaintext connection refused"));
+                    }
+                    return Ok(Progress::Done(Established {
+                        initial: std::mem::take(&mut self.buf),
+                        cipher: None,
+                    }));
+                }
+                if avail < KEY_LEN {
+                    self.state = State::Start;
+                    return Ok(Progress::NeedData);
+                }
+                let (x, y) = keypair();
+                let s = shared(&x, &self.buf[..KEY_LEN]);
+                out.extend_from_slice(&y);
+                out.extend_from_slice(&padding());
+                self.pos = KEY_LEN;
+                self.state = State::Sync { s };
+            }
+            State::Sync { s } => {
+                let req1 = hash_of(&[b"req1", &s]);
+                let window = &self.buf[KEY_LEN..];
+                match window.windows(20).position(|w| w == req1) {
+                    Some(i) if i <= MAX_PAD => {
+                        self.pos = KEY_LEN + i + 20;
+                        self.state = State::SKey { s };
+                    }
+                    _ if window.len() >= MAX_PAD + 20 => {
+                        return Err(io_err_val("Encryption sync not found"));
+                    }
+                    _ => {
+                        self.state = State::Sync { s };
+                        return Ok(Progress::NeedData);
+                    }
+                }
+            }
+            State::SKey { s } => {
+                if avail < 20 {
+                    self.state = State::SKey { s };
+                    return Ok(Progress::NeedData);
+                }
+                let req3 = hash_of(&[b"req3", &s]);
+                let mut req2 = [0u8; 20];
+                for (i, b) in req2.iter_mut().enumerate() {
+                    *b = self.buf[self.pos + i] ^ req3[i];
+                }
+                let skey = *self
+                    .skeys
+                    .find(&req2)
+                    .ok_or_else(|| io_err_val("Unknown torrent in encryption handshake"))?;
+                self.pos += 20;
+                self.state = State::Provide {
+                    cipher: Box::new(Cipher::new(&s, &skey)),
+                };
+            }
+            State::Provide { mut cipher } => {
+                if avail < 14 {
+                    self.state = State::Provide { cipher };
+                    return Ok(Progress::NeedData);
+                }
+                let data = &mut self.buf[self.pos..self.pos + 14];
+                cipher.decrypt(data);
+                if data[..8] != VC {
+                    return Err(io_err_val("Invalid encryption verification constant"));
+                }
+                let provide = u32::from_be_bytes([data[8], data[9], data[10], data[11]]);
+                let len = u16::from_be_bytes([data[12], data[13]]) as usize;
+                if len > MAX_PAD {
+                    return Err(io_err_val("Encryption padding too long"));
+                }
+                self.pos += 14;
+                self.state = State::PadC {
+                    cipher,
+                    provide,
+                    len,
+                };
+            }
+            State::PadC {
+                mut cipher,
+                provide,
+                len,
+            } => {
+                if avail < len + 2 {
+                    self.state = State::PadC {
+                        cipher,
+                        provide,
+                        len,
+                    };
+                    return Ok(Progress::NeedData);
+                }
+                let data = &mut self.buf[self.pos..self.pos + len + 2];
+                cipher.decrypt(data);
+                let ia = u16::from_be_bytes([data[len], data[len + 1]]) as usize;
+                self.pos += len + 2;
+                self.state = State::Initial {
+                    cipher,
+                    provide,
+                    len: ia,
+                };
+            }
+            State::Initial {
+                mut cipher,
+                provide,
+                len,
+            } => {
+                if avail < len {
+                    self.state = State::Initial {
+                        cipher,
+                        provide,
+                        len,
+                    };
+                    return Ok(Progress::NeedData);
+                }
+                let select = if provide & CRYPTO_RC4 != 0 {
+                    CRYPTO_RC4
+                } else if provide & CRYPTO_PLAIN != 0 && !self.required {
+                    CRYPTO_PLAIN
+                } else {
+                    return Err(io_err_val("No supported encryption method"));
+                };
+                let mut reply = Vec::with_capacity(14);
+                reply.extend_from_slice(&VC);
+                reply.extend_from_slice(&select.to_be_bytes());
+                reply.extend_from_slice(&[0, 0]);
+                cipher.encrypt(&mut reply);
+                out.extend_from_slice(&reply);
+
+                let mut initial = self.buf.split_off(self.pos);
+                // IA is always encrypted, anything after it only if RC4 was selected
+                cipher.decrypt(&mut initial[..len]);
+                if select == CRYPTO_RC4 {
+                    cipher.decrypt(&mut initial[len..]);
+                }
+                return Ok(Progress::Done(Established {
+                    initial,
+                    cipher: if select == CRYPTO_RC4 {
+                        Some(cipher)
+                    } else {
+                        None
+                    },
+                }));
+            }
+            State::Failed => return Err(io_err_val("Encryption handshake failed")),
+        }
+        Ok(Progress::Advanced)
+    }
+}
+
+fn hash_of(parts: &[&[u8]]) -> [u8; 20] {
+    let mut ctx = Sha1::new();
+    for part in parts {
+        ctx.update(part);
+    }
+    ctx.finalize().into()
+}
+
+fn to_key(n: &BigUint) -> [u8; KEY_LEN] {
+    let bytes = n.to_bytes_be();
+    let mut key = [0u8; KEY_LEN];
+    key[KEY_LEN - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+/// Generates a private key and the matching public key.
+fn keypair() -> (BigUint, [u8; KEY_LEN]) {
+    let mut x = [0u8; 20];
+    rand::thread_rng().fill(&mut x);
+    let x = BigUint::from_bytes_be(&x);
+    let y = BigUint::from(2u8).modpow(&x, &P);
+    (x, to_key(&y))
+}
+
+fn shared(x: &BigUint, y: &[u8]) -> [u8; KEY_LEN] {
+    to_key(&BigUint::from_bytes_be(y).modpow(x, &P))
+}
+
+fn padding() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let len = rng.gen_range(0, MAX_PAD + 1);
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Nonblocking connection holding the data received so far
+    struct Conn(io::Cursor<Vec<u8>>);
+
+    impl Read for Conn {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.read(buf)? {
+                0 => Err(ErrorKind::WouldBlock.into()),
+                amnt => Ok(amnt),
+            }
+        }
+    }
+
+    fn conn(data: Vec<u8>) -> Conn {
+        Conn(io::Cursor::new(data))
+    }
+
+    #[test]
+    fn test_rc4() {
+        let mut data = *b"Plaintext";
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(data, [0xBB, 0xF3, 0x16, 0xE8, 0xD9, 0x40, 0xAF, 0x0A, 0xD3]);
+    }
+
+    #[test]
+    fn test_plaintext() {
+        let skeys = Arc::new(SKeys::default());
+        let mut data = PROTOCOL.to_vec();
+        data.extend_from_slice(&[0; 48]);
+        let mut out = Vec::new();
+        let mut hs = Handshake::new(skeys.clone(), false);
+        let done = hs
+            .advance(&mut conn(data.clone()), &mut out)
+            .unwrap()
+            .unwrap();
+        assert_eq!(done.initial, data);
+        assert!(done.cipher.is_none() && out.is_empty());
+
+        let mut hs = Handshake::new(skeys, true);
+        assert!(hs.advance(&mut conn(data), &mut out).is_err());
+    }
+
+    #[test]
+    fn test_encrypted() {
+        let hash = [7u8; 20];
+        let mut skeys = SKeys::default();
+        skeys.insert(hash);
+        let mut hs = Handshake::new(Arc::new(skeys), true);
+
+        // Initiator sends its key and some padding
+        let (xa, ya) = keypair();
+        let mut data = ya.to_vec();
+        data.extend_from_slice(&[1; 10]);
+        let mut out = Vec::new();
+        assert!(hs.advance(&mut conn(data), &mut out).unwrap().is_none());
+        let s = shared(&xa, &out[..KEY_LEN]);
+
+        let mut data = hash_of(&[b"req1", &s]).to_vec();
+        let req2 = hash_of(&[b"req2", &hash]);
+        let req3 = hash_of(&[b"req3", &s]);
+        data.extend(req2.iter().zip(req3.iter()).map(|(a, b)| a ^ b));
+        let mut enc = Rc4::new(&hash_of(&[b"keyA", &s, &hash]));
+        enc.apply(&mut [0u8; 1024]);
+        let mut msg = VC.to_vec();
+        msg.extend_from_slice(&(CRYPTO_PLAIN | CRYPTO_RC4).to_be_bytes());
+        msg.extend_from_slice(&[0, 2, 9, 9, 0, 3]);
+        msg.extend_from_slice(b"abcdef");
+        enc.apply(&mut msg);
+        data.extend_from_slice(&msg);
+
+        let mut out = Vec::new();
+        let mut done = hs.advance(&mut conn(data), &mut out).unwrap().unwrap();
+        assert_eq!(done.initial, b"abcdef");
+
+        let mut dec = Rc4::new(&hash_of(&[b"keyB", &s, &hash]));
+        dec.apply(&mut [0u8; 1024]);
+        dec.apply(&mut out);
+        assert_eq!(&out[..8], &VC);
+        assert_eq!(&out[8..], &[0, 0, 0, 2, 0, 0]);
+
+        // The stream continues with the established ciphers
+        let mut more = *b"xyz";
+        enc.apply(&mut more);
+        done.cipher.as_mut().unwrap().decrypt(&mut more);
+        assert_eq!(&more, b"xyz");
+    }
+
+    #[test]
+    fn test_unknown_torrent() {
+        let mut hs = Handshake::new(Arc::new(SKeys::default()), false);
+        let (xa, ya) = keypair();
+        let mut out = Vec::new();
+        assert!(hs
+            .advance(&mut conn(ya.to_vec()), &mut out)
+            .unwrap()
+            .is_none());
+        let s = shared(&xa, &out[..KEY_LEN]);
+        let mut data = hash_of(&[b"req1", &s]).to_vec();
+        data.extend_from_slice(&[0; 20]);
+        assert!(hs.advance(&mut conn(data), &mut out).is_err());
+    }
+}
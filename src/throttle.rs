@@ -3,6 +3,8 @@ use std::cell::RefCell;
 use std::collections::HashSet;
 use std::rc::Rc;
 
+use crate::rpc::proto::message::{Limit, LimitSource};
+
 /// Creates a throttler from which sub throttles may be created.
 /// Note that all created throttle's have a lifetime tied to the
 /// throttler. This invariant must be maintained or undefined
@@ -97,6 +99,21 @@ impl Throttler {
     }
 }
 
+/// Resolves the rate a torrent is held to given its own and the server's
+/// throttle. A torrent rate of -1 ignores the server throttle, otherwise
+/// both apply and the lower one wins.
+pub fn effective_limit(torrent: Option<i64>, server: Option<i64>) -> Limit {
+    let server = server.filter(|&r| r >= 0);
+    let (rate, source) = match (torrent, server) {
+        (Some(t), _) if t < 0 => (None, LimitSource::Unlimited),
+        (Some(t), Some(s)) if s < t => (Some(s), LimitSource::Server),
+        (Some(t), _) => (Some(t), LimitSource::Torrent),
+        (None, Some(s)) => (Some(s), LimitSource::Server),
+        (None, None) => (None, LimitSource::Unlimited),
+    };
+    Limit { rate, source }
+}
+
 struct ThrottleData {
     rate: Option<i64>,
     tokens: usize,
@@ -119,6 +136,18 @@ pub struct Throttle {
 }
 
 impl Throttle {
+    #[cfg(test)]
+    pub fn test(id: usize) -> Throttle {
+        let data = || Rc::new(RefCell::new(ThrottleData::new(None, 0)));
+        Throttle {
+            ul_data: data(),
+            ul_tier: data(),
+            dl_data: data(),
+            dl_tier: data(),
+            id,
+        }
+    }
+
     pub fn new_sibling(&self, id: usize) -> Throttle {
         Throttle {
             ul_data: self.ul_data.clone(),
@@ -278,3 +307,24 @@ impl ThrottleData {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_effective_limit() {
+        let l = effective_limit(None, None);
+        assert_eq!((l.rate, l.source), (None, LimitSource::Unlimited));
+        let l = effective_limit(None, Some(100));
+        assert_eq!((l.rate, l.source), (Some(100), LimitSource::Server));
+        let l = effective_limit(Some(50), Some(100));
+        assert_eq!((l.rate, l.source), (Some(50), LimitSource::Torrent));
+        let l = effective_limit(Some(500), Some(100));
+        assert_eq!((l.rate, l.source), (Some(100), LimitSource::Server));
+        let l = effective_limit(Some(-1), Some(100));
+        assert_eq!((l.rate, l.source), (None, LimitSource::Unlimited));
+        let l = effective_limit(Some(50), Some(-1));
+        assert_eq!((l.rate, l.source), (Some(50), LimitSource::Torrent));
+    }
+}
@@ -0,0 +1,61 @@
+use std::cmp;
+
+/// Most values the piece_availability field of a torrent resource holds
+const MAX_RANGES: usize = 256;
+
+/// Merges the per piece peer counts into at most MAX_RANGES evenly
+/// sized ranges of consecutive pieces, each taking the lowest count
+/// in it, so availability bars of large torrents stay small.
+pub fn ranges(counts: &[usize]) -> Vec<u16> {
+    let n = cmp::min(counts.len(), MAX_RANGES);
+    (0..n)
+        .map(|i| {
+            let range = &counts[i * counts.len() / n..(i + 1) * counts.len() / n];
+            let min = range.iter().cloned().min().unwrap_or(0);
+            cmp::min(min, u16::MAX as usize) as u16
+        })
+        .collect()
+}
+
+/// Number of complete copies of the torrent among peers, plus the
+/// fraction of pieces available more often than the rarest ones.
+pub fn distributed_copies(counts: &[usize]) -> f32 {
+    let min = match counts.iter().min() {
+        Some(&min) => min,
+        None => return 0.,
+    };
+    let above = counts.iter().filter(|&&c| c > min).count();
+    min as f32 + above as f32 / counts.len() as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ranges() {
+        assert_eq!(ranges(&[]), Vec::<u16>::new());
+        assert_eq!(ranges(&[3, 0, 1]), vec![3, 0, 1]);
+
+        let mut counts = vec![2; MAX_RANGES * 4];
+        counts[5] = 1;
+        counts[MAX_RANGES * 4 - 1] = 100_000;
+        let r = ranges(&counts);
+        assert_eq!(r.len(), MAX_RANGES);
+        assert_eq!(&r[..3], &[2, 1, 2]);
+        assert_eq!(r[MAX_RANGES - 1], 2);
+
+        // Uneven splits still cover every piece
+        let mut counts = vec![1; MAX_RANGES + 1];
+        counts[MAX_RANGES] = 0;
+        assert_eq!(ranges(&counts)[MAX_RANGES - 1], 0);
+        assert_eq!(ranges(&[70_000]), vec![u16::MAX]);
+    }
+
+    #[test]
+    fn test_distributed_copies() {
+        assert_eq!(distributed_copies(&[]), 0.);
+        assert_eq!(distributed_copies(&[0, 0, 1, 0]), 0.25);
+        assert_eq!(distributed_copies(&[2, 3, 2, 5]), 2.5);
+    }
+}
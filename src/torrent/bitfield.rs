@@ -21,6 +21,11 @@ impl Bitfield {
         }
     }
 
+    /// Creates a bitfield with every bit set.
+    pub fn full(len: u64) -> Bitfield {
+        Bitfield::C { len }
+    }
+
     pub fn from(b: &[u8], len: u64) -> Bitfield {
         let size = div_round_up!(len, 8);
         let mut vec = b.to_vec();
@@ -213,6 +218,28 @@ impl Bitfield {
         false
     }
 
+    /// Bits which are set here but not in other, which must be as long.
+    pub fn difference(&self, other: &Bitfield) -> Bitfield {
+        debug_assert_eq!(self.len(), other.len());
+        let data: Vec<u8> = self
+            .data()
+            .iter()
+            .zip(other.data().iter())
+            .map(|(a, b)| a & !b)
+            .collect();
+        let set = data.iter().map(|b| u64::from(b.count_ones())).sum();
+        let res = Bitfield::I {
+            len: self.len(),
+            data: data.into_boxed_slice(),
+            set,
+        };
+        if res.complete() {
+            Bitfield::C { len: self.len() }
+        } else {
+            res
+        }
+    }
+
     pub fn b64(&self) -> String {
         base64::encode(&self.data())
     }
@@ -289,6 +316,13 @@ impl<'a> Iterator for BitfieldIter<'a> {
 
     fn next(&mut self) -> Option<u64> {
         while self.idx < self.pf.len() {
+            // Skip over bytes without any set bits
+            if let Bitfield::I { data, .. } = self.pf {
+                if self.idx.is_multiple_of(8) && data[(self.idx / 8) as usize] == 0 {
+                    self.idx += 8;
+                    continue;
+                }
+            }
             self.idx += 1;
             if self.pf.has_bit(self.idx - 1) {
                 return Some(self.idx - 1);
@@ -444,6 +478,26 @@ mod tests {
             .collect::<Vec<_>>();
     }
 
+    #[test]
+    fn test_iter_sparse() {
+        let mut pf = Bitfield::new(30);
+        pf.set_bit(3);
+        pf.set_bit(29);
+        assert_eq!(pf.iter().collect::<Vec<_>>(), vec![3, 29]);
+    }
+
+    #[test]
+    fn test_difference() {
+        let mut pf = Bitfield::new(10);
+        pf.set_bit(2);
+        pf.set_bit(9);
+        let diff = Bitfield::full(10).difference(&pf);
+        assert_eq!(diff.set(), 8);
+        assert!(!diff.has_bit(2) && !diff.has_bit(9));
+        assert!(pf.difference(&Bitfield::new(10)).has_bit(9));
+        assert!(Bitfield::full(10).difference(&Bitfield::new(10)).complete());
+    }
+
     #[test]
     fn test_c_from() {
         let data = vec![0xff; 2];
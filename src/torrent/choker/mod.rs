@@ -0,0 +1,363 @@
+mod strategy;
+
+use std::time::{Duration, Instant};
+
+use self::strategy::{Direction, Stats, Strategy};
+use crate::control::cio;
+use crate::torrent::Peer;
+use crate::util::{random_sample, FHashSet, UHashMap};
+use crate::CONFIG;
+
+/// Number of peers unchoked at a time
+const UNCHOKE_SLOTS: usize = 5;
+/// Peers sent less than this may be given a reserved slot
+const POORLY_SERVED: u64 = 1024 * 1024;
+
+pub struct Choker {
+    unchoked: Vec<usize>,
+    interested: FHashSet<usize>,
+    /// Unchoked peers holding a reserved slot
+    reserved: FHashSet<usize>,
+    last_updated: Instant,
+    strategy: Box<dyn Strategy>,
+    /// Direction of the last rotation
+    dir: Direction,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SwapRes {
+    pub choked: usize,
+    pub unchoked: usize,
+}
+
+impl Choker {
+    pub fn new() -> Choker {
+        Choker {
+            unchoked: Vec::with_capacity(UNCHOKE_SLOTS),
+            interested: FHashSet::default(),
+            reserved: FHashSet::default(),
+            last_updated: Instant::now(),
+            strategy: strategy::from_config(CONFIG.peer.choke_strategy),
+            dir: Direction::Upload,
+        }
+    }
+
+    pub fn add_peer<T: cio::CIO>(&mut self, peer: &mut Peer<T>) {
+        if self.unchoked.len() < UNCHOKE_SLOTS {
+            self.unchoked.push(peer.id());
+            peer.flush();
+            peer.unchoke();
+        } else {
+            self.interested.insert(peer.id());
+        }
+    }
+
+    /// Tracks an interested peer without unchoking it, while
+    /// nothing is being served.
+    pub fn add_choked<T: cio::CIO>(&mut self, peer: &Peer<T>) {
+        self.interested.insert(peer.id());
+    }
+
+    /// Chokes every unchoked peer, keeping them as interested so
+    /// they can be unchoked again with unchoke_all.
+    pub fn choke_all<T: cio::CIO>(&mut self, peers: &mut UHashMap<Peer<T>>) {
+        for id in self.unchoked.drain(..) {
+            peers.get_mut(&id).map(Peer::choke);
+            self.interested.insert(id);
+        }
+        self.reserved.clear();
+    }
+
+    /// Fills every free slot with a random interested peer.
+    pub fn unchoke_all<T: cio::CIO>(&mut self, peers: &mut UHashMap<Peer<T>>) {
+        while self.unchoked.len() < UNCHOKE_SLOTS && self.unchoke_next(peers).is_some() {}
+    }
+
+    /// Unchokes the interested peer picked by the strategy.
+    fn unchoke_next<T: cio::CIO>(&mut self, peers: &mut UHashMap<Peer<T>>) -> Option<usize> {
+        let candidates: Vec<_> = self
+            .interested
+            .iter()
+            .filter_map(|id| peers.get(id).map(|p| stats(p, (0, 0))))
+            .collect();
+        let id = self.strategy.unchoke(&candidates, self.dir)?;
+        let peer = peers.get_mut(&id)?;
+        self.interested.remove(&id);
+        self.add_peer(peer);
+        Some(id)
+    }
+
+    pub fn remove_peer<T: cio::CIO>(
+        &mut self,
+        peer: &mut Peer<T>,
+        peers: &mut UHashMap<Peer<T>>,
+    ) -> Option<SwapRes> {
+        if let Some(idx) = self.unchoked.iter().position(|&id| id == peer.id()) {
+            self.unchoked.remove(idx);
+            self.reserved.remove(&peer.id());
+            peer.choke();
+            self.unchoke_next(peers).map(|unchoked| SwapRes {
+                choked: peer.id(),
+                unchoked,
+            })
+        } else {
+            self.interested.remove(&peer.id());
+            None
+        }
+    }
+
+    fn update_timer(&mut self) -> Result<(), ()> {
+        if self.last_updated.elapsed() < Duration::from_secs(10)
+            || self.unchoked.len() < UNCHOKE_SLOTS
+            || self.interested.is_empty()
+        {
+            Err(())
+        } else {
+            self.last_updated = Instant::now();
+            Ok(())
+        }
+    }
+
+    pub fn update_upload<T: cio::CIO>(&mut self, peers: &mut UHashMap<Peer<T>>) -> Option<SwapRes> {
+        self.rotate(peers, Direction::Upload)
+    }
+
+    pub fn update_download<T: cio::CIO>(
+        &mut self,
+        peers: &mut UHashMap<Peer<T>>,
+    ) -> Option<SwapRes> {
+        self.rotate(peers, Direction::Download)
+    }
+
+    /// Swaps the unchoked peer picked by the strategy for another.
+    fn rotate<T: cio::CIO>(
+        &mut self,
+        peers: &mut UHashMap<Peer<T>>,
+        dir: Direction,
+    ) -> Option<SwapRes> {
+        if self.update_timer().is_err() {
+            return None;
+        }
+        self.dir = dir;
+        // Every unchoked peer's counts start over with the rotation
+        let unchoked: Vec<_> = self
+            .unchoked
+            .iter()
+            .filter_map(|id| {
+                let peer = peers.get_mut(id)?;
+                let counts = peer.flush();
+                Some((*id, stats(peer, counts)))
+            })
+            .filter(|(id, _)| !self.reserved.contains(id))
+            .map(|(_, s)| s)
+            .collect();
+        let idx = self
+            .strategy
+            .choke(&unchoked, dir)
+            .and_then(|id| self.unchoked.iter().position(|&u| u == id))
+            .unwrap_or(0);
+        self.swap_peer(idx, peers)
+    }
+
+    fn swap_peer<T: cio::CIO>(
+        &mut self,
+        idx: usize,
+        peers: &mut UHashMap<Peer<T>>,
+    ) -> Option<SwapRes> {
+        let id = self.unchoked.remove(idx);
+        {
+            peers.get_mut(&id).map(Peer::choke);
+        }
+        // Peers which held a reserved slot go back to competing for
+        // the regular ones.
+        let mut choked = vec![id];
+        for rid in self.reserved.drain() {
+            if let Some(pos) = self.unchoked.iter().position(|&u| u == rid) {
+                self.unchoked.remove(pos);
+                peers.get_mut(&rid).map(Peer::choke);
+                choked.push(rid);
+            }
+        }
+
+        // Give reserved slots to peers we've sent little to, and
+        // unchoke random interested peers with the rest
+        let mut first = self.unchoke_reserved(peers);
+        while self.unchoked.len() < UNCHOKE_SLOTS {
+            match self.unchoke_next(peers) {
+                Some(unchoked) => first = first.or(Some(unchoked)),
+                None => break,
+            }
+        }
+        self.interested.extend(choked);
+        first.map(|unchoked| SwapRes {
+            choked: id,
+            unchoked,
+        })
+    }
+
+    fn unchoke_reserved<T: cio::CIO>(&mut self, peers: &mut UHashMap<Peer<T>>) -> Option<usize> {
+        let slots = (UNCHOKE_SLOTS as f32 * CONFIG.peer.reserved_unchokes).round() as usize;
+        let mut first = None;
+        for _ in 0..slots.min(UNCHOKE_SLOTS - 1) {
+            if self.unchoked.len() == UNCHOKE_SLOTS {
+                break;
+            }
+            let candidate = random_sample(
+                self.interested
+                    .iter()
+                    .filter(|id| peers.get(id).is_some_and(|p| p.sent() < POORLY_SERVED)),
+            )
+            .cloned();
+            let id = match candidate {
+                Some(id) => id,
+                None => break,
+            };
+            self.interested.remove(&id);
+            if let Some(peer) = peers.get_mut(&id) {
+                self.add_peer(peer);
+                self.reserved.insert(id);
+                first = first.or(Some(id));
+            }
+        }
+        first
+    }
+}
+
+fn stats<T: cio::CIO>(peer: &Peer<T>, (ul, dl): (u32, u32)) -> Stats {
+    let (ul_rate, dl_rate) = peer.get_tx_rates();
+    Stats {
+        id: peer.id(),
+        ul,
+        dl,
+        sent: peer.sent(),
+        ul_rate,
+        dl_rate,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Choker, SwapRes};
+    use crate::torrent::{Bitfield, Peer};
+    use crate::util::UHashMap;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_add_peers() {
+        let mut c = Choker::new();
+        for i in 0..6 {
+            let mut p = Peer::test(i, 0, 0, 0, Bitfield::new(1));
+            // Since the socket is a dummy
+            c.add_peer(&mut p);
+        }
+        assert_eq!(c.unchoked.len(), 5);
+        assert_eq!(c.interested.len(), 1);
+    }
+
+    #[test]
+    fn test_remove_peers() {
+        let mut c = Choker::new();
+        let mut v = Vec::new();
+        let mut h = UHashMap::default();
+        for i in 0..6 {
+            let mut p = Peer::test_from_stats(i, 0, 0);
+            c.add_peer(&mut p);
+            v.push(p);
+            // Semi copy
+            let pc = Peer::test_from_stats(i, 0, 0);
+            h.insert(i, pc);
+        }
+        assert_eq!(c.unchoked.contains(&v[0].id()), true);
+        assert_eq!(
+            c.remove_peer(&mut v[0], &mut h),
+            Some(SwapRes {
+                choked: v[0].id(),
+                unchoked: 5,
+            })
+        );
+        assert_eq!(c.unchoked.contains(&v[0].id()), false);
+    }
+
+    #[test]
+    fn test_update_upload() {
+        let mut c = Choker::new();
+        let mut h = UHashMap::default();
+        assert_eq!(c.update_upload(&mut h).is_none(), true);
+        for i in 0..6 {
+            let mut p = Peer::test_from_stats(i, i as u32, 6 - i as u32);
+            c.add_peer(&mut p);
+            h.insert(i, p);
+        }
+        assert_eq!(c.update_upload(&mut h).is_none(), true);
+        c.last_updated = Instant::now() - Duration::from_secs(11);
+        let res = c.update_upload(&mut h).unwrap();
+        assert_eq!(res.choked, 0);
+        assert_eq!(res.unchoked, 5);
+    }
+
+    #[test]
+    fn test_update_download() {
+        let mut c = Choker::new();
+        let mut h = UHashMap::default();
+        assert_eq!(c.update_download(&mut h).is_none(), true);
+        for i in 0..6 {
+            let mut p = Peer::test_from_stats(i, 6 - i as u32, i as u32);
+            c.add_peer(&mut p);
+            h.insert(i, p);
+        }
+        assert_eq!(c.update_download(&mut h).is_none(), true);
+        c.last_updated = Instant::now() - Duration::from_secs(11);
+        let res = c.update_download(&mut h).unwrap();
+        assert_eq!(res.choked, 0);
+        assert_eq!(res.unchoked, 5);
+    }
+
+    #[test]
+    fn test_reserved_slot() {
+        let mut c = Choker::new();
+        let mut h = UHashMap::default();
+        // Peers 0 to 5 have been sent plenty, peer 6 nothing
+        for i in 0..7 {
+            let ul = if i == 6 { 0 } else { 100 + i as u32 };
+            let mut p = Peer::test_from_stats(i, ul, 0);
+            c.add_peer(&mut p);
+            h.insert(i, p);
+        }
+        c.last_updated = Instant::now() - Duration::from_secs(11);
+        let res = c.update_upload(&mut h).unwrap();
+        assert_eq!(res.choked, 0);
+        assert_eq!(res.unchoked, 6);
+        assert!(c.reserved.contains(&6));
+
+        // The reserved slot isn't kept, and nobody else qualifies for it
+        c.last_updated = Instant::now() - Duration::from_secs(11);
+        c.update_upload(&mut h).unwrap();
+        assert!(c.reserved.is_empty());
+        assert!(!c.unchoked.contains(&6));
+        assert_eq!(c.unchoked.len(), 5);
+        assert_eq!(c.interested.len(), 2);
+    }
+
+    #[test]
+    fn test_choke_all() {
+        let mut c = Choker::new();
+        let mut h = UHashMap::default();
+        for i in 0..7 {
+            let mut p = Peer::test_from_stats(i, 0, 0);
+            c.add_peer(&mut p);
+            h.insert(i, p);
+        }
+        c.choke_all(&mut h);
+        assert!(c.unchoked.is_empty());
+        assert_eq!(c.interested.len(), 7);
+        assert!(h.values().all(|p| p.choking()));
+
+        let p = Peer::test_from_stats(7, 0, 0);
+        c.add_choked(&p);
+        h.insert(7, p);
+        c.unchoke_all(&mut h);
+        assert_eq!(c.unchoked.len(), 5);
+        assert_eq!(c.interested.len(), 3);
+        assert_eq!(h.values().filter(|p| !p.choking()).count(), 5);
+    }
+}
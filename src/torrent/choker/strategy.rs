@@ -0,0 +1,143 @@
+use crate::config::ChokeStrategy;
+use crate::util::random_sample;
+
+/// What a strategy knows about a peer when deciding on it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    pub id: usize,
+    /// Blocks sent to and received from the peer since the last
+    /// rotation, only counted while it's unchoked
+    pub ul: u32,
+    pub dl: u32,
+    /// Bytes of piece data sent to the peer so far
+    pub sent: u64,
+    /// Smoothed upload and download rates, in bytes per second
+    pub ul_rate: u64,
+    pub dl_rate: u64,
+}
+
+/// Transfer direction rotations are judged by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+impl Stats {
+    fn moved(&self, dir: Direction) -> u32 {
+        match dir {
+            Direction::Upload => self.ul,
+            Direction::Download => self.dl,
+        }
+    }
+
+    fn rate(&self, dir: Direction) -> u64 {
+        match dir {
+            Direction::Upload => self.ul_rate,
+            Direction::Download => self.dl_rate,
+        }
+    }
+}
+
+/// Decides which peers get a torrent's unchoke slots. Slots reserved
+/// for poorly served peers are handed out by the choker itself, and
+/// reserved peers are never offered to choke.
+pub trait Strategy {
+    /// Picks the unchoked peer to choke every rotation.
+    fn choke(&mut self, unchoked: &[Stats], dir: Direction) -> Option<usize>;
+
+    /// Picks the interested peer to unchoke into a free slot.
+    fn unchoke(&mut self, interested: &[Stats], dir: Direction) -> Option<usize>;
+}
+
+pub fn from_config(strategy: ChokeStrategy) -> Box<dyn Strategy> {
+    match strategy {
+        ChokeStrategy::TitForTat => Box::new(TitForTat),
+        ChokeStrategy::FastestFirst => Box::new(FastestFirst),
+        ChokeStrategy::FairShare => Box::new(FairShare),
+    }
+}
+
+/// Chokes the peer which moved the least data since the last rotation,
+/// and unchokes a random peer in its place.
+pub struct TitForTat;
+
+impl Strategy for TitForTat {
+    fn choke(&mut self, unchoked: &[Stats], dir: Direction) -> Option<usize> {
+        slowest(unchoked, dir)
+    }
+
+    fn unchoke(&mut self, interested: &[Stats], _: Direction) -> Option<usize> {
+        random_sample(interested.iter()).map(|s| s.id)
+    }
+}
+
+/// Chokes like tit-for-tat, but unchokes the peer with the highest
+/// rate rather than a random one, maximizing the data moved.
+pub struct FastestFirst;
+
+impl Strategy for FastestFirst {
+    fn choke(&mut self, unchoked: &[Stats], dir: Direction) -> Option<usize> {
+        slowest(unchoked, dir)
+    }
+
+    fn unchoke(&mut self, interested: &[Stats], dir: Direction) -> Option<usize> {
+        interested.iter().max_by_key(|s| s.rate(dir)).map(|s| s.id)
+    }
+}
+
+/// Chokes the peer which has been sent the most in total, and unchokes
+/// the one sent the least, spreading the upload evenly.
+pub struct FairShare;
+
+impl Strategy for FairShare {
+    fn choke(&mut self, unchoked: &[Stats], _: Direction) -> Option<usize> {
+        unchoked.iter().max_by_key(|s| s.sent).map(|s| s.id)
+    }
+
+    fn unchoke(&mut self, interested: &[Stats], _: Direction) -> Option<usize> {
+        interested.iter().min_by_key(|s| s.sent).map(|s| s.id)
+    }
+}
+
+fn slowest(unchoked: &[Stats], dir: Direction) -> Option<usize> {
+    unchoked.iter().min_by_key(|s| s.moved(dir)).map(|s| s.id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(id: usize, (ul, dl): (u32, u32), sent: u64, (ul_rate, dl_rate): (u64, u64)) -> Stats {
+        Stats {
+            id,
+            ul,
+            dl,
+            sent,
+            ul_rate,
+            dl_rate,
+        }
+    }
+
+    #[test]
+    fn test_strategies() {
+        let peers = [
+            stats(0, (5, 1), 300, (10, 40)),
+            stats(1, (2, 4), 100, (50, 5)),
+            stats(2, (2, 3), 200, (20, 15)),
+        ];
+        let dir = Direction::Upload;
+
+        assert_eq!(TitForTat.choke(&peers, dir), Some(1));
+        assert!(TitForTat.unchoke(&peers, dir).is_some());
+        assert_eq!(TitForTat.unchoke(&[], dir), None);
+
+        assert_eq!(FastestFirst.choke(&peers, dir), Some(1));
+        assert_eq!(FastestFirst.unchoke(&peers, dir), Some(1));
+        assert_eq!(FastestFirst.choke(&peers, Direction::Download), Some(0));
+        assert_eq!(FastestFirst.unchoke(&peers, Direction::Download), Some(0));
+
+        assert_eq!(FairShare.choke(&peers, dir), Some(0));
+        assert_eq!(FairShare.unchoke(&peers, dir), Some(1));
+    }
+}
@@ -0,0 +1,99 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use byteorder::{BigEndian, ByteOrder};
+
+/// The target isn't connected to the relay
+pub const NO_SUCH_PEER: u32 = 1;
+/// The target doesn't support the holepunch extension
+pub const NO_SUPPORT: u32 = 3;
+/// The target is the initiator itself
+pub const NO_SELF: u32 = 4;
+
+/// A holepunch extension message (BEP 55), through which a peer
+/// connected to two others introduces them, so they can connect
+/// to each other even when only one of them accepts connections.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Holepunch {
+    /// Asks the relay to introduce us to the peer at the address
+    Rendezvous(SocketAddr),
+    /// Tells us to connect to the peer at the address
+    Connect(SocketAddr),
+    /// A rendezvous with the peer at the address failed
+    Error(SocketAddr, u32),
+}
+
+impl Holepunch {
+    pub fn decode(payload: &[u8]) -> Option<Holepunch> {
+        let (&kind, rest) = payload.split_first()?;
+        let (&family, rest) = rest.split_first()?;
+        let ip = match family {
+            0 if rest.len() >= 6 => IpAddr::V4(Ipv4Addr::new(rest[0], rest[1], rest[2], rest[3])),
+            1 if rest.len() >= 18 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&rest[..16]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return None,
+        };
+        let rest = &rest[if ip.is_ipv4() { 4 } else { 16 }..];
+        let addr = SocketAddr::new(ip, BigEndian::read_u16(rest));
+        match kind {
+            0 => Some(Holepunch::Rendezvous(addr)),
+            1 => Some(Holepunch::Connect(addr)),
+            2 if rest.len() >= 6 => Some(Holepunch::Error(addr, BigEndian::read_u32(&rest[2..]))),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let (kind, addr, err) = match *self {
+            Holepunch::Rendezvous(addr) => (0, addr, 0),
+            Holepunch::Connect(addr) => (1, addr, 0),
+            Holepunch::Error(addr, err) => (2, addr, err),
+        };
+        let mut buf = vec![kind];
+        // IPv4 peers on a dual stack socket show up as mapped addresses
+        match addr.ip().to_canonical() {
+            IpAddr::V4(ip) => {
+                buf.push(0);
+                buf.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                buf.push(1);
+                buf.extend_from_slice(&ip.octets());
+            }
+        }
+        buf.extend_from_slice(&addr.port().to_be_bytes());
+        buf.extend_from_slice(&err.to_be_bytes());
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let v4: SocketAddr = "1.2.3.4:6881".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:51413".parse().unwrap();
+        for msg in &[
+            Holepunch::Rendezvous(v4),
+            Holepunch::Connect(v6),
+            Holepunch::Error(v6, NO_SUPPORT),
+        ] {
+            assert_eq!(Holepunch::decode(&msg.encode()), Some(*msg));
+        }
+        let enc = Holepunch::Connect(v6).encode();
+        assert_eq!(enc.len(), 1 + 1 + 16 + 2 + 4);
+        assert_eq!(enc[1], 1);
+
+        let mapped: SocketAddr = "[::ffff:1.2.3.4]:6881".parse().unwrap();
+        assert_eq!(
+            Holepunch::Rendezvous(mapped).encode(),
+            Holepunch::Rendezvous(v4).encode()
+        );
+        assert_eq!(Holepunch::decode(&enc[..10]), None);
+        assert_eq!(Holepunch::decode(&[3, 0, 1, 2, 3, 4, 0, 1]), None);
+    }
+}
@@ -1,13 +1,15 @@
 use std::collections::BTreeMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::{cmp, fmt, mem};
 
 use rand::{self, Rng};
 use url::Url;
 
+use super::merkle;
 use crate::bencode::BEncode;
 use crate::disk;
+use crate::protocol::HashRequest;
 use crate::util::{hash_to_id, id_to_hash, sha1_hash};
 
 #[derive(Clone)]
@@ -26,6 +28,15 @@ pub struct Info {
     /// Maps piece idx -> file idx + file offset
     pub piece_idx: Vec<(usize, u64)>,
     pub url_list: Vec<Vec<Arc<Url>>>,
+    /// BEP 19 webseed URLs
+    pub web_seeds: Vec<Arc<Url>>,
+    /// BEP 17 httpseed URLs
+    pub http_seeds: Vec<Arc<Url>>,
+    /// 2 for BEP 52 torrents, whose hashes are the SHA-256 merkle roots of each piece.
+    /// Hybrid torrents are treated as v1.
+    pub meta_version: u8,
+    /// SHA-256 infohash of v2 and hybrid torrents
+    pub hash_v2: Option<[u8; 32]>,
 }
 
 impl fmt::Debug for Info {
@@ -54,6 +65,8 @@ impl fmt::Debug for Info {
 pub struct File {
     pub path: PathBuf,
     pub length: u64,
+    /// Merkle root of the file's blocks in v2 torrents
+    pub pieces_root: Option<[u8; 32]>,
 }
 
 impl File {
@@ -68,6 +81,7 @@ impl File {
                 let f = File {
                     path: PathBuf::from(v.into_string().ok_or("Path must be a valid string.")?),
                     length: l.into_int().ok_or("File length must be a valid int")? as u64,
+                    pieces_root: None,
                 };
                 Ok(f)
             }
@@ -82,6 +96,7 @@ impl File {
                 let f = File {
                     path: p,
                     length: l.into_int().ok_or("File length must be a valid int")? as u64,
+                    pieces_root: None,
                 };
                 Ok(f)
             }
@@ -126,6 +141,12 @@ impl Info {
             .collect();
         rand::thread_rng().shuffle(&mut url_list[..]);
 
+        let web_seeds = url
+            .query_pairs()
+            .filter(|(k, _)| k == "ws")
+            .filter_map(|(_, ref v)| parse_web_seed(v))
+            .collect();
+
         let name = url
             .query_pairs()
             .find(|&(ref k, _)| k == "dn")
@@ -145,6 +166,10 @@ impl Info {
             be_name: None,
             piece_idx: vec![],
             url_list: vec![url_list],
+            web_seeds,
+            http_seeds: vec![],
+            meta_version: 1,
+            hash_v2: None,
         })
     }
 
@@ -162,6 +187,23 @@ impl Info {
             )
         });
         torrent.insert(b"info".to_vec(), info);
+        if self.is_v2() {
+            let pl = u64::from(self.piece_len);
+            let mut layers = BTreeMap::new();
+            let mut piece = 0;
+            for f in &self.files {
+                let pieces = f.length.div_ceil(pl) as usize;
+                match f.pieces_root {
+                    Some(root) if f.length > pl => {
+                        let layer = self.hashes[piece..piece + pieces].concat();
+                        layers.insert(root.to_vec(), BEncode::String(layer));
+                    }
+                    _ => {}
+                }
+                piece += pieces;
+            }
+            torrent.insert(b"piece layers".to_vec(), BEncode::Dict(layers));
+        }
         BEncode::Dict(torrent)
     }
 
@@ -177,6 +219,11 @@ impl Info {
             b"piece length".to_vec(),
             BEncode::Int(i64::from(self.piece_len)),
         );
+        if self.is_v2() {
+            info.insert(b"meta version".to_vec(), BEncode::Int(2));
+            info.insert(b"file tree".to_vec(), self.file_tree());
+            return BEncode::Dict(info);
+        }
         let mut pieces = Vec::with_capacity(self.hashes.len() * 20);
         for h in &self.hashes {
             pieces.extend_from_slice(h);
@@ -213,6 +260,27 @@ impl Info {
         BEncode::Dict(info)
     }
 
+    fn file_tree(&self) -> BEncode {
+        // Undo the name prefix added to multi file paths by parse_file_tree
+        let single = self.files.len() == 1 && self.files[0].path.components().count() == 1;
+        let mut tree = BTreeMap::new();
+        for f in &self.files {
+            let mut props = BTreeMap::new();
+            props.insert(b"length".to_vec(), BEncode::Int(f.length as i64));
+            if let Some(root) = f.pieces_root {
+                props.insert(b"pieces root".to_vec(), BEncode::String(root.to_vec()));
+            }
+            let parts: Vec<_> = f
+                .path
+                .components()
+                .skip(if single { 0 } else { 1 })
+                .map(|c| c.as_os_str().to_string_lossy().into_owned().into_bytes())
+                .collect();
+            insert_file_tree(&mut tree, &parts, props);
+        }
+        BEncode::Dict(tree)
+    }
+
     pub fn from_bencode(data: BEncode) -> Result<Info, &'static str> {
         data.into_dict()
             .and_then(|mut d| {
@@ -224,7 +292,29 @@ impl Info {
             .and_then(|(mut d, mut i)| {
                 let mut info_bytes = Vec::new();
                 BEncode::Dict(i.clone()).encode(&mut info_bytes).unwrap();
-                let hash = sha1_hash(&info_bytes);
+
+                let meta_version = match i.remove(b"meta version".as_ref()) {
+                    Some(v) => v.into_int().ok_or("meta version must be an integer")?,
+                    None => 1,
+                };
+                if !(1..=2).contains(&meta_version) {
+                    return Err("Unsupported meta version");
+                }
+                // Hybrid torrents also carry v1 piece hashes, which are used instead
+                let v2 = meta_version == 2 && !i.contains_key(b"pieces".as_ref());
+                let hash_v2 = if meta_version == 2 {
+                    Some(merkle::sha256(&info_bytes))
+                } else {
+                    None
+                };
+                let hash = match hash_v2 {
+                    Some(h) if v2 => {
+                        let mut hash = [0u8; 20];
+                        hash.copy_from_slice(&h[..20]);
+                        hash
+                    }
+                    _ => sha1_hash(&info_bytes),
+                };
 
                 let announce = d
                     .remove(b"announce".as_ref())
@@ -238,24 +328,9 @@ impl Info {
                     .remove(b"piece length".as_ref())
                     .and_then(|i| i.into_int())
                     .ok_or("Info must specify piece length")? as u64;
-                let hashes = i
-                    .remove(b"pieces".as_ref())
-                    .and_then(|p| p.into_bytes())
-                    .and_then(|p| {
-                        let mut v = Vec::new();
-                        let mut s = &p[..];
-                        while s.len() >= 20 {
-                            let mut next = vec![0u8; 20];
-                            next.clone_from_slice(&s[..20]);
-                            v.push(next);
-                            s = &s[20..];
-                        }
-                        if !s.is_empty() {
-                            return None;
-                        }
-                        Some(v)
-                    })
-                    .ok_or("Info must provide valid hashes")?;
+                if v2 && (pl < merkle::BLOCK_LEN as u64 || !pl.is_power_of_two()) {
+                    return Err("v2 piece length must be a power of two of at least 16 KiB");
+                }
 
                 let private = if let Some(v) = i.remove(b"private".as_ref()) {
                     v.into_int()
@@ -279,7 +354,35 @@ impl Info {
                     None
                 };
 
-                let files = parse_bencode_files(i)?;
+                let (files, hashes) = if v2 {
+                    let files = parse_file_tree(&mut i)?;
+                    let layers = d
+                        .remove(b"piece layers".as_ref())
+                        .and_then(BEncode::into_dict)
+                        .unwrap_or_else(BTreeMap::new);
+                    let hashes = parse_piece_layers(&layers, pl, &files)?;
+                    (files, hashes)
+                } else {
+                    let hashes = i
+                        .remove(b"pieces".as_ref())
+                        .and_then(|p| p.into_bytes())
+                        .and_then(|p| {
+                            let mut v = Vec::new();
+                            let mut s = &p[..];
+                            while s.len() >= 20 {
+                                let mut next = vec![0u8; 20];
+                                next.clone_from_slice(&s[..20]);
+                                v.push(next);
+                                s = &s[20..];
+                            }
+                            if !s.is_empty() {
+                                return None;
+                            }
+                            Some(v)
+                        })
+                        .ok_or("Info must provide valid hashes")?;
+                    (parse_bencode_files(i)?, hashes)
+                };
                 let name = if files.is_empty() {
                     files[0]
                         .path
@@ -301,7 +404,11 @@ impl Info {
                 };
 
                 let total_len = files.iter().map(|f| f.length).sum();
-                let piece_idx = Info::generate_piece_idx(hashes.len(), pl, &files);
+                let piece_idx = if v2 {
+                    Info::generate_piece_idx_v2(pl, &files)
+                } else {
+                    Info::generate_piece_idx(hashes.len(), pl, &files)
+                };
 
                 let url_list: Vec<_> = d
                     .remove(b"announce-list".as_ref())
@@ -321,6 +428,28 @@ impl Info {
                     })
                     .collect();
 
+                let web_seeds = match d.remove(b"url-list".as_ref()) {
+                    Some(BEncode::List(l)) => l
+                        .into_iter()
+                        .filter_map(BEncode::into_string)
+                        .filter_map(|s| parse_web_seed(&s))
+                        .collect(),
+                    Some(b) => b
+                        .into_string()
+                        .and_then(|s| parse_web_seed(&s))
+                        .into_iter()
+                        .collect(),
+                    None => vec![],
+                };
+                let http_seeds = d
+                    .remove(b"httpseeds".as_ref())
+                    .and_then(BEncode::into_list)
+                    .unwrap_or_else(Vec::new)
+                    .into_iter()
+                    .filter_map(BEncode::into_string)
+                    .filter_map(|s| parse_web_seed(&s))
+                    .collect();
+
                 Ok(Info {
                     name,
                     comment,
@@ -335,6 +464,10 @@ impl Info {
                     be_name,
                     piece_idx,
                     url_list,
+                    web_seeds,
+                    http_seeds,
+                    meta_version: if v2 { 2 } else { 1 },
+                    hash_v2,
                 })
             })
     }
@@ -354,6 +487,20 @@ impl Info {
         piece_idx
     }
 
+    /// Pieces of v2 torrents are aligned to file boundaries, so every file
+    /// starts a new piece and only its last piece may be short.
+    pub fn generate_piece_idx_v2(pl: u64, files: &[File]) -> Vec<(usize, u64)> {
+        let mut piece_idx = Vec::new();
+        for (i, f) in files.iter().enumerate() {
+            let mut offset = 0;
+            while offset < f.length {
+                piece_idx.push((i, offset));
+                offset += pl;
+            }
+        }
+        piece_idx
+    }
+
     #[cfg(test)]
     pub fn with_pieces(pieces: usize) -> Info {
         Info {
@@ -369,6 +516,7 @@ impl Info {
                 File {
                     path: PathBuf::new(),
                     length: 16_384 * pieces as u64,
+                    pieces_root: None,
                 };
                 1
             ],
@@ -376,6 +524,10 @@ impl Info {
             be_name: None,
             piece_idx: vec![],
             url_list: vec![],
+            web_seeds: vec![],
+            http_seeds: vec![],
+            meta_version: 1,
+            hash_v2: None,
         }
     }
 
@@ -395,11 +547,15 @@ impl Info {
             be_name: None,
             piece_idx: vec![],
             url_list: vec![],
+            web_seeds: vec![],
+            http_seeds: vec![],
+            meta_version: 1,
+            hash_v2: None,
         }
     }
 
     pub fn block_len(&self, idx: u32, offset: u32) -> u32 {
-        if idx != self.pieces() - 1 {
+        if idx != self.pieces() - 1 && !self.is_v2() {
             16_384
         } else {
             let last_piece_len = self.piece_len(idx);
@@ -418,7 +574,10 @@ impl Info {
         if !self.complete() {
             return 0;
         }
-        if idx != self.pieces().saturating_sub(1) {
+        if self.is_v2() {
+            let (file, offset) = self.piece_idx[idx as usize];
+            cmp::min(u64::from(self.piece_len), self.files[file].length - offset) as u32
+        } else if idx != self.pieces().saturating_sub(1) {
             self.piece_len
         } else {
             (self.total_len - u64::from(self.piece_len) * (u64::from(self.pieces()) - 1)) as u32
@@ -429,12 +588,95 @@ impl Info {
         self.hashes.len() as u32
     }
 
+    /// Returns the piece holding the byte at offset into a file.
+    pub fn piece_at(&self, file: usize, offset: u64) -> u32 {
+        // Pieces start at increasing file positions, so a position is in
+        // the last piece starting before it
+        self.piece_idx
+            .partition_point(|&loc| loc <= (file, offset))
+            .saturating_sub(1) as u32
+    }
+
+    pub fn is_v2(&self) -> bool {
+        self.meta_version == 2
+    }
+
+    /// Checks the data of a piece against its SHA-1 hash, or for v2
+    /// torrents the merkle root of its blocks.
+    pub fn piece_valid(&self, idx: u32, data: &[u8]) -> bool {
+        let hash = &self.hashes[idx as usize][..];
+        if !self.is_v2() {
+            return sha1_hash(data)[..] == *hash;
+        }
+        let file = &self.files[self.piece_idx[idx as usize].0];
+        // Files larger than a piece have their last piece padded out to a full one,
+        // while smaller files are only padded to a power of two blocks.
+        let width = if file.length > u64::from(self.piece_len) {
+            self.piece_len as usize / merkle::BLOCK_LEN
+        } else {
+            (file.length as usize)
+                .div_ceil(merkle::BLOCK_LEN)
+                .next_power_of_two()
+        };
+        merkle::root(&merkle::block_hashes(data), width, 0)[..] == *hash
+    }
+
+    /// Builds the hashes to send in response to a BEP 52 hash request,
+    /// returning None if it should be rejected. Only piece layers are
+    /// retained, so those are the only layers which can be served.
+    pub fn piece_layer_hashes(&self, req: &HashRequest) -> Option<Vec<u8>> {
+        let pl = u64::from(self.piece_len);
+        let depth = (self.piece_len as usize / merkle::BLOCK_LEN).trailing_zeros();
+        if !self.is_v2()
+            || req.base_layer != depth
+            || req.length < 2
+            || req.length > 512
+            || !req.length.is_power_of_two()
+            || req.index & (req.length - 1) != 0
+        {
+            return None;
+        }
+        let file = self
+            .files
+            .iter()
+            .position(|f| f.length > pl && f.pieces_root == Some(req.pieces_root))?;
+        let first = self.piece_idx.iter().position(|&(f, _)| f == file)?;
+        let pieces = self.files[file].length.div_ceil(pl) as usize;
+        let (index, length) = (req.index as usize, req.length as usize);
+        if index + length > pieces.next_power_of_two() {
+            return None;
+        }
+
+        let base: Vec<_> = self.hashes[first..first + pieces]
+            .iter()
+            .map(|h| to_hash(h))
+            .collect();
+        let layers = merkle::layers(&base, pieces.next_power_of_two(), depth);
+        let mut resp = layers[0][index..index + length].concat();
+        // Uncles start from the layer where the requested hashes join into one
+        let covered = req.length.trailing_zeros() as usize;
+        let uncles = (req.proof_layers as usize).saturating_sub(covered);
+        let mut pos = index / length;
+        for layer in layers[covered..layers.len() - 1].iter().take(uncles) {
+            resp.extend_from_slice(&layer[pos ^ 1]);
+            pos /= 2;
+        }
+        Some(resp)
+    }
+
     /// Calculates the file offsets for a given block at index/begin
+    #[cfg(test)]
     pub fn block_disk_locs(info: &Arc<Info>, index: u32, begin: u32) -> LocIter {
         let len = info.block_len(index, begin);
         LocIter::new(info.clone(), None, index, begin, len)
     }
 
+    /// Calculates the file offsets of a peer request, which
+    /// needn't be a single block
+    pub fn request_disk_locs(info: &Arc<Info>, index: u32, begin: u32, len: u32) -> LocIter {
+        LocIter::new(info.clone(), None, index, begin, len)
+    }
+
     pub fn block_disk_locs_pri(
         info: &Arc<Info>,
         priorities: &Arc<Vec<u8>>,
@@ -556,6 +798,141 @@ impl Iterator for LocIter {
     }
 }
 
+fn parse_web_seed(s: &str) -> Option<Arc<Url>> {
+    Url::parse(s)
+        .ok()
+        .filter(|u| u.scheme() == "http" || u.scheme() == "https")
+        .map(Arc::new)
+}
+
+fn to_hash(h: &[u8]) -> merkle::Hash {
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(h);
+    hash
+}
+
+fn insert_file_tree(
+    tree: &mut BTreeMap<Vec<u8>, BEncode>,
+    parts: &[Vec<u8>],
+    props: BTreeMap<Vec<u8>, BEncode>,
+) {
+    let node = tree
+        .entry(parts[0].clone())
+        .or_insert_with(|| BEncode::Dict(BTreeMap::new()));
+    if let BEncode::Dict(ref mut d) = *node {
+        if parts.len() == 1 {
+            d.insert(vec![], BEncode::Dict(props));
+        } else {
+            insert_file_tree(d, &parts[1..], props);
+        }
+    }
+}
+
+/// Parses the file tree of a v2 info dict. As with v1, paths of multi file
+/// torrents are prefixed with the torrent name.
+fn parse_file_tree(data: &mut BTreeMap<Vec<u8>, BEncode>) -> Result<Vec<File>, &'static str> {
+    let name = data
+        .get(b"name".as_ref())
+        .and_then(BEncode::as_str)
+        .map(str::to_owned)
+        .ok_or("v2 torrents must have a name field")?;
+    let tree = data
+        .remove(b"file tree".as_ref())
+        .and_then(BEncode::into_dict)
+        .ok_or("v2 torrents must have a file tree")?;
+    let mut files = Vec::new();
+    walk_file_tree(tree, &mut PathBuf::new(), &mut files)?;
+    if files.is_empty() {
+        return Err("File tree must contain at least one file");
+    }
+    if files.len() > 1 || files[0].path.components().count() > 1 {
+        for f in &mut files {
+            f.path = Path::new(&name).join(&f.path);
+        }
+    }
+    Ok(files)
+}
+
+fn walk_file_tree(
+    tree: BTreeMap<Vec<u8>, BEncode>,
+    path: &mut PathBuf,
+    files: &mut Vec<File>,
+) -> Result<(), &'static str> {
+    for (k, v) in tree {
+        let mut d = v
+            .into_dict()
+            .ok_or("File tree entries must be dictionaries")?;
+        if k.is_empty() {
+            if path.as_os_str().is_empty() {
+                return Err("File tree root can't be a file");
+            }
+            let length = d
+                .remove(b"length".as_ref())
+                .and_then(BEncode::into_int)
+                .filter(|l| *l >= 0)
+                .ok_or("File length must be a valid int")? as u64;
+            let pieces_root = match d.remove(b"pieces root".as_ref()) {
+                Some(r) => match r.into_bytes() {
+                    Some(ref r) if r.len() == 32 => Some(to_hash(r)),
+                    _ => return Err("pieces root must be a 32 byte string"),
+                },
+                None if length > 0 => return Err("Non empty files must have a pieces root"),
+                None => None,
+            };
+            files.push(File {
+                path: path.clone(),
+                length,
+                pieces_root,
+            });
+        } else {
+            let part = String::from_utf8(k).map_err(|_| "Only UTF8 paths are accepted")?;
+            if part == "." || part == ".." || part.contains('/') {
+                return Err("Invalid file path part");
+            }
+            path.push(part);
+            walk_file_tree(d, path, files)?;
+            path.pop();
+        }
+    }
+    Ok(())
+}
+
+/// Collects the piece hashes of a v2 torrent from its piece layers, checking
+/// each layer against the file's pieces root. Files no larger than a piece
+/// have no layer, their single piece's hash is the root itself.
+fn parse_piece_layers(
+    layers: &BTreeMap<Vec<u8>, BEncode>,
+    pl: u64,
+    files: &[File],
+) -> Result<Vec<Vec<u8>>, &'static str> {
+    let depth = (pl / merkle::BLOCK_LEN as u64).trailing_zeros();
+    let mut hashes = Vec::new();
+    for f in files {
+        let root = match f.pieces_root {
+            Some(r) => r,
+            None => continue,
+        };
+        if f.length <= pl {
+            hashes.push(root.to_vec());
+            continue;
+        }
+        let layer = layers
+            .get(&root[..])
+            .and_then(BEncode::as_bytes)
+            .ok_or("Missing piece layer for file")?;
+        let pieces = f.length.div_ceil(pl) as usize;
+        if layer.len() != pieces * 32 {
+            return Err("Piece layer has an invalid length");
+        }
+        let base: Vec<_> = layer.chunks(32).map(to_hash).collect();
+        if merkle::root(&base, pieces.next_power_of_two(), depth) != root {
+            return Err("Piece layer does not match pieces root");
+        }
+        hashes.extend(layer.chunks(32).map(|h| h.to_vec()));
+    }
+    Ok(hashes)
+}
+
 fn parse_bencode_files(mut data: BTreeMap<Vec<u8>, BEncode>) -> Result<Vec<File>, &'static str> {
     match data.remove(b"files".as_ref()).and_then(|l| l.into_list()) {
         Some(fs) => {
@@ -600,6 +977,106 @@ mod tests {
         assert_eq!(info.block_len(pieces, 16_384), (end % 16_384) as u32);
     }
 
+    fn v2_torrent(a: &[u8], b: &[u8], layer: Option<Vec<u8>>) -> (BEncode, Vec<u8>) {
+        fn file(data: &[u8], width: usize) -> BEncode {
+            let mut props = BTreeMap::new();
+            props.insert(b"length".to_vec(), BEncode::Int(data.len() as i64));
+            let root = merkle::root(&merkle::block_hashes(data), width, 0);
+            props.insert(b"pieces root".to_vec(), BEncode::String(root.to_vec()));
+            let mut node = BTreeMap::new();
+            node.insert(vec![], BEncode::Dict(props));
+            BEncode::Dict(node)
+        }
+        let mut tree = BTreeMap::new();
+        tree.insert(b"a".to_vec(), file(a, 4));
+        tree.insert(b"b".to_vec(), file(b, 1));
+        let mut info = BTreeMap::new();
+        info.insert(b"name".to_vec(), BEncode::from_str("t"));
+        info.insert(b"piece length".to_vec(), BEncode::Int(32_768));
+        info.insert(b"meta version".to_vec(), BEncode::Int(2));
+        info.insert(b"file tree".to_vec(), BEncode::Dict(tree));
+        let info = BEncode::Dict(info);
+        let info_bytes = info.encode_to_buf();
+
+        let root = merkle::root(&merkle::block_hashes(a), 4, 0);
+        let layer = layer.unwrap_or_else(|| {
+            let mut layer = merkle::root(&merkle::block_hashes(&a[..32_768]), 2, 0).to_vec();
+            layer.extend_from_slice(&merkle::root(&merkle::block_hashes(&a[32_768..]), 2, 0));
+            layer
+        });
+        let mut layers = BTreeMap::new();
+        layers.insert(root.to_vec(), BEncode::String(layer));
+        let mut torrent = BTreeMap::new();
+        torrent.insert(b"info".to_vec(), info);
+        torrent.insert(b"piece layers".to_vec(), BEncode::Dict(layers));
+        (BEncode::Dict(torrent), info_bytes)
+    }
+
+    #[test]
+    fn parse_v2() {
+        let a: Vec<u8> = (0..40_000u32).map(|i| i as u8).collect();
+        let b = vec![3u8; 100];
+        let (torrent, info_bytes) = v2_torrent(&a, &b, None);
+        let info = Info::from_bencode(torrent).unwrap();
+        assert!(info.is_v2());
+        assert_eq!(info.hash[..], merkle::sha256(&info_bytes)[..20]);
+        assert_eq!(info.hash_v2, Some(merkle::sha256(&info_bytes)));
+        assert_eq!(info.files[0].path, PathBuf::from("t/a"));
+        assert_eq!(info.pieces(), 3);
+        assert_eq!(info.piece_idx, vec![(0, 0), (0, 32_768), (1, 0)]);
+        assert_eq!(
+            (info.piece_len(0), info.piece_len(1), info.piece_len(2)),
+            (32_768, 7_232, 100)
+        );
+        assert_eq!(info.block_len(0, 16_384), 16_384);
+        assert_eq!(info.block_len(1, 0), 7_232);
+        assert!(info.piece_valid(0, &a[..32_768]));
+        assert!(info.piece_valid(1, &a[32_768..]));
+        assert!(info.piece_valid(2, &b));
+        assert!(!info.piece_valid(2, &a[..100]));
+        // The info dict must round trip so it can be served to magnet peers
+        assert_eq!(info.to_bencode().encode_to_buf(), info_bytes);
+
+        let mut req = HashRequest {
+            pieces_root: info.files[0].pieces_root.unwrap(),
+            base_layer: 1,
+            index: 0,
+            length: 2,
+            proof_layers: 0,
+        };
+        assert_eq!(
+            info.piece_layer_hashes(&req).unwrap(),
+            info.hashes.concat()[..64]
+        );
+        req.base_layer = 0;
+        assert!(info.piece_layer_hashes(&req).is_none());
+
+        let (torrent, _) = v2_torrent(&a, &b, Some(vec![0; 64]));
+        assert!(Info::from_bencode(torrent).is_err());
+    }
+
+    #[test]
+    fn parse_hybrid() {
+        let a: Vec<u8> = (0..40_000u32).map(|i| i as u8).collect();
+        let (torrent, _) = v2_torrent(&a, &[3u8; 100], None);
+        let mut torrent = torrent.into_dict().unwrap();
+        let mut info = torrent
+            .remove(b"info".as_ref())
+            .unwrap()
+            .into_dict()
+            .unwrap();
+        info.insert(b"length".to_vec(), BEncode::Int(40_100));
+        info.insert(b"pieces".to_vec(), BEncode::String(vec![0; 40]));
+        let info_bytes = BEncode::Dict(info.clone()).encode_to_buf();
+        torrent.insert(b"info".to_vec(), BEncode::Dict(info));
+
+        // Hybrids are downloaded as v1, but still have a v2 infohash
+        let info = Info::from_bencode(BEncode::Dict(torrent)).unwrap();
+        assert!(!info.is_v2());
+        assert_eq!(info.hash, sha1_hash(&info_bytes));
+        assert_eq!(info.hash_v2, Some(merkle::sha256(&info_bytes)));
+    }
+
     #[test]
     fn loc_iter_bounds() {
         let mut info = Info::with_pieces(4);
@@ -607,10 +1084,12 @@ mod tests {
         info.files.push(File {
             path: PathBuf::from(""),
             length: 40000,
+            pieces_root: None,
         });
         info.files.push(File {
             path: PathBuf::from(""),
             length: 10000,
+            pieces_root: None,
         });
         info.total_len = 50000;
         info.piece_idx =
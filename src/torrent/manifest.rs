@@ -0,0 +1,97 @@
+use std::borrow::Cow;
+
+use crate::rpc::proto::message::ManifestFormat;
+
+/// A file of a torrent, as listed by GET_FILE_MANIFEST.
+#[derive(Clone, Debug, Serialize)]
+pub struct Entry {
+    pub path: String,
+    pub size: u64,
+    pub progress: f32,
+    pub priority: u8,
+    /// Pieces holding the start and end of the file, None if it's empty
+    pub first_piece: Option<u32>,
+    pub last_piece: Option<u32>,
+    /// Hex encoded SHA-256 digest, if computed
+    pub sha256: Option<String>,
+}
+
+const CSV_HEADER: &str = "path,size,progress,priority,first_piece,last_piece,sha256\r\n";
+
+pub fn encode(files: &[Entry], format: ManifestFormat) -> Vec<u8> {
+    match format {
+        ManifestFormat::Json => serde_json::to_vec(files).unwrap(),
+        ManifestFormat::Csv => {
+            let piece = |p: Option<u32>| p.map(|p| p.to_string()).unwrap_or_default();
+            let mut csv = CSV_HEADER.to_owned();
+            for f in files {
+                csv.push_str(&format!(
+                    "{},{},{},{},{},{},{}\r\n",
+                    quote(&f.path),
+                    f.size,
+                    f.progress,
+                    f.priority,
+                    piece(f.first_piece),
+                    piece(f.last_piece),
+                    f.sha256.as_deref().unwrap_or_default()
+                ));
+            }
+            csv.into_bytes()
+        }
+    }
+}
+
+/// Quotes a CSV field which contains separators, quotes or line breaks,
+/// as per RFC 4180.
+fn quote(field: &str) -> Cow<'_, str> {
+    if field.contains(&[',', '"', '\r', '\n'][..]) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let files = vec![
+            Entry {
+                path: "a/b.mkv".to_owned(),
+                size: 100,
+                progress: 0.5,
+                priority: 3,
+                first_piece: Some(0),
+                last_piece: Some(2),
+                sha256: None,
+            },
+            Entry {
+                path: "say \"hi\", world".to_owned(),
+                size: 0,
+                progress: 1.,
+                priority: 0,
+                first_piece: None,
+                last_piece: None,
+                sha256: Some(
+                    "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_owned(),
+                ),
+            },
+        ];
+        let csv = String::from_utf8(encode(&files, ManifestFormat::Csv)).unwrap();
+        assert_eq!(
+            csv,
+            CSV_HEADER.to_owned()
+                + "a/b.mkv,100,0.5,3,0,2,\r\n\"say \"\"hi\"\", world\",0,1,0,,,\
+                   e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855\r\n"
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_slice(&encode(&files, ManifestFormat::Json)).unwrap();
+        assert_eq!(json[0]["path"], "a/b.mkv");
+        assert_eq!(json[0]["last_piece"], 2);
+        assert!(json[1]["first_piece"].is_null());
+        assert!(json[0]["sha256"].is_null());
+    }
+}
@@ -0,0 +1,83 @@
+use sha2::{Digest, Sha256};
+
+/// Size of the leaf blocks of a BEP 52 file tree
+pub const BLOCK_LEN: usize = 16_384;
+
+pub type Hash = [u8; 32];
+
+pub fn sha256(data: &[u8]) -> Hash {
+    let mut ctx = Sha256::new();
+    ctx.update(data);
+    ctx.finalize().into()
+}
+
+fn hash_pair(l: &Hash, r: &Hash) -> Hash {
+    let mut ctx = Sha256::new();
+    ctx.update(l);
+    ctx.update(r);
+    ctx.finalize().into()
+}
+
+/// Root of a subtree with 2^depth zeroed leaves, used to pad
+/// layers which don't have a power of two number of hashes.
+pub fn pad_hash(depth: u32) -> Hash {
+    let mut h = [0u8; 32];
+    for _ in 0..depth {
+        h = hash_pair(&h, &h);
+    }
+    h
+}
+
+/// Hashes the 16 KiB blocks of data, the last of which may be short.
+pub fn block_hashes(data: &[u8]) -> Vec<Hash> {
+    data.chunks(BLOCK_LEN).map(sha256).collect()
+}
+
+/// Builds every layer of the tree rooted above base, which is first padded
+/// to width (a power of two) with the hashes of depth deep zero subtrees.
+/// The first layer is the padded base, the last contains only the root.
+pub fn layers(base: &[Hash], width: usize, depth: u32) -> Vec<Vec<Hash>> {
+    let mut layer = base.to_vec();
+    layer.resize(width.max(1), pad_hash(depth));
+    let mut layers = vec![layer];
+    while layers[layers.len() - 1].len() > 1 {
+        let next = layers[layers.len() - 1]
+            .chunks(2)
+            .map(|p| hash_pair(&p[0], &p[1]))
+            .collect();
+        layers.push(next);
+    }
+    layers
+}
+
+pub fn root(base: &[Hash], width: usize, depth: u32) -> Hash {
+    layers(base, width, depth).pop().unwrap()[0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha256() {
+        let h = sha256(b"abc");
+        assert_eq!(
+            crate::util::hash_to_id(&h),
+            "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD"
+        );
+    }
+
+    #[test]
+    fn test_root() {
+        let data = vec![1u8; BLOCK_LEN * 2 + 10];
+        let leaves = block_hashes(&data);
+        assert_eq!(leaves.len(), 3);
+        // The fourth leaf is zeroed
+        let l = hash_pair(&leaves[0], &leaves[1]);
+        let r = hash_pair(&leaves[2], &[0u8; 32]);
+        assert_eq!(root(&leaves, 4, 0), hash_pair(&l, &r));
+        // Padding a higher layer is the same as padding the leaves
+        assert_eq!(root(&[l], 2, 1), root(&leaves[..2], 4, 0));
+        assert_eq!(layers(&leaves, 4, 0).len(), 3);
+    }
+}
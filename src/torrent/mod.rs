@@ -1,20 +1,27 @@
+mod availability;
 pub mod bitfield;
 mod choker;
+mod holepunch;
 pub mod info;
+mod manifest;
+mod merkle;
 pub mod peer;
 mod picker;
+pub mod policy;
+mod swarm;
+mod webseed;
 
 use std::borrow::Cow;
 use std::collections::{BTreeMap, VecDeque};
-use std::fmt;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{atomic, Arc};
 use std::time::{Duration, Instant};
+use std::{cmp, fmt, mem};
 
 use crate::bencode::BEncode;
 use byteorder::{BigEndian, ByteOrder};
 use chrono::{DateTime, Utc};
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, SocketAddr};
 use url::Url;
 
 pub use self::bitfield::Bitfield;
@@ -22,26 +29,65 @@ pub use self::info::{Info, LocIter};
 pub use self::peer::Message;
 pub use self::peer::{Peer, PeerConn};
 pub use self::picker::Block;
+pub use self::swarm::Source;
 
-use self::picker::Picker;
+use self::holepunch::Holepunch;
+use self::peer::{PEX_HOLEPUNCH, PEX_OUTGOING, PEX_SEED};
+use self::picker::{Mode, Picker};
+use self::policy::Policy;
+use self::webseed::WebSeeds;
 use crate::buffers::Buffer;
+use crate::config::{Encryption, Preallocate};
 use crate::control::cio;
+use crate::rpc::proto::message;
 use crate::rpc::resource::{self, Resource, SResourceUpdate};
 use crate::session::torrent::current::Session;
 use crate::throttle::Throttle;
 use crate::tracker::{self, TrackerResponse};
-use crate::util::{FHashSet, UHashMap};
-use crate::{bencode, disk, rpc, util, CONFIG, EXT_PROTO, UT_META_ID, UT_PEX_ID};
+use crate::util::{FHashMap, FHashSet, UHashMap};
+use crate::{
+    bencode, disk, rpc, util, CONFIG, EXT_PROTO, LT_DONTHAVE_ID, LT_TEX_ID, PORT, UT_HOLEPUNCH_ID,
+    UT_META_ID, UT_PEX_ID,
+};
 use crate::{session, stat};
 
 const MAX_PEERS: usize = 50;
+/// Most trackers sent to or accepted from peers through lt_tex
+const MAX_TEX_TRACKERS: usize = 10;
+/// Most peers of each address family accepted from a PEX message
+const MAX_PEX_PEERS: usize = 50;
+/// Most holepunch introductions asked for per PEX message
+const MAX_RENDEZVOUS: usize = 5;
+/// Seconds a relay has to answer a rendezvous with a connect
+const RENDEZVOUS_TIMEOUT: u64 = 60;
+/// Upper bound of peer.max_request
+const MAX_REQUEST_LEN: u32 = 131_072;
+/// Seconds to wait after a failed announce, doubled on each further failure
+const TRACKER_BACKOFF: u64 = 300;
+const MAX_TRACKER_BACKOFF: u64 = 3600 * 4;
+/// Longest wait a tracker can ask for before it's retried
+const MAX_TRACKER_RETRY: u64 = 86_400;
+/// Seconds which must pass between reannounces asked for over RPC
+const REANNOUNCE_COOLDOWN: u64 = 30;
+/// Seconds after an external IP change before torrents announce again,
+/// giving the DHT time to be bootstrapped again
+const IP_CHANGE_DELAY: u64 = 10;
+/// Seconds over which the announces after an IP change are spread
+const IP_CHANGE_SPREAD: u64 = 60;
+/// Seconds a soft paused torrent keeps its place in the swarm
+/// before becoming fully paused
+const SOFT_PAUSE_GRACE: u64 = 300;
+/// Seconds after seeing another seed before super seeding is engaged automatically
+const SEED_SEEN_SECS: u64 = 1800;
+/// Swarms with more completed downloads than this aren't considered young
+const YOUNG_SWARM_DOWNLOADS: u32 = 10;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum TrackerStatus {
     Updating,
     Ok {
-        seeders: u32,
-        leechers: u32,
+        seeders: Option<u32>,
+        leechers: Option<u32>,
         interval: u32,
     },
     Failure(String),
@@ -60,8 +106,32 @@ pub struct Torrent<T: cio::CIO> {
     files: Files,
     priority: u8,
     priorities: Arc<Vec<u8>>,
+    /// Whether the first and last pieces of files are picked first
+    first_last: bool,
+    /// Whether the DHT is used for this torrent, private torrents never use it
+    dht: bool,
+    /// Peers found through the DHT this session
+    dht_peers: u64,
+    /// Super seeding setting, None if automatic
+    super_seed: Option<bool>,
+    /// Whether new peers are super seeded (BEP 16)
+    super_seeding: bool,
+    /// Encryption and proxy requirements of the torrent's connections
+    policy: Policy,
+    /// When a seed other than us was last connected
+    seed_seen: Option<Instant>,
+    /// When a reannounce was last asked for
+    reannounced: Option<Instant>,
+    /// When a soft pause began, None unless soft paused
+    soft_paused: Option<Instant>,
     throttle: Throttle,
+    /// Trackers ordered by tier (BEP 12)
     trackers: VecDeque<Tracker>,
+    /// Identifies us to trackers across IP changes
+    announce_key: u32,
+    /// Index of the tracker announces are sent to
+    tracker_idx: usize,
+    webseeds: WebSeeds,
     peers: UHashMap<Peer<T>>,
     leechers: FHashSet<usize>,
     picker: Picker,
@@ -69,9 +139,46 @@ pub struct Torrent<T: cio::CIO> {
     choker: choker::Choker,
     dirty: bool,
     path: Option<String>,
+    /// Directory to move the data to once complete, overriding
+    /// the configured one
+    complete_path: Option<String>,
     info_bytes: Vec<u8>,
     info_idx: Option<usize>,
     created: DateTime<Utc>,
+    /// When all wanted pieces were last downloaded
+    completed: Option<DateTime<Utc>>,
+    /// SHA-256 digests of the files, computed on completion
+    file_digests: Vec<Option<[u8; 32]>>,
+    /// Bumped whenever the digests are cleared, so that the results of
+    /// hashing files which changed since are dropped
+    digest_generation: u64,
+    /// Whether a move of the torrent's data is in progress
+    moving: bool,
+    /// Whether the completed data is to be moved once a check or move in
+    /// progress is done
+    move_deferred: bool,
+    /// Fraction copied of a move across filesystems
+    move_progress: Option<f32>,
+    /// Position in the disk thread's checking queue
+    check_position: Option<u32>,
+    /// Pieces trusted from resume data while a background check runs
+    deferred_check: Option<Bitfield>,
+    /// Bumped by every check started, so that the results of checks
+    /// superseded since are dropped
+    check_generation: u64,
+    /// Whether the background check only samples the data after a
+    /// relocation, so a failure leads to a full check
+    quick_check: bool,
+    /// Pieces in the intent log, written to since the last checkpoint
+    intents: FHashSet<u32>,
+    /// Offsets of blocks handed to the disk thread which it hasn't
+    /// reported as written yet, by piece
+    unwritten: FHashMap<u32, Vec<u32>>,
+    /// Relay, address and time of the holepunch introductions asked for,
+    /// the only ones a connect message is followed for
+    rendezvous: Vec<(usize, SocketAddr, Instant)>,
+    /// Distributed copies and piece availability last sent over RPC
+    availability_sent: Option<(f32, Vec<u16>)>,
 }
 
 #[derive(Clone, Debug)]
@@ -95,11 +202,40 @@ pub struct Tracker {
     pub status: TrackerStatus,
     pub last_announce: DateTime<Utc>,
     pub update: Option<Instant>,
+    /// When the announce awaiting a response was sent
+    pub sent: Option<Instant>,
+    /// Successful announces
+    pub announces: u64,
+    /// Announces which failed or were rejected
+    pub failures: u64,
+    /// Total time spent waiting for timed responses
+    pub response_time: Duration,
+    timed: u64,
+    /// Whether a peer told us about the tracker through lt_tex
+    pub from_peer: bool,
+    /// Announce-list tier, lower tiers are tried first
+    pub tier: u32,
+    /// Failed announces since the last successful one
+    pub retries: u32,
+    /// Earliest time of an announce outside the interval, from `min interval`
+    pub min_update: Option<Instant>,
+    /// Set if the tracker asked never to be retried (BEP 31)
+    pub dead: bool,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    /// Sent back in announces once the tracker gave us one
+    pub tracker_id: Option<String>,
+    /// Swarm counts of the last successful announce
+    pub seeders: Option<u32>,
+    pub leechers: Option<u32>,
+    pub downloaded: Option<u32>,
 }
 
 struct Files {
     done: Vec<u64>,
     dirty: FHashSet<usize>,
+    /// Completed files which have been made read-only
+    readonly: FHashSet<usize>,
 }
 
 impl Status {
@@ -163,11 +299,90 @@ impl Status {
     }
 }
 
+impl Tracker {
+    pub fn new(url: Arc<Url>, tier: u32) -> Tracker {
+        Tracker {
+            url,
+            status: TrackerStatus::Updating,
+            last_announce: Utc::now(),
+            update: None,
+            sent: None,
+            announces: 0,
+            failures: 0,
+            response_time: Duration::from_secs(0),
+            timed: 0,
+            from_peer: false,
+            tier,
+            retries: 0,
+            min_update: None,
+            dead: false,
+            last_success: None,
+            last_error: None,
+            tracker_id: None,
+            seeders: None,
+            leechers: None,
+            downloaded: None,
+        }
+    }
+
+    /// Delays the next announce after a failure, waiting at least
+    /// as long as the tracker asked.
+    fn backoff(&mut self, hint: Option<Duration>) {
+        let secs = TRACKER_BACKOFF.saturating_mul(1 << cmp::min(self.retries, 16));
+        let delay = Duration::from_secs(cmp::min(secs, MAX_TRACKER_BACKOFF));
+        let hint = cmp::min(
+            hint.unwrap_or_default(),
+            Duration::from_secs(MAX_TRACKER_RETRY),
+        );
+        self.retries += 1;
+        self.update = Some(Instant::now() + cmp::max(delay, hint));
+    }
+
+    /// Records the response to an announce, timing it if it was sent by us.
+    fn responded(&mut self, failed: bool) {
+        if failed {
+            self.failures += 1;
+        } else {
+            self.announces += 1;
+        }
+        if let Some(sent) = self.sent.take() {
+            self.response_time += sent.elapsed();
+            self.timed += 1;
+        }
+        self.last_announce = Utc::now();
+    }
+
+    /// Average response time in ms
+    pub fn avg_response_time(&self) -> Option<u64> {
+        (self.response_time.as_millis() as u64).checked_div(self.timed)
+    }
+
+    /// Records a failed announce, returning whether the tracker
+    /// wasn't already failing.
+    fn failed(&mut self, reason: String) -> bool {
+        let failing = matches!(self.status, TrackerStatus::Failure(_));
+        self.last_error = Some(reason.clone());
+        self.status = TrackerStatus::Failure(reason);
+        self.responded(true);
+        !failing
+    }
+
+    /// Converts the next update to wall clock time.
+    fn next_announce(&self) -> Option<DateTime<Utc>> {
+        let now = Instant::now();
+        self.update.map(|u| {
+            let wait = chrono::Duration::from_std(u.saturating_duration_since(now));
+            Utc::now() + wait.unwrap_or_else(|_| chrono::Duration::zero())
+        })
+    }
+}
+
 impl Files {
     fn new(info: &Arc<Info>, pieces: &Bitfield) -> Files {
         let mut f = Files {
             done: vec![0; info.files.len()],
             dirty: FHashSet::default(),
+            readonly: FHashSet::default(),
         };
         f.rebuild(info, pieces);
         f
@@ -203,6 +418,23 @@ impl Files {
         }
         res
     }
+
+    /// Returns the files which became complete and those which
+    /// stopped being complete since the last call.
+    fn readonly_changes(&mut self, info: &Info) -> (Vec<usize>, Vec<usize>) {
+        let mut complete = Vec::new();
+        let mut incomplete = Vec::new();
+        for (i, f) in info.files.iter().enumerate() {
+            if f.length != 0 && self.done[i] == f.length {
+                if self.readonly.insert(i) {
+                    complete.push(i);
+                }
+            } else if self.readonly.remove(&i) {
+                incomplete.push(i);
+            }
+        }
+        (complete, incomplete)
+    }
 }
 
 impl<T: cio::CIO> Torrent<T> {
@@ -246,28 +478,18 @@ impl<T: cio::CIO> Torrent<T> {
 
         let mut trackers = VecDeque::with_capacity(1);
         if !info.url_list.is_empty() {
-            for (i, list) in info.url_list.iter().enumerate() {
-                for (j, _) in list.iter().enumerate() {
-                    let tracker = Tracker {
-                        status: TrackerStatus::Updating,
-                        update: None,
-                        last_announce: Utc::now(),
-                        url: Arc::clone(&info.url_list[i][j]),
-                    };
-                    trackers.push_back(tracker);
+            // Tiers were shuffled when parsed, so this tries each in random order
+            for (tier, list) in info.url_list.iter().enumerate() {
+                for url in list {
+                    trackers.push_back(Tracker::new(Arc::clone(url), tier as u32));
                 }
             }
         } else if let Some(ref announce) = info.announce {
-            let tracker = Tracker {
-                status: TrackerStatus::Updating,
-                update: None,
-                last_announce: Utc::now(),
-                url: announce.clone(),
-            };
-            trackers.push_back(tracker);
+            trackers.push_back(Tracker::new(announce.clone(), 0));
         }
 
         let files = Files::new(&info, &pieces);
+        let webseeds = WebSeeds::new(&info);
 
         let mut t = Torrent {
             id,
@@ -279,6 +501,15 @@ impl<T: cio::CIO> Torrent<T> {
             picker,
             priority: 3,
             priorities,
+            first_last: false,
+            dht: true,
+            dht_peers: 0,
+            super_seed: None,
+            super_seeding: false,
+            policy: Policy::default(),
+            seed_seen: None,
+            reannounced: None,
+            soft_paused: None,
             uploaded: 0,
             downloaded: 0,
             wasted: 0,
@@ -288,14 +519,35 @@ impl<T: cio::CIO> Torrent<T> {
             leechers,
             throttle,
             trackers,
+            tracker_idx: 0,
+            announce_key: rand::random(),
+            webseeds,
             choker: choker::Choker::new(),
             dirty: true,
             status,
+            complete_path: None,
             info_bytes,
             info_idx,
             created: Utc::now(),
+            completed: None,
+            file_digests: Vec::new(),
+            digest_generation: 0,
+            moving: false,
+            move_deferred: false,
+            move_progress: None,
+            check_position: None,
+            deferred_check: None,
+            check_generation: 0,
+            quick_check: false,
+            intents: FHashSet::default(),
+            unwritten: FHashMap::default(),
+            rendezvous: Vec::new(),
+            availability_sent: None,
         };
         t.start(true);
+        if t.info_idx.is_none() {
+            t.preallocate();
+        }
         if import {
             t.cio.msg_disk(disk::Request::validate_piece(
                 t.id,
@@ -343,12 +595,27 @@ impl<T: cio::CIO> Torrent<T> {
                 .map(|f| info::File {
                     path: f.path,
                     length: f.length,
+                    pieces_root: f.pieces_root,
                 })
                 .collect(),
             private: d.info.private,
             be_name: d.info.be_name,
             piece_idx: d.info.piece_idx,
+            meta_version: d.info.meta_version,
+            hash_v2: d.info_hash_v2,
             url_list: vec![],
+            web_seeds: d
+                .web_seeds
+                .iter()
+                .filter_map(|u| Url::parse(u).ok())
+                .map(Arc::new)
+                .collect(),
+            http_seeds: d
+                .http_seeds
+                .iter()
+                .filter_map(|u| Url::parse(u).ok())
+                .map(Arc::new)
+                .collect(),
         });
 
         let info_idx = if info.complete() {
@@ -362,35 +629,46 @@ impl<T: cio::CIO> Torrent<T> {
             vec![]
         };
         let pieces = Bitfield::from(&d.pieces.data, d.pieces.len);
-        let picker = picker::Picker::new(&info, &pieces, &d.priorities);
+        let mut picker = picker::Picker::new(&info, &pieces, &d.priorities);
+        match d.strategy {
+            session::torrent::current::Strategy::Rarest => {}
+            session::torrent::current::Strategy::Sequential => {
+                picker.change_picker(Mode::Sequential)
+            }
+            session::torrent::current::Strategy::Streaming { head } => {
+                picker.change_picker(Mode::Streaming { head })
+            }
+        }
+        if d.strategy != session::torrent::current::Strategy::Rarest || d.first_last {
+            picker.set_first_last(d.first_last);
+            picker.set_priorities(&d.priorities, &info);
+        }
+        for (piece, blocks) in &d.partial {
+            if u64::from(*piece) < pieces.len() && !pieces.has_bit(u64::from(*piece)) {
+                picker.restore_partial(*piece, &Bitfield::from(&blocks.data, blocks.len));
+            }
+        }
         throttle.set_ul_rate(d.throttle_ul);
         throttle.set_dl_rate(d.throttle_dl);
 
+        // Sessions from before tiers were kept give each tracker its own tier
+        let tiers = d.tracker_tiers;
         let mut trackers: VecDeque<_> = d
             .trackers
             .into_iter()
-            .filter_map(|url| Url::parse(&url).ok())
-            .map(|url| Tracker {
-                status: TrackerStatus::Updating,
-                update: None,
-                last_announce: Utc::now(),
-                url: Arc::new(url),
-            })
+            .enumerate()
+            .filter_map(|(i, url)| Url::parse(&url).ok().map(|url| (i, url)))
+            .map(|(i, url)| Tracker::new(Arc::new(url), tiers.get(i).cloned().unwrap_or(i as u32)))
             .collect();
 
         if trackers.is_empty() {
             if let Some(ref announce) = info.announce {
-                let tracker = Tracker {
-                    status: TrackerStatus::Updating,
-                    update: None,
-                    last_announce: Utc::now(),
-                    url: announce.clone(),
-                };
-                trackers.push_back(tracker);
+                trackers.push_back(Tracker::new(announce.clone(), 0));
             }
         }
 
         let files = Files::new(&info, &pieces);
+        let webseeds = WebSeeds::new(&info);
 
         let mut t = Torrent {
             id,
@@ -406,10 +684,33 @@ impl<T: cio::CIO> Torrent<T> {
             stat: stat::EMA::new(),
             priorities: Arc::new(d.priorities),
             priority: d.priority,
+            first_last: d.first_last,
+            dht: d.dht,
+            dht_peers: 0,
+            super_seed: d.super_seed,
+            super_seeding: false,
+            policy: Policy {
+                encryption: d.encryption.map(|e| match e {
+                    session::torrent::current::Encryption::Disabled => Encryption::Disabled,
+                    session::torrent::current::Encryption::Enabled => Encryption::Enabled,
+                    session::torrent::current::Encryption::Required => Encryption::Required,
+                }),
+                proxy: match d.proxy {
+                    None => policy::Proxy::Default,
+                    Some(true) => policy::Proxy::Required,
+                    Some(false) => policy::Proxy::Direct,
+                },
+            },
+            seed_seen: None,
+            reannounced: None,
+            soft_paused: None,
             cio,
             leechers,
             throttle,
             trackers,
+            tracker_idx: 0,
+            announce_key: d.announce_key.unwrap_or_else(rand::random),
+            webseeds,
             choker: choker::Choker::new(),
             dirty: false,
             status: Status {
@@ -423,16 +724,38 @@ impl<T: cio::CIO> Torrent<T> {
                 },
             },
             path: d.path,
+            complete_path: d.complete_path,
             info_bytes,
             info_idx,
             created: d.created,
+            completed: d.completed,
+            file_digests: d.file_digests,
+            digest_generation: 0,
+            moving: false,
+            move_deferred: false,
+            move_progress: None,
+            check_position: None,
+            deferred_check: None,
+            check_generation: 0,
+            quick_check: false,
+            intents: disk::suspect_pieces(&d.info.hash).into_iter().collect(),
+            unwritten: FHashMap::default(),
+            rendezvous: Vec::new(),
+            availability_sent: None,
         };
         t.status.error = None;
+        t.backfill_completed();
         t.start(false);
+        t.update_readonly();
         if d.status.validating {
             t.validate();
         } else {
             t.announce_start();
+            if CONFIG.disk.deferred_check && !t.status.magnet() {
+                t.deferred_validate();
+            } else {
+                t.validate_suspect();
+            }
         }
         Some(t)
     }
@@ -456,11 +779,13 @@ impl<T: cio::CIO> Torrent<T> {
                     .map(|f| session::torrent::current::File {
                         path: f.path,
                         length: f.length,
+                        pieces_root: f.pieces_root,
                     })
                     .collect(),
                 private: self.info.private,
                 be_name: self.info.be_name.clone(),
                 piece_idx: self.info.piece_idx.clone(),
+                meta_version: self.info.meta_version,
             },
             pieces: session::torrent::Bitfield {
                 data: self.pieces.data(),
@@ -481,9 +806,33 @@ impl<T: cio::CIO> Torrent<T> {
                 },
             },
             path: self.path.clone(),
+            complete_path: self.complete_path.clone(),
             priorities: self.priorities.as_ref().clone(),
             priority: self.priority,
+            dht: self.dht,
             created: self.created,
+            completed: self.completed,
+            file_digests: self.file_digests.clone(),
+            // Only blocks the disk thread wrote out, others may be lost
+            // to a crash or a failed write
+            partial: self
+                .picker
+                .partial()
+                .into_iter()
+                .filter_map(|(piece, mut blocks)| {
+                    for begin in self.unwritten.get(&piece).into_iter().flatten() {
+                        blocks.unset_bit(u64::from(begin / 16_384));
+                    }
+                    if blocks.set() == 0 {
+                        return None;
+                    }
+                    let bf = session::torrent::Bitfield {
+                        data: blocks.data(),
+                        len: blocks.len(),
+                    };
+                    Some((piece, bf))
+                })
+                .collect(),
             throttle_ul: self.throttle.ul_rate(),
             throttle_dl: self.throttle.dl_rate(),
             trackers: self
@@ -491,6 +840,38 @@ impl<T: cio::CIO> Torrent<T> {
                 .iter()
                 .map(|trk| trk.url.as_str().to_owned())
                 .collect(),
+            tracker_tiers: self.trackers.iter().map(|trk| trk.tier).collect(),
+            announce_key: Some(self.announce_key),
+            super_seed: self.super_seed,
+            encryption: self.policy.encryption.map(|e| match e {
+                Encryption::Disabled => session::torrent::current::Encryption::Disabled,
+                Encryption::Enabled => session::torrent::current::Encryption::Enabled,
+                Encryption::Required => session::torrent::current::Encryption::Required,
+            }),
+            proxy: match self.policy.proxy {
+                policy::Proxy::Default => None,
+                policy::Proxy::Required => Some(true),
+                policy::Proxy::Direct => Some(false),
+            },
+            strategy: match self.picker.mode() {
+                Mode::Rarest => session::torrent::current::Strategy::Rarest,
+                Mode::Sequential => session::torrent::current::Strategy::Sequential,
+                Mode::Streaming { head } => session::torrent::current::Strategy::Streaming { head },
+            },
+            first_last: self.first_last,
+            info_hash_v2: self.info.hash_v2,
+            web_seeds: self
+                .info
+                .web_seeds
+                .iter()
+                .map(|url| url.as_str().to_owned())
+                .collect(),
+            http_seeds: self
+                .info
+                .http_seeds
+                .iter()
+                .map(|url| url.as_str().to_owned())
+                .collect(),
         };
         let data = bincode::serialize(&d).expect("Serialization failed!");
         debug!("Sending serialization request!");
@@ -533,10 +914,14 @@ impl<T: cio::CIO> Torrent<T> {
     pub fn set_tracker_response(&mut self, url: &Url, resp: &tracker::Result<TrackerResponse>) {
         let mut time = Instant::now();
         let mut empty = false;
+        let mut failed = false;
         match *resp {
             Ok(ref r) => {
                 if let Some(tracker) = self.trackers.iter_mut().find(|t| &*t.url == url) {
                     debug!("Got valid response for {}", tracker.url);
+                    tracker.min_update = r
+                        .min_interval
+                        .map(|i| time + Duration::from_secs(u64::from(i)));
                     time += Duration::from_secs(u64::from(r.interval));
                     tracker.status = TrackerStatus::Ok {
                         seeders: r.seeders,
@@ -544,48 +929,152 @@ impl<T: cio::CIO> Torrent<T> {
                         interval: r.interval,
                     };
                     tracker.update = Some(time);
-                    tracker.last_announce = Utc::now();
+                    tracker.retries = 0;
+                    tracker.last_success = Some(Utc::now());
+                    tracker.seeders = r.seeders;
+                    tracker.leechers = r.leechers;
+                    tracker.downloaded = r.downloaded;
+                    if r.tracker_id.is_some() {
+                        tracker.tracker_id = r.tracker_id.clone();
+                    }
+                    tracker.responded(false);
                     if r.peers.is_empty() {
                         empty = true;
                     }
                 }
+                if !empty {
+                    self.promote_tracker(url);
+                }
             }
             Err(tracker::Error(tracker::ErrorKind::TrackerError(ref s), _)) => {
                 if let Some(tracker) = self.trackers.iter_mut().find(|t| &*t.url == url) {
                     debug!("Got tracker level error for {}", tracker.url);
-                    time += Duration::from_secs(300);
-                    tracker.update = Some(time);
-                    tracker.status = TrackerStatus::Failure(s.clone());
-                    tracker.last_announce = Utc::now();
+                    tracker.backoff(None);
+                    failed = tracker.failed(s.clone());
+                }
+            }
+            Err(tracker::Error(tracker::ErrorKind::TrackerRetry(ref s, mins), _)) => {
+                if let Some(tracker) = self.trackers.iter_mut().find(|t| &*t.url == url) {
+                    debug!(
+                        "Got tracker level error for {}, retry in {:?}",
+                        tracker.url, mins
+                    );
+                    match mins {
+                        Some(m) => tracker.backoff(Some(Duration::from_secs(u64::from(m) * 60))),
+                        None => tracker.dead = true,
+                    }
+                    failed = tracker.failed(s.clone());
                 }
             }
             Err(ref e) => {
                 if let Some(tracker) = self.trackers.iter_mut().find(|t| &*t.url == url) {
                     error!("Failed to query tracker {}: {}", tracker.url, e);
-                    // Wait 5 minutes before trying again
-                    time += Duration::from_secs(300);
-                    tracker.update = Some(time);
-                    let reason = format!("Couldn't contact tracker: {}", e);
-                    tracker.status = TrackerStatus::Failure(reason);
-                    tracker.last_announce = Utc::now();
+                    let hint = match *e.kind() {
+                        tracker::ErrorKind::Unavailable(secs) => {
+                            Some(Duration::from_secs(u64::from(secs)))
+                        }
+                        _ => None,
+                    };
+                    tracker.backoff(hint);
+                    failed = tracker.failed(format!("Couldn't contact tracker: {}", e));
                 }
             }
         }
 
-        if (resp.is_err() || empty) && self.trackers.iter().any(|t| &*t.url == url) {
-            if let Some(front) = self.trackers.pop_front() {
-                self.trackers.push_back(front);
-                self.try_update_tracker();
+        if failed {
+            let reason = match self.trackers.iter().find(|t| &*t.url == url) {
+                Some(Tracker {
+                    status: TrackerStatus::Failure(reason),
+                    ..
+                }) => reason.clone(),
+                _ => String::new(),
+            };
+            let host = url.host_str().unwrap_or("").to_owned();
+            self.notify(
+                message::Severity::Warning,
+                message::NotificationKind::TrackerError,
+                format!("Tracker {} failed for {}: {}", host, self.info.name, reason),
+            );
+        }
+
+        let current = self.current_tracker().map(|t| &*t.url) == Some(url);
+        if (resp.is_err() || empty) && current {
+            // Fail over to the rest of the tier, then the following tiers,
+            // skipping trackers which asked never to be retried
+            let len = self.trackers.len();
+            for _ in 0..len {
+                self.tracker_idx = (self.tracker_idx + 1) % len;
+                if !self.trackers[self.tracker_idx].dead {
+                    break;
+                }
             }
+            self.announce_when_due();
         }
         self.update_rpc_tracker();
     }
 
+    /// The tracker announces are sent to.
+    pub fn current_tracker(&self) -> Option<&Tracker> {
+        self.trackers.get(self.tracker_idx)
+    }
+
+    /// Moves a tracker which responded to the front of its tier
+    /// and keeps announcing to it.
+    fn promote_tracker(&mut self, url: &Url) {
+        let idx = match self.trackers.iter().position(|t| &*t.url == url) {
+            Some(idx) => idx,
+            None => return,
+        };
+        let tier = self.trackers[idx].tier;
+        let front = self.trackers.iter().position(|t| t.tier == tier).unwrap();
+        let trk = self.trackers.remove(idx).unwrap();
+        self.trackers.insert(front, trk);
+        if self.tracker_idx == idx {
+            self.tracker_idx = front;
+        } else if self.tracker_idx >= front && self.tracker_idx < idx {
+            self.tracker_idx += 1;
+        }
+    }
+
+    /// Sends an announce to the tracker thread, noting when it was sent
+    /// so the response time can be tracked.
+    fn send_announce(&mut self, req: tracker::Request) {
+        if let Some(url) = req.announce_url() {
+            if let Some(trk) = self.trackers.iter_mut().find(|t| &*t.url == url) {
+                trk.sent = Some(Instant::now());
+            }
+        }
+        self.cio.msg_trk(req);
+    }
+
+    /// Announces once the current tracker's interval has passed. Each
+    /// announce interval starts over from the first tier (BEP 12), the
+    /// trackers failed over to are only kept while they're failing.
     pub fn try_update_tracker(&mut self) {
         if self.status.stopped() {
             return;
         }
-        if let Some(end) = self.trackers.front().and_then(|t| t.update) {
+        let regular = self.current_tracker().is_some_and(|t| {
+            t.retries == 0
+                && t.last_success.is_some()
+                && t.update.is_some_and(|u| u <= Instant::now())
+        });
+        if regular {
+            if let Some(first) = self.trackers.iter().position(|t| !t.dead) {
+                if first != self.tracker_idx {
+                    self.tracker_idx = first;
+                    self.update_rpc_tracker();
+                }
+            }
+        }
+        self.announce_when_due();
+    }
+
+    fn announce_when_due(&mut self) {
+        if self.status.stopped() {
+            return;
+        }
+        if let Some(end) = self.current_tracker().and_then(|t| t.update) {
             debug!("Updating tracker at interval!");
             let cur = Instant::now();
             if cur >= end {
@@ -596,12 +1085,51 @@ impl<T: cio::CIO> Torrent<T> {
         }
     }
 
+    /// Schedules an announce after our external IP changed, so peers
+    /// can reach us at the new address.
+    pub fn external_ip_changed(&mut self) {
+        if self.status.stopped() {
+            return;
+        }
+        for trk in &mut self.trackers {
+            trk.dead = false;
+            trk.retries = 0;
+            trk.min_update = None;
+        }
+        let delay = IP_CHANGE_DELAY + rand::random::<u64>() % IP_CHANGE_SPREAD;
+        if let Some(trk) = self.trackers.get_mut(self.tracker_idx) {
+            trk.update = Some(Instant::now() + Duration::from_secs(delay));
+            self.update_rpc_tracker();
+        }
+    }
+
+    /// Tells peers and trackers about a new listen port.
+    pub fn port_changed(&mut self) {
+        // The extension handshake carries our port
+        self.resend_ext_handshake();
+        self.update_tracker();
+    }
+
     pub fn update_tracker(&mut self) {
         if self.status.stopped() {
             return;
         }
+        if let Some(trk) = self.trackers.get_mut(self.tracker_idx) {
+            if trk.dead {
+                self.dht_announce();
+                return;
+            }
+            // Early announces wait for the tracker's min interval to pass
+            if let Some(min) = trk.min_update.filter(|&m| m > Instant::now()) {
+                debug!("Deferring announce to {} until its min interval", trk.url);
+                trk.update = Some(min);
+                self.update_rpc_tracker();
+                self.dht_announce();
+                return;
+            }
+        }
         if let Some(req) = tracker::Request::interval(self) {
-            self.cio.msg_trk(req);
+            self.send_announce(req);
         }
         self.dht_announce();
     }
@@ -620,12 +1148,10 @@ impl<T: cio::CIO> Torrent<T> {
 
     pub fn add_tracker(&mut self, url: Url) -> String {
         let id = util::trk_rpc_id(&self.info.hash, url.as_str());
-        self.trackers.push_front(Tracker {
-            status: TrackerStatus::Updating,
-            update: None,
-            last_announce: Utc::now(),
-            url: Arc::new(url),
-        });
+        // Trackers added by the user are tried before all others
+        self.trackers.push_front(Tracker::new(Arc::new(url), 0));
+        self.tracker_idx = 0;
+        self.dirty = true;
         {
             let trk = &self.trackers[0];
             let res = vec![resource::Resource::Tracker(resource::Tracker {
@@ -634,6 +1160,7 @@ impl<T: cio::CIO> Torrent<T> {
                 url: trk.url.as_ref().clone(),
                 last_report: trk.last_announce,
                 error: None,
+                from_peer: trk.from_peer,
                 ..Default::default()
             })];
             self.cio.msg_rpc(rpc::CtlMessage::Extant(res));
@@ -642,6 +1169,69 @@ impl<T: cio::CIO> Torrent<T> {
         id
     }
 
+    /// Sends a peer our trackers which have worked (BEP 28).
+    fn send_tex(&self, id: u8, peer: &mut Peer<T>) {
+        if self.info.private {
+            return;
+        }
+        let added: Vec<_> = self
+            .trackers
+            .iter()
+            .filter(|t| t.announces > 0)
+            .take(MAX_TEX_TRACKERS)
+            .map(|t| BEncode::String(t.url.as_str().as_bytes().to_vec()))
+            .collect();
+        if added.is_empty() {
+            return;
+        }
+        let mut dict = BTreeMap::new();
+        dict.insert(b"added".to_vec(), BEncode::List(added));
+        peer.send_message(Message::Extension {
+            id,
+            payload: BEncode::Dict(dict).encode_to_buf(),
+        });
+    }
+
+    /// Adds a tracker a peer told us about, after any we already have.
+    fn add_tex_tracker(&mut self, url: &str) {
+        if self.trackers.iter().filter(|t| t.from_peer).count() >= MAX_TEX_TRACKERS {
+            return;
+        }
+        let url = match Url::parse(url) {
+            Ok(url) => url,
+            Err(_) => return,
+        };
+        if !util::public_tracker(&url) {
+            return;
+        }
+        if self.trackers.iter().any(|t| *t.url == url) {
+            return;
+        }
+        debug!("Learned tracker {} from peer", url);
+        let id = util::trk_rpc_id(&self.info.hash, url.as_str());
+        // Learned trackers make up a final tier of their own
+        let tier = self
+            .trackers
+            .iter()
+            .filter(|t| !t.from_peer)
+            .map(|t| t.tier + 1)
+            .max()
+            .unwrap_or(0);
+        let mut trk = Tracker::new(Arc::new(url), tier);
+        trk.from_peer = true;
+        let res = vec![resource::Resource::Tracker(resource::Tracker {
+            id,
+            torrent_id: self.rpc_id(),
+            url: trk.url.as_ref().clone(),
+            last_report: trk.last_announce,
+            error: None,
+            from_peer: true,
+            ..Default::default()
+        })];
+        self.trackers.push_back(trk);
+        self.cio.msg_rpc(rpc::CtlMessage::Extant(res));
+    }
+
     pub fn remove_tracker(&mut self, rpc_id: &str) {
         let ih = &self.info.hash;
         let mut res = None;
@@ -656,17 +1246,308 @@ impl<T: cio::CIO> Torrent<T> {
 
         if let Some(idx) = res {
             self.trackers.remove(idx);
+            self.dirty = true;
+            if self.tracker_idx > idx {
+                self.tracker_idx -= 1;
+            } else if self.tracker_idx >= self.trackers.len() {
+                self.tracker_idx = 0;
+            }
+        }
+    }
+
+    /// Describes the torrent for GET_TORRENT_INFO, including the
+    /// bencoded info dict if raw is set. The info dict is rebuilt from
+    /// the parsed metadata, so hashes are only reported when they match.
+    pub fn info_dict(&self, raw: bool) -> Result<(message::TorrentInfo, Option<Vec<u8>>), String> {
+        if !self.info.complete() {
+            return Err("Torrent metadata is not yet known".to_owned());
+        }
+        let rebuilt_v2 = merkle::sha256(&self.info_bytes);
+        // Torrents from before the v2 hash was kept only have the rebuilt dict to go by
+        let hash_v2 = self.info.hash_v2.or_else(|| {
+            Some(rebuilt_v2).filter(|h| self.info.is_v2() && h[..20] == self.info.hash)
+        });
+        let exact = if self.info.is_v2() {
+            hash_v2 == Some(rebuilt_v2)
+        } else {
+            util::sha1_hash(&self.info_bytes) == self.info.hash
+        };
+        let info_hash = if self.info.is_v2() {
+            None
+        } else {
+            Some(util::hash_to_id(&self.info.hash))
+        };
+        let info_hash_v2 = hash_v2.map(|h| util::hash_to_id(&h));
+        if raw && !exact {
+            return Err("The original info dict of this torrent is unavailable".to_owned());
+        }
+        let info = message::TorrentInfo {
+            id: self.rpc_id(),
+            info_hash,
+            info_hash_v2,
+            piece_size: self.info.piece_len,
+            pieces: u64::from(self.info.pieces()),
+            info_size: self.info_bytes.len() as u64,
+            raw_expires: None,
+        };
+        Ok((
+            info,
+            if raw {
+                Some(self.info_bytes.clone())
+            } else {
+                None
+            },
+        ))
+    }
+
+    /// Tells RPC clients about an event concerning the torrent.
+    fn notify(
+        &mut self,
+        severity: message::Severity,
+        kind: message::NotificationKind,
+        msg: String,
+    ) {
+        self.cio.msg_rpc(rpc::CtlMessage::Notify {
+            severity,
+            kind,
+            resource: Some(self.rpc_id()),
+            message: msg,
+        });
+    }
+
+    /// Lists the torrent's files for GET_FILE_MANIFEST.
+    pub fn file_manifest(&self, format: message::ManifestFormat) -> Result<Vec<u8>, String> {
+        if !self.info.complete() {
+            return Err("Torrent metadata is not yet known".to_owned());
+        }
+        let files: Vec<_> = self
+            .info
+            .files
+            .iter()
+            .enumerate()
+            .map(|(i, f)| {
+                let (first_piece, last_piece) = if f.length == 0 {
+                    (None, None)
+                } else {
+                    (
+                        Some(self.info.piece_at(i, 0)),
+                        Some(self.info.piece_at(i, f.length - 1)),
+                    )
+                };
+                manifest::Entry {
+                    path: f.path.to_string_lossy().into_owned(),
+                    size: f.length,
+                    progress: if f.length == 0 {
+                        1.
+                    } else {
+                        self.files.done[i] as f32 / f.length as f32
+                    },
+                    priority: self.priorities[i],
+                    first_piece,
+                    last_piece,
+                    sha256: self.file_digest(i),
+                }
+            })
+            .collect();
+        Ok(manifest::encode(&files, format))
+    }
+
+    /// Recounts piece availability from the peers' bitfields for
+    /// AUDIT_AVAILABILITY, logging any drift in the picker's counts.
+    pub fn audit_availability(&self) -> Result<message::AvailabilityAudit, String> {
+        if !self.info.complete() {
+            return Err("Torrent metadata is not yet known".to_owned());
+        }
+        let drift = self
+            .picker
+            .audit_availability(self.peers.values())
+            .ok_or_else(|| "Availability isn't counted when picking sequentially".to_owned())?;
+        for &(piece, counted, actual) in &drift {
+            error!(
+                "{}: piece {} counted as available from {} peers, but {} have it",
+                self, piece, counted, actual
+            );
+        }
+        Ok(message::AvailabilityAudit {
+            id: self.rpc_id(),
+            peers: self.peers.len() as u64,
+            drift: drift
+                .into_iter()
+                .map(|(piece, counted, actual)| message::PieceDrift {
+                    piece,
+                    counted: counted as u64,
+                    actual: actual as u64,
+                })
+                .collect(),
+        })
+    }
+
+    /// Lists the file ranges held by pieces and the pieces holding
+    /// files, for GET_PIECE_MAP.
+    pub fn piece_map(&self, pieces: &[u32], files: &[String]) -> Result<message::PieceMap, String> {
+        if !self.info.complete() {
+            return Err("Torrent metadata is not yet known".to_owned());
+        }
+        let ids: Vec<_> = self
+            .info
+            .files
+            .iter()
+            .map(|f| util::file_rpc_id(&self.info.hash, f.path.to_string_lossy().as_ref()))
+            .collect();
+        let pieces = pieces
+            .iter()
+            .map(|&piece| {
+                if u64::from(piece) >= self.pieces.len() {
+                    return Err(format!("Piece {} does not exist", piece));
+                }
+                let files = Info::piece_disk_locs(&self.info, piece)
+                    .map(|loc| message::FileSpan {
+                        id: ids[loc.file].clone(),
+                        offset: loc.offset,
+                        length: (loc.end - loc.start) as u64,
+                    })
+                    .collect();
+                Ok(message::PieceFiles { piece, files })
+            })
+            .collect::<Result<_, String>>()?;
+        let files = files
+            .iter()
+            .map(|id| {
+                let i = ids
+                    .iter()
+                    .position(|f| f == id)
+                    .ok_or_else(|| format!("File {} is not part of the torrent", id))?;
+                let len = self.info.files[i].length;
+                let (first_piece, last_piece) = if len == 0 {
+                    (None, None)
+                } else {
+                    (
+                        Some(self.info.piece_at(i, 0)),
+                        Some(self.info.piece_at(i, len - 1)),
+                    )
+                };
+                Ok(message::FilePieces {
+                    id: id.clone(),
+                    first_piece,
+                    last_piece,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(message::PieceMap {
+            id: self.rpc_id(),
+            pieces,
+            files,
+        })
+    }
+
+    /// Describes the torrent's peers for GET_SWARM_SNAPSHOT.
+    pub fn swarm_snapshot(&mut self) -> Vec<u8> {
+        let cio = &mut self.cio;
+        let mut peers: Vec<_> = self
+            .peers
+            .values()
+            .map(|p| {
+                let encrypted = cio
+                    .get_peer(p.id(), |conn| conn.sock().encrypted())
+                    .unwrap_or(false);
+                p.snapshot(encrypted)
+            })
+            .collect();
+        peers.sort_by(|a, b| a.addr.cmp(&b.addr));
+        swarm::encode(&swarm::Snapshot {
+            id: self.rpc_id(),
+            taken: Utc::now(),
+            pieces: self.pieces.len(),
+            have: self.pieces.iter().count() as u64,
+            peers,
+        })
+    }
+
+    /// Replaces the URL of a tracker, keeping its place in the tiers.
+    /// A new tracker resource is made since IDs depend on the URL.
+    pub fn replace_tracker(&mut self, rpc_id: &str, url: Url) -> Result<String, String> {
+        let ih = &self.info.hash;
+        let idx = self
+            .trackers
+            .iter()
+            .position(|t| util::trk_rpc_id(ih, t.url.as_str()) == rpc_id)
+            .ok_or_else(|| format!("Tracker {} does not exist", rpc_id))?;
+        if self.trackers.iter().any(|t| *t.url == url) {
+            return Err(format!("Torrent already has tracker {}", url));
+        }
+        let id = util::trk_rpc_id(ih, url.as_str());
+        let trk = Tracker::new(Arc::new(url), self.trackers[idx].tier);
+        let res = vec![resource::Resource::Tracker(resource::Tracker {
+            id: id.clone(),
+            torrent_id: self.rpc_id(),
+            url: trk.url.as_ref().clone(),
+            last_report: trk.last_announce,
+            error: None,
+            ..Default::default()
+        })];
+        let url = trk.url.clone();
+        self.trackers[idx] = trk;
+        self.dirty = true;
+        self.cio
+            .msg_rpc(rpc::CtlMessage::Removed(vec![rpc_id.to_owned()]));
+        self.cio.msg_rpc(rpc::CtlMessage::Extant(res));
+        if idx == self.tracker_idx && !self.status.stopped() {
+            if let Some(req) = tracker::Request::custom(self, url, None) {
+                self.send_announce(req);
+            }
+        }
+        Ok(id)
+    }
+
+    /// Announces right away, or once the tracker's min interval has
+    /// passed, forgetting about failures, unless the torrent was
+    /// reannounced very recently.
+    pub fn reannounce(&mut self) -> Result<(), String> {
+        if self.status.stopped() {
+            return Err("Stopped torrents can't be reannounced".to_owned());
+        }
+        if self
+            .reannounced
+            .is_some_and(|r| r.elapsed() < Duration::from_secs(REANNOUNCE_COOLDOWN))
+        {
+            return Err(format!(
+                "Torrents can only be reannounced every {}s",
+                REANNOUNCE_COOLDOWN
+            ));
         }
+        self.reannounced = Some(Instant::now());
+        for trk in &mut self.trackers {
+            trk.dead = false;
+            trk.retries = 0;
+        }
+        self.update_tracker();
+        Ok(())
     }
 
     pub fn update_tracker_req(&mut self, rpc_id: &str) {
-        if let Some(req) = self
+        let ih = &self.info.hash;
+        let idx = match self
             .trackers
             .iter()
-            .find(|trk| util::trk_rpc_id(&self.info.hash, trk.url.as_str()) == rpc_id)
-            .and_then(|trk| tracker::Request::custom(self, trk.url.clone()))
+            .position(|trk| util::trk_rpc_id(ih, trk.url.as_str()) == rpc_id)
         {
-            self.cio.msg_trk(req)
+            Some(idx) => idx,
+            None => return,
+        };
+        let trk = &mut self.trackers[idx];
+        // Asking for an announce revives a tracker which gave up
+        trk.dead = false;
+        if let Some(min) = trk.min_update.filter(|&m| m > Instant::now()) {
+            debug!("Deferring announce to {} until its min interval", trk.url);
+            if idx == self.tracker_idx {
+                trk.update = Some(min);
+                self.update_rpc_tracker();
+            }
+            return;
+        }
+        let url = self.trackers[idx].url.clone();
+        if let Some(req) = tracker::Request::custom(self, url, None) {
+            self.send_announce(req)
         }
     }
 
@@ -694,8 +1575,12 @@ impl<T: cio::CIO> Torrent<T> {
         &self.info
     }
 
-    pub fn trackers(&self) -> &VecDeque<Tracker> {
-        &self.trackers
+    pub fn announce_key(&self) -> u32 {
+        self.announce_key
+    }
+
+    pub fn find_tracker(&self, url: &Url) -> Option<&Tracker> {
+        self.trackers.iter().find(|t| &*t.url == url)
     }
 
     pub fn handle_disk_resp(&mut self, resp: disk::Response) {
@@ -708,13 +1593,22 @@ impl<T: cio::CIO> Torrent<T> {
                     self.uploaded += u64::from(context.length);
                     self.stat.add_ul(u64::from(context.length));
                     self.dirty = true;
+                    peer.read_done();
                     peer.send_message(p);
                 }
             }
             disk::Response::Moved { path, .. } => {
                 debug!("Moved torrent!");
+                self.moving = false;
+                if self.move_progress.take().is_some() {
+                    self.announce_status();
+                }
                 let id = self.rpc_id();
                 self.path = Some(path.clone());
+                // Files keep their permissions when moved, but the disk
+                // thread only knows them by their old path.
+                self.files.readonly.clear();
+                self.update_readonly();
                 self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
                     resource::SResourceUpdate::TorrentPath {
                         id,
@@ -722,6 +1616,7 @@ impl<T: cio::CIO> Torrent<T> {
                         path,
                     },
                 ]));
+                self.resume_move();
             }
             disk::Response::PieceValidated { piece, valid, .. } => {
                 self.validating.remove(&piece);
@@ -738,6 +1633,7 @@ impl<T: cio::CIO> Torrent<T> {
                     }
                     self.announce_start();
                     self.files.rebuild(&self.info, &self.pieces);
+                    self.update_readonly();
                     self.update_rpc_transfer();
                     return;
                 }
@@ -753,23 +1649,45 @@ impl<T: cio::CIO> Torrent<T> {
                         }
                     }
                     self.files.update(&self.info, piece);
+                    self.update_readonly();
                     self.check_complete();
                 } else {
                     // TODO: trace down the bad peer and block it
                     debug!("Invalid piece downloaded!");
-                    self.picker.invalidate_piece(piece);
+                    self.invalidate_piece(piece);
                     if !self.stat.active() {
                         self.request_all();
                     }
                 }
             }
+            disk::Response::MoveUpdate { percent, .. } => {
+                self.move_progress = Some(percent);
+                self.announce_status();
+            }
+            disk::Response::ValidationQueued { position, .. } => {
+                self.check_position = Some(position);
+                self.announce_status();
+            }
+            disk::Response::ValidationUpdate { generation, .. }
+            | disk::Response::ValidationComplete { generation, .. }
+                if generation != self.check_generation => {}
             disk::Response::ValidationUpdate { percent, .. } => {
-                self.status.validating = Some(percent);
-                self.update_rpc_transfer();
+                if self.check_position.take().is_some() {
+                    self.announce_status();
+                }
+                if self.deferred_check.is_none() {
+                    self.status.validating = Some(percent);
+                    self.update_rpc_transfer();
+                }
+            }
+            disk::Response::ValidationComplete { invalid, .. } if self.deferred_check.is_some() => {
+                self.deferred_validated(invalid);
+                self.resume_move();
             }
             disk::Response::ValidationComplete { mut invalid, .. } => {
                 debug!("Validation completed!");
                 self.status.validating = None;
+                self.check_position = None;
                 // Ignore invalid pieces which are
                 // part of an invalid file(none of the disk locations
                 // refer to files which aren't being downloaded(pri. 1)
@@ -808,40 +1726,270 @@ impl<T: cio::CIO> Torrent<T> {
                             for piece in invalid {
                                 self.pieces.unset_bit(u64::from(piece));
                             }
-                            let seq = self.picker.is_sequential();
-                            self.change_picker(seq);
+                            let mode = self.picker.mode();
+                            self.change_picker(mode);
                         }
                         self.announce_start();
                     } else {
+                        self.gain_valid(&invalid);
                         for piece in invalid {
-                            self.picker.invalidate_piece(piece);
+                            if self.pieces.has_bit(u64::from(piece)) {
+                                self.send_donthave(piece);
+                            }
+                            self.invalidate_piece(piece);
                             self.pieces.unset_bit(u64::from(piece));
                         }
                         self.request_all();
                     }
                     self.status.state = StatusState::Incomplete;
+                    self.clear_digests();
                 }
                 // update the RPC stats once done
                 self.files.rebuild(&self.info, &self.pieces);
+                self.update_readonly();
                 self.update_rpc_transfer();
                 self.rpc_update_pieces();
                 self.announce_status();
+                self.resume_move();
             }
             disk::Response::Error { err, .. } => {
                 error!("Disk error: {:?}", err);
+                self.moving = false;
+                self.move_progress = None;
                 self.status.error = Some(format!("{}", err));
+                let kind = if err.raw_os_error() == Some(libc::ENOSPC) {
+                    message::NotificationKind::DiskFull
+                } else {
+                    message::NotificationKind::DiskError
+                };
+                self.notify(
+                    message::Severity::Error,
+                    kind,
+                    format!("Disk error for {}: {}", self.info.name, err),
+                );
                 self.announce_status();
-                for piece in self.validating.drain() {
-                    self.picker.invalidate_piece(piece);
+                for piece in mem::take(&mut self.validating) {
+                    self.invalidate_piece(piece);
                     self.pieces.unset_bit(u64::from(piece));
                 }
             }
-            disk::Response::FreeSpace(_) => unreachable!(),
-        }
-    }
-
-    fn check_complete(&mut self) {
-        let mut complete = true;
+            disk::Response::FilesHashed { generation, .. }
+                if generation != self.digest_generation =>
+            {
+                debug!("Dropping superseded file digests of {}", self.rpc_id());
+            }
+            disk::Response::FilesHashed { digests, .. } => {
+                self.file_digests = vec![None; self.info.files.len()];
+                for (i, digest) in digests {
+                    self.file_digests[i] = Some(digest);
+                }
+                self.dirty = true;
+                self.update_rpc_digests();
+                if self.complete() {
+                    self.move_complete();
+                }
+            }
+            disk::Response::FilesMissing { files, .. } => {
+                let (path, len) = &files[0];
+                let mut reason = match len {
+                    Some(len) => format!("{} was truncated to {} bytes", path.display(), len),
+                    None => format!("{} is missing", path.display()),
+                };
+                if files.len() > 1 {
+                    reason += &format!(", as are {} other files", files.len() - 1);
+                }
+                error!("Pausing torrent {}: {}", self.rpc_id(), reason);
+                self.notify(
+                    message::Severity::Error,
+                    message::NotificationKind::DiskError,
+                    format!("Paused {}: {}", self.info.name, reason),
+                );
+                // Paused as well, so it stays stopped across restarts
+                self.status.error = Some(reason);
+                self.pause();
+                self.announce_status();
+            }
+            disk::Response::Written { piece, blocks, .. } => {
+                if let Some(pending) = self.unwritten.get_mut(&piece) {
+                    pending.retain(|b| !blocks.contains(b));
+                    if pending.is_empty() {
+                        self.unwritten.remove(&piece);
+                    }
+                }
+            }
+            disk::Response::FreeSpace(_) | disk::Response::CacheStats { .. } => unreachable!(),
+        }
+    }
+
+    fn ext_handshake(&self, addr: SocketAddr) -> Message {
+        let mut ed = BTreeMap::new();
+        let mut m = BTreeMap::new();
+
+        m.insert(
+            b"ut_metadata".to_vec(),
+            bencode::BEncode::Int(i64::from(UT_META_ID)),
+        );
+        if !self.info.private {
+            m.insert(
+                b"ut_pex".to_vec(),
+                bencode::BEncode::Int(i64::from(UT_PEX_ID)),
+            );
+            m.insert(
+                b"lt_tex".to_vec(),
+                bencode::BEncode::Int(i64::from(LT_TEX_ID)),
+            );
+            m.insert(
+                b"ut_holepunch".to_vec(),
+                bencode::BEncode::Int(i64::from(UT_HOLEPUNCH_ID)),
+            );
+        }
+        m.insert(
+            b"lt_donthave".to_vec(),
+            bencode::BEncode::Int(i64::from(LT_DONTHAVE_ID)),
+        );
+
+        ed.insert(b"m".to_vec(), bencode::BEncode::Dict(m));
+        ed.insert(
+            b"metadata_size".to_vec(),
+            bencode::BEncode::Int(self.info_bytes.len() as i64),
+        );
+        ed.insert(
+            b"upload_only".to_vec(),
+            bencode::BEncode::Int(self.partial_seed() as i64),
+        );
+        // We accept up to our upload queue limit, if there is one
+        let reqq = match CONFIG.peer.max_upload_queue {
+            0 => usize::from(peer::MAX_QUEUE_CAP),
+            max => cmp::min(max, usize::from(peer::MAX_QUEUE_CAP)),
+        };
+        ed.insert(b"reqq".to_vec(), bencode::BEncode::Int(reqq as i64));
+        ed.insert(
+            b"v".to_vec(),
+            bencode::BEncode::from_str(&CONFIG.peer.user_agent),
+        );
+        let port = PORT.load(atomic::Ordering::Relaxed);
+        ed.insert(b"p".to_vec(), bencode::BEncode::Int(i64::from(port)));
+        // IPv4 peers on a dual stack socket show up as mapped addresses
+        let yourip = match addr.ip().to_canonical() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        ed.insert(b"yourip".to_vec(), bencode::BEncode::String(yourip));
+        let payload = bencode::BEncode::Dict(ed).encode_to_buf();
+        Message::Extension { id: 0, payload }
+    }
+
+    /// Resends the extension handshake to peers, so they learn
+    /// of our new port or whether we've become a partial seed.
+    fn resend_ext_handshake(&mut self) {
+        let hs: Vec<_> = self
+            .peers
+            .values()
+            .filter(|p| p.ext_proto())
+            .map(|p| (p.id(), self.ext_handshake(p.addr())))
+            .collect();
+        for (pid, msg) in hs {
+            if let Some(peer) = self.peers.get_mut(&pid) {
+                peer.send_message(msg);
+            }
+        }
+    }
+
+    /// Has a piece downloaded again from scratch, forgetting about
+    /// blocks of it which are still being written.
+    fn invalidate_piece(&mut self, piece: u32) {
+        self.picker.invalidate_piece(piece);
+        self.unwritten.remove(&piece);
+    }
+
+    /// Tells peers that we no longer have a piece.
+    fn send_donthave(&mut self, piece: u32) {
+        for peer in self.peers.values_mut() {
+            peer.send_donthave(piece);
+        }
+    }
+
+    /// Makes newly completed files read-only if configured to, and
+    /// files which are no longer complete after a recheck writable again.
+    fn update_readonly(&mut self) {
+        if !CONFIG.disk.readonly_complete {
+            return;
+        }
+        let (complete, incomplete) = self.files.readonly_changes(&self.info);
+        for (idxs, readonly) in [(complete, true), (incomplete, false)] {
+            if idxs.is_empty() {
+                continue;
+            }
+            let files = idxs
+                .into_iter()
+                .map(|i| self.info.files[i].path.clone())
+                .collect();
+            self.cio
+                .msg_disk(disk::Request::readonly(files, self.path.clone(), readonly));
+        }
+    }
+
+    /// Computes the SHA-256 digests of the completed files, if
+    /// configured to, replacing any from a previous completion.
+    fn hash_files(&mut self) {
+        self.clear_digests();
+        if !CONFIG.disk.file_digests {
+            return;
+        }
+        let files = self
+            .info
+            .files
+            .iter()
+            .enumerate()
+            .filter(|&(i, f)| {
+                self.priorities[i] != 0
+                    && (f.length == 0
+                        || (self.info.piece_at(i, 0)..=self.info.piece_at(i, f.length - 1))
+                            .all(|p| self.pieces.has_bit(u64::from(p))))
+            })
+            .map(|(i, f)| (i, f.path.clone(), f.length))
+            .collect();
+        self.cio.msg_disk(disk::Request::hash_files(
+            self.id,
+            self.digest_generation,
+            files,
+            self.path.clone(),
+        ));
+    }
+
+    fn clear_digests(&mut self) {
+        self.digest_generation += 1;
+        if !self.file_digests.is_empty() {
+            self.file_digests.clear();
+            self.update_rpc_digests();
+        }
+    }
+
+    fn update_rpc_digests(&mut self) {
+        let updates = self
+            .info
+            .files
+            .iter()
+            .enumerate()
+            .map(|(i, f)| SResourceUpdate::FileDigest {
+                id: util::file_rpc_id(&self.info.hash, f.path.to_string_lossy().as_ref()),
+                kind: resource::ResourceKind::File,
+                sha256: self.file_digest(i),
+            })
+            .collect();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(updates));
+    }
+
+    fn file_digest(&self, file: usize) -> Option<String> {
+        self.file_digests
+            .get(file)
+            .copied()
+            .flatten()
+            .map(|d| util::to_hex(&d))
+    }
+
+    fn check_complete(&mut self) {
+        let mut complete = true;
         for piece in 0..self.pieces.len() {
             let no_dl = Info::piece_disk_locs(&self.info, piece as u32)
                 .all(|loc| self.priorities[loc.file] == 0);
@@ -859,13 +2007,16 @@ impl<T: cio::CIO> Torrent<T> {
                 self.picker.done();
                 self.set_finished();
                 self.serialize();
+                self.update_interest();
             }
         } else if self.status.state == StatusState::Complete {
             self.status.state = StatusState::Incomplete;
-            let seq = self.picker.is_sequential();
+            self.completed = None;
+            let mode = self.picker.mode();
             self.picker = Picker::new(&self.info, &self.pieces, &self.priorities);
-            self.change_picker(seq);
+            self.change_picker(mode);
             self.announce_status();
+            self.resend_ext_handshake();
             self.announce_start();
             self.request_all();
         }
@@ -874,14 +2025,28 @@ impl<T: cio::CIO> Torrent<T> {
     fn set_finished(&mut self) {
         info!("Torrent {} completed!", self.rpc_id());
         debug!("Wasted: {} MiB", (self.wasted * 16_384) / (1024 * 1024));
+        self.notify(
+            message::Severity::Info,
+            message::NotificationKind::TorrentCompleted,
+            format!("{} finished downloading", self.info.name),
+        );
+        self.completed = Some(Utc::now());
+        self.hash_files();
+        // Files are only moved once hashed, if they're being hashed
+        if !CONFIG.disk.file_digests {
+            self.move_complete();
+        }
         if let Some(req) = tracker::Request::completed(self) {
-            self.cio.msg_trk(req);
+            self.send_announce(req);
         }
         // Order here is important, if we're in an idle status,
         // rpc updates don't occur.
         self.update_rpc_transfer();
         self.status.state = StatusState::Complete;
         self.announce_status();
+        if self.partial_seed() {
+            self.resend_ext_handshake();
+        }
 
         // Remove all seeding peers.
         let leechers = &self.leechers;
@@ -893,6 +2058,7 @@ impl<T: cio::CIO> Torrent<T> {
                 .map(|(id, _)| *id);
             for seeder in seeders {
                 self.cio.remove_peer(seeder);
+                self.seed_seen = Some(Instant::now());
             }
         }
 
@@ -944,42 +2110,36 @@ impl<T: cio::CIO> Torrent<T> {
         match msg {
             Message::Handshake { rsv, .. } => {
                 if (rsv[EXT_PROTO.0] & EXT_PROTO.1) != 0 {
-                    let mut ed = BTreeMap::new();
-                    let mut m = BTreeMap::new();
-
-                    m.insert(
-                        b"ut_metadata".to_vec(),
-                        bencode::BEncode::Int(i64::from(UT_META_ID)),
-                    );
-                    if !self.info.private {
-                        m.insert(
-                            b"ut_pex".to_vec(),
-                            bencode::BEncode::Int(i64::from(UT_PEX_ID)),
-                        );
-                    }
-
-                    ed.insert(b"m".to_vec(), bencode::BEncode::Dict(m));
-                    ed.insert(
-                        b"metadata_size".to_vec(),
-                        bencode::BEncode::Int(self.info_bytes.len() as i64),
-                    );
-                    let payload = bencode::BEncode::Dict(ed).encode_to_buf();
-
-                    peer.send_message(Message::Extension { id: 0, payload });
+                    peer.send_message(self.ext_handshake(peer.addr()));
+                }
+                // Peers without pieces needn't send a bitfield
+                if peer.super_seeded() {
+                    self.reveal_piece(peer);
                 }
             }
             Message::Extension { id, payload } => {
                 self.handle_ext(id, payload, peer)?;
             }
-            Message::Bitfield(_) => {
-                if self.pieces.usable(peer.pieces()) && self.status.validating.is_none() {
+            Message::Bitfield(_) | Message::HaveAll | Message::HaveNone => {
+                if self.status.validating.is_none()
+                    && Torrent::interesting(peer, &self.pieces, &self.picker)
+                {
                     peer.interested();
                 }
                 if self.info.complete() {
                     self.picker.add_peer(peer);
                 }
-                if !peer.pieces().complete() {
+                if peer.pieces().complete() {
+                    self.seed_seen = Some(Instant::now());
+                }
+                if !peer.pieces().complete() && !peer.upload_only() {
                     self.leechers.insert(peer.id());
+                    let stale = peer
+                        .revealed()
+                        .is_none_or(|p| peer.pieces().has_bit(u64::from(p)));
+                    if peer.super_seeded() && stale {
+                        self.reveal_piece(peer);
+                    }
                 } else if self.complete() {
                     // Don't waste a connection on a peer if they're also a seeder
                     return Err(());
@@ -987,22 +2147,28 @@ impl<T: cio::CIO> Torrent<T> {
             }
             Message::Have(idx) => {
                 if self.info.complete() {
-                    self.picker.piece_available(idx);
+                    self.picker.piece_available(peer, idx);
                 }
                 if peer.pieces().complete() {
                     self.leechers.remove(&peer.id());
+                    self.seed_seen = Some(Instant::now());
                     // If they're now a seeder and we're also seeding, drop the conn
                     if self.complete() {
                         return Err(());
                     }
                 }
-                if self.pieces.usable(peer.pieces()) {
+                // Super seeded peers get another piece once they
+                // have the one they were told about
+                if peer.super_seeded() && peer.revealed() == Some(idx) {
+                    self.reveal_piece(peer);
+                }
+                if !self.pieces.has_bit(u64::from(idx)) && self.picker.priority(idx) != 0 {
                     peer.interested();
                 }
             }
             Message::Unchoke => {
                 if self.status.should_dl() && self.info.complete() {
-                    Torrent::make_requests(peer, &mut self.picker, &self.info);
+                    Torrent::make_requests(peer, &mut self.picker, &self.info, &self.pieces);
                 }
             }
             Message::Piece {
@@ -1040,11 +2206,7 @@ impl<T: cio::CIO> Torrent<T> {
 
                     picker.completed(Block::new(index, begin), |pid| {
                         if let Some(p) = peers.get_mut(&pid) {
-                            p.send_message(Message::Cancel {
-                                index,
-                                begin,
-                                length,
-                            })
+                            p.cancel_request(index, begin, length);
                         }
                     })
                 };
@@ -1071,7 +2233,7 @@ impl<T: cio::CIO> Torrent<T> {
                 }
 
                 if self.status.should_dl() {
-                    Torrent::make_requests(peer, &mut self.picker, &self.info);
+                    Torrent::make_requests(peer, &mut self.picker, &self.info, &self.pieces);
                 }
             }
             Message::Request {
@@ -1082,28 +2244,71 @@ impl<T: cio::CIO> Torrent<T> {
                 if !self.pieces.has_bit(u64::from(index)) {
                     return Err(());
                 }
-                if length != self.info.block_len(index, begin) {
+                let end = u64::from(begin) + u64::from(length);
+                if length == 0 || end > u64::from(self.info.piece_len(index)) {
                     return Err(());
                 }
-                if !self.status.stopped() {
-                    if let Some(buf) = Buffer::get() {
-                        self.request_read(peer.id(), index, begin, buf);
+                // Oversized requests are rejected, or just ignored
+                // if the peer doesn't support the fast extension
+                if length > cmp::min(CONFIG.peer.max_request, MAX_REQUEST_LEN) {
+                    debug!("Refusing request of {} bytes", length);
+                    peer.reject(index, begin, length);
+                    return Ok(());
+                }
+                let max_queue = CONFIG.peer.max_upload_queue;
+                if max_queue != 0 && peer.upload_queue() >= max_queue {
+                    debug!("{:?} has too many requests queued, rejecting", peer);
+                    peer.reject(index, begin, length);
+                    return Ok(());
+                }
+                // Only fast peers make it here while choked, and
+                // they're served pieces in their allowed fast set
+                if !self.status.stopped() && (!peer.choking() || peer.allowed_fast(index)) {
+                    if self.upload_capped(peer) {
+                        peer.defer(index, begin, length);
+                        return Ok(());
+                    }
+                    if let Some(buf) = Buffer::with_len(length as usize) {
+                        self.request_read(peer.id(), index, begin, length, buf);
+                        peer.read_started();
                         return Ok(());
                     }
                 }
 
                 // TODO: add this to a queue to fulfill later
+                peer.reject(index, begin, length);
+            }
+            Message::Reject { index, begin, .. } => {
+                self.picker.rejected(Block::new(index, begin), peer.id());
+                if self.status.should_dl() && self.info.complete() {
+                    Torrent::make_requests(peer, &mut self.picker, &self.info, &self.pieces);
+                }
             }
             Message::Interested => {
-                self.choker.add_peer(peer);
+                if self.status.paused {
+                    self.choker.add_choked(peer);
+                } else {
+                    self.choker.add_peer(peer);
+                }
             }
             Message::Uninterested => {
                 self.choker.remove_peer(peer, &mut self.peers);
             }
+            Message::HashRequest(req) => match self.info.piece_layer_hashes(&req) {
+                Some(hashes) => peer.send_message(Message::Hashes { req, hashes }),
+                None => peer.send_message(Message::HashReject(req)),
+            },
+            // Piece layers are part of the torrent file, so we never request hashes
+            Message::Hashes { .. } | Message::HashReject(_) => {}
 
             // These messages are all handled at the peer level, not the torrent level,
             // so just ignore here
-            Message::KeepAlive | Message::Choke | Message::Cancel { .. } | Message::Port(_) => {}
+            Message::KeepAlive
+            | Message::Choke
+            | Message::Cancel { .. }
+            | Message::Port(_)
+            | Message::Suggest(_)
+            | Message::AllowedFast(_) => {}
         }
         Ok(())
     }
@@ -1111,6 +2316,13 @@ impl<T: cio::CIO> Torrent<T> {
     fn handle_ext(&mut self, id: u8, payload: Vec<u8>, peer: &mut Peer<T>) -> Result<(), ()> {
         if id == 0 {
             const MAX_INFO_BYTES: i64 = 100 * 1000 * 1000;
+            if peer.upload_only() {
+                self.leechers.remove(&peer.id());
+                // Neither side will request anything from the other
+                if self.complete() {
+                    return Err(());
+                }
+            }
             let b = bencode::decode_buf(&payload).map_err(|_| ())?;
             let mut d = b.into_dict().ok_or(())?;
             let m = d
@@ -1151,6 +2363,9 @@ impl<T: cio::CIO> Torrent<T> {
                     });
                 }
             }
+            if let Some(tex) = peer.exts().lt_tex {
+                self.send_tex(tex, peer);
+            }
         } else if id == UT_META_ID {
             let utm_id = if let Some(i) = peer.exts().ut_meta {
                 i
@@ -1238,9 +2453,11 @@ impl<T: cio::CIO> Torrent<T> {
                                 ),
                             );
                             b.insert(b"info".to_vec(), bni);
-                            let ni =
+                            let mut ni =
                                 Info::from_bencode(bencode::BEncode::Dict(b)).map_err(|_| ())?;
                             if ni.hash == self.info.hash {
+                                ni.web_seeds = self.info.web_seeds.clone();
+                                ni.http_seeds = self.info.http_seeds.clone();
                                 debug!("Magnet file acquired succesfully!");
                                 self.info_idx = None;
                                 self.info = Arc::new(ni);
@@ -1268,8 +2485,6 @@ impl<T: cio::CIO> Torrent<T> {
                 }
             }
         } else if id == UT_PEX_ID {
-            const PEX_SEED: u8 = 0x02;
-            const PEX_OUTGOING: u8 = 0x10;
             if peer.exts().ut_pex.is_none() {
                 return Ok(());
             }
@@ -1279,22 +2494,53 @@ impl<T: cio::CIO> Torrent<T> {
             let b = bencode::decode_buf(&payload).map_err(|_| ())?;
             let mut d = b.into_dict().ok_or(())?;
             let mut peers = vec![];
-            let flags = d
-                .remove(b"added.f".as_ref())
-                .and_then(bencode::BEncode::into_bytes)
-                .unwrap_or_else(|| vec![0; 50]);
-            if let Some(bencode::BEncode::String(ref data)) = d.remove(b"added".as_ref()) {
-                for (p, flag) in data.chunks(6).zip(flags) {
+            let mut unreachable = vec![];
+            for &(key, fkey, len) in &[
+                (b"added".as_ref(), b"added.f".as_ref(), 6),
+                (b"added6".as_ref(), b"added6.f".as_ref(), 18),
+            ] {
+                let data = match d.remove(key).and_then(bencode::BEncode::into_bytes) {
+                    Some(data) => data,
+                    None => continue,
+                };
+                let flags = d
+                    .remove(fkey)
+                    .and_then(bencode::BEncode::into_bytes)
+                    .unwrap_or_default();
+                for (i, p) in data.chunks_exact(len).take(MAX_PEX_PEERS).enumerate() {
+                    let flag = flags.get(i).cloned().unwrap_or(0);
                     if (flag & PEX_SEED != 0) && self.complete() {
                         continue;
                     }
-                    if flag & PEX_OUTGOING == 0 {
-                        continue;
+                    let addr = if len == 6 {
+                        util::bytes_to_addr(p)
+                    } else {
+                        util::bytes_to_addr6(p)
+                    };
+                    let addr = match util::dialable(addr) {
+                        Some(addr) => addr,
+                        None => continue,
+                    };
+                    if flag & PEX_OUTGOING != 0 {
+                        peers.push(addr);
+                    } else if flag & PEX_HOLEPUNCH != 0 {
+                        unreachable.push(addr);
+                    }
+                }
+            }
+            // Peers which don't accept connections can still be
+            // introduced to us by the peer telling us about them
+            if let Some(id) = peer.exts().ut_holepunch {
+                let timeout = Duration::from_secs(RENDEZVOUS_TIMEOUT);
+                self.rendezvous.retain(|r| r.2.elapsed() < timeout);
+                for addr in unreachable.into_iter().take(MAX_RENDEZVOUS) {
+                    if !self.connected_to(addr) {
+                        peer.send_message(Message::Extension {
+                            id,
+                            payload: Holepunch::Rendezvous(addr).encode(),
+                        });
+                        self.rendezvous.push((peer.id(), addr, Instant::now()));
                     }
-
-                    let ip = Ipv4Addr::new(p[0], p[1], p[2], p[3]);
-                    let socket = SocketAddrV4::new(ip, BigEndian::read_u16(&p[4..]));
-                    peers.push(SocketAddr::V4(socket));
                 }
             }
             if !peers.is_empty() {
@@ -1304,15 +2550,130 @@ impl<T: cio::CIO> Torrent<T> {
                         peers,
                     })));
             }
+        } else if id == UT_HOLEPUNCH_ID {
+            if peer.exts().ut_holepunch.is_none() {
+                return Ok(());
+            }
+            if self.info.private {
+                return Err(());
+            }
+            match Holepunch::decode(&payload).ok_or(())? {
+                Holepunch::Rendezvous(addr) => self.rendezvous(peer, addr),
+                Holepunch::Connect(addr) => {
+                    // Only introductions we asked this peer for are
+                    // followed, addresses are otherwise the relay's choice
+                    let asked = self.take_rendezvous(peer.id(), addr);
+                    let addr = match util::dialable(addr) {
+                        Some(addr) if asked => addr,
+                        _ => return Ok(()),
+                    };
+                    if !self.connected_to(addr) {
+                        self.cio
+                            .propagate(cio::Event::Tracker(Ok(tracker::Response::PEX {
+                                tid: self.id,
+                                peers: vec![addr],
+                            })));
+                    }
+                }
+                Holepunch::Error(addr, err) => {
+                    debug!("Holepunch to {} failed with error {}", addr, err);
+                    self.take_rendezvous(peer.id(), addr);
+                }
+            }
+        } else if id == LT_TEX_ID {
+            if peer.exts().lt_tex.is_none() {
+                return Ok(());
+            }
+            if self.info.private {
+                return Err(());
+            }
+            let b = bencode::decode_buf(&payload).map_err(|_| ())?;
+            let mut d = b.into_dict().ok_or(())?;
+            if let Some(bencode::BEncode::List(added)) = d.remove(b"added".as_ref()) {
+                for url in added.into_iter().filter_map(bencode::BEncode::into_string) {
+                    self.add_tex_tracker(&url);
+                }
+            }
+        } else if id == LT_DONTHAVE_ID {
+            // The peer has already validated the piece and removed it from its bitfield
+            if self.info.complete() {
+                self.picker
+                    .piece_unavailable(peer, BigEndian::read_u32(&payload));
+            }
+            self.leechers.insert(peer.id());
         } else {
             debug!("Got unknown extension id: {}", id);
         }
         Ok(())
     }
 
-    /// Periodically called to update peers, choking the slowest one and
-    /// optimistically unchoking a new peer
+    /// Forgets the introduction to addr asked of the relay, returning
+    /// whether there was one. Relays answer with the address the peer
+    /// listens on, so only the IP has to match.
+    fn take_rendezvous(&mut self, relay: usize, addr: SocketAddr) -> bool {
+        let timeout = Duration::from_secs(RENDEZVOUS_TIMEOUT);
+        let ip = addr.ip().to_canonical();
+        match self.rendezvous.iter().position(|&(pid, a, at)| {
+            pid == relay && a.ip().to_canonical() == ip && at.elapsed() < timeout
+        }) {
+            Some(i) => {
+                self.rendezvous.swap_remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Introduces a peer to the one at addr, as its holepunch relay.
+    fn rendezvous(&mut self, initiator: &mut Peer<T>, addr: SocketAddr) {
+        let id = match initiator.exts().ut_holepunch {
+            Some(id) => id,
+            None => return,
+        };
+        let from = initiator.listen_addr();
+        let target = self
+            .peers
+            .values_mut()
+            .find(|p| p.listen_addr() == addr || p.addr() == addr);
+        let res = match target {
+            _ if util::dialable(from) == util::dialable(addr) => Err(holepunch::NO_SELF),
+            None => Err(holepunch::NO_SUCH_PEER),
+            Some(target) => match target.exts().ut_holepunch {
+                Some(tid) => {
+                    target.send_message(Message::Extension {
+                        id: tid,
+                        payload: Holepunch::Connect(from).encode(),
+                    });
+                    Ok(target.listen_addr())
+                }
+                None => Err(holepunch::NO_SUPPORT),
+            },
+        };
+        let msg = match res {
+            Ok(addr) => Holepunch::Connect(addr),
+            Err(err) => Holepunch::Error(addr, err),
+        };
+        initiator.send_message(Message::Extension {
+            id,
+            payload: msg.encode(),
+        });
+    }
+
+    /// Returns whether a peer at addr is already connected.
+    fn connected_to(&self, addr: SocketAddr) -> bool {
+        let addr = util::dialable(addr);
+        self.peers
+            .values()
+            .any(|p| util::dialable(p.listen_addr()) == addr || util::dialable(p.addr()) == addr)
+    }
+
+    /// Periodically called to update peers, swapping an unchoked peer
+    /// for another as picked by the configured choke strategy
     pub fn update_unchoked(&mut self) {
+        self.update_super_seed();
+        if self.status.paused {
+            return;
+        }
         if self.complete() {
             self.choker.update_download(&mut self.peers)
         } else {
@@ -1328,7 +2689,19 @@ impl<T: cio::CIO> Torrent<T> {
         }
 
         if let Some(p) = u.path {
-            self.set_path(p);
+            if !u.move_data.unwrap_or(true) {
+                if let Err(e) = self.relocate(p) {
+                    info!("Ignoring path update of {}: {}", self.rpc_id(), e);
+                }
+            } else if Path::new(&p) != Path::new(self.location()) {
+                if let Err(e) = self.set_path(p) {
+                    info!("Ignoring path update of {}: {}", self.rpc_id(), e);
+                }
+            }
+        }
+
+        if let Some(p) = u.complete_path {
+            self.set_complete_path(p);
         }
 
         if let Some(p) = u.priority {
@@ -1336,11 +2709,57 @@ impl<T: cio::CIO> Torrent<T> {
         }
 
         match u.strategy {
-            Some(resource::Strategy::Rarest) => self.change_picker(false),
-            Some(resource::Strategy::Sequential) => self.change_picker(true),
+            Some(resource::Strategy::Rarest) => self.change_picker(Mode::Rarest),
+            Some(resource::Strategy::Sequential) => self.change_picker(Mode::Sequential),
+            Some(resource::Strategy::Streaming) => {
+                let head = match self.picker.mode() {
+                    Mode::Streaming { head } => head,
+                    _ => 0,
+                };
+                self.change_picker(Mode::Streaming { head });
+            }
+            None => {}
+        }
+
+        if let Some(first_last) = u.first_last_pieces {
+            self.set_first_last(first_last);
+        }
+
+        if let Some(dht) = u.dht {
+            self.set_dht(dht);
+        }
+
+        match u.super_seed {
+            Some(resource::SuperSeed::Auto) => self.set_super_seed(None),
+            Some(resource::SuperSeed::Enabled) => self.set_super_seed(Some(true)),
+            Some(resource::SuperSeed::Disabled) => self.set_super_seed(Some(false)),
             None => {}
         }
 
+        if u.encryption.is_some() || u.proxy.is_some() {
+            let mut policy = self.policy;
+            match u.encryption {
+                Some(resource::Encryption::Default) => policy.encryption = None,
+                Some(resource::Encryption::Disabled) => {
+                    policy.encryption = Some(Encryption::Disabled)
+                }
+                Some(resource::Encryption::Enabled) => {
+                    policy.encryption = Some(Encryption::Enabled)
+                }
+                Some(resource::Encryption::Required) => {
+                    policy.encryption = Some(Encryption::Required)
+                }
+                None => {}
+            }
+            match u.proxy {
+                Some(resource::Proxy::Default) => policy.proxy = policy::Proxy::Default,
+                Some(resource::Proxy::Required) => policy.proxy = policy::Proxy::Required,
+                Some(resource::Proxy::Direct) => policy.proxy = policy::Proxy::Direct,
+                None => {}
+            }
+            self.set_policy(policy);
+        }
+
         if let Some(user_data) = u.user_data {
             let id = self.rpc_id();
             self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
@@ -1353,6 +2772,49 @@ impl<T: cio::CIO> Torrent<T> {
         }
     }
 
+    /// Moves the read head of a streaming torrent to offset bytes into
+    /// a file, so the pieces from there on are downloaded first.
+    pub fn set_read_head(&mut self, id: &str, offset: u64) -> Result<(), String> {
+        if !matches!(self.picker.mode(), Mode::Streaming { .. }) {
+            return Err("Torrent isn't streaming".to_owned());
+        }
+        let hash = &self.info.hash;
+        let file = self
+            .info
+            .files
+            .iter()
+            .position(|f| {
+                util::file_rpc_id(hash, f.path.as_path().to_string_lossy().as_ref()) == id
+            })
+            .ok_or_else(|| format!("File {} does not exist", id))?;
+        if offset >= self.info.files[file].length {
+            return Err("Offset is past the end of the file".to_owned());
+        }
+        self.picker.set_head(self.info.piece_at(file, offset));
+        Ok(())
+    }
+
+    /// Has pieces picked before any others until they're downloaded,
+    /// ordered by when they're due, deadline milliseconds from now, and
+    /// retrying their requests quickly. Passing the deadline changes
+    /// nothing further.
+    pub fn set_piece_deadline(
+        &mut self,
+        pieces: &[u32],
+        deadline: Option<u64>,
+    ) -> Result<(), String> {
+        if let Some(piece) = pieces.iter().find(|&&p| p >= self.info.pieces()) {
+            return Err(format!("Piece {} does not exist", piece));
+        }
+        let due = deadline.map(|ms| Instant::now() + Duration::from_millis(ms));
+        for &piece in pieces {
+            if !self.pieces.has_bit(u64::from(piece)) {
+                self.picker.set_deadline(piece, due);
+            }
+        }
+        Ok(())
+    }
+
     pub fn rpc_update_file(&mut self, id: String, priority: u8) {
         for (i, f) in self.info.files.iter().enumerate() {
             let fid =
@@ -1366,6 +2828,7 @@ impl<T: cio::CIO> Torrent<T> {
         self.clear_piece_cache();
 
         self.check_complete();
+        self.update_interest();
 
         self.dirty = true;
 
@@ -1381,15 +2844,38 @@ impl<T: cio::CIO> Torrent<T> {
     pub fn rpc_update_pieces(&mut self) {
         let id = self.rpc_id();
         let piece_field = self.pieces.b64();
+        let availability = self.piece_availability();
+        let availability = self.availability_update(availability);
         self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
             resource::SResourceUpdate::TorrentPieces {
                 id,
                 kind: resource::ResourceKind::Torrent,
                 piece_field,
             },
+            availability,
         ]));
     }
 
+    fn availability_update(&mut self, availability: (f32, Vec<u16>)) -> SResourceUpdate<'static> {
+        self.availability_sent = Some(availability.clone());
+        let (distributed_copies, piece_availability) = availability;
+        resource::SResourceUpdate::TorrentAvailability {
+            id: self.rpc_id(),
+            kind: resource::ResourceKind::Torrent,
+            distributed_copies,
+            piece_availability,
+        }
+    }
+
+    /// Distributed copies and availability ranges of the torrent's pieces.
+    fn piece_availability(&self) -> (f32, Vec<u16>) {
+        let counts = self.picker.piece_availability(self.peers.values());
+        (
+            availability::distributed_copies(&counts),
+            availability::ranges(&counts),
+        )
+    }
+
     fn start(&mut self, serialize: bool) {
         debug!("Starting torrent");
         // Update RPC of the torrent, tracker, files, and peers
@@ -1413,7 +2899,7 @@ impl<T: cio::CIO> Torrent<T> {
             return;
         }
         if let Some(req) = tracker::Request::started(self) {
-            self.cio.msg_trk(req);
+            self.send_announce(req);
             self.dump_torrent_file();
         }
         self.dht_announce();
@@ -1423,7 +2909,7 @@ impl<T: cio::CIO> Torrent<T> {
         if self.status.stopped() {
             return;
         }
-        if !self.info.private {
+        if self.uses_dht() {
             let mut req = tracker::Request::DHTAnnounce(self.info.hash);
             self.cio.msg_trk(req);
             req = tracker::Request::GetPeers(tracker::GetPeers {
@@ -1438,6 +2924,12 @@ impl<T: cio::CIO> Torrent<T> {
         self.status.completed()
     }
 
+    /// Whether all wanted pieces are done but deselected ones are
+    /// missing, so we'll only ever upload (BEP 21).
+    pub fn partial_seed(&self) -> bool {
+        self.complete() && !self.pieces.complete()
+    }
+
     fn set_throttle(&mut self, ul: Option<i64>, dl: Option<i64>) {
         self.throttle.set_ul_rate(ul);
         self.throttle.set_dl_rate(dl);
@@ -1480,75 +2972,409 @@ impl<T: cio::CIO> Torrent<T> {
             )]));
         self.serialize();
 
-        let seq = self.picker.is_sequential();
+        let mode = self.picker.mode();
         self.picker = Picker::new(&self.info, &self.pieces, &self.priorities);
-        self.change_picker(seq);
+        self.change_picker(mode);
         self.files = Files::new(&self.info, &self.pieces);
+        self.preallocate();
         self.validate();
         self.dump_torrent_file();
     }
 
-    fn set_path(&mut self, path: String) {
-        let from = if let Some(ref p) = self.path {
-            p.clone()
-        } else {
-            CONFIG.disk.directory.clone()
+    /// Creates the files which aren't skipped as configured by
+    /// disk.preallocate, so running out of space shows up early.
+    fn preallocate(&mut self) {
+        let full = match CONFIG.disk.preallocate {
+            Preallocate::Full => true,
+            Preallocate::Sparse => false,
+            Preallocate::None => return,
         };
+        let files = self
+            .info
+            .files
+            .iter()
+            .zip(self.priorities.iter())
+            .filter(|&(_, &pri)| pri != 0)
+            .map(|(f, _)| (f.path.clone(), f.length))
+            .collect();
+        self.cio.msg_disk(disk::Request::allocate(
+            self.id,
+            files,
+            self.path.clone(),
+            full,
+        ));
+    }
+
+    /// Moves the torrent's data to path. Checks in progress read from
+    /// the current directory, so moves are refused until they're done.
+    pub fn set_path(&mut self, path: String) -> Result<(), String> {
+        if self.moving {
+            return Err("Torrent is already moving".to_owned());
+        }
+        if self.checking() {
+            return Err("Torrent is being checked".to_owned());
+        }
+        let from = self.location().to_owned();
+        self.moving = true;
         self.cio.msg_disk(disk::Request::Move {
             tid: self.id,
             from,
             to: path,
             target: self.info.name.clone(),
         });
+        Ok(())
     }
 
-    fn set_priority(&mut self, priority: u8) {
-        self.priority = priority;
+    /// Whether the torrent's data is being checked, or queued to be.
+    fn checking(&self) -> bool {
+        self.status.validating.is_some() || self.deferred_check.is_some()
+    }
+
+    /// Directory the torrent's data is in.
+    pub fn location(&self) -> &str {
+        self.path.as_ref().unwrap_or(&CONFIG.disk.directory)
+    }
+
+    /// Directory the data is moved to once complete, if any. The
+    /// configured one only applies to torrents in directories chosen
+    /// by placement, not to those added with a path of their own.
+    fn complete_dir(&self) -> Option<&str> {
+        if let Some(ref p) = self.complete_path {
+            return Some(p);
+        }
+        let loc = Path::new(self.location());
+        let placed = loc == Path::new(&CONFIG.disk.directory)
+            || CONFIG.disk.roots.iter().any(|r| loc == Path::new(r));
+        CONFIG.disk.complete_directory.as_deref().filter(|_| placed)
+    }
+
+    /// Moves the data of a completed torrent out of the directory it
+    /// was downloaded in, unless it's already in place.
+    fn move_complete(&mut self) {
+        let dir = match self.complete_dir() {
+            Some(d) if Path::new(d) != Path::new(self.location()) => d.to_owned(),
+            _ => return,
+        };
+        if self.moving || self.checking() {
+            self.move_deferred = true;
+            return;
+        }
+        info!("Moving completed torrent {} to {}", self.rpc_id(), dir);
+        self.set_path(dir).ok();
+    }
+
+    /// Makes the move deferred while the torrent was being checked or moved.
+    fn resume_move(&mut self) {
+        if mem::take(&mut self.move_deferred) && self.complete() {
+            self.move_complete();
+        }
+    }
+
+    fn set_complete_path(&mut self, path: Option<String>) {
+        if path == self.complete_path {
+            return;
+        }
+        self.complete_path = path;
+        self.dirty = true;
         let id = self.rpc_id();
         self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
-            resource::SResourceUpdate::TorrentPriority {
+            resource::SResourceUpdate::TorrentCompletePath {
                 id,
                 kind: resource::ResourceKind::Torrent,
-                priority,
+                complete_path: self.complete_path.clone(),
             },
         ]));
+        if self.complete() {
+            self.move_complete();
+        }
     }
 
-    fn rpc_info(&self) -> resource::Resource {
-        let (name, size, pieces, piece_size, files) = if self.info_idx.is_none() {
-            (
-                Some(self.info.name.clone()),
-                Some(self.info.total_len),
-                Some(u64::from(self.info.pieces())),
-                Some(self.info.piece_len),
-                Some(self.info.files.len() as u32),
-            )
-        } else {
-            let name = if self.info.name == "" {
-                None
-            } else {
-                Some(self.info.name.clone())
-            };
-            (name, None, None, None, None)
-        };
-        Resource::Torrent(resource::Torrent {
-            id: self.rpc_id(),
-            name,
-            size,
-            // TODO: Properly add this
-            path: self.path.as_ref().unwrap_or(&CONFIG.disk.directory).clone(),
+    /// When the torrent was completed, if it is.
+    pub fn completed_at(&self) -> Option<DateTime<Utc>> {
+        self.completed
+    }
+
+    /// Dates torrents completed before completions were recorded by
+    /// when their data was last modified.
+    fn backfill_completed(&mut self) {
+        if self.completed.is_some() || self.status.state != StatusState::Complete {
+            return;
+        }
+        let dir = Path::new(self.location());
+        let modified = self
+            .info
+            .files
+            .iter()
+            .filter_map(|f| {
+                std::fs::metadata(dir.join(&f.path))
+                    .and_then(|m| m.modified())
+                    .ok()
+            })
+            .max();
+        self.completed = Some(modified.map_or_else(Utc::now, DateTime::from));
+        self.dirty = true;
+    }
+
+    pub fn moving(&self) -> bool {
+        self.moving
+    }
+
+    pub fn uses_dht(&self) -> bool {
+        self.dht && !self.info.private && self.policy.dht()
+    }
+
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    /// Changes the torrent's connection requirements, dropping peers
+    /// whose connections no longer meet them.
+    fn set_policy(&mut self, policy: Policy) {
+        if policy == self.policy {
+            return;
+        }
+        let dht = self.uses_dht();
+        self.policy = policy;
+        self.dirty = true;
+        let cio = &mut self.cio;
+        let refused: Vec<_> = self
+            .peers
+            .keys()
+            .copied()
+            .filter(|&pid| {
+                let encrypted = cio.get_peer(pid, |conn| conn.sock().encrypted());
+                encrypted.is_some_and(|e| !policy.accepts(e))
+            })
+            .collect();
+        for pid in refused {
+            debug!("Dropping peer {} refused by the new policy", pid);
+            self.cio.remove_peer(pid);
+        }
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            resource::SResourceUpdate::TorrentPolicy {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                encryption: self.rpc_encryption(),
+                proxy: self.rpc_proxy(),
+            },
+        ]));
+        if self.uses_dht() != dht {
+            self.update_rpc_dht();
+            self.dht_announce();
+        }
+    }
+
+    fn rpc_encryption(&self) -> resource::Encryption {
+        match self.policy.encryption {
+            None => resource::Encryption::Default,
+            Some(Encryption::Disabled) => resource::Encryption::Disabled,
+            Some(Encryption::Enabled) => resource::Encryption::Enabled,
+            Some(Encryption::Required) => resource::Encryption::Required,
+        }
+    }
+
+    fn rpc_proxy(&self) -> resource::Proxy {
+        match self.policy.proxy {
+            policy::Proxy::Default => resource::Proxy::Default,
+            policy::Proxy::Required => resource::Proxy::Required,
+            policy::Proxy::Direct => resource::Proxy::Direct,
+        }
+    }
+
+    fn set_dht(&mut self, dht: bool) {
+        if dht == self.dht {
+            return;
+        }
+        self.dht = dht;
+        self.dirty = true;
+        self.update_rpc_dht();
+        self.dht_announce();
+    }
+
+    /// Records peers found through the DHT.
+    pub fn dht_peers_found(&mut self, count: usize) {
+        self.dht_peers += count as u64;
+        self.update_rpc_dht();
+    }
+
+    /// Whether new peers are being super seeded.
+    pub fn super_seeding(&self) -> bool {
+        self.super_seeding
+    }
+
+    fn set_super_seed(&mut self, super_seed: Option<bool>) {
+        if super_seed == self.super_seed {
+            return;
+        }
+        self.super_seed = super_seed;
+        self.dirty = true;
+        self.update_super_seed();
+        self.update_rpc_super_seed();
+    }
+
+    /// Engages or stops super seeding according to the setting, or
+    /// whether we're the initial seed when automatic.
+    fn update_super_seed(&mut self) {
+        let engage = self.complete()
+            && match self.super_seed {
+                Some(s) => s,
+                None => CONFIG.peer.auto_super_seed && self.initial_seeding(),
+            };
+        if engage == self.super_seeding {
+            return;
+        }
+        info!(
+            "{} super seeding {}",
+            if engage { "Started" } else { "Stopped" },
+            self.info.name
+        );
+        self.super_seeding = engage;
+        if !engage {
+            let pieces = &self.pieces;
+            for peer in self.peers.values_mut() {
+                peer.unveil(pieces);
+            }
+        }
+        self.update_rpc_super_seed();
+    }
+
+    /// Whether we seem to be the only seed of a young swarm: no other seed
+    /// connected to us recently or was reported by trackers, not many
+    /// downloads were completed, and our peers lack a full copy between them.
+    fn initial_seeding(&self) -> bool {
+        if self
+            .seed_seen
+            .is_some_and(|s| s.elapsed() < Duration::from_secs(SEED_SEEN_SECS))
+        {
+            return false;
+        }
+        let crowded = self.trackers.iter().any(|t| {
+            t.seeders.is_some_and(|s| s >= 1)
+                || t.downloaded.is_some_and(|d| d > YOUNG_SWARM_DOWNLOADS)
+        });
+        if crowded || self.peers.is_empty() {
+            return false;
+        }
+        let mut swarm = Bitfield::new(self.pieces.len());
+        for peer in self.peers.values() {
+            for piece in peer.pieces().iter() {
+                swarm.set_bit(piece);
+            }
+        }
+        !swarm.complete()
+    }
+
+    /// Tells a super seeded peer about the next piece it may download:
+    /// the rarest it lacks, counting pieces revealed to other peers.
+    fn reveal_piece(&mut self, peer: &mut Peer<T>) {
+        let mut avail = vec![0u32; self.pieces.len() as usize];
+        for p in self.peers.values() {
+            for piece in p.pieces().iter() {
+                avail[piece as usize] += 1;
+            }
+            if let Some(piece) = p.revealed() {
+                avail[piece as usize] += 1;
+            }
+        }
+        let next = self
+            .pieces
+            .iter()
+            .filter(|&p| !peer.pieces().has_bit(p))
+            .min_by_key(|&p| avail[p as usize]);
+        if let Some(piece) = next {
+            peer.reveal(piece as u32);
+        }
+    }
+
+    fn update_rpc_super_seed(&mut self) {
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            resource::SResourceUpdate::TorrentSuperSeed {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                super_seed: self.rpc_super_seed(),
+                super_seeding: self.super_seeding,
+            },
+        ]));
+    }
+
+    fn rpc_super_seed(&self) -> resource::SuperSeed {
+        match self.super_seed {
+            None => resource::SuperSeed::Auto,
+            Some(true) => resource::SuperSeed::Enabled,
+            Some(false) => resource::SuperSeed::Disabled,
+        }
+    }
+
+    fn update_rpc_dht(&mut self) {
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            resource::SResourceUpdate::TorrentDht {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                dht: self.uses_dht(),
+                dht_peers: self.dht_peers,
+            },
+        ]));
+    }
+
+    fn set_priority(&mut self, priority: u8) {
+        self.priority = priority;
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            resource::SResourceUpdate::TorrentPriority {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                priority,
+            },
+        ]));
+    }
+
+    fn rpc_info(&self) -> resource::Resource {
+        let (name, size, pieces, piece_size, files) = if self.info_idx.is_none() {
+            (
+                Some(self.info.name.clone()),
+                Some(self.info.total_len),
+                Some(u64::from(self.info.pieces())),
+                Some(self.info.piece_len),
+                Some(self.info.files.len() as u32),
+            )
+        } else {
+            let name = if self.info.name == "" {
+                None
+            } else {
+                Some(self.info.name.clone())
+            };
+            (name, None, None, None, None)
+        };
+        let (distributed_copies, piece_availability) = self.piece_availability();
+        Resource::Torrent(resource::Torrent {
+            id: self.rpc_id(),
+            name,
+            size,
+            // TODO: Properly add this
+            path: self.path.as_ref().unwrap_or(&CONFIG.disk.directory).clone(),
+            complete_path: self.complete_path.clone(),
             created: self.created,
             modified: Utc::now(),
             status: self.status.as_rpc(self.stat.avg_ul(), self.stat.avg_dl()),
             error: self.error(),
+            check_position: self.check_position,
+            move_progress: self.move_progress,
             priority: self.priority,
             progress: self.progress(),
             availability: self.availability(),
-            strategy: if self.sequential() {
-                resource::Strategy::Sequential
-            } else {
-                resource::Strategy::Rarest
-            },
+            distributed_copies,
+            piece_availability,
+            strategy: self.strategy(),
+            first_last_pieces: self.first_last,
+            dht: self.uses_dht(),
+            dht_peers: self.dht_peers,
+            super_seed: self.rpc_super_seed(),
+            super_seeding: self.super_seeding,
+            encryption: self.rpc_encryption(),
+            proxy: self.rpc_proxy(),
             rate_up: 0,
             rate_down: 0,
             throttle_up: self.throttle.ul_rate(),
@@ -1599,6 +3425,7 @@ impl<T: cio::CIO> Torrent<T> {
                 priority: self.priorities[i],
                 path: self.info.files[i].path.to_string_lossy().into_owned(),
                 size: total,
+                sha256: self.file_digest(i),
                 ..Default::default()
             }))
         }
@@ -1621,6 +3448,15 @@ impl<T: cio::CIO> Torrent<T> {
                     url: trk.url.as_ref().clone(),
                     last_report: trk.last_announce,
                     error: None,
+                    announces: trk.announces,
+                    failures: trk.failures,
+                    response_time: trk.avg_response_time(),
+                    from_peer: trk.from_peer,
+                    last_announce: trk.last_success,
+                    last_error: trk.last_error.clone(),
+                    seeders: trk.seeders,
+                    leechers: trk.leechers,
+                    downloaded: trk.downloaded,
                     ..Default::default()
                 }))
             })
@@ -1650,8 +3486,12 @@ impl<T: cio::CIO> Torrent<T> {
         self.status.error.clone()
     }
 
-    fn sequential(&self) -> bool {
-        self.picker.is_sequential()
+    fn strategy(&self) -> resource::Strategy {
+        match self.picker.mode() {
+            Mode::Rarest => resource::Strategy::Rarest,
+            Mode::Sequential => resource::Strategy::Sequential,
+            Mode::Streaming { .. } => resource::Strategy::Streaming,
+        }
     }
 
     fn progress(&self) -> f32 {
@@ -1687,16 +3527,185 @@ impl<T: cio::CIO> Torrent<T> {
     /// Resets the last upload/download statistics, adjusting the internal
     /// status if nothing has been uploaded/downloaded in the interval.
     pub fn tick(&mut self) -> bool {
+        if self
+            .soft_paused
+            .is_some_and(|t| t.elapsed() >= Duration::from_secs(SOFT_PAUSE_GRACE))
+        {
+            debug!("Soft pause grace period over");
+            self.pause();
+        }
         self.stat.tick();
         let mut active = self.stat.active();
-        self.picker.tick();
+        for (b, pid) in self.picker.tick() {
+            // Webseeds fetch whole pieces and time out on their own
+            let peer = match self.peers.get_mut(&pid) {
+                Some(peer) => peer,
+                None => continue,
+            };
+            self.picker.withdraw(b, pid);
+            let len = self.info.block_len(b.index, b.offset);
+            if peer.request_timed_out(b.index, b.offset, len) {
+                debug!(
+                    "{:?} keeps timing out, requesting its blocks elsewhere",
+                    peer
+                );
+                for b in self.picker.cancel_peer(pid) {
+                    let len = self.info.block_len(b.index, b.offset);
+                    peer.cancel_request(b.index, b.offset, len);
+                }
+            }
+        }
 
         for (_, peer) in self.peers.iter_mut() {
             active |= peer.tick();
+            if peer.snub() {
+                debug!("{:?} snubbed us, requesting its blocks elsewhere", peer);
+                for b in self.picker.cancel_peer(peer.id()) {
+                    let len = self.info.block_len(b.index, b.offset);
+                    peer.cancel_request(b.index, b.offset, len);
+                }
+            }
         }
+        self.serve_deferred();
+        self.update_webseeds();
         active
     }
 
+    /// Whether a peer takes more than its share of our upload while
+    /// other peers are unchoked, so its requests should be held back.
+    fn upload_capped(&self, peer: &Peer<T>) -> bool {
+        let share = u64::from(CONFIG.peer.max_upload_share);
+        if share == 0 || share >= 100 {
+            return false;
+        }
+        let contended = self
+            .peers
+            .values()
+            .any(|p| p.id() != peer.id() && !p.choking());
+        contended && peer.get_tx_rates().0 * 100 > self.stat.avg_ul() * share
+    }
+
+    /// Serves the held back requests of peers no longer over their share.
+    fn serve_deferred(&mut self) {
+        let pids: Vec<_> = self
+            .peers
+            .values()
+            .filter(|p| p.has_deferred() && !self.upload_capped(p))
+            .map(|p| p.id())
+            .collect();
+        for pid in pids {
+            let mut peer = self.peers.remove(&pid).unwrap();
+            for (index, begin, length) in peer.take_deferred() {
+                match Buffer::with_len(length as usize) {
+                    Some(buf) if !self.status.stopped() => {
+                        self.request_read(pid, index, begin, length, buf);
+                        peer.read_started();
+                    }
+                    _ => peer.reject(index, begin, length),
+                }
+            }
+            self.peers.insert(pid, peer);
+        }
+    }
+
+    /// Starts fetching pieces from idle webseeds if too few peers
+    /// are able to provide us with data.
+    fn update_webseeds(&mut self) {
+        if self.webseeds.is_empty() || !self.status.should_dl() || !self.info.complete() {
+            return;
+        }
+        let sources = self
+            .peers
+            .values()
+            .filter(|p| !p.choked() && self.pieces.usable(p.pieces()))
+            .count();
+        if sources >= webseed::MIN_PEER_SOURCES {
+            return;
+        }
+        for idx in self.webseeds.idle() {
+            let piece = match self.picker.pick_whole(WebSeeds::id(idx)) {
+                Some(p) => p,
+                None => break,
+            };
+            debug!("Requesting piece {} from webseed", piece);
+            for req in self.webseeds.request(&self.info, self.id, idx, piece) {
+                self.cio.msg_trk(tracker::Request::WebSeed(req));
+            }
+        }
+    }
+
+    pub fn handle_webseed(
+        &mut self,
+        seed: usize,
+        piece: u32,
+        begin: u32,
+        resp: tracker::Result<Vec<u8>>,
+    ) {
+        let (index, data) = match self.webseeds.received(seed, piece, begin, resp) {
+            Some(r) => r,
+            None => return,
+        };
+        if self.pieces.has_bit(u64::from(index)) || self.validating.contains(&index) {
+            return;
+        }
+        let data = match data {
+            Ok(ref d) if !self.status.stopped() && !self.status.completed() => d,
+            _ => {
+                // Let peers pick up the blocks instead
+                for offset in (0..self.info.piece_len(index)).step_by(16_384) {
+                    self.picker.rejected(Block::new(index, offset), seed);
+                }
+                return;
+            }
+        };
+
+        let mut piece_done = false;
+        for (i, chunk) in data.chunks(16_384).enumerate() {
+            let begin = i as u32 * 16_384;
+            let length = chunk.len() as u32;
+            let block = Block::new(index, begin);
+            if self.picker.have_block(block) {
+                continue;
+            }
+            let mut buf = match Buffer::get() {
+                Some(b) => b,
+                None => {
+                    self.picker.rejected(block, seed);
+                    continue;
+                }
+            };
+            buf[..chunk.len()].copy_from_slice(chunk);
+
+            let pr = {
+                let peers = &mut self.peers;
+                self.picker.completed(block, |pid| {
+                    if let Some(p) = peers.get_mut(&pid) {
+                        p.cancel_request(index, begin, length);
+                    }
+                })
+            };
+            match pr {
+                Ok(done) => piece_done |= done,
+                Err(()) => continue,
+            }
+
+            self.dirty = true;
+            self.write_piece(index, begin, buf);
+            self.downloaded += u64::from(length);
+            self.stat.add_dl(u64::from(length));
+        }
+
+        if piece_done {
+            self.cio.msg_disk(disk::Request::validate_piece(
+                self.id,
+                self.info.clone(),
+                self.path.clone(),
+                index,
+            ));
+            self.validating.insert(index);
+        }
+    }
+
     pub fn get_last_tx_rate(&self) -> (u64, u64) {
         (self.stat.avg_ul(), self.stat.avg_dl())
     }
@@ -1705,15 +3714,25 @@ impl<T: cio::CIO> Torrent<T> {
     /// piece offset begin, piece length of len, and data bytes.
     /// The disk send handle is also provided.
     fn write_piece(&mut self, index: u32, begin: u32, data: Buffer) {
+        if CONFIG.disk.intent_log && self.intents.insert(index) {
+            self.cio
+                .msg_disk(disk::Request::intent(self.info.hash, index));
+        }
         let locs = Info::block_disk_locs_pri(&self.info, &self.priorities, index, begin);
-        self.cio
-            .msg_disk(disk::Request::write(self.id, data, locs, self.path.clone()));
+        self.unwritten.entry(index).or_default().push(begin);
+        self.cio.msg_disk(disk::Request::write(
+            self.id,
+            index,
+            begin,
+            data,
+            locs,
+            self.path.clone(),
+        ));
     }
 
     /// Issues a read request of the given torrent
-    fn request_read(&mut self, id: usize, index: u32, begin: u32, data: Buffer) {
-        let locs = Info::block_disk_locs(&self.info, index, begin);
-        let len = self.info.block_len(index, begin);
+    fn request_read(&mut self, id: usize, index: u32, begin: u32, len: u32, data: Buffer) {
+        let locs = Info::request_disk_locs(&self.info, index, begin, len);
         let ctx = disk::Ctx::new(id, self.id, index, begin, len);
         self.cio
             .msg_disk(disk::Request::read(ctx, data, locs, self.path.clone()));
@@ -1725,37 +3744,58 @@ impl<T: cio::CIO> Torrent<T> {
                 .peers
                 .get_mut(&pid)
                 .expect("Expected peer id not present");
-            Torrent::make_requests(peer, &mut self.picker, &self.info);
+            Torrent::make_requests(peer, &mut self.picker, &self.info, &self.pieces);
         }
     }
 
-    fn make_requests(peer: &mut Peer<T>, picker: &mut Picker, info: &Info) {
+    fn make_requests(peer: &mut Peer<T>, picker: &mut Picker, info: &Info, pieces: &Bitfield) {
         if let Some(m) = peer.queue_reqs() {
-            for _ in 0..(m) {
-                if let Some(block) = picker.pick(peer) {
-                    peer.request_piece(
-                        block.index,
-                        block.offset,
-                        info.block_len(block.index, block.offset),
-                    );
-                } else {
-                    break;
-                }
+            for block in picker.pick_blocks(peer, usize::from(m)) {
+                peer.request_piece(
+                    block.index,
+                    block.offset,
+                    info.block_len(block.index, block.offset),
+                );
             }
-        } else {
+        } else if Torrent::interesting(peer, pieces, picker) {
             peer.interested();
         }
     }
 
-    pub fn add_peer(&mut self, conn: PeerConn) -> Option<usize> {
-        if self.peers.len() >= MAX_PEERS {
+    /// Whether a peer has pieces we're missing which aren't skipped.
+    fn interesting(peer: &Peer<T>, pieces: &Bitfield, picker: &Picker) -> bool {
+        pieces.usable(peer.pieces())
+            && peer
+                .pieces()
+                .iter()
+                .any(|p| !pieces.has_bit(p) && picker.priority(p as u32) != 0)
+    }
+
+    /// Tells peers whether we're interested in them again, after the
+    /// pieces we want changed. Peers with nothing left for us can then
+    /// give their unchoke slots to others.
+    fn update_interest(&mut self) {
+        if self.status.validating.is_some() {
+            return;
+        }
+        for peer in self.peers.values_mut() {
+            if Torrent::interesting(peer, &self.pieces, &self.picker) {
+                peer.interested();
+            } else {
+                peer.uninterested();
+            }
+        }
+    }
+
+    pub fn add_peer(&mut self, conn: PeerConn, source: Source) -> Option<usize> {
+        if self.peers.len() >= MAX_PEERS || !self.policy.dials() {
             return None;
         }
         if self.peers.values().any(|p| p.addr() == conn.sock().addr()) {
             return None;
         }
         if let Ok(pid) = self.cio.add_peer(conn) {
-            if let Ok(p) = Peer::new(pid, self, None, None) {
+            if let Ok(p) = Peer::new(pid, self, None, None, source) {
                 if self.info_idx.is_none() {
                     self.picker.add_peer(&p);
                 }
@@ -1767,12 +3807,19 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     pub fn add_inc_peer(&mut self, pid: usize, id: [u8; 20], rsv: [u8; 8]) -> Option<usize> {
-        if let Some(addr) = self.cio.get_peer(pid, |pconn| pconn.sock().addr()) {
+        if let Some((addr, encrypted)) = self
+            .cio
+            .get_peer(pid, |pconn| (pconn.sock().addr(), pconn.sock().encrypted()))
+        {
             if self.peers.values().any(|p| p.addr() == addr) {
                 return None;
             }
+            if !self.policy.accepts(encrypted) {
+                debug!("{:?}: Refusing peer {:?} by policy", self.rpc_id(), pid);
+                return None;
+            }
         }
-        if let Ok(p) = Peer::new(pid, self, Some(id), Some(rsv)) {
+        if let Ok(p) = Peer::new(pid, self, Some(id), Some(rsv), Source::Incoming) {
             debug!("{:?}: Adding peer {:?}!", self.rpc_id(), pid);
             if self.info_idx.is_none() {
                 self.picker.add_peer(&p);
@@ -1791,38 +3838,60 @@ impl<T: cio::CIO> Torrent<T> {
                 kind: resource::ResourceKind::Torrent,
                 error: self.status.error.clone(),
                 status: self.status.as_rpc(self.stat.avg_ul(), self.stat.avg_dl()),
+                check_position: self.check_position,
+                move_progress: self.move_progress,
             },
         ]));
     }
 
+    /// Sends the peer count and swarm availability, along with the
+    /// availability of each piece if it changed since it was last sent.
     pub fn update_rpc_peers(&mut self) {
         let availability = self.availability();
         let id = self.rpc_id();
-        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
-            SResourceUpdate::TorrentPeers {
-                id,
-                kind: resource::ResourceKind::Torrent,
-                peers: self.peers.len() as u16,
-                availability,
-            },
-        ]));
+        let mut updates = vec![SResourceUpdate::TorrentPeers {
+            id,
+            kind: resource::ResourceKind::Torrent,
+            peers: self.peers.len() as u16,
+            availability,
+        }];
+        let pieces = self.piece_availability();
+        if self.availability_sent.as_ref() != Some(&pieces) {
+            updates.push(self.availability_update(pieces));
+        }
+        self.cio.msg_rpc(rpc::CtlMessage::Update(updates));
     }
 
     pub fn update_rpc_tracker(&mut self) {
         let updates = self
             .trackers
             .iter()
-            .map(|tracker| {
+            .enumerate()
+            .map(|(i, tracker)| {
                 let id = util::trk_rpc_id(&self.info.hash, tracker.url.as_str());
                 let error = match tracker.status {
                     TrackerStatus::Failure(ref r) => Some(r.clone()),
                     _ => None,
                 };
+                let current = i == self.tracker_idx && !tracker.dead && !self.status.stopped();
                 SResourceUpdate::TrackerStatus {
                     id,
                     kind: resource::ResourceKind::Tracker,
                     last_report: tracker.last_announce,
                     error,
+                    announces: tracker.announces,
+                    failures: tracker.failures,
+                    response_time: tracker.avg_response_time(),
+                    last_announce: tracker.last_success,
+                    next_announce: if current {
+                        tracker.next_announce()
+                    } else {
+                        None
+                    },
+                    last_error: tracker.last_error.clone(),
+                    seeders: tracker.seeders,
+                    leechers: tracker.leechers,
+                    downloaded: tracker.downloaded,
                 }
             })
             .collect();
@@ -1883,28 +3952,65 @@ impl<T: cio::CIO> Torrent<T> {
 
     pub fn pause(&mut self) {
         debug!("Pausing torrent!");
-        if !self.status.paused {
-            debug!("Sending stopped request to trk");
-            if let Some(req) = tracker::Request::stopped(self) {
-                self.cio.msg_trk(req);
-            }
+        if self.soft_paused.take().is_some() {
+            self.announce_stopped();
+        } else if !self.status.paused {
+            self.announce_stopped();
             self.status.paused = true;
             self.announce_status();
         }
     }
 
+    /// Stops transfers while staying connected to peers and listed
+    /// by trackers, so a resume within the grace period picks up
+    /// right where the torrent left off.
+    pub fn soft_pause(&mut self) {
+        debug!("Soft pausing torrent!");
+        if self.status.paused {
+            return;
+        }
+        self.status.paused = true;
+        self.soft_paused = Some(Instant::now());
+        // Peers stay interested, so they keep us unchoked meanwhile
+        self.choker.choke_all(&mut self.peers);
+        self.announce_status();
+    }
+
+    /// Tells the current tracker and every other one which we
+    /// successfully announced to that we're leaving the swarm.
+    fn announce_stopped(&mut self) {
+        debug!("Sending stopped requests to trk");
+        let urls: Vec<_> = self
+            .trackers
+            .iter()
+            .enumerate()
+            .filter(|&(i, t)| !t.dead && (i == self.tracker_idx || t.last_success.is_some()))
+            .map(|(_, t)| t.url.clone())
+            .collect();
+        for url in urls {
+            if let Some(req) = tracker::Request::custom(self, url, Some(tracker::Event::Stopped)) {
+                self.send_announce(req);
+            }
+        }
+    }
+
     pub fn resume(&mut self) {
         debug!("Resuming torrent!");
         if self.status.error.is_some() || self.status.paused {
             if self.status.error.is_some() {
                 self.status.error = None;
             }
-            if self.status.paused {
+            // Trackers were never told a soft paused torrent stopped
+            if self.soft_paused.take().is_none() && self.status.paused {
                 debug!("Sending started request to trk");
                 if let Some(req) = tracker::Request::started(self) {
-                    self.cio.msg_trk(req);
+                    self.send_announce(req);
                 }
+            }
+            if self.status.paused {
                 self.status.paused = false;
+                // Peers which got interested while paused were left choked
+                self.choker.unchoke_all(&mut self.peers);
             }
             self.request_all();
             self.announce_status();
@@ -1913,15 +4019,258 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     pub fn validate(&mut self) {
+        self.check_generation += 1;
         self.cio.msg_disk(disk::Request::validate(
             self.id,
+            self.check_generation,
             self.info.clone(),
             self.path.clone(),
+            self.priority,
         ));
+        // A full check supersedes any background one
+        self.deferred_check = None;
         self.status.validating = Some(0.0);
         self.announce_status();
     }
 
+    /// Checks the torrent in the background without interrupting
+    /// transfers, trusting the current pieces until it's done.
+    fn deferred_validate(&mut self) {
+        if self.pieces.iter().next().is_none() {
+            return;
+        }
+        self.deferred_check = Some(self.pieces.clone());
+        self.check_generation += 1;
+        self.cio.msg_disk(disk::Request::validate(
+            self.id,
+            self.check_generation,
+            self.info.clone(),
+            self.path.clone(),
+            0,
+        ));
+    }
+
+    /// Points the torrent at data which was moved to path outside of
+    /// synapse. The first and last pieces of every file are checked in
+    /// the background, and should any of them fail the whole torrent is
+    /// checked.
+    pub fn relocate(&mut self, path: String) -> Result<(), String> {
+        if self.moving {
+            return Err("Torrent is being moved".to_owned());
+        }
+        if self.status.validating.is_some() || self.deferred_check.is_some() {
+            return Err("Torrent is being checked".to_owned());
+        }
+        info!("Relocating torrent {} to {}", self.rpc_id(), path);
+        self.path = Some(path.clone());
+        self.dirty = true;
+        // Permissions are reapplied to the files at their new path
+        self.files.readonly.clear();
+        self.update_readonly();
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            resource::SResourceUpdate::TorrentPath {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                path,
+            },
+        ]));
+        // Errors are most likely from the files having gone missing
+        if self.status.error.take().is_some() {
+            self.request_all();
+            self.announce_status();
+            self.dht_announce();
+        }
+        self.quick_validate();
+        Ok(())
+    }
+
+    fn quick_validate(&mut self) {
+        let mut sample: Vec<_> = self
+            .info
+            .files
+            .iter()
+            .enumerate()
+            .filter(|&(i, f)| self.priorities[i] != 0 && f.length != 0)
+            .flat_map(|(i, f)| {
+                [
+                    self.info.piece_at(i, 0),
+                    self.info.piece_at(i, f.length - 1),
+                ]
+            })
+            .filter(|&p| self.pieces.has_bit(u64::from(p)))
+            .collect();
+        if sample.is_empty() {
+            return;
+        }
+        sample.sort_unstable();
+        sample.dedup();
+        self.quick_check = true;
+        self.deferred_check = Some(self.pieces.clone());
+        self.check_generation += 1;
+        self.cio.msg_disk(disk::Request::validate_pieces(
+            self.id,
+            self.check_generation,
+            self.info.clone(),
+            self.path.clone(),
+            self.priority,
+            sample,
+        ));
+    }
+
+    /// Rechecks the pieces which were being written when the torrent
+    /// was last shut down uncleanly, as a background check.
+    fn validate_suspect(&mut self) {
+        let mut suspect: Vec<_> = self
+            .intents
+            .iter()
+            .cloned()
+            .filter(|&p| self.pieces.has_bit(u64::from(p)))
+            .collect();
+        if suspect.is_empty() {
+            return;
+        }
+        info!(
+            "Rechecking {} pieces of {} written before an unclean shutdown",
+            suspect.len(),
+            self.rpc_id()
+        );
+        suspect.sort_unstable();
+        self.deferred_check = Some(self.pieces.clone());
+        self.check_generation += 1;
+        self.cio.msg_disk(disk::Request::validate_pieces(
+            self.id,
+            self.check_generation,
+            self.info.clone(),
+            self.path.clone(),
+            self.priority,
+            suspect,
+        ));
+    }
+
+    /// Syncs the files of pieces completed since the last checkpoint,
+    /// dropping those pieces from the intent log.
+    pub fn checkpoint(&mut self) {
+        // Suspect pieces stay logged until their check is done
+        let checking = self.deferred_check.is_some();
+        let (pending, done): (Vec<u32>, Vec<u32>) = self
+            .intents
+            .iter()
+            .partition(|&&p| checking || !self.pieces.has_bit(u64::from(p)));
+        if done.is_empty() {
+            return;
+        }
+        let mut files: Vec<_> = done
+            .iter()
+            .flat_map(|&p| Info::piece_disk_locs(&self.info, p).map(|loc| loc.file))
+            .collect();
+        files.sort_unstable();
+        files.dedup();
+        let files = files
+            .into_iter()
+            .map(|f| self.info.files[f].path.clone())
+            .collect();
+        self.intents = pending.iter().cloned().collect();
+        self.cio.msg_disk(disk::Request::checkpoint(
+            self.id,
+            self.info.hash,
+            files,
+            self.path.clone(),
+            pending,
+        ));
+    }
+
+    /// Checks that the files holding downloaded data are still there, and
+    /// no shorter than that data.
+    pub fn watch_files(&mut self) {
+        if self.status.stopped() || self.moving || self.status.validating.is_some() {
+            return;
+        }
+        let files: Vec<_> = self
+            .info
+            .files
+            .iter()
+            .zip(&self.files.done)
+            .filter(|&(_, &done)| done != 0)
+            .map(|(f, &done)| (f.path.clone(), done))
+            .collect();
+        if files.is_empty() {
+            return;
+        }
+        self.cio
+            .msg_disk(disk::Request::watch(self.id, files, self.path.clone()));
+    }
+
+    /// Takes up pieces of wanted files which a full check found valid
+    /// but weren't had, e.g. as the data was changed externally.
+    fn gain_valid(&mut self, invalid: &[u32]) {
+        for piece in 0..self.info.pieces() {
+            if self.pieces.has_bit(u64::from(piece))
+                || invalid.binary_search(&piece).is_ok()
+                || Info::piece_disk_locs(&self.info, piece)
+                    .all(|loc| self.priorities[loc.file] == 0)
+            {
+                continue;
+            }
+            self.pieces.set_bit(u64::from(piece));
+            self.picker.have_piece(piece);
+            let m = Message::Have(piece);
+            for pid in &self.leechers {
+                if let Some(peer) = self.peers.get_mut(pid) {
+                    if !peer.pieces().has_bit(u64::from(piece)) {
+                        peer.send_message(m.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    fn deferred_validated(&mut self, invalid: Vec<u32>) {
+        self.check_position = None;
+        let trusted = match self.deferred_check.take() {
+            Some(t) => t,
+            None => return,
+        };
+        let quick = mem::take(&mut self.quick_check);
+        // Pieces gained during the check were verified as they arrived
+        let damaged: Vec<_> = invalid
+            .into_iter()
+            .filter(|&p| trusted.has_bit(u64::from(p)))
+            .collect();
+        if damaged.is_empty() {
+            debug!("Background check of {} succeeded", self.rpc_id());
+            self.announce_status();
+            return;
+        }
+        if quick {
+            info!(
+                "Relocated data of {} failed its check, checking it fully",
+                self.rpc_id()
+            );
+            self.validate();
+            return;
+        }
+        info!(
+            "Background check of {} found {} damaged pieces",
+            self.rpc_id(),
+            damaged.len()
+        );
+        for piece in damaged {
+            if self.pieces.has_bit(u64::from(piece)) {
+                self.send_donthave(piece);
+            }
+            self.invalidate_piece(piece);
+            self.pieces.unset_bit(u64::from(piece));
+        }
+        self.check_complete();
+        self.files.rebuild(&self.info, &self.pieces);
+        self.update_readonly();
+        self.update_rpc_transfer();
+        self.rpc_update_pieces();
+        self.announce_status();
+        self.request_all();
+    }
+
     pub fn num_peers(&self) -> usize {
         self.peers.len()
     }
@@ -1931,35 +4280,35 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     pub fn update_pex(&mut self, added: &[SocketAddr], removed: &[SocketAddr]) {
-        let mut a = vec![];
-        let mut a6 = vec![];
-        let mut r = vec![];
-        let mut r6 = vec![];
-        for addr in added {
-            match &addr {
-                SocketAddr::V4(addr) => {
-                    a.extend(&addr.ip().octets());
-                }
-                SocketAddr::V6(addr) => {
-                    a6.extend(&addr.ip().octets());
-                }
-            }
+        if self.info.private {
+            return;
         }
-        for addr in removed {
-            match &addr {
-                SocketAddr::V4(addr) => {
-                    r.extend(&addr.ip().octets());
-                }
-                SocketAddr::V6(addr) => {
-                    r6.extend(&addr.ip().octets());
-                }
-            }
+        let flags: FHashMap<_, _> = self
+            .peers
+            .values()
+            .filter_map(|p| Some((util::dialable(p.listen_addr())?, p.pex_flags())))
+            .collect();
+        let (mut a, mut af, mut a6, mut a6f) = (vec![], vec![], vec![], vec![]);
+        for addr in added.iter().filter_map(|&addr| util::dialable(addr)) {
+            let (list, f) = if addr.is_ipv4() {
+                (&mut a, &mut af)
+            } else {
+                (&mut a6, &mut a6f)
+            };
+            util::append_compact(&addr, list);
+            f.push(flags.get(&addr).cloned().unwrap_or(0));
+        }
+        let (mut d, mut d6) = (vec![], vec![]);
+        for addr in removed.iter().filter_map(|&addr| util::dialable(addr)) {
+            util::append_compact(&addr, if addr.is_ipv4() { &mut d } else { &mut d6 });
         }
         let mut dict = BTreeMap::new();
         dict.insert(b"added".to_vec(), BEncode::String(a));
+        dict.insert(b"added.f".to_vec(), BEncode::String(af));
         dict.insert(b"added6".to_vec(), BEncode::String(a6));
-        dict.insert(b"removed".to_vec(), BEncode::String(r));
-        dict.insert(b"removed6".to_vec(), BEncode::String(r6));
+        dict.insert(b"added6.f".to_vec(), BEncode::String(a6f));
+        dict.insert(b"dropped".to_vec(), BEncode::String(d));
+        dict.insert(b"dropped6".to_vec(), BEncode::String(d6));
         let payload = BEncode::Dict(dict).encode_to_buf();
 
         for peer in self.peers.values_mut() {
@@ -2000,32 +4349,46 @@ impl<T: cio::CIO> Torrent<T> {
         self.peers.keys().cloned().collect()
     }
 
-    pub fn change_picker(&mut self, sequential: bool) {
+    pub fn change_picker(&mut self, mode: Mode) {
         debug!("Swapping pickers!");
-        let prev_seq = self.picker.is_sequential();
-        self.picker.change_picker(sequential);
+        let prev = self.strategy();
+        self.picker.change_picker(mode);
         for peer in self.peers.values() {
             self.picker.add_peer(peer);
         }
+        self.picker.set_first_last(self.first_last);
         self.picker.set_priorities(&self.priorities, &self.info);
-        let id = self.rpc_id();
-        let sequential = self.picker.is_sequential();
         self.clear_piece_cache();
-        if prev_seq != sequential {
-            self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
-                SResourceUpdate::TorrentPicker {
-                    id,
-                    kind: resource::ResourceKind::Torrent,
-                    strategy: if sequential {
-                        resource::Strategy::Sequential
-                    } else {
-                        resource::Strategy::Rarest
-                    },
-                },
-            ]));
+        if prev != self.strategy() {
+            self.dirty = true;
+            self.update_rpc_picker();
         }
     }
 
+    fn set_first_last(&mut self, first_last: bool) {
+        if first_last == self.first_last {
+            return;
+        }
+        self.first_last = first_last;
+        self.dirty = true;
+        self.picker.set_first_last(first_last);
+        self.picker.set_priorities(&self.priorities, &self.info);
+        self.clear_piece_cache();
+        self.update_rpc_picker();
+    }
+
+    fn update_rpc_picker(&mut self) {
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentPicker {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                strategy: self.strategy(),
+                first_last_pieces: self.first_last,
+            },
+        ]));
+    }
+
     fn clear_piece_cache(&mut self) {
         for peer in self.peers.values_mut() {
             peer.piece_cache().clear();
@@ -2051,11 +4414,449 @@ impl<T: cio::CIO> Drop for Torrent<T> {
             trace!("Removing peer {:?}", peer);
             self.leechers.remove(&id);
         }
-        if !self.status.paused {
-            if let Some(msg) = tracker::Request::stopped(self) {
-                self.cio.msg_trk(msg);
-            }
+        if !self.status.paused || self.soft_paused.is_some() {
+            self.announce_stopped();
         }
         self.send_rpc_removal();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::cio::test::TCIO;
+
+    fn info(pieces: usize) -> Info {
+        let mut info = Info::with_pieces(pieces);
+        info.piece_idx = Info::generate_piece_idx(pieces, 16_384, &info.files);
+        info
+    }
+
+    /// Creates a started torrent of empty pieces which has been checked.
+    fn torrent(pieces: usize) -> Torrent<TCIO> {
+        let info = info(pieces);
+        let mut t = Torrent::new(0, None, info, Throttle::test(0), TCIO::new(), true, false);
+        checked(&mut t, (0..pieces as u32).collect());
+        t
+    }
+
+    /// Completes the check last started.
+    fn checked(t: &mut Torrent<TCIO>, invalid: Vec<u32>) {
+        let generation = t.check_generation;
+        t.handle_disk_resp(disk::Response::validation_complete(0, generation, invalid));
+    }
+
+    fn connect(t: &mut Torrent<TCIO>, id: usize, pieces: Bitfield, msg: Message) {
+        let mut peer = Peer::test_from_pieces(id, pieces);
+        assert!(t.handle_msg(msg, &mut peer).is_ok());
+        t.peers.insert(id, peer);
+    }
+
+    fn finish(t: &mut Torrent<TCIO>) {
+        for piece in 0..t.pieces.len() as u32 {
+            t.handle_disk_resp(disk::Response::PieceValidated {
+                tid: 0,
+                piece,
+                valid: true,
+            });
+        }
+        assert!(t.complete());
+    }
+
+    fn moves(t: &mut Torrent<TCIO>) -> Vec<String> {
+        t.cio
+            .take_disk_msgs()
+            .into_iter()
+            .filter_map(|r| match r {
+                disk::Request::Move { to, .. } => Some(to),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_move_during_move() {
+        let mut t = torrent(4);
+        t.set_path("/elsewhere".to_owned()).unwrap();
+        t.complete_path = Some("/complete".to_owned());
+        finish(&mut t);
+        assert_eq!(moves(&mut t), vec!["/elsewhere".to_owned()]);
+
+        // The completion move follows once the first is done
+        t.handle_disk_resp(disk::Response::moved(
+            0,
+            "./".to_owned(),
+            "/elsewhere".to_owned(),
+        ));
+        assert_eq!(moves(&mut t), vec!["/complete".to_owned()]);
+        t.handle_disk_resp(disk::Response::moved(
+            0,
+            "/elsewhere".to_owned(),
+            "/complete".to_owned(),
+        ));
+        assert!(moves(&mut t).is_empty());
+
+        // A new complete path applies to complete torrents right away
+        t.set_complete_path(Some("/other".to_owned()));
+        assert_eq!(moves(&mut t), vec!["/other".to_owned()]);
+    }
+
+    #[test]
+    fn test_move_during_check() {
+        let mut t = torrent(4);
+        t.pieces.set_bit(0);
+        t.deferred_validate();
+        assert!(t.set_path("/elsewhere".to_owned()).is_err());
+
+        // Completing during the check moves the data once it's done
+        t.complete_path = Some("/complete".to_owned());
+        finish(&mut t);
+        assert!(moves(&mut t).is_empty());
+        checked(&mut t, vec![]);
+        assert_eq!(moves(&mut t), vec!["/complete".to_owned()]);
+    }
+
+    #[test]
+    fn test_superseded_check() {
+        let mut t = torrent(4);
+        for piece in 0..3 {
+            t.pieces.set_bit(piece);
+        }
+        t.deferred_validate();
+        let background = t.check_generation;
+        t.validate();
+
+        // The background check finishing late isn't taken for the full one
+        t.handle_disk_resp(disk::Response::validation_complete(0, background, vec![0]));
+        t.handle_disk_resp(disk::Response::ValidationUpdate {
+            tid: 0,
+            generation: background,
+            percent: 1.0,
+        });
+        assert_eq!(t.status.validating, Some(0.0));
+        assert!(t.pieces.has_bit(0));
+
+        checked(&mut t, vec![1, 3]);
+        assert_eq!(t.status.validating, None);
+        assert!(t.pieces.has_bit(0));
+        assert!(!t.pieces.has_bit(1));
+    }
+
+    #[test]
+    fn test_unwritten_invalidated() {
+        let mut t = torrent(4);
+        for piece in 0..3 {
+            t.unwritten.insert(piece, vec![0]);
+        }
+        t.handle_disk_resp(disk::Response::PieceValidated {
+            tid: 0,
+            piece: 0,
+            valid: false,
+        });
+        assert!(!t.unwritten.contains_key(&0));
+
+        t.pieces.set_bit(1);
+        t.pieces.set_bit(2);
+        t.deferred_validate();
+        checked(&mut t, vec![1]);
+        assert!(!t.unwritten.contains_key(&1));
+        assert!(t.unwritten.contains_key(&2));
+    }
+
+    #[test]
+    fn test_piece_map() {
+        let mut info = Info::with_pieces(4);
+        let len = info.total_len;
+        info.files = ["a", "b"]
+            .iter()
+            .zip(&[20_000, len - 20_000])
+            .map(|(path, &length)| info::File {
+                path: PathBuf::from(path),
+                length,
+                pieces_root: None,
+            })
+            .collect();
+        info.piece_idx = Info::generate_piece_idx(4, 16_384, &info.files);
+        let t = Torrent::new(0, None, info, Throttle::test(0), TCIO::new(), false, false);
+        let ids: Vec<_> = ["a", "b"]
+            .iter()
+            .map(|f| util::file_rpc_id(&t.info.hash, f))
+            .collect();
+
+        let map = t.piece_map(&[1], &ids).unwrap();
+        let spans: Vec<_> = map.pieces[0]
+            .files
+            .iter()
+            .map(|s| (s.id.as_str(), s.offset, s.length))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                (ids[0].as_str(), 16_384, 20_000 - 16_384),
+                (ids[1].as_str(), 0, 32_768 - 20_000),
+            ]
+        );
+        let ends: Vec<_> = map
+            .files
+            .iter()
+            .map(|f| (f.first_piece, f.last_piece))
+            .collect();
+        assert_eq!(ends, vec![(Some(0), Some(1)), (Some(1), Some(3))]);
+
+        assert!(t.piece_map(&[4], &[]).is_err());
+        assert!(t.piece_map(&[], &["nope".to_owned()]).is_err());
+    }
+
+    /// Pieces of the checks sent to the disk thread, None for full ones.
+    fn checks(t: &mut Torrent<TCIO>) -> Vec<Option<Vec<u32>>> {
+        t.cio
+            .take_disk_msgs()
+            .into_iter()
+            .filter_map(|r| match r {
+                disk::Request::Validate { pieces, .. } => Some(pieces),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_relocate() {
+        let mut t = torrent(4);
+        for piece in 0..4 {
+            t.pieces.set_bit(piece);
+        }
+        t.status.error = Some("Files are missing".to_owned());
+        t.cio.take_disk_msgs();
+        t.relocate("/found".to_owned()).unwrap();
+        assert_eq!(t.status.error, None);
+        assert_eq!(t.path.as_deref(), Some("/found"));
+        // Only the ends of the file are sampled
+        assert_eq!(checks(&mut t), vec![Some(vec![0, 3])]);
+        assert!(t.relocate("/other".to_owned()).is_err());
+        checked(&mut t, vec![]);
+        assert!(checks(&mut t).is_empty());
+        assert_eq!(t.status.validating, None);
+
+        // A failed sample has the whole torrent checked
+        t.relocate("/other".to_owned()).unwrap();
+        assert_eq!(checks(&mut t), vec![Some(vec![0, 3])]);
+        checked(&mut t, vec![3]);
+        assert_eq!(checks(&mut t), vec![None]);
+        assert_eq!(t.status.validating, Some(0.0));
+        assert!(t.deferred_check.is_none());
+    }
+
+    #[test]
+    fn test_recheck_gains_pieces() {
+        let mut t = torrent(4);
+        t.pieces.set_bit(0);
+        t.pieces.set_bit(1);
+        // Piece 3 was put in place outside of synapse, 1 was damaged
+        t.validate();
+        checked(&mut t, vec![1, 2]);
+        let have: Vec<_> = (0..4).map(|p| t.pieces.has_bit(p)).collect();
+        assert_eq!(have, vec![true, false, false, true]);
+        assert_eq!(t.status.validating, None);
+    }
+
+    #[test]
+    fn test_suspect_rechecked() {
+        let mut t = torrent(4);
+        t.pieces.set_bit(0);
+        t.pieces.set_bit(1);
+        t.intents = [1, 2].iter().cloned().collect();
+        t.cio.take_disk_msgs();
+        t.validate_suspect();
+        // Only pieces the resume data claims are worth checking
+        assert_eq!(checks(&mut t), vec![Some(vec![1])]);
+
+        checked(&mut t, vec![1]);
+        assert!(t.pieces.has_bit(0));
+        assert!(!t.pieces.has_bit(1));
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let mut t = torrent(4);
+        t.pieces.set_bit(0);
+        t.intents = [0, 2].iter().cloned().collect();
+        t.cio.take_disk_msgs();
+        t.checkpoint();
+        let pending: Vec<_> = t
+            .cio
+            .take_disk_msgs()
+            .into_iter()
+            .filter_map(|r| match r {
+                disk::Request::Checkpoint { files, pending, .. } => {
+                    assert_eq!(files.len(), 1);
+                    Some(pending)
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(pending, vec![vec![2]]);
+        assert_eq!(t.intents.iter().cloned().collect::<Vec<_>>(), vec![2]);
+
+        // Nothing more to do until another piece completes
+        t.checkpoint();
+        assert!(t.cio.take_disk_msgs().is_empty());
+    }
+
+    #[test]
+    fn test_tier_reset() {
+        let mut t = torrent(4);
+        for (tier, host) in ["a", "b"].iter().enumerate() {
+            let url = Url::parse(&format!("http://{}.example.org/announce", host)).unwrap();
+            t.trackers
+                .push_back(Tracker::new(Arc::new(url), tier as u32));
+        }
+        // Failed over to the second tier, which is still backing off
+        t.tracker_idx = 1;
+        t.trackers[1].retries = 1;
+        t.trackers[1].update = Some(Instant::now());
+        t.try_update_tracker();
+        assert_eq!(t.tracker_idx, 1);
+        assert!(t.trackers[0].sent.is_none());
+
+        // Its regular interval passing starts over from the first tier
+        t.trackers[1].retries = 0;
+        t.trackers[1].last_success = Some(Utc::now());
+        t.try_update_tracker();
+        assert_eq!(t.tracker_idx, 0);
+        assert!(t.trackers[0].sent.is_some());
+    }
+
+    #[test]
+    fn test_backfill_completed() {
+        let dir = std::env::temp_dir().join(format!("synapse-backfill-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut info = info(1);
+        info.files[0].path = PathBuf::from("a");
+        std::fs::write(dir.join("a"), b"data").unwrap();
+        let modified = std::fs::metadata(dir.join("a"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        let mut t = Torrent::new(0, None, info, Throttle::test(0), TCIO::new(), false, false);
+        t.path = Some(dir.to_string_lossy().into_owned());
+
+        t.backfill_completed();
+        assert_eq!(t.completed_at(), None);
+        t.status.state = StatusState::Complete;
+        t.backfill_completed();
+        assert_eq!(t.completed_at(), Some(DateTime::from(modified)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Piece availability sent over RPC since last asked.
+    fn availability_sent(t: &mut Torrent<TCIO>) -> Vec<Vec<u16>> {
+        let mut sent = Vec::new();
+        for msg in t.cio.take_rpc_msgs() {
+            if let rpc::CtlMessage::Update(updates) = msg {
+                for u in updates {
+                    if let SResourceUpdate::TorrentAvailability {
+                        piece_availability, ..
+                    } = u
+                    {
+                        sent.push(piece_availability);
+                    }
+                }
+            }
+        }
+        sent
+    }
+
+    #[test]
+    fn test_availability_updates() {
+        let mut t = torrent(4);
+        availability_sent(&mut t);
+        t.rpc_update_pieces();
+        let before = availability_sent(&mut t);
+        assert_eq!(before.len(), 1);
+        t.update_rpc_peers();
+        assert!(availability_sent(&mut t).is_empty());
+
+        // Peers coming and going change it
+        let mut pieces = Bitfield::new(4);
+        pieces.set_bit(1);
+        connect(&mut t, 1, pieces, Message::Bitfield(Bitfield::new(4)));
+        t.update_rpc_peers();
+        let sent = availability_sent(&mut t);
+        assert_eq!(sent.len(), 1);
+        assert_ne!(sent[0], before[0]);
+        t.update_rpc_peers();
+        assert!(availability_sent(&mut t).is_empty());
+    }
+
+    #[test]
+    fn test_auto_super_seed() {
+        let full = || {
+            let mut b = Bitfield::new(4);
+            for i in 0..4 {
+                b.set_bit(i);
+            }
+            b
+        };
+
+        // The only seed of a swarm super seeds
+        let mut t = torrent(4);
+        connect(&mut t, 1, Bitfield::new(4), Message::HaveNone);
+        finish(&mut t);
+        t.update_super_seed();
+        assert!(t.super_seeding());
+
+        // Having downloaded from a seed, we aren't the initial seed
+        let mut t = torrent(4);
+        connect(&mut t, 1, Bitfield::new(4), Message::HaveNone);
+        connect(&mut t, 2, full(), Message::HaveAll);
+        finish(&mut t);
+        t.peers.remove(&2);
+        t.update_super_seed();
+        assert!(!t.super_seeding());
+
+        // Nor with a tracker reporting another seed
+        let mut t = torrent(4);
+        let url = Url::parse("http://tracker.example.org/announce").unwrap();
+        let mut trk = Tracker::new(Arc::new(url), 0);
+        trk.seeders = Some(1);
+        t.trackers.push_back(trk);
+        connect(&mut t, 1, Bitfield::new(4), Message::HaveNone);
+        finish(&mut t);
+        t.update_super_seed();
+        assert!(!t.super_seeding());
+    }
+
+    #[test]
+    fn test_backoff() {
+        let url = Url::parse("http://tracker.example.org/announce").unwrap();
+        let mut trk = Tracker::new(Arc::new(url), 0);
+        let mut wait = |hint: Option<u64>| {
+            trk.backoff(hint.map(Duration::from_secs));
+            let left = trk
+                .update
+                .unwrap()
+                .saturating_duration_since(Instant::now());
+            left.as_secs() + 1
+        };
+        assert_eq!(wait(None), 300);
+        assert_eq!(wait(None), 600);
+        // Hints only ever extend the wait
+        assert_eq!(wait(Some(60)), 1200);
+        assert_eq!(wait(Some(7200)), 7200);
+        for _ in 0..20 {
+            wait(None);
+        }
+        assert_eq!(wait(None), MAX_TRACKER_BACKOFF);
+        assert_eq!(wait(Some(u64::from(u32::MAX))), MAX_TRACKER_RETRY);
+    }
+
+    #[test]
+    fn test_responded() {
+        let url = Url::parse("http://tracker.example.org/announce").unwrap();
+        let mut trk = Tracker::new(Arc::new(url), 0);
+        trk.responded(false);
+        trk.responded(true);
+        trk.responded(true);
+        assert_eq!((trk.announces, trk.failures), (1, 2));
+    }
+}
@@ -1,23 +1,29 @@
 pub mod reader;
 pub mod writer;
 
-use std::net::SocketAddr;
+use std::collections::{BTreeMap, VecDeque};
 use std::net::TcpStream;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
 use std::{cmp, fmt, io, mem, time};
 
+use byteorder::{BigEndian, ByteOrder};
+
 pub use self::message::Message;
 use self::reader::{RRes, Reader};
 use self::writer::Writer;
 use crate::bencode;
+use crate::config::Encryption;
 use crate::control::cio;
 use crate::rpc::{self, resource};
-use crate::socket::Socket;
+use crate::socket::{mse, Socket};
 use crate::stat;
 use crate::throttle::Throttle;
+use crate::torrent::swarm::{self, Source};
 use crate::torrent::{Bitfield, Info, Torrent};
 use crate::tracker;
 use crate::util;
-use crate::{CONFIG, DHT_EXT, PEER_ID};
+use crate::{CONFIG, DHT_EXT, EXT_PROTO, FAST_EXT, LT_DONTHAVE_ID, PEER_ID};
 
 error_chain! {
     errors {
@@ -29,7 +35,25 @@ error_chain! {
 }
 
 const INIT_MAX_QUEUE: u16 = 5;
-const MAX_QUEUE_CAP: u16 = 600;
+/// Fewest requests kept queued with an active peer
+const MIN_QUEUE: u16 = 2;
+/// Seconds of data which requests are kept queued for, so the pipeline
+/// doesn't run dry between a block arriving and the next request
+const PIPELINE_SECS: f64 = 3.;
+/// Seconds without a requested block arriving after which a peer
+/// is considered to be snubbing us
+const SNUB_SECS: u64 = 60;
+/// Requests timing out in a row after which a peer is considered
+/// to be snubbing us
+const SNUB_TIMEOUTS: u8 = 3;
+/// Number of pieces a fast peer may request while choked (BEP 6)
+const ALLOWED_FAST: usize = 10;
+pub const MAX_QUEUE_CAP: u16 = 600;
+/// PEX flags (BEP 11) for seeds, peers supporting holepunching,
+/// and peers which accept connections
+pub const PEX_SEED: u8 = 0x02;
+pub const PEX_HOLEPUNCH: u8 = 0x08;
+pub const PEX_OUTGOING: u8 = 0x10;
 
 pub mod message {
     use crate::buffers;
@@ -50,25 +74,67 @@ pub struct Peer<T: cio::CIO> {
     local_status: Status,
     /// Current number of queued requests
     queued: u16,
+    /// Requests which no longer count as queued, as (index, begin), but
+    /// which a peer without the fast extension may still answer
+    cancelled: Vec<(u32, u32)>,
     /// Maximum number of requests that can be queued
     /// at a time.
     max_queue: u16,
+    /// Smoothed round trip time of requests sent while none were queued
+    rtt: Option<time::Duration>,
+    /// When the request measuring the round trip time was sent
+    rtt_probe: Option<time::Instant>,
+    /// When a block last arrived, or requests were last sent while
+    /// none were queued
+    last_block: time::Instant,
+    /// Whether blocks stopped arriving, in which case only one
+    /// request is kept queued until one does
+    snubbed: bool,
+    /// Requests which timed out since a block last arrived
+    timeouts: u8,
     pieces_updated: bool,
     tid: usize,
     downloaded: u32,
     uploaded: u32,
+    /// Bytes of piece data sent over the connection
+    sent: u64,
+    /// Requests of the peer being read from disk
+    reading: u16,
+    /// Requests held back while the peer takes more than its share
+    /// of the upload, as (index, begin, length)
+    deferred: VecDeque<(u32, u32, u32)>,
     stat: stat::EMA,
     addr: SocketAddr,
     t_hash: [u8; 20],
     cid: Option<[u8; 20]>,
     rsv: Option<[u8; 8]>,
+    /// Whether or not we've told the peer which pieces we have
+    pieces_sent: bool,
     ext_ids: ExtIDs,
+    /// The peer is a partial seed which won't request pieces (BEP 21)
+    upload_only: bool,
+    /// Maximum number of requests the peer accepts at a time
+    reqq: Option<u16>,
+    /// Port the peer accepts connections on, if it told us
+    listen_port: Option<u16>,
+    /// Whether we connected to the peer, so it's known to accept connections
+    outgoing: bool,
+    source: Source,
+    /// Pieces the peer may request from us while choked
+    allowed_fast: Vec<u32>,
+    /// The peer is only told about pieces one at a time (BEP 16)
+    super_seeded: bool,
+    /// Pieces revealed to a super seeded peer, the last one most recently
+    revealed: Vec<u32>,
     pub rank: usize,
 }
 
 pub struct ExtIDs {
     pub ut_meta: Option<u8>,
     pub ut_pex: Option<u8>,
+    pub lt_donthave: Option<u8>,
+    pub lt_tex: Option<u8>,
+    pub ut_holepunch: Option<u8>,
 }
 
 #[derive(Debug)]
@@ -128,12 +194,42 @@ impl PeerConn {
 
     /// Creates a peer where we are acting as the server.
     /// Once the handshake is received, set_torrent should be called.
-    pub fn new_incoming(sock: TcpStream) -> io::Result<PeerConn> {
-        Ok(PeerConn::new(Socket::from_stream(sock)?))
+    /// Nothing past the handshake is read until the connection is accepted.
+    /// Unless encryption is disabled, the handshake may be MSE encrypted
+    /// for any of the torrents in skeys.
+    pub fn new_incoming(
+        sock: TcpStream,
+        skeys: &Arc<mse::SKeys>,
+        encryption: Encryption,
+    ) -> io::Result<PeerConn> {
+        let mut sock = Socket::from_stream(sock)?;
+        match encryption {
+            Encryption::Disabled => {}
+            Encryption::Enabled => sock.expect_handshake(skeys.clone(), false),
+            Encryption::Required => sock.expect_handshake(skeys.clone(), true),
+        }
+        let mut conn = PeerConn::new(sock);
+        conn.reader = Reader::new_held();
+        Ok(conn)
+    }
+
+    /// Accepts an incoming connection once its handshake has been
+    /// validated, resuming reads.
+    pub fn accept(&mut self) -> io::Result<()> {
+        self.sock.tune()?;
+        self.reader.resume();
+        Ok(())
     }
 
     pub fn writable(&mut self) -> io::Result<()> {
         self.last_action = time::Instant::now();
+        // Encrypted data may be buffered even if the writer is idle
+        match self.sock.flush_out() {
+            Err(ref e) if e.kind() != io::ErrorKind::WouldBlock => {
+                return Err(io::Error::new(e.kind(), "Failed to send data"));
+            }
+            _ => {}
+        }
         self.writer.writable(&mut self.sock)
     }
 
@@ -176,19 +272,37 @@ impl Peer<cio::test::TCIO> {
             local_status: Status::new(),
             uploaded,
             downloaded,
+            sent: u64::from(uploaded) * 16_384,
+            reading: 0,
+            deferred: VecDeque::new(),
+            cancelled: Vec::new(),
             stat: stat::EMA::new(),
             addr: "127.0.0.1:0".parse().unwrap(),
             cio: cio::test::TCIO::new(),
             queued,
             max_queue: queued,
+            rtt: None,
+            rtt_probe: None,
+            last_block: time::Instant::now(),
+            snubbed: false,
+            timeouts: 0,
             pieces,
             piece_cache: Vec::new(),
             piece_count,
             tid: 0,
             t_hash: [0u8; 20],
             rsv: None,
+            pieces_sent: false,
             cid: None,
             ext_ids: ExtIDs::new(),
+            upload_only: false,
+            reqq: None,
+            listen_port: None,
+            outgoing: false,
+            source: Source::Incoming,
+            allowed_fast: Vec::new(),
+            super_seeded: false,
+            revealed: Vec::new(),
             pieces_updated: false,
             rank: 0,
         }
@@ -219,6 +333,7 @@ impl<T: cio::CIO> Peer<T> {
         t: &mut Torrent<T>,
         cid: Option<[u8; 20]>,
         rsv: Option<[u8; 8]>,
+        source: Source,
     ) -> cio::Result<Peer<T>> {
         let throttle = t.get_throttle(0);
         let addr = Peer::setup_conn(&mut t.cio, id, throttle)?;
@@ -229,24 +344,62 @@ impl<T: cio::CIO> Peer<T> {
             local_status: Status::new(),
             uploaded: 0,
             downloaded: 0,
+            sent: 0,
+            reading: 0,
+            deferred: VecDeque::new(),
+            cancelled: Vec::new(),
             stat: stat::EMA::new(),
             cio: t.cio.new_handle(),
             queued: 0,
             max_queue: INIT_MAX_QUEUE,
+            rtt: None,
+            rtt_probe: None,
+            last_block: time::Instant::now(),
+            snubbed: false,
+            timeouts: 0,
             pieces: Bitfield::new(t.info.hashes.len() as u64),
             piece_cache: Vec::new(),
             piece_count: 0,
             tid: t.id,
             t_hash: t.info.hash,
             rsv,
+            pieces_sent: false,
             cid,
             ext_ids: ExtIDs::new(),
+            upload_only: false,
+            reqq: None,
+            listen_port: None,
+            outgoing: cid.is_none(),
+            source,
+            allowed_fast: Vec::new(),
+            super_seeded: t.super_seeding(),
+            revealed: Vec::new(),
             pieces_updated: false,
             rank: t.num_peers(),
         };
         p.send_message(Message::handshake(&*PEER_ID, &t.info.hash));
-        if t.info.complete() {
+        if p.super_seeded {
+            // Pieces are revealed once the peer tells us what it has
+            if p.fast_ext() {
+                p.send_message(Message::HaveNone);
+                p.pieces_sent = true;
+            }
+        } else if p.fast_ext() {
+            // The first message after the handshake must describe our pieces
+            if !t.info.complete() || t.pieces.set() == 0 {
+                p.send_message(Message::HaveNone);
+            } else if t.pieces.complete() {
+                p.send_message(Message::HaveAll);
+            } else {
+                p.send_message(Message::Bitfield(t.pieces.clone()));
+            }
+            p.pieces_sent = true;
+        } else if t.info.complete() {
             p.send_message(Message::Bitfield(t.pieces.clone()));
+            p.pieces_sent = true;
+        }
+        if t.info.complete() {
+            p.send_allowed_fast(t.info.pieces());
         }
         p.send_rpc_info();
         Ok(p)
@@ -266,18 +419,108 @@ impl<T: cio::CIO> Peer<T> {
 
     pub fn magnet_complete(&mut self, info: &Info) -> Result<()> {
         if self.pieces.len() == 0 {
-            self.pieces = Bitfield::new(u64::from(info.pieces()));
+            // A HaveAll received before the metadata leaves an empty complete bitfield
+            self.pieces = if self.pieces.complete() {
+                Bitfield::full(u64::from(info.pieces()))
+            } else {
+                Bitfield::new(u64::from(info.pieces()))
+            };
+            self.piece_count = self.pieces.set() as usize;
         } else if !self.pieces.cap(u64::from(info.pieces())) {
             return Err(ErrorKind::ProtocolError("Invalid pieces size").into());
         }
+        self.send_allowed_fast(info.pieces());
         Ok(())
     }
 
+    /// Sends the peer its allowed fast set once the fast extension
+    /// is negotiated and the number of pieces is known.
+    fn send_allowed_fast(&mut self, pieces: u32) {
+        if !self.fast_ext() || pieces == 0 || !self.allowed_fast.is_empty() || self.super_seeded {
+            return;
+        }
+        // The canonical set is only defined for IPv4 peers
+        if let IpAddr::V4(ip) = self.addr.ip().to_canonical() {
+            self.allowed_fast = allowed_fast_set(ip, &self.t_hash, pieces, ALLOWED_FAST);
+            for idx in self.allowed_fast.clone() {
+                self.send_message(Message::AllowedFast(idx));
+            }
+        }
+    }
+
+    /// Returns whether the peer may request piece while choked
+    pub fn allowed_fast(&self, piece: u32) -> bool {
+        self.allowed_fast.contains(&piece)
+    }
+
+    pub fn super_seeded(&self) -> bool {
+        self.super_seeded
+    }
+
+    /// The piece most recently revealed to a super seeded peer.
+    pub fn revealed(&self) -> Option<u32> {
+        self.revealed.last().cloned()
+    }
+
+    /// Tells a super seeded peer about a piece, which a fast peer may
+    /// also download while choked.
+    pub fn reveal(&mut self, piece: u32) {
+        self.revealed.push(piece);
+        self.send_message(Message::Have(piece));
+        if self.fast_ext() && !self.allowed_fast.contains(&piece) {
+            self.allowed_fast.push(piece);
+            self.send_message(Message::AllowedFast(piece));
+        }
+    }
+
+    /// Stops super seeding the peer, telling it about the rest of pieces.
+    pub fn unveil(&mut self, pieces: &Bitfield) {
+        if !self.super_seeded {
+            return;
+        }
+        self.super_seeded = false;
+        let revealed = mem::take(&mut self.revealed);
+        for piece in pieces.iter() {
+            if !self.pieces.has_bit(piece) && !revealed.contains(&(piece as u32)) {
+                self.send_message(Message::Have(piece as u32));
+            }
+        }
+    }
+
     /// Returns whether or not the peer has received a handshake
     pub fn ready(&self) -> bool {
         self.cid.is_some()
     }
 
+    /// Returns whether or not the fast extension was negotiated
+    pub fn fast_ext(&self) -> bool {
+        self.rsv
+            .map(|rsv| (rsv[FAST_EXT.0] & FAST_EXT.1) != 0)
+            .unwrap_or(false)
+    }
+
+    /// Returns whether or not the extension protocol was negotiated
+    pub fn ext_proto(&self) -> bool {
+        self.rsv
+            .map(|rsv| (rsv[EXT_PROTO.0] & EXT_PROTO.1) != 0)
+            .unwrap_or(false)
+    }
+
+    /// Returns whether or not the peer said it will only upload
+    pub fn upload_only(&self) -> bool {
+        self.upload_only
+    }
+
+    /// Returns whether or not we're choking the peer
+    pub fn choking(&self) -> bool {
+        self.local_status.choked
+    }
+
+    /// Returns whether or not the peer is choking us
+    pub fn choked(&self) -> bool {
+        self.remote_status.choked
+    }
+
     pub fn exts(&self) -> &ExtIDs {
         &self.ext_ids
     }
@@ -286,10 +529,73 @@ impl<T: cio::CIO> Peer<T> {
         self.addr
     }
 
+    /// Returns the address the peer can be connected to at, which
+    /// differs from its address for incoming connections.
+    pub fn listen_addr(&self) -> SocketAddr {
+        let mut addr = self.addr;
+        if let Some(port) = self.listen_port {
+            addr.set_port(port);
+        }
+        addr
+    }
+
+    /// Returns the flags describing the peer in PEX messages (BEP 11).
+    pub fn pex_flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.upload_only || self.pieces.complete() {
+            flags |= PEX_SEED;
+        }
+        if self.ext_ids.ut_holepunch.is_some() {
+            flags |= PEX_HOLEPUNCH;
+        }
+        if self.outgoing {
+            flags |= PEX_OUTGOING;
+        }
+        flags
+    }
+
     pub fn pieces(&self) -> &Bitfield {
         &self.pieces
     }
 
+    /// Describes the peer for GET_SWARM_SNAPSHOT.
+    pub fn snapshot(&self, encrypted: bool) -> swarm::PeerEntry {
+        let (rate_up, rate_down) = self.get_tx_rates();
+        swarm::PeerEntry {
+            id: util::peer_rpc_id(&self.t_hash, self.id as u64),
+            addr: self.addr.to_string(),
+            listen_addr: if self.outgoing || self.listen_port.is_some() {
+                Some(self.listen_addr().to_string())
+            } else {
+                None
+            },
+            client_id: self.cid.map(|cid| util::hash_to_id(&cid[..])),
+            source: self.source,
+            rate_up,
+            rate_down,
+            sent: self.sent,
+            pieces: self.piece_count as u64,
+            availability: self.piece_count as f32 / self.pieces.len() as f32,
+            flags: swarm::Flags {
+                choking: self.local_status.choked,
+                choked: self.remote_status.choked,
+                interested: self.remote_status.interested,
+                interesting: self.local_status.interested,
+                snubbed: self.snubbed,
+                seed: self.pieces.complete(),
+                upload_only: self.upload_only,
+                outgoing: self.outgoing,
+                encrypted,
+                fast_ext: self.fast_ext(),
+                ext_proto: self.ext_proto(),
+                super_seeded: self.super_seeded,
+            },
+            queued: self.queued,
+            max_queue: self.max_queue,
+            rtt_ms: self.rtt.map(|rtt| rtt.as_millis() as u64),
+        }
+    }
+
     pub fn piece_cache(&mut self) -> &mut Vec<u32> {
         &mut self.piece_cache
     }
@@ -310,6 +616,49 @@ impl<T: cio::CIO> Peer<T> {
         )
     }
 
+    /// Bytes of piece data sent to the peer so far.
+    pub fn sent(&self) -> u64 {
+        self.sent
+    }
+
+    /// Number of requests of the peer we've accepted but not yet
+    /// sent, whether being read, held back, or waiting to be written.
+    pub fn upload_queue(&mut self) -> usize {
+        let writing = self
+            .cio
+            .get_peer(self.id, |conn| {
+                conn.writer
+                    .write_queue
+                    .iter()
+                    .filter(|m| matches!(m, Message::Piece { .. }))
+                    .count()
+            })
+            .unwrap_or(0);
+        usize::from(self.reading) + self.deferred.len() + writing
+    }
+
+    pub fn read_started(&mut self) {
+        self.reading += 1;
+    }
+
+    pub fn read_done(&mut self) {
+        self.reading = self.reading.saturating_sub(1);
+    }
+
+    /// Holds back a request until the peer takes less of our upload.
+    pub fn defer(&mut self, index: u32, begin: u32, length: u32) {
+        self.deferred.push_back((index, begin, length));
+    }
+
+    /// Takes the requests held back so far, as (index, begin, length).
+    pub fn take_deferred(&mut self) -> VecDeque<(u32, u32, u32)> {
+        mem::take(&mut self.deferred)
+    }
+
+    pub fn has_deferred(&self) -> bool {
+        !self.deferred.is_empty()
+    }
+
     pub fn active(&self) -> bool {
         self.stat.active()
     }
@@ -319,17 +668,18 @@ impl<T: cio::CIO> Peer<T> {
         if !self.stat.active() {
             return false;
         }
-        let dl = self.stat.avg_dl();
-        let rate = (dl / 1024) as u16;
-        // Taken from rtorrent's pipeline calculation
-        let nmq = if rate < 20 { rate + 2 } else { rate / 5 + 18 };
+        let nmq = pipeline_len(self.stat.avg_dl(), self.rtt);
         // Clamp between -15 / +50 for queue len changes
-        self.max_queue = cmp::min(
-            cmp::max(nmq, self.max_queue.saturating_sub(15)),
-            self.max_queue + 50,
-        );
-        // Keep it under the max cap
-        self.max_queue = cmp::min(self.max_queue, MAX_QUEUE_CAP);
+        self.max_queue = if self.snubbed {
+            1
+        } else {
+            cmp::min(
+                cmp::max(nmq, self.max_queue.saturating_sub(15)),
+                self.max_queue + 50,
+            )
+        };
+        // Keep it under the max cap, and what the peer will accept
+        self.max_queue = cmp::min(self.max_queue, self.reqq.unwrap_or(MAX_QUEUE_CAP));
         if self.pieces_updated {
             self.pieces_updated = false;
             self.send_rpc_update();
@@ -353,26 +703,53 @@ impl<T: cio::CIO> Peer<T> {
     pub fn handle_msg(&mut self, msg: &mut Message) -> Result<()> {
         match *msg {
             Message::Handshake { rsv, id, .. } => {
+                self.rsv = Some(rsv);
+                self.cid = Some(id);
+                if self.fast_ext() && !self.pieces_sent {
+                    self.send_message(Message::HaveNone);
+                    self.pieces_sent = true;
+                }
+                let pieces = self.pieces.len() as u32;
+                self.send_allowed_fast(pieces);
                 if (rsv[DHT_EXT.0] & DHT_EXT.1) != 0 {
                     self.send_message(Message::Port(CONFIG.dht.port));
                 }
-                self.rsv = Some(rsv);
-                self.cid = Some(id);
                 self.send_rpc_info();
             }
-            Message::Piece { length, .. } => {
+            Message::Piece {
+                index,
+                begin,
+                length,
+                ..
+            } => {
                 self.stat.add_dl(u64::from(length));
                 self.downloaded += 1;
-                self.queued -= 1;
+                if let Some(sent) = self.rtt_probe.take() {
+                    let sample = sent.elapsed();
+                    self.rtt = Some(self.rtt.map_or(sample, |rtt| (rtt * 7 + sample) / 8));
+                }
+                self.last_block = time::Instant::now();
+                self.snubbed = false;
+                self.timeouts = 0;
+                // Pieces of cancelled requests may still arrive
+                if let Some(i) = self.cancelled.iter().position(|&c| c == (index, begin)) {
+                    self.cancelled.swap_remove(i);
+                } else {
+                    self.queued = self.queued.saturating_sub(1);
+                }
             }
             Message::Request { .. } => {
-                if self.local_status.choked {
+                // Fast peers may request while choked, the torrent rejects these
+                if self.local_status.choked && !self.fast_ext() {
                     info!("Got request while choked!");
                     return Err(ErrorKind::ProtocolError("Peer requested while choked!").into());
                 }
             }
             Message::Choke => {
                 self.remote_status.choked = true;
+                self.rtt_probe = None;
+                // Choking discards the requests, cancelled ones included
+                self.cancelled.clear();
             }
             Message::Unchoke => {
                 self.remote_status.choked = false;
@@ -406,10 +783,37 @@ impl<T: cio::CIO> Peer<T> {
                 self.piece_count = self.pieces.iter().count();
                 self.send_rpc_update();
             }
+            Message::HaveAll => {
+                self.check_fast()?;
+                self.pieces = Bitfield::full(self.pieces.len());
+                self.piece_count = self.pieces.len() as usize;
+                self.send_rpc_update();
+            }
+            Message::HaveNone => {
+                self.check_fast()?;
+            }
+            Message::Suggest(idx) => {
+                self.check_fast()?;
+                // Suggestions are advisory, so just prioritize the piece in our cache
+                if u64::from(idx) < self.pieces.len() && self.pieces.has_bit(u64::from(idx)) {
+                    self.piece_cache.push(idx);
+                }
+            }
+            Message::Reject { .. } => {
+                self.check_fast()?;
+                self.queued = self.queued.saturating_sub(1);
+                self.rtt_probe = None;
+            }
+            Message::AllowedFast(_) => {
+                self.check_fast()?;
+            }
+            Message::HashRequest(_) | Message::Hashes { .. } | Message::HashReject(_) => {}
             Message::KeepAlive => {
                 self.send_message(Message::KeepAlive);
             }
             Message::Cancel { index, begin, .. } => {
+                self.deferred
+                    .retain(|&(i, b, _)| !(i == index && b == begin));
                 self.cio.get_peer(self.id, |conn| {
                     conn.writer.write_queue.retain(|m| {
                         if let Message::Piece {
@@ -435,6 +839,12 @@ impl<T: cio::CIO> Peer<T> {
                     let mut d = b.into_dict().ok_or_else(|| {
                         ErrorKind::ProtocolError("Invalid bencode type in ext handshake")
                     })?;
+                    self.upload_only = d
+                        .remove(b"upload_only".as_ref())
+                        .and_then(|v| v.into_int())
+                        .map(|v| v != 0)
+                        .unwrap_or(false);
+                    self.parse_ext_fields(&mut d);
                     let mut m = d
                         .remove(b"m".as_ref())
                         .and_then(|v| v.into_dict())
@@ -449,22 +859,139 @@ impl<T: cio::CIO> Peer<T> {
                         .remove(b"ut_pex".as_ref())
                         .and_then(|v| v.into_int())
                         .map(|v| v as u8);
+                    self.ext_ids.lt_donthave = m
+                        .remove(b"lt_donthave".as_ref())
+                        .and_then(|v| v.into_int())
+                        .map(|v| v as u8);
+                    self.ext_ids.lt_tex = m
+                        .remove(b"lt_tex".as_ref())
+                        .and_then(|v| v.into_int())
+                        .map(|v| v as u8);
+                    self.ext_ids.ut_holepunch = m
+                        .remove(b"ut_holepunch".as_ref())
+                        .and_then(|v| v.into_int())
+                        .map(|v| v as u8);
+                } else if id == LT_DONTHAVE_ID {
+                    if payload.len() != 4 {
+                        return Err(ErrorKind::ProtocolError("Invalid lt_donthave length").into());
+                    }
+                    let idx = BigEndian::read_u32(payload);
+                    if idx >= self.pieces.len() as u32 {
+                        return Err(ErrorKind::ProtocolError(
+                            "Invalid piece provided in DONTHAVE!",
+                        )
+                        .into());
+                    }
+                    if !self.pieces.has_bit(u64::from(idx)) {
+                        return Err(ErrorKind::ProtocolError(
+                            "Missing piece provided in DONTHAVE!",
+                        )
+                        .into());
+                    }
+                    self.pieces.unset_bit(u64::from(idx));
+                    self.piece_count -= 1;
+                    self.pieces_updated = true;
                 }
             }
         }
         Ok(())
     }
 
+    fn check_fast(&self) -> Result<()> {
+        if self.fast_ext() {
+            Ok(())
+        } else {
+            Err(ErrorKind::ProtocolError("Fast extension message without negotiation").into())
+        }
+    }
+
+    /// Rejects a request the peer made, if they support doing so.
+    pub fn reject(&mut self, index: u32, begin: u32, length: u32) {
+        if self.fast_ext() {
+            self.send_message(Message::Reject {
+                index,
+                begin,
+                length,
+            });
+        }
+    }
+
     pub fn request_piece(&mut self, idx: u32, offset: u32, len: u32) {
         let m = Message::request(idx, offset, len);
+        if self.queued == 0 {
+            self.last_block = time::Instant::now();
+            // Blocks requested into an empty queue arrive after about a round trip
+            if self.rtt_probe.is_none() {
+                self.rtt_probe = Some(self.last_block);
+            }
+        }
         self.queued += 1;
         self.send_message(m);
     }
 
+    /// Returns true once no requested block has arrived for a while,
+    /// after which the peer only gets one request at a time until it
+    /// sends one. The requests it holds should then be cancelled and
+    /// handed to other peers.
+    pub fn snub(&mut self) -> bool {
+        if self.snubbed
+            || self.queued == 0
+            || self.remote_status.choked
+            || self.last_block.elapsed() < time::Duration::from_secs(SNUB_SECS)
+        {
+            return false;
+        }
+        self.snubbed = true;
+        self.max_queue = 1;
+        true
+    }
+
+    /// Cancels a request which timed out, returning true once enough
+    /// have in a row that the peer is considered to be snubbing us,
+    /// as with snub.
+    pub fn request_timed_out(&mut self, index: u32, begin: u32, length: u32) -> bool {
+        self.cancel_request(index, begin, length);
+        self.timeouts = self.timeouts.saturating_add(1);
+        if self.snubbed || self.timeouts < SNUB_TIMEOUTS {
+            return false;
+        }
+        self.snubbed = true;
+        self.max_queue = 1;
+        true
+    }
+
+    /// Cancels a request which arrived from another peer or timed out. Fast peers
+    /// answer cancels with the piece or a reject, others may not answer
+    /// at all, so the request stops counting towards their queue.
+    pub fn cancel_request(&mut self, index: u32, begin: u32, length: u32) {
+        if !self.fast_ext() {
+            self.queued = self.queued.saturating_sub(1);
+            if self.cancelled.len() >= usize::from(MAX_QUEUE_CAP) {
+                self.cancelled.remove(0);
+            }
+            self.cancelled.push((index, begin));
+        }
+        self.rtt_probe = None;
+        self.send_message(Message::Cancel {
+            index,
+            begin,
+            length,
+        });
+    }
+
     pub fn choke(&mut self) {
         if !self.local_status.choked {
             self.local_status.choked = true;
             self.send_message(Message::Choke);
+            // Held back requests outside the allowed fast set won't be
+            // served anymore, fast peers expect them to be rejected
+            for (index, begin, length) in mem::take(&mut self.deferred) {
+                if self.allowed_fast(index) {
+                    self.deferred.push_back((index, begin, length));
+                } else {
+                    self.reject(index, begin, length);
+                }
+            }
         }
     }
 
@@ -482,9 +1009,59 @@ impl<T: cio::CIO> Peer<T> {
         }
     }
 
+    pub fn uninterested(&mut self) {
+        if self.local_status.interested {
+            self.local_status.interested = false;
+            self.send_message(Message::Uninterested);
+        }
+    }
+
+    /// Handles the optional fields of an extension handshake.
+    fn parse_ext_fields(&mut self, d: &mut BTreeMap<Vec<u8>, bencode::BEncode>) {
+        let int = |d: &mut BTreeMap<_, bencode::BEncode>, k: &[u8]| {
+            d.remove(k)
+                .and_then(|v| v.into_int())
+                .filter(|&v| v > 0 && v <= i64::from(u16::MAX))
+                .map(|v| v as u16)
+        };
+        if let Some(reqq) = int(d, b"reqq") {
+            self.reqq = Some(reqq);
+            self.max_queue = cmp::min(self.max_queue, reqq);
+        }
+        if let Some(port) = int(d, b"p") {
+            self.listen_port = Some(port);
+        }
+        if let Some(v) = d.remove(b"v".as_ref()).and_then(|v| v.into_string()) {
+            debug!("Peer {} is running {}", self.addr, v);
+        }
+        let ip = match d.remove(b"yourip".as_ref()).and_then(|v| v.into_bytes()) {
+            Some(ref b) if b.len() == 4 => IpAddr::V4(Ipv4Addr::new(b[0], b[1], b[2], b[3])),
+            Some(ref b) if b.len() == 16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(b);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return,
+        };
+        self.cio.msg_trk(tracker::Request::ExternalIp {
+            reporter: self.addr.ip(),
+            ip,
+        });
+    }
+
+    /// Tells the peer we no longer have a piece, if it supports lt_donthave.
+    pub fn send_donthave(&mut self, idx: u32) {
+        if let Some(id) = self.ext_ids.lt_donthave {
+            let mut payload = vec![0u8; 4];
+            BigEndian::write_u32(&mut payload, idx);
+            self.send_message(Message::Extension { id, payload });
+        }
+    }
+
     pub fn send_message(&mut self, msg: Message) {
         if let Message::Piece { length, .. } = msg {
             self.uploaded += 1;
+            self.sent += u64::from(length);
             self.stat.add_ul(u64::from(length));
         }
         self.cio.msg_peer(self.id, msg);
@@ -554,16 +1131,54 @@ impl ExtIDs {
         ExtIDs {
             ut_meta: None,
             ut_pex: None,
+            lt_donthave: None,
+            lt_tex: None,
+            ut_holepunch: None,
         }
     }
 }
 
+/// Computes the canonical allowed fast set of k pieces for a peer at
+/// ip, as described in BEP 6.
+fn allowed_fast_set(ip: Ipv4Addr, hash: &[u8; 20], pieces: u32, k: usize) -> Vec<u32> {
+    let k = cmp::min(k, pieces as usize);
+    let mut set = Vec::with_capacity(k);
+    let mut x = (u32::from(ip) & 0xFFFF_FF00).to_be_bytes().to_vec();
+    x.extend_from_slice(hash);
+    while set.len() < k {
+        let digest = util::sha1_hash(&x);
+        for chunk in digest.chunks(4) {
+            let idx = BigEndian::read_u32(chunk) % pieces;
+            if set.len() < k && !set.contains(&idx) {
+                set.push(idx);
+            }
+        }
+        x = digest.to_vec();
+    }
+    set
+}
+
+/// Number of requests to keep queued with a peer downloading at rate
+/// bytes/s, enough for a few seconds of data, or twice the round trip
+/// time on slow links, to be in flight.
+fn pipeline_len(rate: u64, rtt: Option<time::Duration>) -> u16 {
+    let secs = rtt
+        .map_or(0., |rtt| rtt.as_secs_f64() * 2.)
+        .max(PIPELINE_SECS);
+    let blocks = rate as f64 * secs / 16_384.;
+    cmp::min(
+        blocks as u64 + u64::from(MIN_QUEUE),
+        u64::from(MAX_QUEUE_CAP),
+    ) as u16
+}
+
 #[cfg(test)]
 mod tests {
     use super::Peer;
     use crate::buffers::Buffer;
     use crate::control::cio::{test, CIO};
-    use crate::torrent::Message;
+    use crate::torrent::{Bitfield, Message};
+    use crate::{FAST_EXT, LT_DONTHAVE_ID};
 
     #[test]
     fn test_cancel() {
@@ -605,4 +1220,243 @@ mod tests {
         assert_eq!(wq[0], p1);
         assert_eq!(wq[1], p3);
     }
+
+    #[test]
+    fn test_upload_queue() {
+        let tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        let mut rsv = [0u8; 8];
+        rsv[FAST_EXT.0] |= FAST_EXT.1;
+        peer.rsv = Some(rsv);
+        peer.unchoke();
+        peer.read_started();
+        peer.read_started();
+        peer.read_done();
+        peer.send_message(Message::Piece {
+            index: 0,
+            begin: 0,
+            data: Buffer::get().unwrap(),
+            length: 16_384,
+        });
+        peer.defer(1, 0, 16_384);
+        peer.defer(2, 0, 16_384);
+        peer.defer(3, 0, 16_384);
+        assert_eq!(peer.upload_queue(), 5);
+
+        let mut c = Message::Cancel {
+            index: 2,
+            begin: 0,
+            length: 16_384,
+        };
+        peer.handle_msg(&mut c).unwrap();
+        assert_eq!(peer.upload_queue(), 4);
+
+        // Held back requests are rejected on choking, unless allowed fast
+        peer.allowed_fast.push(3);
+        peer.choke();
+        let rejects: Vec<_> = tcio
+            .peer_msgs()
+            .into_iter()
+            .filter_map(|(_, m)| match m {
+                Message::Reject { index, .. } => Some(index),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(rejects, vec![1]);
+        assert_eq!(peer.take_deferred(), vec![(3, 0, 16_384)]);
+        assert!(!peer.has_deferred());
+    }
+
+    #[test]
+    fn test_donthave() {
+        let mut peer = Peer::test_from_pieces(0, Bitfield::full(4));
+        let mut dh = Message::Extension {
+            id: LT_DONTHAVE_ID,
+            payload: vec![0, 0, 0, 2],
+        };
+        peer.handle_msg(&mut dh).unwrap();
+        assert!(!peer.pieces().has_bit(2));
+        assert_eq!(peer.piece_count, 3);
+        // Pieces the peer doesn't have or which don't exist are rejected
+        assert!(peer.handle_msg(&mut dh).is_err());
+        let mut dh = Message::Extension {
+            id: LT_DONTHAVE_ID,
+            payload: vec![0, 0, 0, 4],
+        };
+        assert!(peer.handle_msg(&mut dh).is_err());
+    }
+
+    #[test]
+    fn test_upload_only() {
+        let mut peer = Peer::test_from_pieces(0, Bitfield::new(4));
+        let mut hs = Message::Extension {
+            id: 0,
+            payload: b"d1:md11:lt_donthavei3ee11:upload_onlyi1ee".to_vec(),
+        };
+        peer.handle_msg(&mut hs).unwrap();
+        assert!(peer.upload_only());
+        assert_eq!(peer.exts().lt_donthave, Some(3));
+    }
+
+    #[test]
+    fn test_ext_fields() {
+        let mut peer = Peer::test(0, 0, 0, 0, Bitfield::new(4));
+        peer.max_queue = 100;
+        let mut hs = Message::Extension {
+            id: 0,
+            payload: b"d1:mde1:pi6881e4:reqqi50e1:v4:test6:yourip4:\x01\x02\x03\x04e".to_vec(),
+        };
+        peer.handle_msg(&mut hs).unwrap();
+        assert_eq!(peer.max_queue, 50);
+        assert_eq!(peer.listen_addr(), "127.0.0.1:6881".parse().unwrap());
+    }
+
+    #[test]
+    fn test_super_seed() {
+        let tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        peer.super_seeded = true;
+        peer.reveal(1);
+        peer.handle_msg(&mut Message::Have(1)).unwrap();
+        peer.reveal(2);
+        assert_eq!(peer.revealed(), Some(2));
+        peer.unveil(&Bitfield::full(4));
+        assert!(!peer.super_seeded());
+        // Revealed pieces aren't announced again
+        let haves: Vec<_> = tcio
+            .peer_msgs()
+            .into_iter()
+            .filter_map(|(_, m)| match m {
+                Message::Have(i) => Some(i),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(haves, vec![1, 2, 0, 3]);
+    }
+
+    #[test]
+    fn test_pipeline_len() {
+        use super::{pipeline_len, MAX_QUEUE_CAP, MIN_QUEUE};
+        use std::time::Duration;
+
+        assert_eq!(pipeline_len(0, None), MIN_QUEUE);
+        // A block a second, three seconds of which are kept in flight
+        assert_eq!(pipeline_len(16_384, None), 3 + MIN_QUEUE);
+        assert_eq!(pipeline_len(16_384 * 10, None), 30 + MIN_QUEUE);
+        // Short round trips don't shrink the pipeline, long ones grow it
+        let rtt = Some(Duration::from_millis(100));
+        assert_eq!(pipeline_len(16_384 * 10, rtt), 30 + MIN_QUEUE);
+        let rtt = Some(Duration::from_secs(5));
+        assert_eq!(pipeline_len(16_384 * 10, rtt), 100 + MIN_QUEUE);
+        assert_eq!(pipeline_len(u64::MAX, None), MAX_QUEUE_CAP);
+    }
+
+    #[test]
+    fn test_snub() {
+        use std::time::{Duration, Instant};
+
+        let tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        peer.remote_status.choked = false;
+        assert!(!peer.snub());
+        peer.request_piece(0, 0, 16_384);
+        assert!(!peer.snub());
+        peer.last_block = Instant::now() - Duration::from_secs(super::SNUB_SECS);
+        assert!(peer.snub());
+        assert!(!peer.snub());
+        assert_eq!(peer.queue_reqs(), None);
+        peer.cancel_request(0, 0, 16_384);
+        assert_eq!(peer.queue_reqs(), Some(1));
+
+        let mut piece = Message::Piece {
+            index: 0,
+            begin: 0,
+            data: Buffer::get().unwrap(),
+            length: 16_384,
+        };
+        peer.handle_msg(&mut piece).unwrap();
+        assert!(!peer.snubbed);
+    }
+
+    #[test]
+    fn test_cancel_queued() {
+        let tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        assert!(!peer.fast_ext());
+        peer.remote_status.choked = false;
+        peer.request_piece(0, 0, 16_384);
+        peer.request_piece(1, 0, 16_384);
+        peer.cancel_request(0, 0, 16_384);
+        assert_eq!(peer.queued, 1);
+
+        // The cancelled block arriving anyway doesn't count twice
+        for index in 0..2 {
+            let mut piece = Message::Piece {
+                index,
+                begin: 0,
+                data: Buffer::get().unwrap(),
+                length: 16_384,
+            };
+            peer.handle_msg(&mut piece).unwrap();
+            assert_eq!(peer.queued, 1 - index as u16);
+        }
+
+        peer.request_piece(2, 0, 16_384);
+        peer.request_piece(3, 0, 16_384);
+        peer.cancel_request(2, 0, 16_384);
+        assert_eq!(peer.cancelled, vec![(2, 0)]);
+        peer.handle_msg(&mut Message::Choke).unwrap();
+        assert!(peer.cancelled.is_empty());
+    }
+
+    #[test]
+    fn test_request_timed_out() {
+        let tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        peer.remote_status.choked = false;
+        for i in 0..super::SNUB_TIMEOUTS {
+            peer.request_piece(i.into(), 0, 16_384);
+        }
+        assert!(!peer.request_timed_out(0, 0, 16_384));
+        assert!(!peer.request_timed_out(1, 0, 16_384));
+        assert!(peer.request_timed_out(2, 0, 16_384));
+        assert!(!peer.request_timed_out(2, 0, 16_384));
+        assert!(peer.snubbed);
+        let msgs: Vec<_> = tcio.peer_msgs().into_iter().map(|(_, m)| m).collect();
+        assert_eq!(
+            msgs.last(),
+            Some(&Message::Cancel {
+                index: 2,
+                begin: 0,
+                length: 16_384
+            })
+        );
+    }
+
+    #[test]
+    fn test_interest() {
+        let tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        peer.uninterested();
+        peer.interested();
+        peer.interested();
+        peer.uninterested();
+        let msgs: Vec<_> = tcio.peer_msgs().into_iter().map(|(_, m)| m).collect();
+        assert_eq!(msgs, vec![Message::Interested, Message::Uninterested]);
+    }
+
+    #[test]
+    fn test_allowed_fast_set() {
+        let ip = "80.4.4.200".parse().unwrap();
+        let hash = [0xaa; 20];
+        assert_eq!(
+            super::allowed_fast_set(ip, &hash, 1313, 7),
+            vec![1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            super::allowed_fast_set(ip, &hash, 1313, 9),
+            vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+        assert_eq!(super::allowed_fast_set(ip, &hash, 3, 10).len(), 3);
+    }
 }
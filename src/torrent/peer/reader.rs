@@ -4,16 +4,20 @@ use std::mem;
 use byteorder::{BigEndian, ByteOrder};
 
 use crate::buffers::{Buffer, BUF_SIZE};
+use crate::protocol::HashRequest;
 use crate::torrent::peer::Message;
 use crate::torrent::Bitfield;
 use crate::util::{aread, io_err_val, IOR};
 
 const MAX_EXT_MSG_BYTES: u32 = 100 * 1000 * 1000;
+const MAX_HASH_MSG_BYTES: u32 = 64 * 1024;
 
 pub struct Reader {
     state: State,
     prefix: [u8; 17],
     idx: usize,
+    /// Stop reading after the handshake until resumed
+    hold: bool,
 }
 
 enum State {
@@ -23,12 +27,32 @@ enum State {
     Request,
     Cancel,
     Port,
-    Handshake { data: [u8; 68] },
+    Suggest,
+    Reject,
+    AllowedFast,
+    Handshake {
+        data: [u8; 68],
+    },
+    /// Handshake read, waiting for the peer to be accepted
+    Held,
     PiecePrefix,
-    Piece { data: Option<Buffer>, len: u32 },
-    Bitfield { data: Vec<u8> },
+    Piece {
+        data: Option<Buffer>,
+        len: u32,
+    },
+    Bitfield {
+        data: Vec<u8>,
+    },
     ExtensionID,
-    Extension { id: u8, payload: Vec<u8> },
+    Extension {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    /// BEP 52 hash request, hashes or hash reject
+    Hash {
+        id: u8,
+        data: Vec<u8>,
+    },
 }
 
 #[derive(Debug)]
@@ -55,13 +79,32 @@ impl Reader {
             prefix: [0u8; 17],
             idx: 0,
             state: State::Handshake { data: [0u8; 68] },
+            hold: false,
+        }
+    }
+
+    /// Creates a reader which stops after the handshake,
+    /// reading nothing further until resume is called.
+    pub fn new_held() -> Reader {
+        Reader {
+            hold: true,
+            ..Reader::new()
+        }
+    }
+
+    pub fn resume(&mut self) {
+        if let State::Held = self.state {
+            self.state = State::Len;
         }
     }
 
     pub fn readable<R: Read>(&mut self, conn: &mut R) -> RRes {
         let res = self.readable_(conn);
         if let RRes::Success(_) = &res {
-            self.state = State::Len;
+            self.state = match self.state {
+                State::Handshake { .. } if self.hold => State::Held,
+                _ => State::Len,
+            };
             self.idx = 0;
         }
         res
@@ -71,6 +114,7 @@ impl Reader {
         loop {
             let len = self.state.len();
             match self.state {
+                State::Held => return RRes::Blocked,
                 State::Handshake { ref mut data } => match aread(&mut data[self.idx..len], conn) {
                     IOR::Complete => {
                         if &data[1..20] != b"BitTorrent protocol" {
@@ -143,7 +187,27 @@ impl Reader {
                             7 => self.state = State::PiecePrefix,
                             8 => self.state = State::Cancel,
                             9 => self.state = State::Port,
+                            0x0D => self.state = State::Suggest,
+                            0x0E => return RRes::Success(Message::HaveAll),
+                            0x0F => return RRes::Success(Message::HaveNone),
+                            0x10 => self.state = State::Reject,
+                            0x11 => self.state = State::AllowedFast,
                             20 => self.state = State::ExtensionID,
+                            21..=23 => {
+                                let id = self.prefix[4];
+                                let mlen = BigEndian::read_u32(&self.prefix[0..4]);
+                                if mlen < 49
+                                    || (id != 22 && mlen != 49)
+                                    || mlen > MAX_HASH_MSG_BYTES
+                                {
+                                    return RRes::Err(io_err_val("Invalid hash message length"));
+                                }
+                                self.idx = 0;
+                                self.state = State::Hash {
+                                    id,
+                                    data: vec![0u8; mlen as usize - 1],
+                                };
+                            }
                             _ => return RRes::Err(io_err_val("Invalid ID used!")),
                         }
                     }
@@ -263,6 +327,42 @@ impl Reader {
                     IOR::EOF => return RRes::Err(io_err_val("EOF")),
                     IOR::Err(e) => return RRes::Err(e),
                 },
+                State::Suggest => match aread(&mut self.prefix[self.idx..len], conn) {
+                    IOR::Complete => {
+                        let piece = BigEndian::read_u32(&self.prefix[5..9]);
+                        return RRes::Success(Message::Suggest(piece));
+                    }
+                    IOR::Incomplete(a) => self.idx += a,
+                    IOR::Blocked => return RRes::Blocked,
+                    IOR::EOF => return RRes::Err(io_err_val("EOF")),
+                    IOR::Err(e) => return RRes::Err(e),
+                },
+                State::Reject => match aread(&mut self.prefix[self.idx..len], conn) {
+                    IOR::Complete => {
+                        let index = BigEndian::read_u32(&self.prefix[5..9]);
+                        let begin = BigEndian::read_u32(&self.prefix[9..13]);
+                        let length = BigEndian::read_u32(&self.prefix[13..17]);
+                        return RRes::Success(Message::Reject {
+                            index,
+                            begin,
+                            length,
+                        });
+                    }
+                    IOR::Incomplete(a) => self.idx += a,
+                    IOR::Blocked => return RRes::Blocked,
+                    IOR::EOF => return RRes::Err(io_err_val("EOF")),
+                    IOR::Err(e) => return RRes::Err(e),
+                },
+                State::AllowedFast => match aread(&mut self.prefix[self.idx..len], conn) {
+                    IOR::Complete => {
+                        let piece = BigEndian::read_u32(&self.prefix[5..9]);
+                        return RRes::Success(Message::AllowedFast(piece));
+                    }
+                    IOR::Incomplete(a) => self.idx += a,
+                    IOR::Blocked => return RRes::Blocked,
+                    IOR::EOF => return RRes::Err(io_err_val("EOF")),
+                    IOR::Err(e) => return RRes::Err(e),
+                },
                 State::ExtensionID => match aread(&mut self.prefix[5..6], conn) {
                     IOR::Complete => {
                         let id = self.prefix[5];
@@ -292,6 +392,30 @@ impl Reader {
                     IOR::EOF => return RRes::Err(io_err_val("EOF")),
                     IOR::Err(e) => return RRes::Err(e),
                 },
+                State::Hash { id, ref mut data } => match aread(&mut data[self.idx..len], conn) {
+                    IOR::Complete => {
+                        let hashes = data.split_off(48);
+                        let mut pieces_root = [0u8; 32];
+                        pieces_root.copy_from_slice(&data[..32]);
+                        let req = Box::new(HashRequest {
+                            pieces_root,
+                            base_layer: BigEndian::read_u32(&data[32..36]),
+                            index: BigEndian::read_u32(&data[36..40]),
+                            length: BigEndian::read_u32(&data[40..44]),
+                            proof_layers: BigEndian::read_u32(&data[44..48]),
+                        });
+                        let msg = match id {
+                            21 => Message::HashRequest(req),
+                            22 => Message::Hashes { req, hashes },
+                            _ => Message::HashReject(req),
+                        };
+                        return RRes::Success(msg);
+                    }
+                    IOR::Incomplete(a) => self.idx += a,
+                    IOR::Blocked => return RRes::Blocked,
+                    IOR::EOF => return RRes::Err(io_err_val("EOF")),
+                    IOR::Err(e) => return RRes::Err(e),
+                },
             }
         }
     }
@@ -302,15 +426,17 @@ impl State {
         match *self {
             State::Len => 4,
             State::ID => 5,
-            State::Have => 9,
-            State::Request | State::Cancel => 17,
+            State::Have | State::Suggest | State::AllowedFast => 9,
+            State::Request | State::Cancel | State::Reject => 17,
             State::PiecePrefix => 13,
             State::Port => 7,
             State::Handshake { .. } => 68,
+            State::Held => 0,
             State::Piece { len, .. } => len as usize,
             State::Bitfield { ref data, .. } => data.len(),
             State::ExtensionID => 6,
             State::Extension { ref payload, .. } => payload.len(),
+            State::Hash { ref data, .. } => data.len(),
         }
     }
 }
@@ -513,6 +639,48 @@ mod tests {
         test_message(data, Message::Port(6881));
     }
 
+    #[test]
+    fn test_read_fast() {
+        test_message(vec![0u8, 0, 0, 1, 0x0E], Message::HaveAll);
+        test_message(vec![0u8, 0, 0, 1, 0x0F], Message::HaveNone);
+        test_message(vec![0u8, 0, 0, 5, 0x0D, 0, 0, 0, 3], Message::Suggest(3));
+        test_message(
+            vec![0u8, 0, 0, 5, 0x11, 0, 0, 0, 7],
+            Message::AllowedFast(7),
+        );
+        test_message(
+            vec![0u8, 0, 0, 13, 0x10, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3],
+            Message::Reject {
+                index: 1,
+                begin: 2,
+                length: 3,
+            },
+        );
+    }
+
+    #[test]
+    fn test_read_hashes() {
+        let req = Box::new(HashRequest {
+            pieces_root: [7; 32],
+            base_layer: 1,
+            index: 4,
+            length: 2,
+            proof_layers: 3,
+        });
+        for m in &[
+            Message::HashRequest(req.clone()),
+            Message::Hashes {
+                req: req.clone(),
+                hashes: vec![9; 64],
+            },
+            Message::HashReject(req),
+        ] {
+            let mut data = vec![0; m.len()];
+            m.encode(&mut data[..]).unwrap();
+            test_message(data, m.clone());
+        }
+    }
+
     #[test]
     fn test_read_handshake() {
         use crate::PEER_ID;
@@ -527,4 +695,25 @@ mod tests {
         let mut c = Cursor::new(&data);
         assert_eq!(r.readable(&mut c).unwrap().unwrap(), m);
     }
+
+    #[test]
+    fn test_read_held() {
+        use crate::PEER_ID;
+        let mut r = Reader::new_held();
+        let m = Message::Handshake {
+            rsv: [0; 8],
+            hash: [0; 20],
+            id: *PEER_ID,
+        };
+        let mut data = vec![0; 73];
+        m.encode(&mut data[..]).unwrap();
+        data[68..].copy_from_slice(&[0, 0, 0, 1, 1]);
+        let mut c = Cursor::new(&data);
+        assert_eq!(r.readable(&mut c).unwrap().unwrap(), m);
+        // Nothing past the handshake is read until resumed
+        assert!(r.readable(&mut c).unwrap().is_none());
+        assert_eq!(c.idx, 68);
+        r.resume();
+        assert_eq!(r.readable(&mut c).unwrap().unwrap(), Message::Unchoke);
+    }
 }
@@ -1,10 +1,13 @@
 use std::collections::VecDeque;
-use std::io::{self, ErrorKind, Write};
+use std::io::{self, ErrorKind, IoSlice, Write};
 
 use crate::buffers::Buffer;
 use crate::torrent::peer::Message;
 use crate::util::io_err;
 
+/// Maximum size of a run of small messages coalesced into one write
+const MAX_BATCH_BYTES: usize = 4096;
+
 pub struct Writer {
     // Needed so that the peer can filter out cancel'd messages.
     // The state of this isn't critical to any invariants of the Writer
@@ -29,7 +32,7 @@ enum WriteState {
     WritingPiece {
         prefix: [u8; 17],
         data: Buffer,
-        idx: u16,
+        idx: u32,
     },
 }
 
@@ -62,7 +65,18 @@ impl Writer {
     }
 
     fn setup_write(&mut self, msg: Message) {
-        self.state = if !msg.is_special() {
+        self.state = if batchable(&msg) && self.batch_next() {
+            // Coalesce queued haves, requests, etc. so they go out in a single syscall
+            let mut data = vec![0; msg.len()];
+            msg.encode(&mut data).unwrap();
+            while data.len() < MAX_BATCH_BYTES && self.batch_next() {
+                let m = self.write_queue.pop_front().unwrap();
+                let start = data.len();
+                data.resize(start + m.len(), 0);
+                m.encode(&mut data[start..]).unwrap();
+            }
+            WriteState::WritingOther { data, idx: 0 }
+        } else if !msg.is_special() {
             let mut buf = [0; 17];
             let len = msg.len();
             // Should never go wrong
@@ -88,6 +102,13 @@ impl Writer {
         };
     }
 
+    fn batch_next(&self) -> bool {
+        match self.write_queue.front() {
+            Some(msg) => batchable(msg),
+            None => false,
+        }
+    }
+
     fn write<W: Write>(&mut self, conn: &mut W) -> io::Result<()> {
         if let WriteState::Idle = self.state {
             return Ok(());
@@ -95,7 +116,7 @@ impl Writer {
         loop {
             match self.write_(conn) {
                 Ok(true) => {
-                    if let Some(msg) = self.write_queue.pop_back() {
+                    if let Some(msg) = self.write_queue.pop_front() {
                         self.setup_write(msg);
                     } else {
                         self.state = WriteState::Idle;
@@ -143,25 +164,20 @@ impl Writer {
                 ref data,
                 ref mut idx,
             } => {
-                if *idx < 13 as u16 {
-                    let amnt = conn.write(&prefix[(*idx as usize)..13])? as u16;
-                    if amnt == 0 {
-                        return io_err("EOF");
-                    }
-                    *idx += amnt;
-                    if *idx != 13 as u16 {
-                        self.writable = false;
-                        return Ok(false);
-                    }
-                }
-
-                let amnt = conn.write(&data[(*idx as usize - 13)..])?;
+                let amnt = if *idx < 13 {
+                    // Send the prefix and block together
+                    conn.write_vectored(&[
+                        IoSlice::new(&prefix[(*idx as usize)..13]),
+                        IoSlice::new(&data[..]),
+                    ])?
+                } else {
+                    conn.write(&data[(*idx as usize - 13)..])?
+                };
                 if amnt == 0 {
                     return io_err("EOF");
                 }
-                // piece should never exceed u16 size
-                *idx += amnt as u16;
-                if *idx == (13 + data.len()) as u16 {
+                *idx += amnt as u32;
+                if *idx == (13 + data.len()) as u32 {
                     self.blocks_written += 1;
                     Ok(true)
                 } else {
@@ -189,11 +205,19 @@ impl Writer {
     }
 }
 
+fn batchable(msg: &Message) -> bool {
+    match *msg {
+        Message::Piece { .. } => false,
+        _ => !msg.is_special(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::Writer;
     use crate::buffers::Buffer;
     use crate::torrent::peer::Message;
+    use std::io::{self, Write};
 
     #[test]
     fn test_write_keepalive() {
@@ -302,6 +326,26 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_write_large_piece() {
+        use std::io::Cursor;
+        let mut w = Writer::new();
+        let mut piece = Buffer::with_len(32_768).expect("buffers should be present in tests");
+        piece.iter_mut().for_each(|b| *b = 1);
+        let mut sbuf = vec![0u8; 32_768 + 13];
+        let mut buf = Cursor::new(&mut sbuf[..]);
+        let m = Message::Piece {
+            index: 1,
+            begin: 1,
+            length: 32_768,
+            data: piece,
+        };
+        w.write_message(m, &mut buf).unwrap();
+        let buf = buf.into_inner();
+        assert_eq!(buf[0..13], [0, 0, 0x80, 0x09, 7, 0, 0, 0, 1, 0, 0, 0, 1]);
+        assert!(buf[13..].iter().all(|&b| b == 1));
+    }
+
     #[test]
     fn test_write_cancel() {
         let mut w = Writer::new();
@@ -315,6 +359,81 @@ mod tests {
         assert_eq!(buf, [0, 0, 0, 13, 8, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1])
     }
 
+    #[test]
+    fn test_write_reject() {
+        let mut w = Writer::new();
+        let mut buf = [0u8; 17];
+        let m = Message::Reject {
+            index: 1,
+            begin: 1,
+            length: 1,
+        };
+        w.write_message(m, &mut &mut buf[..]).unwrap();
+        assert_eq!(buf, [0, 0, 0, 13, 0x10, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1])
+    }
+
+    #[test]
+    fn test_write_have_all() {
+        let mut w = Writer::new();
+        let mut buf = [0u8; 5];
+        w.write_message(Message::HaveAll, &mut &mut buf[..])
+            .unwrap();
+        assert_eq!(buf, [0, 0, 0, 1, 0x0E])
+    }
+
+    #[test]
+    fn test_write_allowed_fast() {
+        let mut w = Writer::new();
+        let mut buf = [0u8; 9];
+        w.write_message(Message::AllowedFast(1), &mut &mut buf[..])
+            .unwrap();
+        assert_eq!(buf, [0, 0, 0, 5, 0x11, 0, 0, 0, 1])
+    }
+
+    /// Sink which blocks until unblocked, counting write calls.
+    struct Sink {
+        data: Vec<u8>,
+        writes: usize,
+        blocked: bool,
+    }
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.blocked {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, ""));
+            }
+            self.writes += 1;
+            self.data.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_batch() {
+        let mut w = Writer::new();
+        let mut s = Sink {
+            data: Vec::new(),
+            writes: 0,
+            blocked: true,
+        };
+        for i in 0..4 {
+            w.write_message(Message::Have(i), &mut s).unwrap();
+        }
+        s.blocked = false;
+        w.writable(&mut s).unwrap();
+        // The first message is already in flight, the rest are coalesced
+        assert_eq!(s.writes, 2);
+        let mut expected = vec![];
+        for i in 0..4 {
+            expected.extend_from_slice(&[0, 0, 0, 5, 4, 0, 0, 0, i]);
+        }
+        assert_eq!(s.data, expected);
+    }
+
     #[test]
     fn test_write_handshake() {
         use crate::PEER_ID;
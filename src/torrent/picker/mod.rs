@@ -1,10 +1,11 @@
 use std::collections::HashMap;
 use std::sync::Arc;
-use std::time;
+use std::{cmp, time};
 
 use crate::control::cio;
 use crate::torrent::{Bitfield, Info, Peer};
-use crate::util::FHashSet;
+use crate::util::{random_sample, FHashMap, FHashSet, UHashMap};
+use crate::CONFIG;
 
 mod rarest;
 mod sequential;
@@ -16,10 +17,12 @@ mod tests;
 pub struct Picker {
     /// Number of blocks per piece
     scale: u32,
-    last_piece_scale: u32,
-    last_piece: u32,
-    /// Number of detected seeders
-    seeders: u16,
+    /// Number of blocks in pieces shorter than the rest. These are only the last
+    /// piece in v1 torrents, but each file's last piece in v2 ones.
+    short: FHashMap<u32, u32>,
+    /// Peers which were seeders when added, and so don't count
+    /// towards piece availability
+    seeders: FHashSet<usize>,
     /// Currently active requests
     downloading: HashMap<Block, Request>,
     /// Blocks requested/completed per piece picked
@@ -33,6 +36,43 @@ pub struct Picker {
     picker: PickerKind,
     /// Piece priorities
     priorities: Vec<u8>,
+    /// Recent timed out or rejected requests per piece. Requests of
+    /// pieces which keep failing to arrive expire sooner, so they're
+    /// handed to other holders rather than holding up completion.
+    failures: FHashMap<u32, u8>,
+    /// When failure counts were last decayed
+    aged: time::Instant,
+    /// Pieces each peer has which are still unpicked, along with the
+    /// generation they're up to date with
+    wanted: UHashMap<(Bitfield, u64)>,
+    /// Bumped when pieces become unpicked again, since that can't be
+    /// applied to the caches without the peers' bitfields
+    generation: u64,
+    /// Piece being played back when streaming
+    head: Option<u32>,
+    /// Pieces given a deadline, and when they're due. These are
+    /// picked before anything else, earliest due first.
+    deadlines: FHashMap<u32, time::Instant>,
+    /// Number of completed pieces
+    have: u32,
+    /// Completed pieces below which pieces are picked at random rather
+    /// than rarest first, so a fresh torrent quickly has pieces to trade
+    random_first: u32,
+    /// Whether the first and last pieces of files are given the top
+    /// priority, so media files can be opened before they're complete
+    first_last: bool,
+}
+
+/// How pieces are chosen for download
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Rarest,
+    Sequential,
+    /// Rarest first, except for the pieces from the read head on,
+    /// which are picked before anything else and given deadlines
+    Streaming {
+        head: u32,
+    },
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
@@ -71,7 +111,19 @@ struct Request {
 const MAX_DUP_REQS: usize = 3;
 const MAX_PC_SIZE: usize = 50;
 const MAX_DL_REREQ: usize = 150;
-const REQ_TIMEOUT: u64 = 10;
+/// Outstanding blocks below which the remaining ones are requested
+/// from several peers at once
+const ENDGAME_BLOCKS: usize = 64;
+/// Shortest time a request is waited on, however often its piece failed
+const MIN_REQ_TIMEOUT: u64 = 2;
+/// Most times the request timeout of a failing piece is halved
+const MAX_FAILURE_SHIFT: u8 = 3;
+/// Interval at which failure counts are halved
+const FAILURE_DECAY_SECS: u64 = 60;
+/// Pieces from the read head on which are picked first when streaming
+const STREAM_WINDOW: u32 = 16;
+/// Highest piece priority
+const MAX_PRIORITY: u8 = 5;
 
 impl Picker {
     /// Creates a new picker, which will select over
@@ -81,13 +133,11 @@ impl Picker {
     pub fn new(info: &Arc<Info>, pieces: &Bitfield, priorities: &[u8]) -> Picker {
         let scale = info.piece_len / 16_384;
         let picker = rarest::Picker::new(pieces);
-        let last_piece = info.pieces().saturating_sub(1);
-        let lpl = info.piece_len(last_piece);
-        let last_piece_scale = if lpl % 16_384 == 0 {
-            lpl / 16_384
-        } else {
-            lpl / 16_384 + 1
-        };
+        let short = (0..info.pieces())
+            .map(|piece| (piece, info.piece_len(piece)))
+            .filter(|&(_, len)| len < info.piece_len)
+            .map(|(piece, len)| (piece, len.div_ceil(16_384)))
+            .collect();
         let downloading = if pieces.complete() {
             HashMap::with_capacity(0)
         } else {
@@ -101,14 +151,22 @@ impl Picker {
         let mut picker = Picker {
             picker: PickerKind::Rarest(picker),
             scale,
-            last_piece,
-            last_piece_scale,
+            short,
             downloading,
-            seeders: 0,
+            seeders: FHashSet::default(),
             unpicked: pieces.clone(),
             stalled: FHashSet::default(),
             priorities: vec![3; info.pieces() as usize],
             blocks,
+            failures: FHashMap::default(),
+            aged: time::Instant::now(),
+            wanted: UHashMap::default(),
+            generation: 0,
+            head: None,
+            deadlines: FHashMap::default(),
+            have: pieces.set() as u32,
+            random_first: CONFIG.peer.random_first_pieces,
+            first_last: false,
         };
         picker.set_priorities(priorities, info);
         picker
@@ -122,28 +180,86 @@ impl Picker {
         }
     }
 
+    pub fn mode(&self) -> Mode {
+        match (&self.picker, self.head) {
+            (_, Some(head)) => Mode::Streaming { head },
+            (PickerKind::Sequential(_), None) => Mode::Sequential,
+            (PickerKind::Rarest(_), None) => Mode::Rarest,
+        }
+    }
+
+    /// Moves the read head, if streaming.
+    pub fn set_head(&mut self, piece: u32) {
+        if let Some(ref mut head) = self.head {
+            *head = piece;
+        }
+    }
+
+    /// Priority of a piece, 0 if it's skipped.
+    pub fn priority(&self, piece: u32) -> u8 {
+        self.priorities.get(piece as usize).cloned().unwrap_or(0)
+    }
+
+    /// Sets or clears the time by which a piece is wanted, which only
+    /// orders it among other pieces with one.
+    pub fn set_deadline(&mut self, piece: u32, due: Option<time::Instant>) {
+        match due {
+            Some(due) => self.deadlines.insert(piece, due),
+            None => self.deadlines.remove(&piece),
+        };
+    }
+
+    /// Returns how far a piece is past the read head, if it's
+    /// within the streaming window.
+    fn stream_distance(&self, piece: u32) -> Option<u32> {
+        piece.checked_sub(self.head?).filter(|&d| d < STREAM_WINDOW)
+    }
+
     pub fn done(&mut self) {
         self.downloading = HashMap::with_capacity(0);
         self.blocks = vec![];
         self.stalled = FHashSet::default();
+        self.failures = FHashMap::default();
+        self.wanted = UHashMap::default();
+        self.deadlines = FHashMap::default();
     }
 
-    pub fn tick(&mut self) {
-        let mut expired = 0;
-        for (block, req) in &mut self.downloading {
-            let reqd = self.blocks[block.index as usize].0;
-            let _fully_reqd = reqd == self.scale as usize
-                || (block.index == self.last_piece && reqd == self.last_piece_scale as usize);
-            let deadline = (REQ_TIMEOUT as isize
-                + (3 - self.priorities[block.index as usize] as isize))
-                as u64;
+    /// Expires requests which took too long, so their blocks are
+    /// requested again, returning each expired block along with the
+    /// peers it was requested from.
+    pub fn tick(&mut self) -> Vec<(Block, usize)> {
+        if self.aged.elapsed().as_secs() >= FAILURE_DECAY_SECS {
+            self.aged = time::Instant::now();
+            self.failures.retain(|_, f| {
+                *f /= 2;
+                *f > 0
+            });
+        }
+        let mut expired = Vec::new();
+        for (block, req) in &self.downloading {
+            let failures = self.failures.get(&block.index).cloned().unwrap_or(0);
+            let mut deadline = req_deadline(self.priorities[block.index as usize], failures);
+            // Pieces about to be played back are due one after another
+            if let Some(d) = self.stream_distance(block.index) {
+                deadline = cmp::min(deadline, MIN_REQ_TIMEOUT + u64::from(d));
+            }
+            if self.deadlines.contains_key(&block.index) {
+                deadline = MIN_REQ_TIMEOUT;
+            }
             if req.requested_at.elapsed().as_secs() >= deadline && !self.stalled.contains(block) {
-                expired += 1;
-                self.stalled.insert(*block);
+                expired.push(*block);
             }
         }
-        if expired != 0 {
-            debug!("Expired {} chunks!", expired);
+        let mut timed_out = Vec::new();
+        for block in &expired {
+            self.stalled.insert(*block);
+            let f = self.failures.entry(block.index).or_insert(0);
+            *f = f.saturating_add(1);
+            let req = self.downloading.get_mut(block).unwrap();
+            timed_out.extend(req.reqd_from[..req.num_reqd].iter().map(|&id| (*block, id)));
+        }
+        if !expired.is_empty() {
+            debug!("Expired {} chunks!", expired.len());
         }
         if !self.downloading.is_empty() {
             debug!(
@@ -153,31 +269,182 @@ impl Picker {
                 self.downloading.len()
             );
         }
+        timed_out
     }
 
-    /// Attempts to select a block for a peer.
-    pub fn pick<T: cio::CIO>(&mut self, peer: &mut Peer<T>) -> Option<Block> {
+    /// Withdraws a request which timed out from a peer, after which the
+    /// peer's copy of the block is no longer waited on or cancelled.
+    pub fn withdraw(&mut self, b: Block, pid: usize) {
+        if let Some(req) = self.downloading.get_mut(&b) {
+            req.remove(pid);
+        }
+    }
+
+    /// Picks up to n blocks for a peer, so its request pipeline can be
+    /// filled in one pass. Whole runs of a piece's blocks are taken at
+    /// once, so the ordering is only consulted per piece rather than per block.
+    pub fn pick_blocks<T: cio::CIO>(&mut self, peer: &mut Peer<T>, n: usize) -> Vec<Block> {
+        let mut picked = Vec::with_capacity(n);
         if !self.stalled.is_empty() {
-            let block = self.stalled.iter().cloned().find(|b| {
-                peer.pieces().has_bit(u64::from(b.index))
-                    && !self.downloading[b].has_peer(peer.id())
+            let mut stalled: Vec<_> = self
+                .stalled
+                .iter()
+                .cloned()
+                .filter(|b| {
+                    peer.pieces().has_bit(u64::from(b.index))
+                        && !self.downloading[b].has_peer(peer.id())
+                })
+                .collect();
+            // Pieces due soonest, then those closest to the read head,
+            // then those failing most often are retried first
+            let failures = &self.failures;
+            stalled.sort_by_key(|b| {
+                let due = self.deadlines.get(&b.index);
+                (
+                    due.is_none(),
+                    due.cloned(),
+                    self.stream_distance(b.index).unwrap_or(STREAM_WINDOW),
+                    cmp::Reverse(failures.get(&b.index).cloned().unwrap_or(0)),
+                )
             });
-            if let Some(b) = block {
+            stalled.truncate(n);
+            for b in stalled {
                 self.stalled.remove(&b);
                 if let Some(req) = self.downloading.get_mut(&b) {
                     req.force_rereq(peer.id(), peer.rank);
                 }
-                return Some(b);
+                picked.push(b);
             }
         }
 
-        let piece = match self.picker {
-            PickerKind::Sequential(ref mut p) => p.pick(peer),
-            PickerKind::Rarest(ref mut p) => p.pick(peer),
-        };
-        piece
-            .map(|p| self.pick_piece(p, peer.id(), peer.rank))
-            .or_else(|| self.pick_dl(peer))
+        self.refresh_wanted(peer);
+        if !self.deadlines.is_empty() {
+            let mut due: Vec<_> = self.deadlines.iter().map(|(&p, &d)| (d, p)).collect();
+            due.sort_unstable();
+            for (_, piece) in due {
+                if picked.len() == n {
+                    break;
+                }
+                if self.priorities[piece as usize] != 0 {
+                    self.pick_remaining(piece, peer, n, &mut picked);
+                }
+            }
+        }
+        if let Some(head) = self.head {
+            let end = cmp::min(
+                head.saturating_add(STREAM_WINDOW),
+                self.unpicked.len() as u32,
+            );
+            for piece in head..end {
+                if picked.len() == n {
+                    break;
+                }
+                if self.priorities[piece as usize] != 0 {
+                    self.pick_remaining(piece, peer, n, &mut picked);
+                }
+            }
+        }
+        while picked.len() < n {
+            let wanted = &self.wanted[&peer.id()].0;
+            if wanted.set() == 0 {
+                break;
+            }
+            let piece = match self.picker {
+                PickerKind::Sequential(ref p) => p.pick(wanted),
+                PickerKind::Rarest(_) if self.have < self.random_first => {
+                    random_piece(wanted, &self.priorities, &self.blocks)
+                }
+                PickerKind::Rarest(ref mut p) => p.pick(peer, wanted),
+            };
+            let piece = match piece {
+                Some(p) => p,
+                None => break,
+            };
+            let left = self.piece_blocks(piece) - self.blocks[piece as usize].0;
+            for _ in 0..cmp::min(left, n - picked.len()) {
+                picked.push(self.pick_piece(piece, peer.id(), peer.rank));
+            }
+        }
+
+        while picked.len() < n {
+            match self.pick_dl(peer) {
+                Some(b) => picked.push(b),
+                None => break,
+            }
+        }
+        picked
+    }
+
+    /// Picks the unpicked blocks of a piece, if the peer has it, until
+    /// n blocks are picked.
+    fn pick_remaining<T: cio::CIO>(
+        &mut self,
+        piece: u32,
+        peer: &Peer<T>,
+        n: usize,
+        picked: &mut Vec<Block>,
+    ) {
+        if !self.wanted[&peer.id()].0.has_bit(u64::from(piece)) {
+            return;
+        }
+        let left = self.piece_blocks(piece) - self.blocks[piece as usize].0;
+        for _ in 0..cmp::min(left, n - picked.len()) {
+            picked.push(self.pick_piece(piece, peer.id(), peer.rank));
+        }
+    }
+
+    /// Picks every block of the highest priority piece which has no blocks
+    /// requested yet, for sources like webseeds which fetch whole pieces.
+    pub fn pick_whole(&mut self, id: usize) -> Option<u32> {
+        if self.blocks.is_empty() {
+            return None;
+        }
+        let mut best: Option<u32> = None;
+        for piece in 0..self.unpicked.len() as u32 {
+            let pri = self.priorities[piece as usize];
+            if pri == 0
+                || self.unpicked.has_bit(u64::from(piece))
+                || self.blocks[piece as usize].0 != 0
+            {
+                continue;
+            }
+            if best
+                .map(|b| pri > self.priorities[b as usize])
+                .unwrap_or(true)
+            {
+                best = Some(piece);
+            }
+        }
+        let piece = best?;
+        for _ in 0..self.piece_blocks(piece) {
+            self.pick_piece(piece, id, 0);
+        }
+        Some(piece)
+    }
+
+    /// Recomputes the pieces a peer has which are unpicked if
+    /// they're unknown or out of date.
+    fn refresh_wanted<T: cio::CIO>(&mut self, peer: &Peer<T>) {
+        let current = self
+            .wanted
+            .get(&peer.id())
+            .is_some_and(|&(_, gen)| gen == self.generation);
+        if !current {
+            let wanted = peer.pieces().difference(&self.unpicked);
+            self.wanted.insert(peer.id(), (wanted, self.generation));
+        }
+    }
+
+    /// Marks every block of a piece as picked.
+    fn picked(&mut self, piece: u32) {
+        match self.picker {
+            PickerKind::Sequential(ref mut p) => p.completed(piece),
+            PickerKind::Rarest(ref mut p) => p.completed(piece),
+        }
+        self.unpicked.set_bit(u64::from(piece));
+        for (wanted, _) in self.wanted.values_mut() {
+            wanted.unset_bit(u64::from(piece));
+        }
     }
 
     /// Picks a block from a given piece for a peer
@@ -185,14 +452,8 @@ impl Picker {
         self.blocks[piece as usize].0 += 1;
         let amnt = self.blocks[piece as usize].0;
         let offset = (amnt - 1) as u32 * 16_384;
-        if amnt == self.scale as usize
-            || (piece == self.last_piece && amnt == self.last_piece_scale as usize)
-        {
-            match self.picker {
-                PickerKind::Sequential(ref mut p) => p.completed(piece),
-                PickerKind::Rarest(ref mut p) => p.completed(piece),
-            }
-            self.unpicked.set_bit(u64::from(piece));
+        if amnt == self.piece_blocks(piece) {
+            self.picked(piece);
         }
         let block = Block {
             index: piece,
@@ -202,11 +463,21 @@ impl Picker {
         block
     }
 
-    /// Attempts to pick the highest priority piece in the dl q
+    /// Attempts to pick the least requested block in the dl q during
+    /// endgame, so the last blocks don't wait on the slowest peers.
+    /// Duplicates are cancelled once one of them arrives.
     fn pick_dl<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<Block> {
+        // Before that, blocks are only requested again once stalled
+        if self.downloading.len() >= ENDGAME_BLOCKS {
+            return None;
+        }
         self.downloading
             .iter_mut()
-            .filter(|&(_, ref req)| req.num_reqd < MAX_DUP_REQS && !req.has_peer(peer.id()))
+            .filter(|&(block, ref req)| {
+                req.num_reqd < MAX_DUP_REQS
+                    && !req.has_peer(peer.id())
+                    && peer.pieces().has_bit(u64::from(block.index))
+            })
             .take(MAX_DL_REREQ)
             .fold(None, |c: Option<(&Block, &mut Request)>, this| match &c {
                 Some(min) => {
@@ -234,22 +505,95 @@ impl Picker {
             Some(dl) => dl,
             None => return Err(()),
         };
-        for peer in dl.reqd_from.iter() {
+        for peer in &dl.reqd_from[..dl.num_reqd] {
             cancel(*peer);
         }
+        // A piece arriving again makes up for a past failure
+        if let Some(f) = self.failures.get_mut(&b.index) {
+            *f -= 1;
+            if *f == 0 {
+                self.failures.remove(&b.index);
+            }
+        }
 
         self.blocks[b.index as usize].1 += 1;
         let amnt = self.blocks[b.index as usize].1;
-        if amnt == self.scale as usize
-            || (b.index == self.last_piece && amnt == self.last_piece_scale as usize)
+        let done = amnt == self.piece_blocks(b.index);
+        if done {
+            self.have += 1;
+            self.deadlines.remove(&b.index);
+        }
+        Ok(done)
+    }
+
+    fn piece_blocks(&self, piece: u32) -> usize {
+        self.short.get(&piece).cloned().unwrap_or(self.scale) as usize
+    }
+
+    /// Marks a block as rejected by a peer, making it available
+    /// to be requested from another peer.
+    pub fn rejected(&mut self, b: Block, pid: usize) {
+        if let Some(req) = self.downloading.get_mut(&b) {
+            if req.remove(pid) {
+                self.stalled.insert(b);
+                let f = self.failures.entry(b.index).or_insert(0);
+                *f = f.saturating_add(1);
+            }
+        }
+    }
+
+    /// Returns the completed blocks of pieces which are partially
+    /// downloaded, so they can be restored with restore_partial.
+    pub fn partial(&self) -> Vec<(u32, Bitfield)> {
+        let mut partial = Vec::new();
+        for (piece, &(picked, done)) in self.blocks.iter().enumerate() {
+            let piece = piece as u32;
+            let total = self.piece_blocks(piece);
+            if done == 0 || done == total {
+                continue;
+            }
+            let mut blocks = Bitfield::new(total as u64);
+            for i in 0..picked {
+                if self.have_block(Block::new(piece, i as u32 * 16_384)) {
+                    blocks.set_bit(i as u64);
+                }
+            }
+            partial.push((piece, blocks));
+        }
+        partial
+    }
+
+    /// Marks the given blocks of a piece as completed, so only the
+    /// remaining ones are requested.
+    pub fn restore_partial(&mut self, piece: u32, done: &Bitfield) {
+        let total = self.piece_blocks(piece);
+        if self.blocks.is_empty()
+            || self.unpicked.has_bit(u64::from(piece))
+            || self.blocks[piece as usize] != (0, 0)
+            || done.len() != total as u64
         {
-            Ok(true)
-        } else {
-            Ok(false)
+            return;
+        }
+        let picked = match done.iter().last() {
+            Some(last) => last as usize + 1,
+            None => return,
+        };
+        // Blocks below the last completed one are treated as stalled requests,
+        // picking continues normally after it.
+        for i in 0..picked {
+            if !done.has_bit(i as u64) {
+                let block = Block::new(piece, i as u32 * 16_384);
+                self.downloading.insert(block, Request::empty());
+                self.stalled.insert(block);
+            }
+        }
+        self.blocks[piece as usize] = (picked, done.set() as usize);
+        if picked == total {
+            self.picked(piece);
         }
     }
 
-    pub fn have_block(&mut self, b: Block) -> bool {
+    pub fn have_block(&self, b: Block) -> bool {
         !self.downloading.contains_key(&b)
     }
 
@@ -262,106 +606,251 @@ impl Picker {
         if self.blocks.is_empty() {
             self.blocks = vec![(0, 0); self.priorities.len()];
         }
+        // Pieces which were picked but not completed yet weren't had
+        let (picked, done) = self.blocks[idx as usize];
+        if self.unpicked.has_bit(u64::from(idx)) && (picked == 0 || done == self.piece_blocks(idx))
+        {
+            self.have = self.have.saturating_sub(1);
+        }
         self.blocks[idx as usize] = (0, 0);
         self.unpicked.unset_bit(u64::from(idx));
+        self.generation += 1;
+    }
+
+    /// Marks a piece as had without it being downloaded, e.g. as a check
+    /// found it on disk. Outstanding requests of its blocks are dropped.
+    pub fn have_piece(&mut self, idx: u32) {
+        if self.blocks.is_empty() {
+            return;
+        }
+        let total = self.piece_blocks(idx);
+        let (picked, done) = self.blocks[idx as usize];
+        if self.unpicked.has_bit(u64::from(idx)) && (picked == 0 || done == total) {
+            return;
+        }
+        for i in 0..total {
+            let block = Block::new(idx, i as u32 * 16_384);
+            self.downloading.remove(&block);
+            self.stalled.remove(&block);
+        }
+        if !self.unpicked.has_bit(u64::from(idx)) {
+            self.picked(idx);
+        }
+        self.blocks[idx as usize] = (total, total);
+        self.failures.remove(&idx);
+        self.deadlines.remove(&idx);
+        self.have += 1;
     }
 
-    pub fn piece_available(&mut self, idx: u32) {
+    /// Handles a peer getting a piece, after it was added to the
+    /// peer's bitfield.
+    pub fn piece_available<T: cio::CIO>(&mut self, peer: &Peer<T>, idx: u32) {
         if let PickerKind::Rarest(ref mut p) = self.picker {
             p.piece_available(idx);
         }
+        if let Some((wanted, _)) = self.wanted.get_mut(&peer.id()) {
+            if !self.unpicked.has_bit(u64::from(idx)) {
+                wanted.set_bit(u64::from(idx));
+            }
+        }
+    }
+
+    /// Handles a peer no longer having a piece, after it was
+    /// removed from the peer's bitfield.
+    pub fn piece_unavailable<T: cio::CIO>(&mut self, peer: &Peer<T>, idx: u32) {
+        if let Some((wanted, _)) = self.wanted.get_mut(&peer.id()) {
+            wanted.unset_bit(u64::from(idx));
+        }
+        if self.seeders.remove(&peer.id()) {
+            // Seeders aren't counted piece by piece, so count all the remaining ones
+            if let PickerKind::Rarest(ref mut p) = self.picker {
+                p.add_peer(peer);
+            }
+        } else if let PickerKind::Rarest(ref mut p) = self.picker {
+            p.piece_unavailable(idx);
+        }
     }
 
     pub fn add_peer<T: cio::CIO>(&mut self, peer: &Peer<T>) {
+        self.wanted.remove(&peer.id());
+        self.refresh_wanted(peer);
         if peer.pieces().complete() {
-            self.seeders += 1;
+            self.seeders.insert(peer.id());
         } else if let PickerKind::Rarest(ref mut p) = self.picker {
             p.add_peer(peer);
         }
     }
 
     pub fn remove_peer<T: cio::CIO>(&mut self, peer: &Peer<T>) {
-        // Have to consider situation where a peer became a seeder but joined as leecher.
-        if peer.pieces().complete() && self.seeders > 0 {
-            self.seeders -= 1;
-        } else if let PickerKind::Rarest(ref mut p) = self.picker {
-            p.remove_peer(peer);
+        self.wanted.remove(&peer.id());
+        // Peers which became seeders after joining as leechers are counted piece by piece
+        if !self.seeders.remove(&peer.id()) {
+            if let PickerKind::Rarest(ref mut p) = self.picker {
+                p.remove_peer(peer);
+            }
         }
 
         for (_, req) in self.downloading.iter_mut() {
-            if let Some((idx, _)) = req
-                .reqd_from
-                .iter()
-                .enumerate()
-                .find(|&(_, id)| *id == peer.id())
-            {
-                req.num_reqd -= 1;
-                req.reqd_from[idx] = req.reqd_from[req.num_reqd];
+            req.remove(peer.id());
+        }
+    }
+
+    /// Withdraws the requests sent to a peer, so their blocks are
+    /// requested from others, returning the blocks to cancel.
+    pub fn cancel_peer(&mut self, peer: usize) -> Vec<Block> {
+        let mut cancelled = Vec::new();
+        for (block, req) in self.downloading.iter_mut() {
+            if req.remove(peer) {
+                self.stalled.insert(*block);
+                cancelled.push(*block);
+            }
+        }
+        cancelled
+    }
+
+    /// Number of peers having each piece, taken from the rarest picker's
+    /// counts, or counted from the bitfields of peers, which should be all
+    /// of the torrent's, when pieces are picked sequentially.
+    pub fn piece_availability<'a, T, I>(&self, peers: I) -> Vec<usize>
+    where
+        T: cio::CIO + 'a,
+        I: IntoIterator<Item = &'a Peer<T>>,
+    {
+        let len = self.unpicked.len() as u32;
+        match self.picker {
+            PickerKind::Rarest(ref p) => (0..len)
+                .map(|piece| p.availability(piece) + self.seeders.len())
+                .collect(),
+            PickerKind::Sequential(_) => {
+                let mut counts = vec![0; len as usize];
+                for peer in peers {
+                    for piece in peer.pieces().iter() {
+                        counts[piece as usize] += 1;
+                    }
+                }
+                counts
+            }
+        }
+    }
+
+    /// Recomputes piece availability from the bitfields of peers, which
+    /// should be all of the torrent's, returning the pieces whose count
+    /// has drifted as (piece, counted, actual), or None if availability
+    /// isn't counted because pieces are picked sequentially.
+    pub fn audit_availability<'a, T, I>(&self, peers: I) -> Option<Vec<(u32, usize, usize)>>
+    where
+        T: cio::CIO + 'a,
+        I: IntoIterator<Item = &'a Peer<T>>,
+    {
+        let p = match self.picker {
+            PickerKind::Rarest(ref p) => p,
+            PickerKind::Sequential(_) => return None,
+        };
+        let mut actual = vec![0; self.unpicked.len() as usize];
+        for peer in peers {
+            // Seeders count towards no piece in particular
+            if self.seeders.contains(&peer.id()) {
+                continue;
+            }
+            for piece in peer.pieces().iter() {
+                actual[piece as usize] += 1;
             }
         }
+        let drift = actual
+            .into_iter()
+            .enumerate()
+            .map(|(piece, actual)| (piece as u32, p.availability(piece as u32), actual))
+            .filter(|&(_, counted, actual)| counted != actual)
+            .collect();
+        Some(drift)
     }
 
-    /// Alters the picker to sequential/non sequential. If changing
-    /// from sequential to non sequential, peer state will need to be loaded
-    /// after this.
-    pub fn change_picker(&mut self, sequential: bool) {
-        self.picker = if sequential {
+    /// Alters the picking mode. If changing to a mode picking rarest
+    /// first, peer state will need to be loaded after this. Requests
+    /// and the blocks picked and completed of each piece are kept apart
+    /// from the ordering, so downloads in progress carry over.
+    pub fn change_picker(&mut self, mode: Mode) {
+        self.picker = if mode == Mode::Sequential {
             PickerKind::Sequential(sequential::Picker::new(&self.unpicked))
         } else {
             PickerKind::Rarest(rarest::Picker::new(&self.unpicked))
         };
+        self.head = match mode {
+            Mode::Streaming { head } => Some(head),
+            _ => None,
+        };
     }
 
     pub fn set_priorities(&mut self, pri: &[u8], info: &Arc<Info>) {
         self.unapply_priorities();
         self.priorities = generate_piece_pri(pri, info);
+        if self.first_last {
+            boost_first_last(&mut self.priorities, pri, info);
+        }
         self.apply_priorities();
     }
 
+    /// Sets whether the first and last pieces of files are picked
+    /// first, taking effect with the next set_priorities.
+    pub fn set_first_last(&mut self, first_last: bool) {
+        self.first_last = first_last;
+    }
+
     pub fn apply_priorities(&mut self) {
         if self.is_sequential() {
             self.picker = PickerKind::Sequential(sequential::Picker::with_pri(
                 &self.unpicked,
                 &self.priorities,
             ));
-        } else {
+        } else if let PickerKind::Rarest(ref mut p) = self.picker {
             for (piece, pri) in self.priorities.iter().enumerate() {
-                if let PickerKind::Rarest(ref mut p) = self.picker {
-                    for _ in 0..*pri {
-                        p.piece_unavailable(piece as u32);
-                    }
-                }
-
+                p.set_priority(piece as u32, *pri);
+                // Skipped pieces are kept out of the ordering entirely
                 if *pri == 0 && !self.unpicked.has_bit(piece as u64) {
-                    match self.picker {
-                        PickerKind::Rarest(ref mut p) => p.completed(piece as u32),
-                        _ => unreachable!(),
-                    }
+                    p.completed(piece as u32);
                 }
             }
         }
     }
 
     pub fn unapply_priorities(&mut self) {
-        if !self.is_sequential() {
+        if let PickerKind::Rarest(ref mut p) = self.picker {
             for (piece, pri) in self.priorities.iter().enumerate() {
-                if let PickerKind::Rarest(ref mut p) = self.picker {
-                    for _ in 0..*pri {
-                        p.piece_available(piece as u32);
-                    }
-                }
-
                 if *pri == 0 && !self.unpicked.has_bit(piece as u64) {
-                    match self.picker {
-                        PickerKind::Rarest(ref mut p) => p.incomplete(piece as u32),
-                        _ => unreachable!(),
-                    }
+                    p.incomplete(piece as u32);
                 }
             }
         }
     }
 }
 
+/// Picks a random piece of wanted with the highest priority, preferring
+/// ones already in progress so they complete sooner.
+fn random_piece(wanted: &Bitfield, priorities: &[u8], blocks: &[(usize, usize)]) -> Option<u32> {
+    let top = wanted.iter().map(|p| priorities[p as usize]).max()?;
+    if top == 0 {
+        return None;
+    }
+    let candidates = || {
+        wanted
+            .iter()
+            .map(|p| p as u32)
+            .filter(|&p| priorities[p as usize] == top)
+    };
+    random_sample(candidates().filter(|&p| blocks[p as usize].0 != 0))
+        .or_else(|| random_sample(candidates()))
+}
+
+/// Seconds a request is waited on before being handed to another peer,
+/// shorter for high priority pieces and ones which keep failing.
+fn req_deadline(priority: u8, failures: u8) -> u64 {
+    let base = (CONFIG.peer.request_timeout + 3).saturating_sub(u64::from(priority));
+    cmp::max(
+        base >> cmp::min(failures, MAX_FAILURE_SHIFT),
+        MIN_REQ_TIMEOUT,
+    )
+}
+
 fn generate_piece_pri(pri: &[u8], info: &Arc<Info>) -> Vec<u8> {
     // Map piece -> priority
     let mut priorities = Vec::with_capacity(info.pieces() as usize);
@@ -377,14 +866,36 @@ fn generate_piece_pri(pri: &[u8], info: &Arc<Info>) -> Vec<u8> {
     priorities
 }
 
+/// Raises the first and last pieces of each file which isn't skipped
+/// to the top priority.
+fn boost_first_last(priorities: &mut [u8], pri: &[u8], info: &Info) {
+    for (file, f) in info.files.iter().enumerate() {
+        if pri[file] == 0 || f.length == 0 {
+            continue;
+        }
+        for piece in [info.piece_at(file, 0), info.piece_at(file, f.length - 1)] {
+            if let Some(p) = priorities.get_mut(piece as usize) {
+                *p = MAX_PRIORITY;
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 impl Picker {
+    /// Attempts to select a block for a peer.
+    pub fn pick<T: cio::CIO>(&mut self, peer: &mut Peer<T>) -> Option<Block> {
+        self.pick_blocks(peer, 1).pop()
+    }
+
     pub fn new_rarest(info: &Info, pieces: &Bitfield) -> Picker {
-        Picker::new(
+        let mut p = Picker::new(
             &Arc::new(info.clone()),
             pieces,
             &vec![3u8; info.files.len()],
-        )
+        );
+        p.random_first = 0;
+        p
     }
 
     pub fn new_sequential(info: &Info, pieces: &Bitfield) -> Picker {
@@ -393,7 +904,7 @@ impl Picker {
             pieces,
             &vec![3u8; info.files.len()],
         );
-        p.change_picker(true);
+        p.change_picker(Mode::Sequential);
         p
     }
 }
@@ -416,6 +927,16 @@ impl Request {
         }
     }
 
+    /// A request which hasn't been sent to any peer yet.
+    fn empty() -> Request {
+        Request {
+            rank: 0,
+            requested_at: time::Instant::now(),
+            reqd_from: [usize::MAX; MAX_DUP_REQS],
+            num_reqd: 0,
+        }
+    }
+
     fn rereq(&mut self, peer: usize, rank: usize) {
         self.rank = rank;
         self.reqd_from[self.num_reqd] = peer;
@@ -434,6 +955,22 @@ impl Request {
     }
 
     fn has_peer(&self, peer: usize) -> bool {
-        self.reqd_from.contains(&peer)
+        self.reqd_from[..self.num_reqd].contains(&peer)
+    }
+
+    /// Forgets that the block was requested from peer, returning
+    /// whether it was.
+    fn remove(&mut self, peer: usize) -> bool {
+        match self.reqd_from[..self.num_reqd]
+            .iter()
+            .position(|id| *id == peer)
+        {
+            Some(idx) => {
+                self.num_reqd -= 1;
+                self.reqd_from[idx] = self.reqd_from[self.num_reqd];
+                true
+            }
+            None => false,
+        }
     }
 }
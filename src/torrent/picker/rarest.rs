@@ -1,4 +1,5 @@
 // Implementation based off of http://blog.libtorrent.org/2011/11/writing-a-fast-piece-picker/
+use std::cmp;
 use std::ops::IndexMut;
 
 use super::MAX_PC_SIZE;
@@ -7,12 +8,19 @@ use crate::torrent::{Bitfield, Peer};
 
 #[derive(Clone, Debug)]
 pub struct Picker {
-    /// Current order of pieces
+    /// Current order of pieces. Incomplete pieces come first, grouped into
+    /// priority bands from the highest priority down and ordered by
+    /// availability within each band, followed by completed ones in no
+    /// particular order.
     pieces: Vec<u32>,
-    /// Indices into pieces which indicate priority bounds
-    priorities: Vec<usize>,
+    /// Indices into pieces which indicate the availability bounds
+    /// of each priority band
+    priorities: Vec<Vec<usize>>,
     /// Index mapping a piece to a position in the pieces field
     piece_idx: Vec<PieceInfo>,
+    /// Number of incomplete pieces, which are the only ones ordered
+    /// and scanned when picking
+    active: usize,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -26,24 +34,40 @@ struct PieceInfo {
     idx: usize,
     availability: usize,
     status: PieceStatus,
+    band: usize,
 }
 
-const PIECE_COMPLETE_DEC: usize = 100;
+/// Number of priority bands, one for each non zero priority
+const BANDS: usize = 5;
+
+/// Returns the band of a priority, with the highest priority first.
+fn band(priority: u8) -> usize {
+    BANDS - cmp::min(cmp::max(priority, 1), BANDS as u8) as usize
+}
 
 impl Picker {
     pub fn new(pieces: &Bitfield) -> Picker {
+        let len = pieces.len() as usize;
+        let default = band(3);
         let mut piece_idx = Vec::new();
-        for i in 0..pieces.len() {
+        for i in 0..len {
             piece_idx.push(PieceInfo {
-                idx: i as usize,
+                idx: i,
                 availability: 0,
                 status: PieceStatus::Incomplete,
+                band: default,
             });
         }
+        // Every piece starts in the default band, so the ones before it are empty
+        let mut priorities = vec![vec![0]; BANDS];
+        for bounds in &mut priorities[default..] {
+            bounds[0] = len;
+        }
         let mut p = Picker {
-            pieces: (0..pieces.len() as u32).collect(),
+            pieces: (0..len as u32).collect(),
             piece_idx,
-            priorities: vec![pieces.len() as usize],
+            priorities,
+            active: len,
         };
 
         // Start every piece at an availability of 6.
@@ -73,23 +97,42 @@ impl Picker {
         }
     }
 
+    /// Number of peers counted as having a piece.
+    pub fn availability(&self, piece: u32) -> usize {
+        // Counts start at 12 and go up by 2 per peer, less 1 once picked
+        let count = self.piece_idx[piece as usize].availability;
+        if count < 11 {
+            error!(
+                "Availability count of piece {} drifted below its base: {}",
+                piece, count
+            );
+        }
+        count.div_ceil(2).saturating_sub(6)
+    }
+
     pub fn piece_available(&mut self, piece: u32) {
         self.dec_pri(piece);
         self.dec_pri(piece);
     }
 
     pub fn dec_pri(&mut self, piece: u32) {
-        let (idx, avail) = {
+        let (idx, avail, band) = {
             let piece = self.piece_idx.index_mut(piece as usize);
-            self.priorities[piece.availability] -= 1;
             piece.availability += 1;
-            if self.priorities.len() == piece.availability {
-                self.priorities.push(self.pieces.len());
+            // Completed pieces are outside of the ordering
+            if piece.status == PieceStatus::Complete {
+                return;
             }
-            (piece.idx, piece.availability - 1)
+            (piece.idx, piece.availability - 1, piece.band)
         };
 
-        let swap_idx = self.priorities[avail];
+        let bounds = &mut self.priorities[band];
+        let end = *bounds.last().unwrap();
+        bounds[avail] -= 1;
+        if bounds.len() == avail + 1 {
+            bounds.push(end);
+        }
+        let swap_idx = bounds[avail];
         self.swap_piece(idx, swap_idx);
     }
 
@@ -99,18 +142,38 @@ impl Picker {
     }
 
     pub fn inc_pri(&mut self, piece: u32) {
-        let (idx, avail) = {
+        let (idx, avail, band) = {
             let piece = self.piece_idx.index_mut(piece as usize);
             piece.availability -= 1;
-            self.priorities[piece.availability] += 1;
-            (piece.idx, piece.availability)
+            if piece.status == PieceStatus::Complete {
+                return;
+            }
+            (piece.idx, piece.availability, piece.band)
         };
 
-        let swap_idx = self.priorities[avail - 1];
+        // The first piece of the bucket joins the one below
+        let bounds = &mut self.priorities[band];
+        let swap_idx = bounds[avail];
+        bounds[avail] += 1;
         self.swap_piece(idx, swap_idx);
     }
 
-    pub fn pick<T: cio::CIO>(&mut self, peer: &mut Peer<T>) -> Option<u32> {
+    /// Moves a piece into the band of a priority, so it's picked
+    /// before all pieces of lower priorities.
+    pub fn set_priority(&mut self, piece: u32, priority: u8) {
+        let info = &mut self.piece_idx[piece as usize];
+        if info.status == PieceStatus::Complete {
+            info.band = band(priority);
+            return;
+        }
+        let avail = info.availability;
+        self.move_band(piece, band(priority));
+        self.set_availability(piece, avail);
+    }
+
+    /// Picks the rarest piece of wanted, which are the unpicked
+    /// pieces the peer has.
+    pub fn pick<T: cio::CIO>(&mut self, peer: &mut Peer<T>, wanted: &Bitfield) -> Option<u32> {
         while !peer.piece_cache().is_empty() {
             let p = peer.piece_cache().last().cloned().unwrap();
             if self.piece_idx[p as usize].status == PieceStatus::Complete {
@@ -121,14 +184,25 @@ impl Picker {
         }
 
         if peer.piece_cache().is_empty() {
-            for piece in &self.pieces {
-                if peer.pieces().has_bit(u64::from(*piece))
-                    && self.piece_idx[*piece as usize].status == PieceStatus::Incomplete
-                {
-                    peer.piece_cache().push(*piece);
-                }
-                if peer.piece_cache().len() >= MAX_PC_SIZE {
-                    break;
+            if (wanted.set() as usize) * MAX_PC_SIZE < self.active {
+                // With few candidates, ordering them is faster than
+                // scanning the ordering for them
+                let mut pieces: Vec<_> = wanted
+                    .iter()
+                    .map(|p| p as u32)
+                    .filter(|&p| self.piece_idx[p as usize].status == PieceStatus::Incomplete)
+                    .collect();
+                pieces.sort_unstable_by_key(|&p| self.piece_idx[p as usize].idx);
+                pieces.truncate(MAX_PC_SIZE);
+                peer.piece_cache().extend(pieces);
+            } else {
+                for piece in &self.pieces[..self.active] {
+                    if wanted.has_bit(u64::from(*piece)) {
+                        peer.piece_cache().push(*piece);
+                    }
+                    if peer.piece_cache().len() >= MAX_PC_SIZE {
+                        break;
+                    }
                 }
             }
             peer.piece_cache().reverse();
@@ -143,23 +217,105 @@ impl Picker {
         piece.cloned()
     }
 
+    /// Puts a completed piece back into the ordering.
     pub fn incomplete(&mut self, piece: u32) {
-        if self.piece_idx[piece as usize].status != PieceStatus::Incomplete {
-            self.piece_idx[piece as usize].status = PieceStatus::Incomplete;
-            for _ in 0..PIECE_COMPLETE_DEC {
-                self.inc_pri(piece);
-            }
+        if self.piece_idx[piece as usize].status == PieceStatus::Incomplete {
+            return;
         }
+        // Grow the most available bucket of the last band by the first
+        // completed piece's slot, then move the piece to its actual bucket.
+        let info = &self.piece_idx[piece as usize];
+        let (avail, band, idx) = (info.availability, info.band, info.idx);
+        self.swap_piece(idx, self.active);
+        self.active += 1;
+        let bounds = &mut self.priorities[BANDS - 1];
+        *bounds.last_mut().unwrap() += 1;
+        let top = bounds.len() - 1;
+        let info = &mut self.piece_idx[piece as usize];
+        info.status = PieceStatus::Incomplete;
+        info.band = BANDS - 1;
+        info.availability = top;
+        self.move_band(piece, band);
+        self.set_availability(piece, avail);
     }
 
+    /// Removes a piece from the ordering, so it's no longer
+    /// scanned when picking.
     pub fn completed(&mut self, piece: u32) {
-        if self.piece_idx[piece as usize].status != PieceStatus::Complete {
-            self.piece_idx[piece as usize].status = PieceStatus::Complete;
-            // As hacky as this is, it's a good way to ensure that
-            // we never waste time picking already selected pieces
-            for _ in 0..PIECE_COMPLETE_DEC {
+        if self.piece_idx[piece as usize].status == PieceStatus::Complete {
+            return;
+        }
+        // Move the piece to the end of the last band, which
+        // borders the completed ones.
+        let info = &self.piece_idx[piece as usize];
+        let (avail, band) = (info.availability, info.band);
+        self.move_band(piece, BANDS - 1);
+        self.move_to_edge(piece, false);
+        self.active -= 1;
+        *self.priorities[BANDS - 1].last_mut().unwrap() -= 1;
+        let info = &mut self.piece_idx[piece as usize];
+        info.status = PieceStatus::Complete;
+        info.availability = avail;
+        info.band = band;
+    }
+
+    /// Moves an incomplete piece one band at a time into band. Its
+    /// availability is left at the bucket it entered the band in.
+    fn move_band(&mut self, piece: u32, band: usize) {
+        loop {
+            let current = self.piece_idx[piece as usize].band;
+            if current < band {
+                // The last slot of the band becomes the first of the next one
+                self.move_to_edge(piece, false);
+                *self.priorities[current].last_mut().unwrap() -= 1;
+                let info = &mut self.piece_idx[piece as usize];
+                info.band = current + 1;
+                info.availability = 0;
+            } else if current > band {
+                // The first slot of the band becomes the last of the previous one
+                self.move_to_edge(piece, true);
+                let bounds = &mut self.priorities[current - 1];
+                *bounds.last_mut().unwrap() += 1;
+                let top = bounds.len() - 1;
+                let info = &mut self.piece_idx[piece as usize];
+                info.band = current - 1;
+                info.availability = top;
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Moves a piece to the first or last slot of its band.
+    fn move_to_edge(&mut self, piece: u32, first: bool) {
+        let band = self.piece_idx[piece as usize].band;
+        let slot = if first {
+            while self.piece_idx[piece as usize].availability > 0 {
+                self.inc_pri(piece);
+            }
+            if band == 0 {
+                0
+            } else {
+                *self.priorities[band - 1].last().unwrap()
+            }
+        } else {
+            let top = self.priorities[band].len() - 1;
+            while self.piece_idx[piece as usize].availability < top {
                 self.dec_pri(piece);
             }
+            *self.priorities[band].last().unwrap() - 1
+        };
+        let idx = self.piece_idx[piece as usize].idx;
+        self.swap_piece(idx, slot);
+    }
+
+    /// Moves a piece within its band to the bucket of avail.
+    fn set_availability(&mut self, piece: u32, avail: usize) {
+        while self.piece_idx[piece as usize].availability > avail {
+            self.inc_pri(piece);
+        }
+        while self.piece_idx[piece as usize].availability < avail {
+            self.dec_pri(piece);
         }
     }
 
@@ -175,6 +331,13 @@ mod tests {
     use super::Picker;
     use crate::torrent::{Bitfield, Peer};
 
+    type TPeer = Peer<crate::control::cio::test::TCIO>;
+
+    fn pick(picker: &mut Picker, peer: &mut TPeer) -> Option<u32> {
+        let wanted = peer.pieces().clone();
+        picker.pick(peer, &wanted)
+    }
+
     #[test]
     fn test_available() {
         let b = Bitfield::new(3);
@@ -184,7 +347,7 @@ mod tests {
             Peer::test_from_pieces(0, b.clone()),
             Peer::test_from_pieces(0, b.clone()),
         ];
-        assert_eq!(picker.pick(&mut peers[0]), None);
+        assert_eq!(pick(&mut picker, &mut peers[0]), None);
 
         peers[0].pieces_mut().set_bit(0);
         peers[1].pieces_mut().set_bit(0);
@@ -194,13 +357,13 @@ mod tests {
         for peer in peers.iter() {
             picker.add_peer(peer);
         }
-        assert_eq!(picker.pick(&mut peers[1]), Some(2));
+        assert_eq!(pick(&mut picker, &mut peers[1]), Some(2));
         picker.completed(2);
-        assert_eq!(picker.pick(&mut peers[1]), Some(0));
+        assert_eq!(pick(&mut picker, &mut peers[1]), Some(0));
         picker.completed(0);
-        assert_eq!(picker.pick(&mut peers[1]), None);
-        assert_eq!(picker.pick(&mut peers[0]), None);
-        assert_eq!(picker.pick(&mut peers[2]), Some(1));
+        assert_eq!(pick(&mut picker, &mut peers[1]), None);
+        assert_eq!(pick(&mut picker, &mut peers[0]), None);
+        assert_eq!(pick(&mut picker, &mut peers[2]), Some(1));
         picker.completed(1);
     }
 
@@ -214,7 +377,7 @@ mod tests {
             Peer::test_from_pieces(0, b.clone()),
             Peer::test_from_pieces(0, b.clone()),
         ];
-        assert_eq!(picker.pick(&mut peers[0]), None);
+        assert_eq!(pick(&mut picker, &mut peers[0]), None);
 
         peers[0].pieces_mut().set_bit(0);
         peers[0].pieces_mut().set_bit(1);
@@ -228,15 +391,111 @@ mod tests {
         }
         picker.remove_peer(&mut peers[0]);
 
-        assert_eq!(picker.pick(&mut peers[1]), Some(2));
+        assert_eq!(pick(&mut picker, &mut peers[1]), Some(2));
         picker.completed(2);
-        assert_eq!(picker.pick(&mut peers[2]), Some(0));
+        assert_eq!(pick(&mut picker, &mut peers[2]), Some(0));
         picker.completed(0);
-        assert_eq!(picker.pick(&mut peers[2]), Some(1));
+        assert_eq!(pick(&mut picker, &mut peers[2]), Some(1));
         picker.completed(1);
 
-        assert_eq!(picker.pick(&mut peers[1]), None);
+        assert_eq!(pick(&mut picker, &mut peers[1]), None);
         picker.incomplete(1);
-        assert_eq!(picker.pick(&mut peers[1]), Some(1));
+        assert_eq!(pick(&mut picker, &mut peers[1]), Some(1));
+    }
+
+    /// Checks that incomplete pieces are ordered by band, then availability.
+    fn assert_ordered(picker: &Picker) {
+        let mut start = 0;
+        for (band, bounds) in picker.priorities.iter().enumerate() {
+            let mut bucket = 0;
+            for i in start..*bounds.last().unwrap() {
+                while bounds[bucket] <= i {
+                    bucket += 1;
+                }
+                let info = &picker.piece_idx[picker.pieces[i] as usize];
+                assert_eq!(info.availability, bucket);
+                assert_eq!(info.band, band);
+                assert_eq!(info.idx, i);
+            }
+            start = *bounds.last().unwrap();
+        }
+        assert_eq!(start, picker.active);
+    }
+
+    #[test]
+    fn test_availability_drift() {
+        let b = Bitfield::new(2);
+        let mut picker = Picker::new(&b);
+        let mut peer = Peer::test_from_pieces(0, b.clone());
+        peer.pieces_mut().set_bit(0);
+        // A peer removed without having been added must not wrap the count
+        picker.remove_peer(&peer);
+        assert_eq!(picker.availability(0), 0);
+        picker.add_peer(&peer);
+        picker.add_peer(&peer);
+        assert_eq!(picker.availability(0), 1);
+    }
+
+    #[test]
+    fn test_compaction() {
+        let mut b = Bitfield::new(5);
+        b.set_bit(1);
+        let mut picker = Picker::new(&b);
+        let mut peer = Peer::test_from_pieces(0, Bitfield::full(5));
+        peer.pieces_mut().unset_bit(4);
+        picker.add_peer(&peer);
+        assert_eq!(picker.active, 4);
+        picker.completed(3);
+        picker.completed(0);
+        assert_eq!(picker.active, 2);
+        assert_ordered(&picker);
+        assert_eq!(pick(&mut picker, &mut peer), Some(2));
+
+        // Availability of completed pieces is still tracked
+        picker.remove_peer(&peer);
+        picker.incomplete(3);
+        assert_eq!(picker.active, 3);
+        assert_ordered(&picker);
+        assert_eq!(picker.piece_idx[3].availability, 12);
+        picker.incomplete(1);
+        assert_ordered(&picker);
+    }
+
+    #[test]
+    fn test_bands() {
+        let mut b = Bitfield::new(6);
+        let mut picker = Picker::new(&b);
+        picker.set_priority(2, 5);
+        picker.set_priority(0, 4);
+        picker.set_priority(3, 1);
+        assert_ordered(&picker);
+        for i in 0..5 {
+            b.set_bit(i);
+        }
+        let peer = Peer::test_from_pieces(0, b);
+        picker.add_peer(&peer);
+        assert_ordered(&picker);
+
+        // Bands come first, with the rarest piece first within them
+        let mut all = Peer::test_from_pieces(1, Bitfield::full(6));
+        for &piece in &[2, 0, 5] {
+            assert_eq!(pick(&mut picker, &mut all), Some(piece));
+            picker.completed(piece);
+            assert_ordered(&picker);
+        }
+        let next = pick(&mut picker, &mut all).unwrap();
+        assert!(next == 1 || next == 4);
+        // Pieces becoming rarer move past the picked one's bucket
+        picker.remove_peer(&peer);
+        assert_ordered(&picker);
+
+        picker.set_priority(3, 5);
+        picker.incomplete(2);
+        assert_ordered(&picker);
+        let mut top = picker.pieces[..2].to_vec();
+        top.sort_unstable();
+        assert_eq!(top, vec![2, 3]);
+        picker.completed(3);
+        assert_ordered(&picker);
     }
 }
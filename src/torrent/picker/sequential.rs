@@ -1,5 +1,4 @@
-use crate::control::cio;
-use crate::torrent::{Bitfield, Peer};
+use crate::torrent::Bitfield;
 
 #[derive(Clone, Debug)]
 pub struct Picker {
@@ -69,10 +68,11 @@ impl Picker {
         }
     }
 
-    pub fn pick<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<u32> {
+    /// Picks the first incomplete piece of wanted.
+    pub fn pick(&self, wanted: &Bitfield) -> Option<u32> {
         self.pieces[self.piece_idx..]
             .iter()
-            .find(|p| peer.pieces().has_bit(u64::from(p.pos)))
+            .find(|p| p.status == PieceStatus::Incomplete && wanted.has_bit(u64::from(p.pos)))
             .map(|p| p.pos)
     }
 
@@ -101,10 +101,11 @@ impl Picker {
     }
 
     fn update_piece_idx(&mut self) {
-        for i in self.piece_idx..self.pieces.len() {
-            if self.pieces[i].status == PieceStatus::Complete {
-                self.piece_idx += 1;
-            }
+        // Pieces may be completed out of order, e.g. by webseeds
+        while self.piece_idx < self.pieces.len()
+            && self.pieces[self.piece_idx].status == PieceStatus::Complete
+        {
+            self.piece_idx += 1;
         }
     }
 }
@@ -119,19 +120,19 @@ mod tests {
         let b = Bitfield::new(3);
         let mut picker = Picker::new(&b);
         let mut peer = Peer::test_from_pieces(0, b);
-        assert_eq!(picker.pick(&peer), None);
+        assert_eq!(picker.pick(peer.pieces()), None);
         peer.pieces_mut().set_bit(1);
-        assert_eq!(picker.pick(&peer), Some(1));
+        assert_eq!(picker.pick(peer.pieces()), Some(1));
         peer.pieces_mut().set_bit(0);
-        assert_eq!(picker.pick(&peer), Some(0));
+        assert_eq!(picker.pick(peer.pieces()), Some(0));
         picker.completed(0);
         picker.completed(1);
         peer.pieces_mut().set_bit(2);
-        assert_eq!(picker.pick(&peer), Some(2));
+        assert_eq!(picker.pick(peer.pieces()), Some(2));
 
         picker.completed(2);
-        assert_eq!(picker.pick(&peer), None);
+        assert_eq!(picker.pick(peer.pieces()), None);
         picker.incomplete(1);
-        assert_eq!(picker.pick(&peer), Some(1));
+        assert_eq!(picker.pick(peer.pieces()), Some(1));
     }
 }
@@ -1,9 +1,12 @@
-use super::{Block, Picker};
+use super::{req_deadline, Block, Mode, Picker, MIN_REQ_TIMEOUT};
 use crate::control;
+use crate::torrent::info::File;
 use crate::torrent::{Bitfield, Info, Peer as TGPeer};
 use rand::distributions::{Distribution, Range};
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 type TPeer = TGPeer<control::cio::test::TCIO>;
 
@@ -113,7 +116,7 @@ impl Simulation {
                     for pid in received.connected.iter() {
                         self.peers.borrow_mut()[*pid]
                             .picker
-                            .piece_available(req.piece);
+                            .piece_available(&received.data, req.piece);
                     }
                 }
             }
@@ -276,3 +279,550 @@ fn test_seq_picker() {
 
     assert_eq!(p.pick(&mut peer), Some(Block::new(5, 0)));
 }
+
+#[test]
+fn test_rejected() {
+    let mut i = Info::with_pieces(10);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(10);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut pb = Bitfield::new(10);
+    for i in 0..10 {
+        pb.set_bit(i);
+    }
+    let mut p1 = TPeer::test_from_pieces(1, pb.clone());
+    let mut p2 = TPeer::test_from_pieces(2, pb);
+
+    assert_eq!(p.pick(&mut p1), Some(Block::new(0, 0)));
+    p.rejected(Block::new(0, 0), 1);
+    // The rejected block should be handed out again before anything new
+    assert_eq!(p.pick(&mut p2), Some(Block::new(0, 0)));
+    assert_eq!(p.pick(&mut p2), Some(Block::new(1, 0)));
+}
+
+#[test]
+fn test_cancel_peer() {
+    let mut i = Info::with_pieces(10);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(10);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut p1 = TPeer::test_from_pieces(1, Bitfield::full(10));
+    let mut p2 = TPeer::test_from_pieces(2, Bitfield::full(10));
+
+    assert_eq!(
+        p.pick_blocks(&mut p1, 2),
+        vec![Block::new(0, 0), Block::new(1, 0)]
+    );
+    assert_eq!(p.pick(&mut p2), Some(Block::new(2, 0)));
+    let mut cancelled = p.cancel_peer(1);
+    cancelled.sort_by_key(|b| b.index);
+    assert_eq!(cancelled, vec![Block::new(0, 0), Block::new(1, 0)]);
+    assert!(p.cancel_peer(1).is_empty());
+    // The blocks go to other peers before anything new
+    let mut rereq = p.pick_blocks(&mut p2, 2);
+    rereq.sort_by_key(|b| b.index);
+    assert_eq!(rereq, vec![Block::new(0, 0), Block::new(1, 0)]);
+    // Arriving late from the snubbed peer still completes them
+    assert_eq!(
+        p.completed(Block::new(0, 0), |pid| assert_eq!(pid, 2)),
+        Ok(true)
+    );
+}
+
+#[test]
+fn test_pick_whole() {
+    let mut i = Info::with_pieces_scale(4, 2);
+    i.files.push(crate::torrent::info::File {
+        path: std::path::PathBuf::new(),
+        length: i.total_len,
+        pieces_root: None,
+    });
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(4);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut pb = Bitfield::new(4);
+    for i in 0..4 {
+        pb.set_bit(i);
+    }
+    let mut p1 = TPeer::test_from_pieces(1, pb);
+
+    // Partially picked pieces are left to peers
+    assert_eq!(p.pick(&mut p1), Some(Block::new(0, 0)));
+    assert_eq!(p.pick_whole(100), Some(1));
+    // Peers never get blocks of a piece picked whole
+    assert_eq!(p.pick(&mut p1), Some(Block::new(0, 16_384)));
+    assert_eq!(p.pick(&mut p1), Some(Block::new(2, 0)));
+    assert_eq!(p.pick_whole(100), Some(3));
+    assert_eq!(p.pick_whole(100), None);
+    assert_eq!(p.completed(Block::new(1, 0), |_| {}), Ok(false));
+    assert_eq!(p.completed(Block::new(1, 16_384), |_| {}), Ok(true));
+}
+
+#[test]
+fn test_piece_unavailable() {
+    let mut i = Info::with_pieces(3);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(3);
+    let mut p = Picker::new_rarest(&i, &b);
+    let mut p1 = TPeer::test_from_pieces(1, Bitfield::full(3));
+    let mut pb = Bitfield::new(3);
+    pb.set_bit(0);
+    pb.set_bit(2);
+    let mut p2 = TPeer::test_from_pieces(2, pb);
+    let mut p3 = TPeer::test_from_pieces(3, Bitfield::full(3));
+    p.add_peer(&p1);
+    p.add_peer(&p2);
+
+    // The seeder now counts towards pieces 1 and 2, then
+    // nobody is left with piece 0, making it the rarest
+    p1.pieces_mut().unset_bit(0);
+    p.piece_unavailable(&p1, 0);
+    p2.pieces_mut().unset_bit(0);
+    p.piece_unavailable(&p2, 0);
+    assert_eq!(p.pick(&mut p3), Some(Block::new(0, 0)));
+    p.remove_peer(&p1);
+    p.remove_peer(&p2);
+}
+
+#[test]
+fn test_audit_availability() {
+    let mut i = Info::with_pieces(3);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(3);
+    let mut p = Picker::new_rarest(&i, &b);
+    let seeder = TPeer::test_from_pieces(1, Bitfield::full(3));
+    let mut pb = Bitfield::new(3);
+    pb.set_bit(1);
+    let mut leecher = TPeer::test_from_pieces(2, pb);
+    p.add_peer(&seeder);
+    p.add_peer(&leecher);
+    assert_eq!(p.audit_availability(vec![&seeder, &leecher]), Some(vec![]));
+    // Picking doesn't change the counts
+    assert_eq!(p.pick(&mut leecher), Some(Block::new(1, 0)));
+    assert_eq!(p.audit_availability(vec![&seeder, &leecher]), Some(vec![]));
+
+    // Pieces the picker isn't told about, or peers it forgot, drift
+    leecher.pieces_mut().set_bit(2);
+    assert_eq!(
+        p.audit_availability(vec![&seeder, &leecher]),
+        Some(vec![(2, 0, 1)])
+    );
+    assert_eq!(p.audit_availability(vec![&seeder]), Some(vec![(1, 1, 0)]));
+
+    p.change_picker(Mode::Sequential);
+    assert_eq!(p.audit_availability(vec![&seeder, &leecher]), None);
+}
+
+#[test]
+fn test_piece_availability() {
+    let mut i = Info::with_pieces(3);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(3);
+    let mut p = Picker::new_rarest(&i, &b);
+    let seeder = TPeer::test_from_pieces(1, Bitfield::full(3));
+    let mut pb = Bitfield::new(3);
+    pb.set_bit(1);
+    let leecher = TPeer::test_from_pieces(2, pb);
+    p.add_peer(&seeder);
+    p.add_peer(&leecher);
+    // Seeders count towards every piece
+    assert_eq!(p.piece_availability(vec![&seeder, &leecher]), vec![1, 2, 1]);
+
+    p.change_picker(Mode::Sequential);
+    assert_eq!(p.piece_availability(vec![&seeder, &leecher]), vec![1, 2, 1]);
+}
+
+#[test]
+fn test_restore_partial() {
+    let mut i = Info::with_pieces_scale(2, 4);
+    i.files.push(crate::torrent::info::File {
+        path: std::path::PathBuf::new(),
+        length: i.total_len,
+        pieces_root: None,
+    });
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(2);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut peer = TPeer::test_from_pieces(1, Bitfield::full(2));
+    for _ in 0..3 {
+        p.pick(&mut peer);
+    }
+    // Blocks 0 and 2 of the first piece are done, block 1 is still requested
+    p.completed(Block::new(0, 0), |_| {}).unwrap();
+    p.completed(Block::new(0, 32_768), |_| {}).unwrap();
+    let partial = p.partial();
+    assert_eq!(partial.len(), 1);
+    assert_eq!(partial[0].1.iter().collect::<Vec<_>>(), vec![0, 2]);
+
+    let mut p = Picker::new_sequential(&i, &b);
+    p.restore_partial(0, &partial[0].1);
+    assert_eq!(p.pick(&mut peer), Some(Block::new(0, 16_384)));
+    assert_eq!(p.pick(&mut peer), Some(Block::new(0, 49_152)));
+    assert_eq!(p.completed(Block::new(0, 16_384), |_| {}), Ok(false));
+    assert_eq!(p.completed(Block::new(0, 49_152), |_| {}), Ok(true));
+}
+
+#[test]
+fn test_have_piece() {
+    let mut i = Info::with_pieces_scale(3, 2);
+    i.files.push(crate::torrent::info::File {
+        path: std::path::PathBuf::new(),
+        length: i.total_len,
+        pieces_root: None,
+    });
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(3);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut peer = TPeer::test_from_pieces(1, Bitfield::full(3));
+    assert_eq!(p.pick(&mut peer), Some(Block::new(0, 0)));
+    p.completed(Block::new(0, 0), |_| {}).unwrap();
+    // Piece 0 is partially downloaded, piece 1 untouched
+    p.have_piece(0);
+    p.have_piece(1);
+    p.have_piece(1);
+    assert_eq!(p.have, 2);
+    assert!(p.downloading.is_empty());
+    assert_eq!(p.completed(Block::new(0, 16_384), |_| {}), Err(()));
+    assert_eq!(p.pick(&mut peer), Some(Block::new(2, 0)));
+
+    p.invalidate_piece(1);
+    assert_eq!(p.have, 1);
+    assert_eq!(p.pick(&mut peer), Some(Block::new(1, 0)));
+}
+
+#[test]
+fn test_failure_aging() {
+    let mut i = Info::with_pieces(2);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(2);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut p1 = TPeer::test_from_pieces(1, Bitfield::full(2));
+    let mut p2 = TPeer::test_from_pieces(2, Bitfield::full(2));
+    let block = Block::new(0, 0);
+    let ago = |secs| Instant::now() - Duration::from_secs(secs);
+
+    assert_eq!(p.pick(&mut p1), Some(block));
+    p.downloading.get_mut(&block).unwrap().requested_at = ago(req_deadline(3, 0));
+    p.tick();
+    assert_eq!(p.failures[&0], 1);
+    assert_eq!(p.pick(&mut p2), Some(block));
+    // The second attempt expires in half the time
+    p.downloading.get_mut(&block).unwrap().requested_at = ago(req_deadline(3, 0) / 2);
+    p.tick();
+    assert_eq!(p.failures[&0], 2);
+    assert_eq!(req_deadline(3, 10), 2);
+
+    assert_eq!(p.completed(block, |_| {}), Ok(true));
+    assert_eq!(p.failures[&0], 1);
+    p.aged = ago(60);
+    p.tick();
+    assert!(p.failures.is_empty());
+}
+
+#[test]
+fn test_withdraw() {
+    let mut i = Info::with_pieces(2);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(2);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut p1 = TPeer::test_from_pieces(1, Bitfield::full(2));
+    let block = Block::new(0, 0);
+
+    assert_eq!(p.pick(&mut p1), Some(block));
+    p.downloading.get_mut(&block).unwrap().requested_at =
+        Instant::now() - Duration::from_secs(req_deadline(3, 0));
+    assert_eq!(p.tick(), vec![(block, 1)]);
+    assert_eq!(p.tick(), vec![]);
+    p.withdraw(block, 1);
+    // The peer may be asked again, and isn't cancelled once it's delivered
+    assert_eq!(p.pick(&mut p1), Some(block));
+    p.withdraw(block, 1);
+    let mut cancelled = vec![];
+    assert_eq!(p.completed(block, |id| cancelled.push(id)), Ok(true));
+    assert!(cancelled.is_empty());
+}
+
+#[test]
+fn test_wanted_cache() {
+    let mut i = Info::with_pieces(3);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(3);
+    let mut p = Picker::new_rarest(&i, &b);
+    let mut pb = Bitfield::new(3);
+    pb.set_bit(1);
+    let mut p1 = TPeer::test_from_pieces(1, pb);
+    p.add_peer(&p1);
+    assert_eq!(p.wanted[&1].0.set(), 1);
+
+    assert_eq!(p.pick(&mut p1), Some(Block::new(1, 0)));
+    // Picked pieces are no longer wanted from anyone
+    assert_eq!(p.wanted[&1].0.set(), 0);
+    assert_eq!(p.pick(&mut p1), None);
+    p1.pieces_mut().set_bit(2);
+    p.piece_available(&p1, 2);
+    assert_eq!(p.pick(&mut p1), Some(Block::new(2, 0)));
+
+    // Invalidated pieces are wanted again
+    p.invalidate_piece(1);
+    assert_eq!(p.pick(&mut p1), Some(Block::new(1, 0)));
+    p.remove_peer(&p1);
+    assert!(p.wanted.is_empty());
+}
+
+#[test]
+fn test_pick_blocks() {
+    let mut i = Info::with_pieces_scale(3, 2);
+    i.files.push(crate::torrent::info::File {
+        path: std::path::PathBuf::new(),
+        length: i.total_len,
+        pieces_root: None,
+    });
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(3);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut pb = Bitfield::new(3);
+    for i in 0..3 {
+        pb.set_bit(i);
+    }
+    let mut p1 = TPeer::test_from_pieces(1, pb);
+    let mut pb = Bitfield::new(3);
+    pb.set_bit(0);
+    pb.set_bit(1);
+    let mut p2 = TPeer::test_from_pieces(2, pb);
+
+    // Batches run across pieces
+    assert_eq!(
+        p.pick_blocks(&mut p1, 3),
+        vec![Block::new(0, 0), Block::new(0, 16_384), Block::new(1, 0)]
+    );
+    p.rejected(Block::new(0, 0), 1);
+    assert_eq!(
+        p.pick_blocks(&mut p2, 2),
+        vec![Block::new(0, 0), Block::new(1, 16_384)]
+    );
+    // Only blocks already requested from others are left for p2
+    let mut rereq = p.pick_blocks(&mut p2, 5);
+    rereq.sort_by_key(|b| (b.index, b.offset));
+    assert_eq!(rereq, vec![Block::new(0, 16_384), Block::new(1, 0)]);
+    assert_eq!(
+        p.pick_blocks(&mut p1, 2),
+        vec![Block::new(2, 0), Block::new(2, 16_384)]
+    );
+}
+
+#[test]
+fn test_switch_mode() {
+    let mut i = Info::with_pieces_scale(4, 2);
+    i.files.push(crate::torrent::info::File {
+        path: std::path::PathBuf::new(),
+        length: i.total_len,
+        pieces_root: None,
+    });
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(4);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut p1 = TPeer::test_from_pieces(1, Bitfield::full(4));
+    p.add_peer(&p1);
+    let mut picked = p.pick_blocks(&mut p1, 3);
+    assert_eq!(
+        picked,
+        vec![Block::new(0, 0), Block::new(0, 16_384), Block::new(1, 0)]
+    );
+    assert_eq!(p.completed(Block::new(0, 0), |_| {}), Ok(false));
+
+    for mode in [Mode::Streaming { head: 2 }, Mode::Rarest] {
+        p.change_picker(mode);
+        p.add_peer(&p1);
+        p.set_priorities(&[3], &Arc::new(i.clone()));
+        picked.extend(p.pick_blocks(&mut p1, 1));
+    }
+    // Blocks in flight are neither picked again nor forgotten
+    assert_eq!(picked[3], Block::new(2, 0));
+    picked.extend(p.pick_blocks(&mut p1, 10));
+    picked.sort_by_key(|b| (b.index, b.offset));
+    assert!(
+        picked.windows(2).all(|w| w[0] != w[1]),
+        "blocks picked twice: {:?}",
+        picked
+    );
+    assert_eq!(picked.len(), 8);
+    assert_eq!(p.completed(Block::new(0, 16_384), |_| {}), Ok(true));
+    assert_eq!(p.completed(Block::new(1, 0), |_| {}), Ok(false));
+    let partial = p.partial();
+    assert_eq!(partial.len(), 1);
+    assert_eq!(partial[0].0, 1);
+    assert!(partial[0].1.has_bit(0) && !partial[0].1.has_bit(1));
+}
+
+#[test]
+fn test_streaming() {
+    let mut i = Info::with_pieces(40);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(40);
+    let mut p = Picker::new_rarest(&i, &b);
+    p.change_picker(Mode::Streaming { head: 20 });
+    let mut p1 = TPeer::test_from_pieces(1, Bitfield::full(40));
+    p.add_peer(&p1);
+
+    assert_eq!(
+        p.pick_blocks(&mut p1, 3),
+        vec![Block::new(20, 0), Block::new(21, 0), Block::new(22, 0)]
+    );
+    p.set_head(36);
+    assert_eq!(p.mode(), Mode::Streaming { head: 36 });
+    // The window ends with the torrent, the rest is picked rarest first
+    let picked = p.pick_blocks(&mut p1, 5);
+    assert_eq!(&picked[..4], &[36, 37, 38, 39].map(|i| Block::new(i, 0)));
+    assert!(picked[4].index < 36 && !(20..23).contains(&picked[4].index));
+
+    // Requests closer to the head expire sooner
+    let ago = Instant::now() - Duration::from_secs(MIN_REQ_TIMEOUT);
+    for i in 36..40 {
+        p.downloading
+            .get_mut(&Block::new(i, 0))
+            .unwrap()
+            .requested_at = ago;
+    }
+    p.tick();
+    assert!(p.stalled.contains(&Block::new(36, 0)));
+    assert!(!p.stalled.contains(&Block::new(39, 0)));
+
+    p.change_picker(Mode::Rarest);
+    p.set_head(0);
+    assert_eq!(p.mode(), Mode::Rarest);
+}
+
+#[test]
+fn test_deadlines() {
+    let mut i = Info::with_pieces(40);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(40);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut p1 = TPeer::test_from_pieces(1, Bitfield::full(40));
+    p.add_peer(&p1);
+
+    let now = Instant::now();
+    p.set_deadline(30, Some(now + Duration::from_secs(2)));
+    p.set_deadline(20, Some(now + Duration::from_secs(1)));
+    p.set_deadline(10, Some(now + Duration::from_secs(3)));
+    p.set_deadline(10, None);
+    assert_eq!(
+        p.pick_blocks(&mut p1, 3),
+        vec![Block::new(20, 0), Block::new(30, 0), Block::new(0, 0)]
+    );
+
+    // Their requests expire as soon as possible
+    let ago = Instant::now() - Duration::from_secs(MIN_REQ_TIMEOUT);
+    for b in [Block::new(20, 0), Block::new(0, 0)] {
+        p.downloading.get_mut(&b).unwrap().requested_at = ago;
+    }
+    p.tick();
+    assert!(p.stalled.contains(&Block::new(20, 0)));
+    assert!(!p.stalled.contains(&Block::new(0, 0)));
+
+    assert_eq!(p.completed(Block::new(20, 0), |_| {}), Ok(true));
+    assert_eq!(p.completed(Block::new(30, 0), |_| {}), Ok(true));
+    assert!(p.deadlines.is_empty());
+}
+
+#[test]
+fn test_first_last() {
+    let mut i = Info::with_pieces(20);
+    let file = i.files[0].clone();
+    i.files = [16_384 * 5 + 100, 16_384 * 10 - 200, 16_384 * 5 + 100]
+        .iter()
+        .map(|&length| File {
+            length,
+            ..file.clone()
+        })
+        .collect();
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let info = Arc::new(i.clone());
+    let b = Bitfield::new(20);
+    let mut p1 = TPeer::test_from_pieces(1, Bitfield::full(20));
+
+    // Pieces shared with the skipped file are still boosted
+    let mut p = Picker::new_sequential(&i, &b);
+    p.set_first_last(true);
+    p.set_priorities(&[3, 0, 3], &info);
+    p.add_peer(&p1);
+    let picked: Vec<_> = p.pick_blocks(&mut p1, 5).iter().map(|b| b.index).collect();
+    assert_eq!(picked, [0, 5, 14, 19, 1]);
+
+    let mut p = Picker::new_rarest(&i, &b);
+    p.set_first_last(true);
+    p.set_priorities(&[3, 0, 3], &info);
+    p.add_peer(&p1);
+    let mut picked: Vec<_> = p.pick_blocks(&mut p1, 4).iter().map(|b| b.index).collect();
+    picked.sort_unstable();
+    assert_eq!(picked, [0, 5, 14, 19]);
+
+    p.set_first_last(false);
+    p.set_priorities(&[3, 3, 3], &info);
+    assert!(p.priorities.iter().all(|&pri| pri == 3));
+}
+
+#[test]
+fn test_endgame() {
+    let mut i = Info::with_pieces(70);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(70);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut p1 = TPeer::test_from_pieces(1, Bitfield::full(70));
+    let mut p2 = TPeer::test_from_pieces(2, Bitfield::full(70));
+    let mut pb = Bitfield::new(70);
+    pb.set_bit(69);
+    let mut p3 = TPeer::test_from_pieces(3, pb);
+
+    assert_eq!(p.pick_blocks(&mut p1, 70).len(), 70);
+    // Plenty of blocks are still outstanding
+    assert!(p.pick_blocks(&mut p2, 1).is_empty());
+    for i in 0..10 {
+        assert_eq!(p.completed(Block::new(i, 0), |_| {}), Ok(true));
+    }
+    assert_eq!(p.pick_blocks(&mut p2, 2).len(), 2);
+    // Only blocks of pieces the peer has are requested again
+    let dup = p.pick_blocks(&mut p3, 5);
+    assert!(dup.iter().all(|b| b.index == 69) && dup.len() <= 1);
+
+    let mut cancelled = Vec::new();
+    let block = p.pick_blocks(&mut p2, 1)[0];
+    assert_eq!(p.completed(block, |id| cancelled.push(id)), Ok(true));
+    cancelled.sort_unstable();
+    assert_eq!(cancelled, vec![1, 2]);
+}
+
+#[test]
+fn test_random_first() {
+    let mut i = Info::with_pieces(10);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(10);
+    let mut p = Picker::new_rarest(&i, &b);
+    p.random_first = 2;
+    let pieces = |r: std::ops::Range<u64>| {
+        let mut pb = Bitfield::new(10);
+        r.for_each(|i| pb.set_bit(i));
+        pb
+    };
+    let mut p1 = TPeer::test_from_pieces(1, pieces(0..9));
+    let mut p2 = TPeer::test_from_pieces(2, pieces(1..10));
+    let p3 = TPeer::test_from_pieces(3, pieces(0..1));
+    p.add_peer(&p1);
+    p.add_peer(&p2);
+    p.add_peer(&p3);
+
+    let picked: Vec<_> = (0..2).map(|_| p.pick(&mut p1).unwrap()).collect();
+    assert_ne!(picked[0], picked[1]);
+    for &b in &picked {
+        assert_eq!(p.completed(b, |_| {}), Ok(true));
+    }
+    assert_eq!(p.have, 2);
+    // Piece 9 is the rarest, now that enough pieces are complete
+    assert_eq!(p.pick(&mut p2), Some(Block::new(9, 0)));
+
+    // Invalid pieces no longer count as had, ones merely picked never did
+    p.invalidate_piece(9);
+    assert_eq!(p.have, 2);
+    p.invalidate_piece(picked[0].index);
+    assert_eq!(p.have, 1);
+}
@@ -0,0 +1,127 @@
+use crate::config::Encryption;
+use crate::CONFIG;
+
+/// Whether a torrent's announces go through the tracker proxy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Proxy {
+    /// Through the proxy, if one is configured
+    Default,
+    /// Only through the proxy, announces which can't be sent through
+    /// it aren't made
+    Required,
+    /// Never through the proxy
+    Direct,
+}
+
+/// Connection requirements of a torrent, falling back to the config
+/// for whatever it doesn't set. Consulted whenever a connection is made
+/// for the torrent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Policy {
+    pub encryption: Option<Encryption>,
+    pub proxy: Proxy,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            encryption: None,
+            proxy: Proxy::Default,
+        }
+    }
+}
+
+impl Policy {
+    pub fn encryption(&self) -> Encryption {
+        self.encryption.unwrap_or(CONFIG.peer.encryption)
+    }
+
+    /// Whether a peer which connected to us may be added.
+    pub fn accepts(&self, encrypted: bool) -> bool {
+        match self.encryption() {
+            Encryption::Disabled => !encrypted,
+            Encryption::Enabled => true,
+            Encryption::Required => encrypted,
+        }
+    }
+
+    /// Whether we may connect to peers, which is always done in plaintext.
+    /// The config's setting only applies to incoming peers, so just torrents
+    /// set to require encryption themselves don't.
+    pub fn dials(&self) -> bool {
+        self.encryption != Some(Encryption::Required)
+    }
+
+    /// Whether announces to trackers go through the proxy.
+    pub fn proxied(&self) -> bool {
+        match self.proxy {
+            Proxy::Default => CONFIG.trk.proxy.is_some(),
+            Proxy::Required => true,
+            Proxy::Direct => false,
+        }
+    }
+
+    /// Whether the torrent may be announced to the DHT, which can't be
+    /// reached through the proxy.
+    pub fn dht(&self) -> bool {
+        self.proxy != Proxy::Required
+    }
+}
+
+/// Encryption incoming connections are accepted with before it's known
+/// which torrent they're for, the loosest of the torrents' requirements.
+pub fn incoming<I: IntoIterator<Item = Encryption>>(torrents: I) -> Encryption {
+    let mut torrents = torrents.into_iter();
+    match torrents.next() {
+        Some(first) if torrents.all(|e| e == first) => first,
+        Some(_) => Encryption::Enabled,
+        None => CONFIG.peer.encryption,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(encryption: Encryption) -> Policy {
+        Policy {
+            encryption: Some(encryption),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_encryption() {
+        let required = policy(Encryption::Required);
+        assert!(required.accepts(true));
+        assert!(!required.accepts(false));
+        assert!(!required.dials());
+
+        let disabled = policy(Encryption::Disabled);
+        assert!(!disabled.accepts(true));
+        assert!(disabled.dials());
+        assert!(policy(Encryption::Enabled).accepts(true));
+        assert!(Policy::default().dials());
+
+        use Encryption::*;
+        assert_eq!(incoming(vec![Required, Required]), Required);
+        assert_eq!(incoming(vec![Disabled]), Disabled);
+        assert_eq!(incoming(vec![Required, Disabled]), Enabled);
+    }
+
+    #[test]
+    fn test_proxy() {
+        let required = Policy {
+            proxy: Proxy::Required,
+            ..Default::default()
+        };
+        assert!(required.proxied());
+        assert!(!required.dht());
+        let direct = Policy {
+            proxy: Proxy::Direct,
+            ..Default::default()
+        };
+        assert!(!direct.proxied());
+        assert!(direct.dht());
+    }
+}
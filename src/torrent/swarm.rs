@@ -0,0 +1,115 @@
+use chrono::{DateTime, Utc};
+
+/// How we learned about a peer.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Tracker,
+    Dht,
+    Pex,
+    /// The peer connected to us
+    Incoming,
+    /// Added over RPC with ADD_PEER
+    Rpc,
+}
+
+/// The peers of a torrent, as exported by GET_SWARM_SNAPSHOT.
+#[derive(Clone, Debug, Serialize)]
+pub struct Snapshot {
+    pub id: String,
+    pub taken: DateTime<Utc>,
+    pub pieces: u64,
+    /// Pieces we have
+    pub have: u64,
+    pub peers: Vec<PeerEntry>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PeerEntry {
+    pub id: String,
+    pub addr: String,
+    /// Address the peer accepts connections on, if known
+    pub listen_addr: Option<String>,
+    /// None until the handshake is received
+    pub client_id: Option<String>,
+    pub source: Source,
+    pub rate_up: u64,
+    pub rate_down: u64,
+    /// Bytes of piece data sent to the peer
+    pub sent: u64,
+    /// Pieces the peer has
+    pub pieces: u64,
+    pub availability: f32,
+    pub flags: Flags,
+    /// Requests queued with the peer, and the most which may be
+    pub queued: u16,
+    pub max_queue: u16,
+    pub rtt_ms: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Flags {
+    /// We're choking the peer
+    pub choking: bool,
+    /// The peer is choking us
+    pub choked: bool,
+    /// The peer is interested in our pieces
+    pub interested: bool,
+    /// We're interested in the peer's pieces
+    pub interesting: bool,
+    pub snubbed: bool,
+    pub seed: bool,
+    pub upload_only: bool,
+    pub outgoing: bool,
+    pub encrypted: bool,
+    pub fast_ext: bool,
+    pub ext_proto: bool,
+    pub super_seeded: bool,
+}
+
+pub fn encode(snapshot: &Snapshot) -> Vec<u8> {
+    serde_json::to_vec(snapshot).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let snapshot = Snapshot {
+            id: "t".to_owned(),
+            taken: Utc::now(),
+            pieces: 10,
+            have: 4,
+            peers: vec![PeerEntry {
+                id: "p".to_owned(),
+                addr: "1.2.3.4:6881".to_owned(),
+                listen_addr: None,
+                client_id: None,
+                source: Source::Pex,
+                rate_up: 0,
+                rate_down: 100,
+                sent: 0,
+                pieces: 5,
+                availability: 0.5,
+                flags: Flags {
+                    choked: true,
+                    encrypted: true,
+                    ..Default::default()
+                },
+                queued: 2,
+                max_queue: 10,
+                rtt_ms: Some(40),
+            }],
+        };
+        let json: serde_json::Value = serde_json::from_slice(&encode(&snapshot)).unwrap();
+        assert_eq!(json["have"], 4);
+        let peer = &json["peers"][0];
+        assert_eq!(peer["source"], "pex");
+        assert!(peer["listen_addr"].is_null());
+        assert_eq!(peer["flags"]["choked"], true);
+        assert_eq!(peer["flags"]["choking"], false);
+        assert_eq!(peer["rtt_ms"], 40);
+    }
+}
@@ -0,0 +1,281 @@
+use std::path::Component;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use url::Url;
+
+use crate::torrent::Info;
+use crate::tracker;
+
+/// Webseeds are only used while fewer than this many peers are
+/// unchoking us and have pieces we want.
+pub const MIN_PEER_SOURCES: usize = 4;
+/// Base delay before retrying a failed webseed, multiplied by the
+/// number of consecutive failures.
+const RETRY_SECS: u64 = 30;
+const MAX_RETRY_SECS: u64 = 3600;
+
+/// BEP 19 webseeds and BEP 17 httpseeds of a torrent. Each seed downloads
+/// one whole piece at a time, picked so that peers aren't requesting any
+/// of its blocks.
+pub struct WebSeeds {
+    seeds: Vec<Seed>,
+}
+
+struct Seed {
+    url: Arc<Url>,
+    /// BEP 17 httpseed, which serves pieces rather than files
+    http: bool,
+    piece: Option<Pending>,
+    failures: u32,
+    retry: Option<Instant>,
+}
+
+/// A piece being fetched, possibly over multiple requests
+/// if it spans several files.
+struct Pending {
+    index: u32,
+    data: Vec<u8>,
+    remaining: usize,
+    failed: bool,
+    /// Delay requested by the server before retrying
+    retry_after: Option<u64>,
+}
+
+impl WebSeeds {
+    pub fn new(info: &Info) -> WebSeeds {
+        let web = info.web_seeds.iter().map(|url| (url, false));
+        let http = info.http_seeds.iter().map(|url| (url, true));
+        WebSeeds {
+            seeds: web
+                .chain(http)
+                .map(|(url, http)| Seed {
+                    url: url.clone(),
+                    http,
+                    piece: None,
+                    failures: 0,
+                    retry: None,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seeds.is_empty()
+    }
+
+    /// Picker id used for the seed at idx, chosen to never collide with peer ids.
+    pub fn id(idx: usize) -> usize {
+        usize::MAX - idx
+    }
+
+    /// Returns the seeds which aren't fetching a piece and may be used.
+    pub fn idle(&self) -> Vec<usize> {
+        let now = Instant::now();
+        self.seeds
+            .iter()
+            .enumerate()
+            .filter(|&(_, s)| s.piece.is_none() && s.retry.map(|r| r <= now).unwrap_or(true))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Creates the requests needed to fetch piece from the seed at idx.
+    pub fn request(
+        &mut self,
+        info: &Arc<Info>,
+        tid: usize,
+        idx: usize,
+        piece: u32,
+    ) -> Vec<tracker::WebSeed> {
+        let seed = &mut self.seeds[idx];
+        let reqs: Vec<_> = if seed.http {
+            vec![tracker::WebSeed {
+                id: tid,
+                seed: WebSeeds::id(idx),
+                url: seed.url.clone(),
+                piece,
+                begin: 0,
+                offset: 0,
+                length: u64::from(info.piece_len(piece)),
+                hash: Some(info.hash),
+            }]
+        } else {
+            Info::piece_disk_locs(info, piece)
+                .filter(|loc| loc.end > loc.start)
+                .map(|loc| tracker::WebSeed {
+                    id: tid,
+                    seed: WebSeeds::id(idx),
+                    url: Arc::new(file_url(&seed.url, info, loc.file)),
+                    piece,
+                    begin: loc.start as u32,
+                    offset: loc.offset,
+                    length: (loc.end - loc.start) as u64,
+                    hash: None,
+                })
+                .collect()
+        };
+        seed.piece = Some(Pending {
+            index: piece,
+            data: vec![0; info.piece_len(piece) as usize],
+            remaining: reqs.len(),
+            failed: false,
+            retry_after: None,
+        });
+        reqs
+    }
+
+    /// Handles a response for the seed with the given id. Once all
+    /// responses for its piece are in, returns the piece index and
+    /// either the full piece data or an error if any request failed.
+    pub fn received(
+        &mut self,
+        id: usize,
+        piece: u32,
+        begin: u32,
+        resp: tracker::Result<Vec<u8>>,
+    ) -> Option<(u32, Result<Vec<u8>, ()>)> {
+        let seed = self.seeds.get_mut(usize::MAX - id)?;
+        {
+            let pending = match seed.piece {
+                Some(ref mut p) if p.index == piece => p,
+                _ => return None,
+            };
+            pending.remaining -= 1;
+            match resp {
+                Ok(data) => {
+                    let start = begin as usize;
+                    pending.data[start..start + data.len()].copy_from_slice(&data);
+                }
+                Err(e) => {
+                    debug!("Webseed {} failed: {}", seed.url, e);
+                    if let tracker::ErrorKind::Unavailable(secs) = *e.kind() {
+                        pending.retry_after = Some(u64::from(secs));
+                    }
+                    pending.failed = true;
+                }
+            }
+            if pending.remaining != 0 {
+                return None;
+            }
+        }
+
+        let pending = seed.piece.take().unwrap();
+        if pending.failed {
+            seed.failures += 1;
+            let delay = pending
+                .retry_after
+                .unwrap_or(RETRY_SECS * u64::from(seed.failures))
+                .min(MAX_RETRY_SECS);
+            seed.retry = Some(Instant::now() + Duration::from_secs(delay));
+            Some((piece, Err(())))
+        } else {
+            seed.failures = 0;
+            seed.retry = None;
+            Some((piece, Ok(pending.data)))
+        }
+    }
+}
+
+/// Builds the URL of a torrent file on a webseed. Single file torrents use
+/// the URL as is unless it names a directory, otherwise the file's path
+/// (which includes the torrent name) is appended.
+fn file_url(base: &Url, info: &Info, file: usize) -> Url {
+    let mut url = base.clone();
+    if info.files.len() == 1 && !base.path().ends_with('/') {
+        return url;
+    }
+    if let Ok(mut segments) = url.path_segments_mut() {
+        segments.pop_if_empty();
+        for c in info.files[file].path.components() {
+            if let Component::Normal(s) = c {
+                segments.push(&s.to_string_lossy());
+            }
+        }
+    }
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::info::File;
+    use std::path::PathBuf;
+
+    fn info(files: &[(&str, u64)]) -> Arc<Info> {
+        let mut info = Info::with_pieces(0);
+        info.files = files
+            .iter()
+            .map(|&(path, length)| File {
+                path: PathBuf::from(path),
+                length,
+                pieces_root: None,
+            })
+            .collect();
+        info.total_len = files.iter().map(|f| f.1).sum();
+        info.hashes = vec![vec![0u8]; (info.total_len / 16_384 + 1) as usize];
+        info.piece_idx = Info::generate_piece_idx(info.hashes.len(), 16_384, &info.files);
+        Arc::new(info)
+    }
+
+    #[test]
+    fn test_file_url() {
+        let single = info(&[("a b.txt", 10)]);
+        let base = Url::parse("http://example.com/files/x.txt").unwrap();
+        assert_eq!(file_url(&base, &single, 0).as_str(), base.as_str());
+        let base = Url::parse("http://example.com/files/").unwrap();
+        assert_eq!(
+            file_url(&base, &single, 0).as_str(),
+            "http://example.com/files/a%20b.txt"
+        );
+
+        let multi = info(&[("dir/a", 10), ("dir/sub/b", 10)]);
+        let base = Url::parse("http://example.com/files").unwrap();
+        assert_eq!(
+            file_url(&base, &multi, 1).as_str(),
+            "http://example.com/files/dir/sub/b"
+        );
+    }
+
+    #[test]
+    fn test_request_spanning() {
+        let mut i = (*info(&[("dir/a", 10_000), ("dir/b", 10_000)])).clone();
+        i.web_seeds = vec![Arc::new(Url::parse("http://example.com/").unwrap())];
+        let i = Arc::new(i);
+        let mut ws = WebSeeds::new(&i);
+        assert_eq!(ws.idle(), vec![0]);
+        let reqs = ws.request(&i, 1, 0, 0);
+        assert_eq!(reqs.len(), 2);
+        assert_eq!(
+            (reqs[1].begin, reqs[1].offset, reqs[1].length),
+            (10_000, 0, 6_384)
+        );
+        assert!(ws.idle().is_empty());
+
+        let id = WebSeeds::id(0);
+        assert!(ws.received(id, 0, 0, Ok(vec![1; 10_000])).is_none());
+        let (piece, data) = ws.received(id, 0, 10_000, Ok(vec![2; 6_384])).unwrap();
+        assert_eq!(piece, 0);
+        let data = data.unwrap();
+        assert_eq!((data[9_999], data[10_000]), (1, 2));
+        assert_eq!(ws.idle(), vec![0]);
+    }
+
+    #[test]
+    fn test_http_seed() {
+        let mut i = (*info(&[("dir/a", 10_000), ("dir/b", 10_000)])).clone();
+        i.http_seeds = vec![Arc::new(Url::parse("http://example.com/seed").unwrap())];
+        let i = Arc::new(i);
+        let mut ws = WebSeeds::new(&i);
+        // The whole piece is fetched at once regardless of file boundaries
+        let reqs = ws.request(&i, 1, 0, 0);
+        assert_eq!(reqs.len(), 1);
+        assert_eq!(reqs[0].url.as_str(), "http://example.com/seed");
+        assert_eq!((reqs[0].length, reqs[0].hash), (16_384, Some(i.hash)));
+
+        let busy = tracker::ErrorKind::Unavailable(120).into();
+        let (_, data) = ws.received(WebSeeds::id(0), 0, 0, Err(busy)).unwrap();
+        assert!(data.is_err());
+        assert!(ws.idle().is_empty());
+    }
+}
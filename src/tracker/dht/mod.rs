@@ -1,6 +1,6 @@
-use std::fs::OpenOptions;
-use std::io::{self, Read};
-use std::net::{SocketAddr, UdpSocket};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
 use std::path::Path;
 use std::time;
 
@@ -29,6 +29,8 @@ pub struct Manager {
     sock: UdpSocket,
     buf: Vec<u8>,
     db: amy::Sender<disk::Request>,
+    /// External IPs reported by responding nodes, with their address
+    ip_reports: Vec<(IpAddr, IpAddr)>,
 }
 
 impl Manager {
@@ -66,6 +68,7 @@ impl Manager {
             id,
             db,
             buf: vec![0u8; 500],
+            ip_reports: Vec::new(),
             dht_flush: time::Instant::now(),
         })
     }
@@ -88,9 +91,13 @@ impl Manager {
                 Ok((v, addr)) => {
                     trace!("Processing msg from {}", addr);
                     if let Ok(req) = proto::Request::decode(&self.buf[..v]) {
-                        let resp = self.table.handle_req(req, addr).encode();
-                        self.send_msg(&resp, addr);
+                        let mut resp = self.table.handle_req(req, addr);
+                        resp.ip = Some(addr);
+                        self.send_msg(&resp.encode(), addr);
                     } else if let Ok(resp) = proto::Response::decode(&self.buf[..v]) {
+                        if let Some(ip) = resp.ip {
+                            self.ip_reports.push((addr.ip(), ip.ip()));
+                        }
                         match self.table.handle_resp(resp, addr) {
                             Ok(r) => resps.push(r),
                             Err(q) => {
@@ -125,6 +132,34 @@ impl Manager {
         }
     }
 
+    /// Returns the external IPs nodes have reported since the last call.
+    pub fn take_ip_reports(&mut self) -> Vec<(IpAddr, IpAddr)> {
+        std::mem::take(&mut self.ip_reports)
+    }
+
+    /// Makes sure our node id is valid for our external IP (BEP 42).
+    pub fn set_external_ip(&mut self, ip: IpAddr) {
+        if self.table.set_external_ip(ip) {
+            info!("Regenerated DHT node id for external IP {}", ip);
+        }
+    }
+
+    /// Looks up our own id again, from the nodes we know and the
+    /// bootstrap node, so nodes learn our new address after it changed.
+    pub fn rebootstrap(&mut self) {
+        if CONFIG.dht.bootstrap_node.is_none() {
+            return;
+        }
+        info!("Bootstrapping DHT again");
+        for (req, a) in self.table.rebootstrap() {
+            self.send_msg(&req.encode(), a);
+        }
+        if let Some(addr) = CONFIG.dht.bootstrap_node {
+            let (req, a) = self.table.add_addr(addr);
+            self.send_msg(&req.encode(), a);
+        }
+    }
+
     pub fn add_addr(&mut self, addr: SocketAddr) {
         self.table.add_addr(addr);
     }
@@ -147,6 +182,23 @@ impl Manager {
         }
     }
 
+    /// Writes the routing table to the session directory directly, for use
+    /// at shutdown when the disk thread may already be gone.
+    pub fn save(&self) {
+        let path = Path::new(&CONFIG.disk.session[..]).join(SESSION_FILE);
+        let temp = path.with_extension("temp");
+        let res = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp)
+            .and_then(|mut f| f.write_all(&self.table.serialize()))
+            .and_then(|_| fs::rename(&temp, &path));
+        if let Err(e) = res {
+            error!("Failed to save DHT table: {}", e);
+        }
+    }
+
     fn send_msg(&mut self, msg: &[u8], addr: SocketAddr) {
         // Cap tries to avoid burning CPU
         for _ in 0..25 {
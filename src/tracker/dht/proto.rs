@@ -71,6 +71,8 @@ pub enum RequestKind {
 #[derive(Debug)]
 pub struct Response {
     pub transaction: Vec<u8>,
+    /// Address of the requester as seen by the responder (BEP 42)
+    pub ip: Option<SocketAddr>,
     pub kind: ResponseKind,
 }
 
@@ -350,6 +352,7 @@ impl Response {
     pub fn id(transaction: Vec<u8>, id: ID) -> Self {
         Response {
             transaction,
+            ip: None,
             kind: ResponseKind::ID(id),
         }
     }
@@ -357,6 +360,7 @@ impl Response {
     pub fn find_node(transaction: Vec<u8>, id: ID, nodes: Vec<Node>) -> Self {
         Response {
             transaction,
+            ip: None,
             kind: ResponseKind::FindNode { id, nodes },
         }
     }
@@ -364,6 +368,7 @@ impl Response {
     pub fn peers(transaction: Vec<u8>, id: ID, token: Vec<u8>, nodes: Vec<SocketAddr>) -> Self {
         Response {
             transaction,
+            ip: None,
             kind: ResponseKind::GetPeers {
                 id,
                 token,
@@ -376,6 +381,7 @@ impl Response {
     pub fn nodes(transaction: Vec<u8>, id: ID, token: Vec<u8>, nodes: Vec<Node>) -> Self {
         Response {
             transaction,
+            ip: None,
             kind: ResponseKind::GetPeers {
                 id,
                 token,
@@ -388,6 +394,7 @@ impl Response {
     pub fn error(transaction: Vec<u8>, error: ErrorKind) -> Self {
         Response {
             transaction,
+            ip: None,
             kind: ResponseKind::Error(error),
         }
     }
@@ -396,6 +403,10 @@ impl Response {
         let mut b = BTreeMap::new();
         let is_err = self.is_err();
         b.insert(b"t".to_vec(), BEncode::String(self.transaction));
+        if let Some(SocketAddr::V4(ip)) = self.ip {
+            let ip = addr_to_bytes(&SocketAddr::V4(ip)).to_vec();
+            b.insert(b"ip".to_vec(), BEncode::String(ip));
+        }
         let mut args = BTreeMap::new();
         match self.kind {
             ResponseKind::ID(id) => {
@@ -478,6 +489,11 @@ impl Response {
                     "Invalid BEncoded data(dict must have t field)",
                 ))
             })?;
+        let ip = d
+            .remove(b"ip".as_ref())
+            .and_then(|b| b.into_bytes())
+            .filter(|b| b.len() == 6)
+            .map(|b| bytes_to_addr(&b));
         let y = d
             .remove(b"y".as_ref())
             .and_then(|b| b.into_string())
@@ -526,6 +542,7 @@ impl Response {
                 };
                 Ok(Response {
                     transaction,
+                    ip,
                     kind: ResponseKind::Error(err),
                 })
             }
@@ -587,7 +604,11 @@ impl Response {
                 } else {
                     ResponseKind::ID(id)
                 };
-                Ok(Response { transaction, kind })
+                Ok(Response {
+                    transaction,
+                    ip,
+                    kind,
+                })
             }
             _ => {
                 Err(ErrorKind::InvalidResponse("Invalid BEncoded data(y field must be e/r)").into())
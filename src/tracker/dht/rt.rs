@@ -5,10 +5,12 @@ use chrono::{DateTime, Utc};
 use num_bigint::BigUint;
 use rand::{self, Rng};
 use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::{cmp, mem};
 
 const MAX_SEARCH_DEPTH: u8 = 5;
+/// Minutes without activity before a bucket is refreshed
+const BUCKET_REFRESH_MINS: i64 = 15;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RoutingTable {
@@ -444,6 +446,7 @@ impl RoutingTable {
             let tx = self.new_query_tx(node.id);
             reqs.push((proto::Request::ping(tx, self.id.clone()), node.addr));
         }
+        reqs.extend(self.refresh_buckets());
         let buckets = &self.buckets;
         self.torrents.retain(|_, t| {
             t.peers.retain(|p| {
@@ -463,6 +466,73 @@ impl RoutingTable {
         self.buckets.len() >= MIN_BOOTSTRAP_BKTS
     }
 
+    /// Regenerates our node id to be valid for our external IP (BEP 42),
+    /// returning whether it changed. Existing nodes are kept, but the
+    /// buckets are rebuilt around the new id.
+    pub fn set_external_ip(&mut self, ip: IpAddr) -> bool {
+        if id_valid(&self.id, ip) {
+            return false;
+        }
+        self.id = secure_id(ip, rand::random());
+        let buckets = mem::replace(
+            &mut self.buckets,
+            vec![Bucket::new(BigUint::from(0u8), id_from_pow(160))],
+        );
+        for node in buckets.into_iter().flat_map(|b| b.nodes) {
+            self.add_node(node).ok();
+        }
+        true
+    }
+
+    /// Pings every node, looking up our id from each which responds
+    /// as when first bootstrapping.
+    pub fn rebootstrap(&mut self) -> Vec<(proto::Request, SocketAddr)> {
+        self.bootstrapping = true;
+        let nodes: Vec<proto::Node> = self
+            .buckets
+            .iter()
+            .flat_map(|b| b.nodes.iter().map(Into::into))
+            .collect();
+        nodes
+            .into_iter()
+            .map(|node| {
+                let tx = self.new_query_tx(node.id);
+                (proto::Request::ping(tx, self.id.clone()), node.addr)
+            })
+            .collect()
+    }
+
+    /// Looks up a random id in the range of each bucket which hasn't
+    /// seen activity in a while, such as those loaded from an old table.
+    fn refresh_buckets(&mut self) -> Vec<(proto::Request, SocketAddr)> {
+        let mut stale = Vec::new();
+        let now = Utc::now();
+        for bucket in &mut self.buckets {
+            let last = bucket
+                .nodes
+                .iter()
+                .map(|n| n.last_updated)
+                .fold(bucket.last_updated, cmp::max);
+            if now.signed_duration_since(last).num_minutes() < BUCKET_REFRESH_MINS {
+                continue;
+            }
+            bucket.last_updated = now;
+            if let Some(node) = bucket.nodes.iter().max_by_key(|n| n.last_updated) {
+                let node: proto::Node = node.into();
+                stale.push((bucket.random_id(), node));
+            }
+        }
+        let mut reqs = Vec::new();
+        for (target, node) in stale {
+            let tx = self.new_query_tx(node.id);
+            reqs.push((
+                proto::Request::find_node(tx, self.id.clone(), target),
+                node.addr,
+            ));
+        }
+        reqs
+    }
+
     /// Send a bogus get_peers query and internally refresh our token.
     fn refresh_tokens(&mut self) -> Vec<(proto::Request, SocketAddr)> {
         let mut nodes: Vec<proto::Node> = Vec::new();
@@ -643,6 +713,12 @@ impl Bucket {
         self.idx_of(id).is_some()
     }
 
+    fn random_id(&self) -> ID {
+        let mut id = [0u8; 20];
+        rand::thread_rng().fill(&mut id[..]);
+        self.start.clone() + BigUint::from_bytes_be(&id) % (&self.end - &self.start)
+    }
+
     fn idx_of(&self, id: &ID) -> Option<usize> {
         self.nodes.iter().position(|node| &node.id == id)
     }
@@ -724,10 +800,86 @@ fn id_from_pow(pow: usize) -> ID {
     BigUint::from_bytes_be(&id)
 }
 
+/// Generates a node id for the given external IP as described in BEP 42,
+/// with r as the random number mixed into the IP.
+fn secure_id(ip: IpAddr, r: u8) -> ID {
+    let mut rng = rand::thread_rng();
+    let crc = ip_crc(ip, r);
+    let mut id = [0u8; 20];
+    rng.fill(&mut id[..]);
+    id[0] = (crc >> 24) as u8;
+    id[1] = (crc >> 16) as u8;
+    id[2] = ((crc >> 8) as u8 & 0xf8) | (id[2] & 0x7);
+    id[19] = r;
+    BigUint::from_bytes_be(&id)
+}
+
+/// Checks whether a node id is valid for the IP it's used from. Local
+/// addresses are exempt, since any id may be used on a private network.
+fn id_valid(id: &ID, ip: IpAddr) -> bool {
+    let local = match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_loopback(),
+    };
+    if local {
+        return true;
+    }
+    let b = id.to_bytes_be();
+    if b.len() > 20 {
+        return false;
+    }
+    let mut id = [0u8; 20];
+    id[20 - b.len()..].copy_from_slice(&b);
+    let crc = ip_crc(ip, id[19]);
+    id[0] == (crc >> 24) as u8
+        && id[1] == (crc >> 16) as u8
+        && id[2] & 0xf8 == (crc >> 8) as u8 & 0xf8
+}
+
+/// CRC32-C of the masked IP with the low 3 bits of r mixed in.
+fn ip_crc(ip: IpAddr, r: u8) -> u32 {
+    const V4_MASK: [u8; 4] = [0x03, 0x0f, 0x3f, 0xff];
+    const V6_MASK: [u8; 8] = [0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff];
+    let mut data = match ip {
+        IpAddr::V4(ip) => ip
+            .octets()
+            .iter()
+            .zip(V4_MASK.iter())
+            .map(|(b, m)| b & m)
+            .collect::<Vec<_>>(),
+        IpAddr::V6(ip) => ip
+            .octets()
+            .iter()
+            .zip(V6_MASK.iter())
+            .map(|(b, m)| b & m)
+            .collect(),
+    };
+    data[0] |= (r & 0x7) << 5;
+    crc32c(&data)
+}
+
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{id_from_pow, Bucket, Node, RoutingTable};
+    use super::{id_from_pow, id_valid, secure_id, Bucket, Node, RoutingTable};
+    use crate::tracker::dht::proto::RequestKind;
+    use chrono::{Duration, Utc};
     use num_bigint::BigUint;
+    use std::net::IpAddr;
 
     #[test]
     fn test_id_from_pow() {
@@ -753,6 +905,50 @@ mod tests {
         assert_eq!(rt.buckets[1].nodes.len(), 0);
     }
 
+    #[test]
+    fn test_secure_id() {
+        // Test vectors from BEP 42
+        let cases = [
+            ("124.31.75.21", 1u8, [0x5f, 0xbf, 0xbf]),
+            ("21.75.31.124", 86, [0x5a, 0x3c, 0xe9]),
+            ("65.23.51.170", 22, [0xa5, 0xd4, 0x32]),
+            ("84.124.73.14", 65, [0x1b, 0x03, 0x21]),
+            ("43.213.53.83", 90, [0xe5, 0x6f, 0x6c]),
+        ];
+        for &(ip, r, prefix) in &cases {
+            let ip: IpAddr = ip.parse().unwrap();
+            let id = secure_id(ip, r);
+            let mut b = id.to_bytes_be();
+            while b.len() < 20 {
+                b.insert(0, 0);
+            }
+            assert_eq!(&b[..2], &prefix[..2]);
+            assert_eq!(b[2] & 0xf8, prefix[2] & 0xf8);
+            assert_eq!(b[19], r);
+            assert!(id_valid(&id, ip));
+            assert!(!id_valid(&id, "1.2.3.4".parse().unwrap()));
+        }
+        assert!(id_valid(&id_from_pow(100), "192.168.1.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_refresh_buckets() {
+        let mut rt = RoutingTable::new();
+        assert!(rt.refresh_buckets().is_empty());
+        let mut node = Node::new_test(id_from_pow(100));
+        node.last_updated = Utc::now() - Duration::minutes(60);
+        rt.buckets[0].last_updated = node.last_updated;
+        rt.buckets[0].nodes.push(node);
+        let reqs = rt.refresh_buckets();
+        assert_eq!(reqs.len(), 1);
+        match reqs[0].0.kind {
+            RequestKind::FindNode { ref target, .. } => assert!(rt.buckets[0].could_hold(target)),
+            _ => panic!("Expected find_node"),
+        }
+        // The bucket isn't refreshed again until it goes stale again
+        assert!(rt.refresh_buckets().is_empty());
+    }
+
     #[test]
     fn test_bucket_split_close() {
         let mut rt = RoutingTable::new();
@@ -15,6 +15,22 @@ error_chain! {
             display("tracker error: {}", e)
         }
 
+        /// BEP 31 error response hinting when to retry, None meaning never
+        TrackerRetry(e: String, mins: Option<u32>) {
+            description("tracker error response")
+            display("tracker error: {}", e)
+        }
+
+        Unavailable(secs: u32) {
+            description("the server is busy and asked to retry later")
+            display("server unavailable, retry in {}s", secs)
+        }
+
+        Proxy(r: &'static str) {
+            description("the tracker proxy failed to establish a connection")
+            display("tracker proxy error: {}", r)
+        }
+
         EOF {
             description("the tracker closed the connection unexpectedly")
             display("tracker EOF")
@@ -0,0 +1,163 @@
+use std::collections::VecDeque;
+use std::net::IpAddr;
+
+use crate::util::FHashMap;
+
+/// Number of recent reports used to determine the external IP
+const MAX_VOTES: usize = 32;
+/// Reports needed before an address is trusted
+const MIN_VOTES: usize = 3;
+
+/// Tracks the external IP which peers report seeing us as via
+/// the `yourip` extension handshake field, and trackers via their
+/// `external ip` response field. Each reporter only gets one vote,
+/// so a single peer can't skew the result. IPv4 and IPv6 addresses
+/// are tracked apart, as dual stack hosts have one of each.
+pub struct ExternalIp {
+    v4: Family,
+    v6: Family,
+}
+
+#[derive(Default)]
+struct Family {
+    votes: VecDeque<(Reporter, IpAddr)>,
+    current: Option<IpAddr>,
+    /// Whether current came from the configured check, which peers
+    /// can't vote against
+    confirmed: bool,
+}
+
+/// Source of an external IP report
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reporter {
+    /// A peer or DHT node at the address
+    Peer(IpAddr),
+    /// The HTTP tracker with the hostname
+    Tracker(String),
+}
+
+impl ExternalIp {
+    pub fn new() -> ExternalIp {
+        ExternalIp {
+            v4: Family::default(),
+            v6: Family::default(),
+        }
+    }
+
+    fn family(&mut self, ip: IpAddr) -> &mut Family {
+        match ip {
+            IpAddr::V4(_) => &mut self.v4,
+            IpAddr::V6(_) => &mut self.v6,
+        }
+    }
+
+    /// Records that reporter sees us as ip, returning the previous and
+    /// new external IP of its family if this changed the consensus.
+    pub fn vote(&mut self, reporter: Reporter, ip: IpAddr) -> Option<(Option<IpAddr>, IpAddr)> {
+        let f = self.family(ip);
+        f.votes.retain(|(r, _)| *r != reporter);
+        if f.votes.len() == MAX_VOTES {
+            f.votes.pop_front();
+        }
+        f.votes.push_back((reporter, ip));
+        if f.confirmed {
+            return None;
+        }
+
+        let mut tally = FHashMap::default();
+        for &(_, ip) in &f.votes {
+            *tally.entry(ip).or_insert(0) += 1;
+        }
+        let (best, count) = tally.into_iter().max_by_key(|&(_, c)| c)?;
+        if count < MIN_VOTES || f.current == Some(best) {
+            return None;
+        }
+        Some((f.current.replace(best), best))
+    }
+
+    /// Takes ip as our external IP of its family, as determined by a
+    /// configured check, which overrides what peers report from then
+    /// on. Returns the previous and new IP if it changed.
+    pub fn confirm(&mut self, ip: IpAddr) -> Option<(Option<IpAddr>, IpAddr)> {
+        let f = self.family(ip);
+        f.confirmed = true;
+        if f.current == Some(ip) {
+            return None;
+        }
+        Some((f.current.replace(ip), ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn peer(s: &str) -> Reporter {
+        Reporter::Peer(ip(s))
+    }
+
+    #[test]
+    fn test_vote() {
+        let mut e = ExternalIp::new();
+        let us = ip("1.2.3.4");
+        assert_eq!(e.vote(peer("10.0.0.1"), us), None);
+        // Repeated reports from one peer don't count
+        assert_eq!(e.vote(peer("10.0.0.1"), us), None);
+        assert_eq!(e.vote(peer("10.0.0.1"), us), None);
+        assert_eq!(e.vote(peer("10.0.0.2"), us), None);
+        assert_eq!(e.vote(peer("10.0.0.3"), us), Some((None, us)));
+        assert_eq!(e.vote(peer("10.0.0.4"), us), None);
+        assert_eq!(e.v4.current, Some(us));
+
+        let new = ip("5.6.7.8");
+        for i in 1..5 {
+            e.vote(peer(&format!("10.0.0.{}", i)), new);
+        }
+        assert_eq!(e.v4.current, Some(new));
+
+        // Trackers are told apart by name
+        let tracker = |s: &str| Reporter::Tracker(s.to_owned());
+        assert_eq!(e.vote(tracker("a.example"), us), None);
+        assert_eq!(e.vote(tracker("a.example"), us), None);
+        assert_eq!(e.v4.votes.len(), 5);
+    }
+
+    #[test]
+    fn test_dual_stack() {
+        let mut e = ExternalIp::new();
+        let (v4, v6) = (ip("1.2.3.4"), ip("2001:db8::1"));
+        for i in 1..4 {
+            e.vote(peer(&format!("10.0.0.{}", i)), v4);
+        }
+        assert_eq!(e.vote(peer("fd00::1"), v6), None);
+        assert_eq!(e.vote(peer("fd00::2"), v6), None);
+        assert_eq!(e.vote(peer("fd00::3"), v6), Some((None, v6)));
+        // Interleaved reports of both families don't flip between them
+        for i in 4..10 {
+            assert_eq!(e.vote(peer(&format!("fd00::{}", i)), v6), None);
+            assert_eq!(e.vote(peer(&format!("10.0.0.{}", i)), v4), None);
+        }
+        assert_eq!(e.v4.current, Some(v4));
+        assert_eq!(e.v6.current, Some(v6));
+    }
+
+    #[test]
+    fn test_confirm() {
+        let mut e = ExternalIp::new();
+        let (old, new) = (ip("1.2.3.4"), ip("5.6.7.8"));
+        for i in 1..10 {
+            e.vote(peer(&format!("10.0.0.{}", i)), old);
+        }
+        assert_eq!(e.confirm(new), Some((Some(old), new)));
+        assert_eq!(e.confirm(new), None);
+        // Peers don't vote against the check
+        for i in 10..20 {
+            assert_eq!(e.vote(peer(&format!("10.0.0.{}", i)), old), None);
+        }
+        assert_eq!(e.v4.current, Some(new));
+    }
+}
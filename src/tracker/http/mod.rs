@@ -1,29 +1,42 @@
+mod proxy;
 mod reader;
 mod writer;
 
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
-use std::{io, mem};
+use std::{fs, io, mem, result};
 
+use rustls::{self, Certificate, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError};
 use sstream::SStream;
 use url::Url;
 
+use self::proxy::{Handshake, Proxy};
 use self::reader::{ReadRes, Reader};
 use self::writer::Writer;
+use crate::config::IpStack;
 use crate::tracker::{
-    self, dns, Announce, Error, ErrorKind, Response, Result, ResultExt, TrackerResponse,
+    self, dns, Announce, Error, ErrorKind, Response, Result, ResultExt, TrackerResponse, WebSeed,
 };
-use crate::util::{http, UHashMap};
-use crate::{bencode, PEER_ID};
+use crate::util::{self, http, UHashMap};
+use crate::{bencode, CONFIG, PEER_ID};
 
 const TIMEOUT_MS: u64 = 5_000;
+/// Longest accepted external IP check response
+const IP_CHECK_LEN: usize = 256;
+/// Longest accepted release listing
+const UPDATE_CHECK_LEN: usize = 2 * 1024 * 1024;
 
 pub struct Handler {
     reg: amy::Registrar,
     connections: UHashMap<Tracker>,
+    tls: Arc<rustls::ClientConfig>,
+    proxy: Option<Proxy>,
 }
 
+/// Accepts any certificate, used when verify_tls is disabled.
+struct NoVerify;
+
 enum Event {
     DNSResolved(dns::QueryResponse),
     Readable,
@@ -35,43 +48,191 @@ struct Tracker {
     url: Arc<Url>,
     last_updated: Instant,
     redirect: bool,
+    kind: Kind,
     state: TrackerState,
 }
 
+/// What an HTTP request is being made for.
+#[derive(Clone)]
+enum Kind {
+    Announce {
+        /// Whether to go through the proxy
+        proxied: bool,
+    },
+    WebSeed {
+        seed: usize,
+        piece: u32,
+        begin: u32,
+        offset: u64,
+        length: u64,
+        /// Whether the data is addressed with a Range header (BEP 19)
+        /// or the request query (BEP 17)
+        ranged: bool,
+    },
+    /// Fetches our external IP from the configured check
+    IpCheck,
+    /// Fetches the releases listed by the configured update check
+    UpdateCheck,
+}
+
 enum TrackerState {
     Error,
     ResolvingDNS {
         sock: SStream,
         req: Vec<u8>,
         port: u16,
+        limit: usize,
+    },
+    /// Tunneling to the tracker through the proxy
+    Proxying {
+        sock: SStream,
+        handshake: Handshake,
+        req: Vec<u8>,
+        limit: usize,
     },
     Writing {
         sock: SStream,
         writer: Writer,
+        limit: usize,
     },
     Reading {
         sock: SStream,
         reader: Reader,
     },
     Redirect(String),
-    Complete(TrackerResponse),
+    Complete(u16, Vec<u8>),
 }
 
 enum HTTPRes {
     None,
     Redirect(String),
-    Complete(TrackerResponse),
+    Complete(u16, Vec<u8>),
+}
+
+impl Tracker {
+    fn error(&self, e: Error) -> Response {
+        match self.kind {
+            Kind::Announce { .. } => Response::Tracker {
+                tid: self.torrent,
+                url: self.url.clone(),
+                resp: Err(e),
+            },
+            Kind::WebSeed {
+                seed, piece, begin, ..
+            } => Response::WebSeed {
+                tid: self.torrent,
+                seed,
+                piece,
+                begin,
+                resp: Err(e),
+            },
+            Kind::IpCheck => Response::IpCheck(Err(e)),
+            Kind::UpdateCheck => Response::UpdateCheck(Err(e)),
+        }
+    }
+
+    fn complete(&self, code: u16, data: Vec<u8>) -> Response {
+        let resp = match self.kind {
+            Kind::Announce { .. } => bencode::decode_buf(&data)
+                .chain_err(|| ErrorKind::InvalidResponse("Invalid BEncoded response!"))
+                .and_then(TrackerResponse::from_bencode)
+                .map(|r| Response::Tracker {
+                    tid: self.torrent,
+                    url: self.url.clone(),
+                    resp: Ok(r),
+                }),
+            Kind::WebSeed {
+                seed,
+                piece,
+                begin,
+                offset,
+                length,
+                ..
+            } => {
+                // A plain 200 is only acceptable if it contains exactly the requested range,
+                // i.e. the server ignored the Range header but the range was the whole file.
+                let valid = match code {
+                    206 => true,
+                    200 => offset == 0,
+                    _ => false,
+                };
+                if code == 503 {
+                    // BEP 17 servers respond with the number of seconds to wait when busy
+                    let secs = String::from_utf8_lossy(&data).trim().parse().unwrap_or(60);
+                    Err(ErrorKind::Unavailable(secs).into())
+                } else if !valid {
+                    Err(ErrorKind::InvalidResponse("Unexpected webseed response status").into())
+                } else if data.len() as u64 != length {
+                    Err(ErrorKind::InvalidResponse("Webseed response has invalid length").into())
+                } else {
+                    Ok(Response::WebSeed {
+                        tid: self.torrent,
+                        seed,
+                        piece,
+                        begin,
+                        resp: Ok(data),
+                    })
+                }
+            }
+            Kind::IpCheck if code != 200 => {
+                Err(ErrorKind::InvalidResponse("Unexpected IP check response status").into())
+            }
+            Kind::IpCheck => String::from_utf8_lossy(&data)
+                .trim()
+                .parse()
+                .map(|ip| Response::IpCheck(Ok(ip)))
+                .map_err(|_| ErrorKind::InvalidResponse("IP check response isn't an IP").into()),
+            Kind::UpdateCheck if code != 200 => {
+                Err(ErrorKind::InvalidResponse("Unexpected update check response status").into())
+            }
+            Kind::UpdateCheck => serde_json::from_slice(&data)
+                .map(|releases| Response::UpdateCheck(Ok(releases)))
+                .map_err(|_| ErrorKind::InvalidResponse("Invalid release listing").into()),
+        };
+        resp.unwrap_or_else(|e| self.error(e))
+    }
+}
+
+impl Kind {
+    /// Value of the Range header to send, if any
+    fn range(&self) -> Option<String> {
+        match *self {
+            Kind::Announce { .. } | Kind::IpCheck | Kind::UpdateCheck => None,
+            Kind::WebSeed {
+                offset,
+                length,
+                ranged: true,
+                ..
+            } => Some(format!("bytes={}-{}", offset, offset + length - 1)),
+            Kind::WebSeed { ranged: false, .. } => None,
+        }
+    }
+
+    /// Maximum accepted response body length
+    fn limit(&self) -> usize {
+        match *self {
+            Kind::Announce { .. } => usize::MAX,
+            Kind::WebSeed { length, .. } => length as usize,
+            Kind::IpCheck => IP_CHECK_LEN,
+            Kind::UpdateCheck => UPDATE_CHECK_LEN,
+        }
+    }
 }
 
 impl TrackerState {
-    fn new(sock: SStream, req: Vec<u8>, port: u16) -> TrackerState {
-        TrackerState::ResolvingDNS { sock, req, port }
+    fn new(sock: SStream, req: Vec<u8>, port: u16, limit: usize) -> TrackerState {
+        TrackerState::ResolvingDNS {
+            sock,
+            req,
+            port,
+            limit,
+        }
     }
 
     fn handle(&mut self, event: Event) -> Result<HTTPRes> {
         let s = mem::replace(self, TrackerState::Error);
         match s.next(event)? {
-            TrackerState::Complete(r) => Ok(HTTPRes::Complete(r)),
+            TrackerState::Complete(c, d) => Ok(HTTPRes::Complete(c, d)),
             TrackerState::Redirect(l) => Ok(HTTPRes::Redirect(l)),
             n => {
                 *self = n;
@@ -87,6 +248,7 @@ impl TrackerState {
                     mut sock,
                     req,
                     port,
+                    limit,
                 },
                 Event::DNSResolved(r),
             ) => {
@@ -95,6 +257,33 @@ impl TrackerState {
                 Ok(TrackerState::Writing {
                     sock,
                     writer: Writer::new(req),
+                    limit,
+                }
+                .next(Event::Writable)?
+                .next(Event::Readable)?)
+            }
+            (
+                TrackerState::Proxying {
+                    sock,
+                    mut handshake,
+                    req,
+                    limit,
+                },
+                _,
+            ) => {
+                if !handshake.advance(&mut sock.get_stream())? {
+                    return Ok(TrackerState::Proxying {
+                        sock,
+                        handshake,
+                        req,
+                        limit,
+                    });
+                }
+                debug!("Proxy tunnel established");
+                Ok(TrackerState::Writing {
+                    sock,
+                    writer: Writer::new(req),
+                    limit,
                 }
                 .next(Event::Writable)?
                 .next(Event::Readable)?)
@@ -103,15 +292,20 @@ impl TrackerState {
                 TrackerState::Writing {
                     mut sock,
                     mut writer,
+                    limit,
                 },
                 _,
             ) => match writer.writable(&mut sock)? {
                 Some(()) => {
                     debug!("Tracker write completed, beginning read");
-                    let r = Reader::new();
+                    let r = Reader::new(limit);
                     Ok(TrackerState::Reading { sock, reader: r }.next(Event::Readable)?)
                 }
-                None => Ok(TrackerState::Writing { sock, writer }),
+                None => Ok(TrackerState::Writing {
+                    sock,
+                    writer,
+                    limit,
+                }),
             },
             (
                 TrackerState::Reading {
@@ -120,12 +314,7 @@ impl TrackerState {
                 },
                 _,
             ) => match reader.readable(&mut sock)? {
-                ReadRes::Done(data) => {
-                    let content = bencode::decode_buf(&data)
-                        .chain_err(|| ErrorKind::InvalidResponse("Invalid BEncoded response!"))?;
-                    let resp = TrackerResponse::from_bencode(content)?;
-                    Ok(TrackerState::Complete(resp))
-                }
+                ReadRes::Done(code, data) => Ok(TrackerState::Complete(code, data)),
                 ReadRes::Redirect(l) => Ok(TrackerState::Redirect(l)),
                 ReadRes::None => Ok(TrackerState::Reading { sock, reader }),
             },
@@ -140,11 +329,19 @@ impl Handler {
         Ok(Handler {
             reg: reg.clone(),
             connections: UHashMap::default(),
+            tls: Arc::new(tls_config()?),
+            proxy: CONFIG.trk.proxy.as_deref().map(Proxy::parse).transpose()?,
         })
     }
 
     pub fn active_requests(&self) -> usize {
-        self.connections.len()
+        self.connections
+            .values()
+            .filter(|t| match t.kind {
+                Kind::Announce { .. } => true,
+                Kind::WebSeed { .. } | Kind::IpCheck | Kind::UpdateCheck => false,
+            })
+            .count()
     }
 
     pub fn complete(&self) -> bool {
@@ -162,11 +359,7 @@ impl Handler {
             trk.last_updated = Instant::now();
             match trk.state.handle(Event::DNSResolved(resp)) {
                 Ok(_) => None,
-                Err(e) => Some(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url.clone(),
-                    resp: Err(e),
-                }),
+                Err(e) => Some(trk.error(e)),
             }
         } else {
             None
@@ -182,11 +375,7 @@ impl Handler {
             trk.last_updated = Instant::now();
             match trk.state.handle(Event::Writable) {
                 Ok(_) => None,
-                Err(e) => Some(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url.clone(),
-                    resp: Err(e),
-                }),
+                Err(e) => Some(trk.error(e)),
             }
         } else {
             None
@@ -202,24 +391,16 @@ impl Handler {
         let mut resp = if let Some(trk) = self.connections.get_mut(&id) {
             trk.last_updated = Instant::now();
             match trk.state.handle(Event::Readable) {
-                Ok(HTTPRes::Complete(r)) => {
-                    debug!("Announce response received for {:?} succesfully", id);
-                    Some(Response::Tracker {
-                        tid: trk.torrent,
-                        url: trk.url.clone(),
-                        resp: Ok(r),
-                    })
+                Ok(HTTPRes::Complete(code, data)) => {
+                    debug!("HTTP response received for {:?} succesfully", id);
+                    Some(trk.complete(code, data))
                 }
                 Ok(HTTPRes::Redirect(l)) => {
-                    loc = Some((l, trk.url.clone()));
+                    loc = Some(l);
                     None
                 }
                 Ok(HTTPRes::None) => None,
-                Err(e) => Some(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url.clone(),
-                    resp: Err(e),
-                }),
+                Err(e) => Some(trk.error(e)),
             }
         } else {
             None
@@ -229,38 +410,21 @@ impl Handler {
             self.connections.remove(&id);
         }
 
-        if let Some((l, old)) = loc {
+        if let Some(l) = loc {
             let trk = self.connections.remove(&id).unwrap();
             // Disallow 2 levels of redirection
             if trk.redirect {
-                resp = Some(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url.clone(),
-                    resp: Err(ErrorKind::InvalidResponse("Too many redirects").into()),
-                });
-            }
-            if let Err(e) = self.try_redirect(&l, old, trk.torrent, dns) {
-                debug!(
-                    "Announce response received for {:?}, redirecting!",
-                    trk.torrent
-                );
-                resp = Some(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url,
-                    resp: Err(e),
-                });
+                resp = Some(trk.error(ErrorKind::InvalidResponse("Too many redirects").into()));
+            } else if let Err(e) = self.try_redirect(&l, &trk, dns) {
+                debug!("HTTP response received for {:?}, redirecting!", trk.torrent);
+                resp = Some(trk.error(e));
             }
         }
         resp
     }
 
-    fn try_redirect(
-        &mut self,
-        url: &str,
-        original_url: Arc<Url>,
-        torrent: usize,
-        dns: &mut dns::Resolver,
-    ) -> Result<()> {
+    fn try_redirect(&mut self, url: &str, trk: &Tracker, dns: &mut dns::Resolver) -> Result<()> {
+        let original_url = &trk.url;
         let url = match Url::parse(url) {
             Ok(url) => Ok(url),
             Err(url::ParseError::RelativeUrlWithoutBase) => Ok(original_url
@@ -276,58 +440,37 @@ impl Handler {
             error!("{}", url);
             Error::from(ErrorKind::InvalidResponse("Malformed redirect!"))
         })?;
+        let range = trk.kind.range();
         let mut http_req = Vec::with_capacity(512);
         http::RequestBuilder::new("GET", url.path(), url.query())
-            .header("User-agent", concat!("synapse/", env!("CARGO_PKG_VERSION")))
+            .header("User-agent", &CONFIG.peer.user_agent)
             .header("Connection", "close")
             .header("Host", host)
+            .header_opt("Range", range.as_deref())
             .encode(&mut http_req);
 
-        let ohost = if url.scheme() == "https" {
-            Some(host.to_owned())
-        } else {
-            None
-        };
-
-        // Setup actual connection and start DNS query
-        let sock = SStream::new_v4(ohost).chain_err(|| ErrorKind::IO)?;
-        let id = self
-            .reg
-            .register(&sock, amy::Event::Both)
-            .chain_err(|| ErrorKind::IO)?;
-        let port = url.port().unwrap_or(80);
-        self.connections.insert(
-            id,
+        debug!("Dispatching redirect for {:?}", trk.torrent);
+        self.dispatch(
+            &url,
             Tracker {
                 last_updated: Instant::now(),
                 redirect: true,
-                torrent,
-                url: original_url,
-                state: TrackerState::new(sock, http_req, port),
+                torrent: trk.torrent,
+                url: trk.url.clone(),
+                kind: trk.kind.clone(),
+                state: TrackerState::Error,
             },
-        );
-
-        debug!("Dispatching redirect DNS req, id {:?}", id);
-        if let Some(ip) = dns.new_query(id, host).chain_err(|| ErrorKind::IO)? {
-            debug!("Using cached DNS response");
-            let res = self.dns_resolved(dns::QueryResponse { id, res: Ok(ip) });
-            if res.is_some() {
-                bail!("Failed to establish connection to tracker!");
-            }
-        }
-        Ok(())
+            http_req,
+            dns,
+        )
     }
 
     pub fn tick(&mut self) -> Vec<Response> {
         let mut resps = Vec::new();
         self.connections.retain(|id, trk| {
             if trk.last_updated.elapsed() > Duration::from_millis(TIMEOUT_MS) {
-                debug!("Announce {:?} timed out", id);
-                resps.push(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url.clone(),
-                    resp: Err(ErrorKind::Timeout.into()),
-                });
+                debug!("HTTP request {:?} timed out", id);
+                resps.push(trk.error(ErrorKind::Timeout.into()));
                 false
             } else {
                 true
@@ -346,10 +489,21 @@ impl Handler {
 
         let mut http_req = Vec::with_capacity(512);
         let num_want = req.num_want.map(|nw| nw.to_string());
+        let key = format!("{:08X}", req.key);
+        let ip = CONFIG.trk.announce_ip.map(|ip| ip.to_string());
+        // BEP 7, tell the tracker our IPv6 address so it can hand it out
+        // to peers even when announcing over IPv4.
+        let ipv6 = match CONFIG.trk.announce_ip {
+            _ if CONFIG.net.ip_stack == IpStack::Ipv4 => None,
+            Some(IpAddr::V6(ip)) => Some(ip),
+            _ => util::global_ipv6(),
+        }
+        .map(|ip| ip.to_string());
         let event = match req.event {
             Some(tracker::Event::Started) => Some("started"),
             Some(tracker::Event::Stopped) => Some("stopped"),
             Some(tracker::Event::Completed) => Some("completed"),
+            Some(tracker::Event::Paused) => Some("paused"),
             None => None,
         };
         http::RequestBuilder::new("GET", req.url.path(), req.url.query())
@@ -362,48 +516,215 @@ impl Handler {
             .query("port", req.port.to_string().as_bytes())
             .query_opt("numwant", num_want.as_ref().map(|nw| nw.as_bytes()))
             .query_opt("event", event.map(|e| e.as_bytes()))
-            .header("User-agent", concat!("synapse/", env!("CARGO_PKG_VERSION")))
+            .query("key", key.as_bytes())
+            .query_opt("trackerid", req.tracker_id.as_ref().map(|t| t.as_bytes()))
+            .query_opt("ip", ip.as_ref().map(|ip| ip.as_bytes()))
+            .query_opt("ipv6", ipv6.as_ref().map(|ip| ip.as_bytes()))
+            .header("User-agent", &CONFIG.peer.user_agent)
             .header("Connection", "close")
             .header("Host", host)
             .encode(&mut http_req);
 
-        let port =
-            req.url
-                .port()
-                .unwrap_or_else(|| if req.url.scheme() == "https" { 443 } else { 80 });
+        self.dispatch(
+            &req.url,
+            Tracker {
+                url: req.url.clone(),
+                last_updated: Instant::now(),
+                torrent: req.id,
+                kind: Kind::Announce {
+                    proxied: req.proxied,
+                },
+                state: TrackerState::Error,
+                redirect: false,
+            },
+            http_req,
+            dns,
+        )
+    }
 
-        let ohost = if req.url.scheme() == "https" {
-            Some(host.to_owned())
-        } else {
-            None
+    pub fn new_web_seed(&mut self, req: WebSeed, dns: &mut dns::Resolver) -> Result<()> {
+        debug!("Received a new webseed req for {:?}", req.url);
+        let host = req.url.host_str().ok_or_else(|| {
+            Error::from(ErrorKind::InvalidRequest(
+                "Webseed url has no host!".to_owned(),
+            ))
+        })?;
+
+        let kind = Kind::WebSeed {
+            seed: req.seed,
+            piece: req.piece,
+            begin: req.begin,
+            offset: req.offset,
+            length: req.length,
+            ranged: req.hash.is_none(),
         };
+        let range = kind.range();
+        let piece = req.piece.to_string();
+        let mut http_req = Vec::with_capacity(512);
+        http::RequestBuilder::new("GET", req.url.path(), req.url.query())
+            .query_opt("info_hash", req.hash.as_ref().map(|h| &h[..]))
+            .query_opt("piece", req.hash.map(|_| piece.as_bytes()))
+            .header("User-agent", &CONFIG.peer.user_agent)
+            .header("Connection", "close")
+            .header("Host", host)
+            .header_opt("Range", range.as_deref())
+            .encode(&mut http_req);
 
-        // Setup actual connection and start DNS query
-        let sock = SStream::new_v4(ohost).chain_err(|| ErrorKind::IO)?;
-        let id = self
-            .reg
-            .register(&sock, amy::Event::Both)
-            .chain_err(|| ErrorKind::IO)?;
-        self.connections.insert(
-            id,
+        self.dispatch(
+            &req.url,
             Tracker {
                 url: req.url.clone(),
                 last_updated: Instant::now(),
                 torrent: req.id,
-                state: TrackerState::new(sock, http_req, port),
+                kind,
+                state: TrackerState::Error,
                 redirect: false,
             },
-        );
+            http_req,
+            dns,
+        )
+    }
+
+    /// Fetches our external IP from url.
+    pub fn new_ip_check(&mut self, url: &Arc<Url>, dns: &mut dns::Resolver) -> Result<()> {
+        debug!("Checking external IP at {:?}", url);
+        self.new_check(url, Kind::IpCheck, dns)
+    }
+
+    /// Fetches the list of releases from url.
+    pub fn new_update_check(&mut self, url: &Arc<Url>, dns: &mut dns::Resolver) -> Result<()> {
+        debug!("Checking for updates at {:?}", url);
+        self.new_check(url, Kind::UpdateCheck, dns)
+    }
+
+    /// Sends a plain GET request to url for an IP or update check.
+    fn new_check(&mut self, url: &Arc<Url>, kind: Kind, dns: &mut dns::Resolver) -> Result<()> {
+        let host = url
+            .host_str()
+            .ok_or_else(|| Error::from(ErrorKind::InvalidRequest("URL has no host!".to_owned())))?;
+        let mut http_req = Vec::with_capacity(512);
+        http::RequestBuilder::new("GET", url.path(), url.query())
+            .header("User-agent", &CONFIG.peer.user_agent)
+            .header("Connection", "close")
+            .header("Host", host)
+            .encode(&mut http_req);
+
+        self.dispatch(
+            url,
+            Tracker {
+                url: url.clone(),
+                last_updated: Instant::now(),
+                torrent: 0,
+                kind,
+                state: TrackerState::Error,
+                redirect: false,
+            },
+            http_req,
+            dns,
+        )
+    }
+
+    /// Opens a connection to url for trk, sending http_req once connected.
+    fn dispatch(
+        &mut self,
+        url: &Url,
+        mut trk: Tracker,
+        http_req: Vec<u8>,
+        dns: &mut dns::Resolver,
+    ) -> Result<()> {
+        let host = url
+            .host_str()
+            .ok_or_else(|| Error::from(ErrorKind::InvalidRequest("URL has no host!".to_owned())))?;
+        let port = url
+            .port()
+            .unwrap_or_else(|| if url.scheme() == "https" { 443 } else { 80 });
+
+        // Only announces go through the proxy, web seeds are peer traffic
+        // and IP checks would only see the proxy's address
+        let proxy = match trk.kind {
+            Kind::Announce { proxied } => self.proxy.as_ref().filter(|_| proxied),
+            Kind::WebSeed { .. } | Kind::IpCheck | Kind::UpdateCheck => None,
+        };
+        let v6 = proxy.is_some_and(|p| p.addr.is_ipv6());
+
+        // Setup actual connection and start DNS query
+        let mut sock = match (url.scheme() == "https", v6) {
+            (true, false) => SStream::new_v4_tls(host.to_owned(), &self.tls),
+            (true, true) => SStream::new_v6_tls(host.to_owned(), &self.tls),
+            (false, false) => SStream::new_v4(None),
+            (false, true) => SStream::new_v6(None),
+        }
+        .chain_err(|| ErrorKind::IO)?;
+        let id = self
+            .reg
+            .register(&sock, amy::Event::Both)
+            .chain_err(|| ErrorKind::IO)?;
+
+        // The proxy resolves the tracker's host itself, so no DNS query is needed
+        if let Some(proxy) = proxy {
+            sock.connect(proxy.addr).chain_err(|| ErrorKind::IO)?;
+            trk.state = TrackerState::Proxying {
+                sock,
+                handshake: proxy.handshake(host, port),
+                req: http_req,
+                limit: trk.kind.limit(),
+            };
+            self.connections.insert(id, trk);
+            debug!("Connecting to tracker through proxy, id {:?}", id);
+            return Ok(());
+        }
+        trk.state = TrackerState::new(sock, http_req, port, trk.kind.limit());
+        self.connections.insert(id, trk);
 
         debug!("Dispatching DNS req, id {:?}", id);
         if let Some(ip) = dns.new_query(id, host).chain_err(|| ErrorKind::IO)? {
             debug!("Using cached DNS response");
             let res = self.dns_resolved(dns::QueryResponse { id, res: Ok(ip) });
             if res.is_some() {
-                bail!("Failed to establish connection to tracker!");
+                bail!("Failed to establish connection!");
             }
         }
 
         Ok(())
     }
 }
+
+impl ServerCertVerifier for NoVerify {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        _certs: &[Certificate],
+        _host: webpki::DNSNameRef<'_>,
+        _ocsp: &[u8],
+    ) -> result::Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Builds the TLS configuration used for HTTPS connections, trusting
+/// the configured CA bundle if given and the built-in roots otherwise.
+fn tls_config() -> io::Result<rustls::ClientConfig> {
+    let mut config = match CONFIG.trk.ca_bundle {
+        Some(ref path) => {
+            let mut config = rustls::ClientConfig::new();
+            let mut reader = io::BufReader::new(fs::File::open(path)?);
+            match config.root_store.add_pem_file(&mut reader) {
+                Ok((valid, _)) if valid > 0 => {}
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("no valid certificates in CA bundle {}", path),
+                    ));
+                }
+            }
+            config
+        }
+        None => SStream::client_config(),
+    };
+    if !CONFIG.trk.verify_tls {
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(NoVerify));
+    }
+    Ok(config)
+}
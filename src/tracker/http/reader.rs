@@ -7,12 +7,14 @@ use crate::util::{aread, IOR};
 pub struct Reader {
     data: Vec<u8>,
     idx: usize,
+    code: u16,
+    limit: usize,
     state: ReadState,
 }
 
 pub enum ReadRes {
     None,
-    Done(Vec<u8>),
+    Done(u16, Vec<u8>),
     Redirect(String),
 }
 
@@ -22,10 +24,13 @@ enum ReadState {
 }
 
 impl Reader {
-    pub fn new() -> Reader {
+    /// Creates a reader which errors if the response body exceeds limit bytes.
+    pub fn new(limit: usize) -> Reader {
         Reader {
             data: vec![0; 75],
             idx: 0,
+            code: 0,
+            limit,
             state: ReadState::Header,
         }
     }
@@ -52,7 +57,7 @@ impl Reader {
                     ReadState::Body => {
                         let mut data = mem::replace(&mut self.data, Vec::with_capacity(0));
                         data.truncate(self.idx);
-                        return Ok(ReadRes::Done(data));
+                        return Ok(ReadRes::Done(self.code, data));
                     }
                     _ => return Err(ErrorKind::EOF.into()),
                 },
@@ -87,6 +92,7 @@ impl Reader {
                             }
                             return Ok(Some(ReadRes::Redirect(loc.unwrap())));
                         }
+                        self.code = resp.code.unwrap_or(0);
                         header_done = Some(i);
                     }
                     Ok(httparse::Status::Partial) => {}
@@ -95,7 +101,11 @@ impl Reader {
                     }
                 }
             }
-            ReadState::Body => {}
+            ReadState::Body => {
+                if self.idx > self.limit {
+                    return Err(ErrorKind::InvalidResponse("response body too large").into());
+                }
+            }
         }
         if let Some(i) = header_done {
             let body = self.data.split_off(i);
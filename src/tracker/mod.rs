@@ -1,13 +1,17 @@
 mod dht;
 mod dns;
 mod errors;
+mod extip;
 mod http;
 mod udp;
+mod update;
 
 use std::collections::VecDeque;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::sync::Arc;
-use std::{io, result, thread};
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{atomic, Arc};
+use std::time::{Duration, Instant};
+use std::{cmp, io, result, thread};
 
 use byteorder::{BigEndian, ByteOrder};
 use url::Url;
@@ -18,7 +22,8 @@ use crate::control::cio;
 use crate::disk;
 use crate::handle;
 use crate::torrent::Torrent;
-use crate::CONFIG;
+use crate::util;
+use crate::{CONFIG, PORT};
 
 pub struct Tracker {
     poll: amy::Poller,
@@ -28,6 +33,15 @@ pub struct Tracker {
     udp: udp::Handler,
     dht: dht::Manager,
     dns: dns::Resolver,
+    external: extip::ExternalIp,
+    /// Configured external IP check, and when it was last fetched
+    ip_check: Option<Arc<Url>>,
+    ip_checked: Option<Instant>,
+    /// Enabled update check, when it was last fetched, and the newest
+    /// version reported to control
+    update_check: Option<Arc<Url>>,
+    update_checked: Option<Instant>,
+    update_found: Option<String>,
     timer: usize,
     shutting_down: bool,
 }
@@ -37,7 +51,13 @@ pub enum Request {
     Announce(Announce),
     GetPeers(GetPeers),
     AddNode(SocketAddr),
+    /// A peer at reporter told us it sees our address as ip
+    ExternalIp {
+        reporter: IpAddr,
+        ip: IpAddr,
+    },
     DHTAnnounce([u8; 20]),
+    WebSeed(WebSeed),
     PurgeDNS,
     Ping,
     Shutdown,
@@ -54,6 +74,31 @@ pub struct Announce {
     left: u64,
     num_want: Option<u16>,
     event: Option<Event>,
+    key: u32,
+    /// `tracker id` of a previous response
+    tracker_id: Option<String>,
+    /// Whether the announce must go through the proxy
+    proxied: bool,
+}
+
+/// Request for a byte range of a file from a BEP 19 webseed, or a piece
+/// from a BEP 17 httpseed
+#[derive(Debug)]
+pub struct WebSeed {
+    pub id: usize,
+    /// Torrent local id of the webseed
+    pub seed: usize,
+    /// Full URL of the file
+    pub url: Arc<Url>,
+    pub piece: u32,
+    /// Offset of the range in the piece
+    pub begin: u32,
+    /// Offset of the range in the file
+    pub offset: u64,
+    pub length: u64,
+    /// Info hash, set for BEP 17 httpseeds which are addressed
+    /// by piece rather than by file range
+    pub hash: Option<[u8; 20]>,
 }
 
 #[derive(Debug)]
@@ -67,6 +112,8 @@ pub enum Event {
     Started,
     Stopped,
     Completed,
+    /// Sent instead of regular and completed announces by partial seeds (BEP 21)
+    Paused,
 }
 
 #[derive(Debug)]
@@ -84,17 +131,51 @@ pub enum Response {
         tid: usize,
         peers: Vec<SocketAddr>,
     },
+    WebSeed {
+        tid: usize,
+        seed: usize,
+        piece: u32,
+        begin: u32,
+        resp: Result<Vec<u8>>,
+    },
+    /// Our external IP changed to the address
+    ExternalIp(IpAddr),
+    /// Result of the configured external IP check, which is handled
+    /// by the tracker thread itself
+    IpCheck(Result<IpAddr>),
+    /// Releases listed by the update check, also handled here
+    UpdateCheck(Result<Vec<update::Release>>),
+    /// A release newer than the running version is available
+    UpdateAvailable {
+        version: String,
+        url: String,
+    },
 }
 
 #[derive(Debug)]
 pub struct TrackerResponse {
     pub peers: Vec<SocketAddr>,
     pub interval: u32,
-    pub leechers: u32,
-    pub seeders: u32,
+    /// Announces must not be sent more often than this
+    pub min_interval: Option<u32>,
+    /// Swarm counts, if the tracker reports them
+    pub leechers: Option<u32>,
+    pub seeders: Option<u32>,
+    /// Times the torrent was completed, if the tracker reports it
+    pub downloaded: Option<u32>,
+    pub tracker_id: Option<String>,
+    /// Address the tracker saw the announce come from (BEP 24)
+    pub external_ip: Option<IpAddr>,
 }
 
 const POLL_INT_MS: usize = 1000;
+/// Longest time spent waiting for the last announces when shutting down
+const SHUTDOWN_WAIT_MS: u64 = 5_000;
+/// Bounds of the announce intervals trackers can ask for, in seconds
+const MIN_INTERVAL: i64 = 60;
+const MAX_INTERVAL: i64 = 86_400;
+/// Longest BEP 31 retry delay honored, in minutes
+const MAX_RETRY_MINS: i64 = 1_440;
 
 impl Tracker {
     pub fn start(
@@ -109,6 +190,28 @@ impl Tracker {
         let dht = dht::Manager::new(&reg, db)?;
         let http = http::Handler::new(&reg)?;
         let dns = dns::Resolver::new(&reg)?;
+        let ip_check = CONFIG
+            .trk
+            .ip_check_url
+            .as_ref()
+            .and_then(|u| match Url::parse(u) {
+                Ok(url) => Some(Arc::new(url)),
+                Err(e) => {
+                    error!("Invalid external IP check URL {}: {}", u, e);
+                    None
+                }
+            });
+        let update_check = if CONFIG.update.check {
+            match Url::parse(&CONFIG.update.url) {
+                Ok(url) => Some(Arc::new(url)),
+                Err(e) => {
+                    error!("Invalid update check URL {}: {}", CONFIG.update.url, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
         let th = dh.run("trk", move |h| {
             Tracker {
                 poll,
@@ -117,6 +220,12 @@ impl Tracker {
                 dht,
                 http,
                 dns,
+                external: extip::ExternalIp::new(),
+                ip_check,
+                ip_checked: None,
+                update_check,
+                update_checked: None,
+                update_found: None,
                 timer,
                 queue: VecDeque::new(),
                 shutting_down: false,
@@ -146,14 +255,21 @@ impl Tracker {
         }
 
         self.shutting_down = true;
-
-        // Shutdown loop - wait for all requests to complete
-        loop {
+        self.dht.save();
+
+        // Shutdown loop - wait a bit for requests, mostly stopped
+        // announces, to complete
+        let deadline = Instant::now() + Duration::from_millis(SHUTDOWN_WAIT_MS);
+        while !(self.http.complete() && self.udp.complete() && self.queue.is_empty()) {
+            if Instant::now() >= deadline {
+                info!(
+                    "Giving up on {} announces after shutdown",
+                    self.http.active_requests() + self.udp.active_requests() + self.queue.len()
+                );
+                return;
+            }
             for event in self.poll.wait(POLL_INT_MS).unwrap() {
                 if self.handle_event(event).is_err() {}
-                if self.http.complete() && self.udp.complete() {
-                    return;
-                }
             }
         }
     }
@@ -183,10 +299,14 @@ impl Tracker {
                     trace!("Handling dht node addition req!");
                     self.dht.add_addr(addr);
                 }
+                Request::ExternalIp { reporter, ip } => {
+                    self.vote_external_ip(extip::Reporter::Peer(reporter), ip)
+                }
                 Request::DHTAnnounce(hash) => {
                     trace!("Handling dht announce req!");
                     self.dht.announce(hash);
                 }
+                Request::WebSeed(ws) => self.handle_web_seed(ws),
                 Request::Ping => {}
                 Request::PurgeDNS => {
                     self.dns.res.purge();
@@ -208,7 +328,14 @@ impl Tracker {
             let id = req.id;
             let url = req.url.clone();
             let response = match url.scheme() {
+                "http" | "https" if req.proxied && CONFIG.trk.proxy.is_none() => {
+                    Err(ErrorKind::InvalidRequest("No proxy is configured".to_owned()).into())
+                }
                 "http" | "https" => self.http.new_announce(req, &mut self.dns),
+                "udp" if req.proxied => Err(ErrorKind::InvalidRequest(
+                    "UDP trackers can't be reached through the proxy".to_owned(),
+                )
+                .into()),
                 "udp" => self.udp.new_announce(req, &mut self.dns),
                 s => Err(
                     ErrorKind::InvalidRequest(format!("Unknown tracker url scheme: {}", s)).into(),
@@ -224,6 +351,20 @@ impl Tracker {
         }
     }
 
+    fn handle_web_seed(&mut self, req: WebSeed) {
+        trace!("Handling webseed request!");
+        let (tid, seed, piece, begin) = (req.id, req.seed, req.piece, req.begin);
+        if let Err(e) = self.http.new_web_seed(req, &mut self.dns) {
+            self.send_response(Response::WebSeed {
+                tid,
+                seed,
+                piece,
+                begin,
+                resp: Err(e),
+            });
+        }
+    }
+
     fn dequeue_req(&mut self) {
         // Attempt to dequeue next request if we can
         if let Some(a) = self.queue.pop_front() {
@@ -268,6 +409,8 @@ impl Tracker {
         }
 
         self.dht.tick();
+        self.check_ip();
+        self.check_update();
         let mut dresps = vec![];
         let res = self.dns.res.tick(&mut self.dns.sock, |resp| {
             dresps.push(resp);
@@ -298,12 +441,113 @@ impl Tracker {
             for resp in self.dht.readable() {
                 self.send_response(resp);
             }
+            for (reporter, ip) in self.dht.take_ip_reports() {
+                self.vote_external_ip(extip::Reporter::Peer(reporter), ip);
+            }
         } else {
             error!("Unknown event occured for tracker: {:?}", event);
         };
     }
 
+    fn vote_external_ip(&mut self, reporter: extip::Reporter, ip: IpAddr) {
+        if let Some((prev, ip)) = self.external.vote(reporter, ip) {
+            self.external_ip_changed(prev, ip);
+        }
+    }
+
+    fn external_ip_changed(&mut self, prev: Option<IpAddr>, ip: IpAddr) {
+        info!("External IP is now {}", ip);
+        // The DHT only runs over IPv4
+        if ip.is_ipv4() {
+            self.dht.set_external_ip(ip);
+        }
+        // The first address found isn't a change peers need to hear about
+        if prev.is_some() {
+            if ip.is_ipv4() {
+                self.dht.rebootstrap();
+            }
+            self.send_response(Response::ExternalIp(ip));
+        }
+    }
+
+    /// Fetches the configured external IP check when it's due.
+    fn check_ip(&mut self) {
+        let url = match self.ip_check {
+            Some(ref url) => url.clone(),
+            None => return,
+        };
+        let interval = Duration::from_secs(CONFIG.trk.ip_check_interval);
+        if self.ip_checked.is_some_and(|c| c.elapsed() < interval) {
+            return;
+        }
+        self.ip_checked = Some(Instant::now());
+        if let Err(e) = self.http.new_ip_check(&url, &mut self.dns) {
+            debug!("Failed to check external IP: {}", e);
+        }
+    }
+
+    /// Fetches the release listing when the update check is due.
+    fn check_update(&mut self) {
+        let url = match self.update_check {
+            Some(ref url) => url.clone(),
+            None => return,
+        };
+        let interval = Duration::from_secs(CONFIG.update.interval);
+        if self.update_checked.is_some_and(|c| c.elapsed() < interval) {
+            return;
+        }
+        self.update_checked = Some(Instant::now());
+        if let Err(e) = self.http.new_update_check(&url, &mut self.dns) {
+            debug!("Failed to check for updates: {}", e);
+        }
+    }
+
     fn send_response(&mut self, r: Response) {
+        let r = match r {
+            Response::UpdateCheck(Ok(releases)) => {
+                let current = env!("CARGO_PKG_VERSION");
+                match update::newer(&releases, CONFIG.update.channel, current) {
+                    Some(r) if self.update_found.as_ref() != Some(&r.tag_name) => {
+                        info!("Update to {} available: {}", r.tag_name, r.html_url);
+                        self.update_found = Some(r.tag_name.clone());
+                        Response::UpdateAvailable {
+                            version: r.tag_name.clone(),
+                            url: r.html_url.clone(),
+                        }
+                    }
+                    _ => return,
+                }
+            }
+            Response::UpdateCheck(Err(e)) => {
+                debug!("Failed to check for updates: {}", e);
+                return;
+            }
+            Response::IpCheck(res) => {
+                match res {
+                    Ok(ip) => {
+                        if let Some((prev, ip)) = self.external.confirm(ip) {
+                            self.external_ip_changed(prev, ip);
+                        }
+                    }
+                    Err(e) => debug!("Failed to check external IP: {}", e),
+                }
+                return;
+            }
+            Response::Tracker {
+                ref url,
+                resp: Ok(ref resp),
+                ..
+            } => {
+                // Through a proxy trackers only see the proxy's address
+                if let (Some(host), Some(ip), None) =
+                    (url.host_str(), resp.external_ip, &CONFIG.trk.proxy)
+                {
+                    self.vote_external_ip(extip::Reporter::Tracker(host.to_owned()), ip);
+                }
+                r
+            }
+            r => r,
+        };
         if !self.shutting_down {
             trace!("Sending trk response to control!");
             self.ch.send(r).ok();
@@ -320,16 +564,20 @@ impl Request {
         torrent: &Torrent<T>,
         event: Option<Event>,
     ) -> Option<Request> {
-        let url = if let Some(trk) = torrent.trackers().front() {
-            trk.url.clone()
+        let (url, tracker_id) = if let Some(trk) = torrent.current_tracker() {
+            (trk.url.clone(), trk.tracker_id.clone())
         } else {
             return None;
         };
+        let event = match event {
+            None | Some(Event::Completed) if torrent.partial_seed() => Some(Event::Paused),
+            e => e,
+        };
         Some(Request::Announce(Announce {
             id: torrent.id(),
             url,
             hash: torrent.info().hash,
-            port: CONFIG.port,
+            port: PORT.load(atomic::Ordering::Relaxed),
             uploaded: torrent.uploaded(),
             downloaded: torrent.downloaded(),
             // This should be fine because the true len is usually slightly less than
@@ -343,6 +591,9 @@ impl Request {
             // let existing peers connect otherwise
             num_want: if torrent.complete() { None } else { Some(50) },
             event,
+            key: torrent.announce_key(),
+            tracker_id,
+            proxied: torrent.policy().proxied(),
         }))
     }
 
@@ -350,10 +601,6 @@ impl Request {
         Request::new_announce(torrent, Some(Event::Started))
     }
 
-    pub fn stopped<T: cio::CIO>(torrent: &Torrent<T>) -> Option<Request> {
-        Request::new_announce(torrent, Some(Event::Stopped))
-    }
-
     pub fn completed<T: cio::CIO>(torrent: &Torrent<T>) -> Option<Request> {
         Request::new_announce(torrent, Some(Event::Completed))
     }
@@ -362,14 +609,29 @@ impl Request {
         Request::new_announce(torrent, None)
     }
 
-    pub fn custom<T: cio::CIO>(torrent: &Torrent<T>, url: Arc<Url>) -> Option<Request> {
-        Request::new_announce(torrent, None).map(|mut r| {
+    pub fn custom<T: cio::CIO>(
+        torrent: &Torrent<T>,
+        url: Arc<Url>,
+        event: Option<Event>,
+    ) -> Option<Request> {
+        Request::new_announce(torrent, event).map(|mut r| {
             if let Request::Announce(ref mut a) = r {
+                a.tracker_id = torrent
+                    .find_tracker(&url)
+                    .and_then(|t| t.tracker_id.clone());
                 a.url = url
             }
             r
         })
     }
+
+    /// The tracker an announce is sent to, if this is one.
+    pub fn announce_url(&self) -> Option<&Url> {
+        match *self {
+            Request::Announce(ref a) => Some(&a.url),
+            _ => None,
+        }
+    }
 }
 
 impl TrackerResponse {
@@ -377,8 +639,12 @@ impl TrackerResponse {
         TrackerResponse {
             peers: vec![],
             interval: 900,
-            leechers: 0,
-            seeders: 0,
+            min_interval: None,
+            leechers: None,
+            seeders: None,
+            downloaded: None,
+            tracker_id: None,
+            external_ip: None,
         }
     }
 
@@ -389,28 +655,221 @@ impl TrackerResponse {
         if let Some(BEncode::String(data)) = d.remove(b"failure reason".as_ref()) {
             let reason = String::from_utf8(data)
                 .chain_err(|| ErrorKind::InvalidResponse("Failure reason must be UTF8!"))?;
-            return Err(ErrorKind::TrackerError(reason).into());
+            return Err(match d.remove(b"retry in".as_ref()) {
+                Some(BEncode::Int(mins)) if mins > 0 => {
+                    ErrorKind::TrackerRetry(reason, Some(cmp::min(mins, MAX_RETRY_MINS) as u32))
+                }
+                Some(BEncode::String(ref s)) if s == b"never" => {
+                    ErrorKind::TrackerRetry(reason, None)
+                }
+                _ => ErrorKind::TrackerError(reason),
+            }
+            .into());
         }
         let mut resp = TrackerResponse::empty();
-        if let Some(BEncode::String(ref data)) = d.remove(b"peers".as_ref()) {
-            for p in data.chunks(6) {
-                if p.len() != 6 {
+        match d.remove(b"peers".as_ref()) {
+            Some(BEncode::String(ref data)) => {
+                for p in data.chunks(6) {
+                    if p.len() != 6 {
+                        debug!("Unusual trailing bytes received for tracker!");
+                        continue;
+                    }
+                    let ip = Ipv4Addr::new(p[0], p[1], p[2], p[3]);
+                    let socket = SocketAddrV4::new(ip, BigEndian::read_u16(&p[4..]));
+                    resp.peers.push(SocketAddr::V4(socket));
+                }
+            }
+            // Trackers ignoring compact=1 send a list of dictionaries,
+            // where IPv6 peers are given alongside IPv4 ones.
+            Some(BEncode::List(peers)) => {
+                for peer in peers {
+                    let mut p = match peer.into_dict() {
+                        Some(p) => p,
+                        None => continue,
+                    };
+                    let ip = p
+                        .remove(b"ip".as_ref())
+                        .and_then(BEncode::into_string)
+                        .and_then(|ip| ip.parse::<IpAddr>().ok());
+                    // Out of range ports would otherwise wrap into valid ones
+                    let port = p
+                        .remove(b"port".as_ref())
+                        .and_then(BEncode::into_int)
+                        .and_then(|port| u16::try_from(port).ok())
+                        .filter(|&port| port != 0);
+                    if let (Some(ip), Some(port)) = (ip, port) {
+                        resp.peers.push(SocketAddr::new(ip, port));
+                    }
+                }
+            }
+            _ => {}
+        }
+        if let Some(BEncode::String(ref data)) = d.remove(b"peers6".as_ref()) {
+            for p in data.chunks(18) {
+                if p.len() != 18 {
                     debug!("Unusual trailing bytes received for tracker!");
                     continue;
                 }
-                let ip = Ipv4Addr::new(p[0], p[1], p[2], p[3]);
-                let socket = SocketAddrV4::new(ip, BigEndian::read_u16(&p[4..]));
-                resp.peers.push(SocketAddr::V4(socket));
+                resp.peers.push(util::bytes_to_addr6(p));
             }
         }
+        resp.peers = resp.peers.into_iter().filter_map(util::dialable).collect();
         match d.remove(b"interval".as_ref()) {
-            Some(BEncode::Int(ref i)) => {
-                resp.interval = *i as u32;
+            Some(BEncode::Int(i)) => {
+                resp.interval = i.clamp(MIN_INTERVAL, MAX_INTERVAL) as u32;
             }
             _ => {
                 return Err(ErrorKind::InvalidResponse("Response must have interval!").into());
             }
         };
+        if let Some(id) = d
+            .remove(b"tracker id".as_ref())
+            .and_then(BEncode::into_string)
+        {
+            resp.tracker_id = Some(id);
+        }
+        if let Some(BEncode::Int(i)) = d.remove(b"complete".as_ref()) {
+            resp.seeders = u32::try_from(i).ok();
+        }
+        if let Some(BEncode::Int(i)) = d.remove(b"incomplete".as_ref()) {
+            resp.leechers = u32::try_from(i).ok();
+        }
+        if let Some(BEncode::Int(i)) = d.remove(b"downloaded".as_ref()) {
+            resp.downloaded = u32::try_from(i).ok();
+        }
+        resp.external_ip = match d.remove(b"external ip".as_ref()) {
+            Some(BEncode::String(ref ip)) if ip.len() == 4 => {
+                Some(IpAddr::V4(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3])))
+            }
+            Some(BEncode::String(ref ip)) if ip.len() == 16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(ip);
+                Some(IpAddr::from(octets))
+            }
+            _ => None,
+        };
+        if let Some(BEncode::Int(i)) = d.remove(b"min interval".as_ref()) {
+            let min = i.clamp(0, MAX_INTERVAL) as u32;
+            resp.min_interval = Some(min);
+            resp.interval = cmp::max(resp.interval, min);
+        }
         Ok(resp)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode;
+
+    #[test]
+    fn test_response_peers() {
+        let mut data = b"d8:intervali900e5:peers6:".to_vec();
+        data.extend_from_slice(&[1, 2, 3, 4, 0x1a, 0xe1]);
+        data.extend_from_slice(b"6:peers636:");
+        let mut mapped = [0u8; 18];
+        mapped[10..12].copy_from_slice(&[0xff, 0xff]);
+        mapped[12..].copy_from_slice(&[5, 6, 7, 8, 0x1a, 0xe1]);
+        let mut global = [0u8; 18];
+        global[..2].copy_from_slice(&[0x20, 0x01]);
+        global[15] = 1;
+        global[16..].copy_from_slice(&[0x1a, 0xe1]);
+        data.extend_from_slice(&mapped);
+        data.extend_from_slice(&global);
+        data.push(b'e');
+
+        let resp = TrackerResponse::from_bencode(bencode::decode_buf(&data).unwrap()).unwrap();
+        let peers: Vec<_> = resp.peers.iter().map(|p| p.to_string()).collect();
+        assert_eq!(peers, ["1.2.3.4:6881", "5.6.7.8:6881", "[2001::1]:6881"]);
+
+        let data = b"d8:intervali900e5:peersld2:ip7:2001::24:porti6881eed2:ip3:bad4:porti1eed\
+                     2:ip7:1.2.3.44:porti72417eed2:ip7:1.2.3.44:porti-1eed2:ip7:1.2.3.44:porti0eeee";
+        let resp = TrackerResponse::from_bencode(bencode::decode_buf(data).unwrap()).unwrap();
+        assert_eq!(
+            resp.peers,
+            ["[2001::2]:6881".parse::<SocketAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_response_external_ip() {
+        let mut data = b"d11:external ip4:".to_vec();
+        data.extend_from_slice(&[1, 2, 3, 4]);
+        data.extend_from_slice(b"8:intervali900ee");
+        let resp = TrackerResponse::from_bencode(bencode::decode_buf(&data).unwrap()).unwrap();
+        assert_eq!(resp.external_ip, Some("1.2.3.4".parse().unwrap()));
+
+        let mut data = b"d11:external ip16:".to_vec();
+        data.extend_from_slice(
+            &"2001:db8::1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        data.extend_from_slice(b"8:intervali900ee");
+        let resp = TrackerResponse::from_bencode(bencode::decode_buf(&data).unwrap()).unwrap();
+        assert_eq!(resp.external_ip, Some("2001:db8::1".parse().unwrap()));
+
+        let data = b"d11:external ip3:abc8:intervali900ee";
+        let resp = TrackerResponse::from_bencode(bencode::decode_buf(data).unwrap()).unwrap();
+        assert_eq!(resp.external_ip, None);
+    }
+
+    #[test]
+    fn test_response_intervals() {
+        let parse = |data: &[u8]| TrackerResponse::from_bencode(bencode::decode_buf(data).unwrap());
+
+        let resp = parse(b"d8:intervali1800e12:min intervali600ee").unwrap();
+        assert_eq!((resp.interval, resp.min_interval), (1800, Some(600)));
+
+        // Nonsensical intervals are clamped rather than wrapped
+        let resp = parse(b"d8:intervali-5e12:min intervali-1ee").unwrap();
+        assert_eq!((resp.interval, resp.min_interval), (60, Some(0)));
+        let resp = parse(b"d8:intervali4294967396ee").unwrap();
+        assert_eq!(resp.interval, 86_400);
+        let resp = parse(b"d8:intervali900e12:min intervali99999999999ee").unwrap();
+        assert_eq!((resp.interval, resp.min_interval), (86_400, Some(86_400)));
+    }
+
+    #[test]
+    fn test_response_counts() {
+        let parse = |data: &[u8]| TrackerResponse::from_bencode(bencode::decode_buf(data).unwrap());
+
+        let resp =
+            parse(b"d8:completei5e10:downloadedi9e10:incompletei0e8:intervali900ee").unwrap();
+        assert_eq!(
+            (resp.seeders, resp.leechers, resp.downloaded),
+            (Some(5), Some(0), Some(9))
+        );
+
+        // Counts left out or out of range aren't reported as 0
+        let resp = parse(b"d8:completei-1e8:intervali900ee").unwrap();
+        assert_eq!(
+            (resp.seeders, resp.leechers, resp.downloaded),
+            (None, None, None)
+        );
+    }
+
+    #[test]
+    fn test_response_retry_in() {
+        let retry =
+            |data: &[u8]| match TrackerResponse::from_bencode(bencode::decode_buf(data).unwrap()) {
+                Err(Error(ErrorKind::TrackerRetry(_, mins), _)) => Some(mins),
+                _ => None,
+            };
+        assert_eq!(
+            retry(b"d14:failure reason4:busy8:retry ini30ee"),
+            Some(Some(30))
+        );
+        assert_eq!(
+            retry(b"d14:failure reason4:busy8:retry in5:nevere"),
+            Some(None)
+        );
+        assert_eq!(
+            retry(b"d14:failure reason4:busy8:retry ini4294967326ee"),
+            Some(Some(1_440))
+        );
+        // Non positive delays are plain failures
+        assert_eq!(retry(b"d14:failure reason4:busy8:retry ini-1ee"), None);
+    }
+}
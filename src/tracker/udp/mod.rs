@@ -1,5 +1,5 @@
 use std::io::{self, Cursor, Read, Write};
-use std::net::{SocketAddr, UdpSocket};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
 use std::time;
 
 use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
@@ -8,7 +8,7 @@ use rand::random;
 use crate::tracker::{
     dns, Announce, Error, ErrorKind, Event, Response, Result, ResultExt, TrackerResponse,
 };
-use crate::util::{bytes_to_addr, FHashMap, UHashMap};
+use crate::util::{self, bytes_to_addr, FHashMap, UHashMap};
 use crate::{CONFIG, PEER_ID};
 
 // We're not going to bother with backoff, if the tracker/network aren't working now
@@ -273,15 +273,23 @@ impl Handler {
                     Some(Event::Completed) => {
                         announce_req.write_u32::<BigEndian>(1).unwrap();
                     }
+                    Some(Event::Paused) => {
+                        announce_req.write_u32::<BigEndian>(4).unwrap();
+                    }
                     None => {
                         announce_req.write_u32::<BigEndian>(0).unwrap();
                     }
                 }
 
-                // IP
-                announce_req.write_u32::<BigEndian>(0).unwrap();
-                // Key - TODO: randomly generate this
-                announce_req.write_u32::<BigEndian>(0xFFFF_00BA).unwrap();
+                // IP, only IPv4 addresses can be given
+                let ip = match CONFIG.trk.announce_ip {
+                    Some(IpAddr::V4(ip)) => u32::from(ip),
+                    _ => 0,
+                };
+                announce_req.write_u32::<BigEndian>(ip).unwrap();
+                announce_req
+                    .write_u32::<BigEndian>(conn.announce.key)
+                    .unwrap();
                 // Num want
                 let nw = conn.announce.num_want.map(i32::from).unwrap_or(-1);
                 announce_req.write_i32::<BigEndian>(nw).unwrap();
@@ -312,12 +320,12 @@ impl Handler {
         };
 
         resp.interval = announce_resp.read_u32::<BigEndian>().unwrap();
-        resp.leechers = announce_resp.read_u32::<BigEndian>().unwrap();
-        resp.seeders = announce_resp.read_u32::<BigEndian>().unwrap();
+        resp.leechers = Some(announce_resp.read_u32::<BigEndian>().unwrap());
+        resp.seeders = Some(announce_resp.read_u32::<BigEndian>().unwrap());
         if len > 20 {
             let pos = announce_resp.position() as usize;
-            for p in announce_resp.get_ref()[pos..].chunks(6) {
-                resp.peers.push(bytes_to_addr(p));
+            for p in announce_resp.get_ref()[pos..].chunks_exact(6) {
+                resp.peers.extend(util::dialable(bytes_to_addr(p)));
             }
         }
         Some(Response::Tracker {
@@ -0,0 +1,120 @@
+use std::cmp::Ordering;
+
+use crate::config::Channel;
+
+/// A release, as listed by the update check URL in the format of
+/// GitHub's releases API.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Release {
+    pub tag_name: String,
+    pub html_url: String,
+    #[serde(default)]
+    pub prerelease: bool,
+    #[serde(default)]
+    pub draft: bool,
+}
+
+/// A version such as "v1.2.3-rc1", compared by its numbers with
+/// releases ordered after their pre-releases.
+#[derive(Debug, PartialEq, Eq)]
+struct Version {
+    nums: [u64; 3],
+    pre: Option<String>,
+}
+
+impl Version {
+    fn parse(s: &str) -> Option<Version> {
+        let s = s.trim().trim_start_matches('v');
+        let (core, pre) = match s.split_once('-') {
+            Some((core, pre)) => (core, Some(pre.to_owned())),
+            None => (s, None),
+        };
+        let mut nums = [0; 3];
+        for (i, n) in core.split('.').enumerate() {
+            *nums.get_mut(i)? = n.parse().ok()?;
+        }
+        Some(Version { nums, pre })
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Version) -> Ordering {
+        self.nums
+            .cmp(&other.nums)
+            .then_with(|| match (&self.pre, &other.pre) {
+                (None, None) => Ordering::Equal,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(_), None) => Ordering::Less,
+                (Some(a), Some(b)) => a.cmp(b),
+            })
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Version) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Finds the newest release on the channel, if it's newer than current.
+/// Drafts and releases with unparseable tags are ignored.
+pub fn newer<'a>(releases: &'a [Release], channel: Channel, current: &str) -> Option<&'a Release> {
+    let current = Version::parse(current)?;
+    releases
+        .iter()
+        .filter(|r| !r.draft)
+        .filter_map(|r| Version::parse(&r.tag_name).map(|v| (v, r)))
+        .filter(|(v, r)| channel == Channel::Beta || !(r.prerelease || v.pre.is_some()))
+        .max_by(|(a, _), (b, _)| a.cmp(b))
+        .filter(|(v, _)| *v > current)
+        .map(|(_, r)| r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn release(tag: &str, prerelease: bool) -> Release {
+        Release {
+            tag_name: tag.to_owned(),
+            html_url: format!("https://example.com/{}", tag),
+            prerelease,
+            draft: false,
+        }
+    }
+
+    #[test]
+    fn test_version() {
+        let v = |s| Version::parse(s).unwrap();
+        assert!(v("v1.0.0") > v("0.9.9"));
+        assert!(v("1.0.0") > v("1.0.0-rc2"));
+        assert!(v("1.0.0-rc2") > v("1.0.0-rc1"));
+        assert!(v("0.10") > v("0.9.1"));
+        assert_eq!(v("1.2"), v("v1.2.0"));
+        assert_eq!(Version::parse("latest"), None);
+        assert_eq!(Version::parse("1.2.3.4"), None);
+    }
+
+    #[test]
+    fn test_newer() {
+        let mut releases = vec![
+            release("v1.1.0-beta", true),
+            release("v1.0.1", false),
+            release("v1.0.0", false),
+            release("nightly", true),
+        ];
+        let tag = |r: Option<&Release>| r.map(|r| r.tag_name.clone());
+        assert_eq!(
+            tag(newer(&releases, Channel::Stable, "1.0.0")),
+            Some("v1.0.1".to_owned())
+        );
+        assert_eq!(
+            tag(newer(&releases, Channel::Beta, "1.0.0")),
+            Some("v1.1.0-beta".to_owned())
+        );
+        assert_eq!(tag(newer(&releases, Channel::Stable, "1.0.1")), None);
+
+        releases[1].draft = true;
+        assert_eq!(tag(newer(&releases, Channel::Stable, "1.0.0")), None);
+    }
+}
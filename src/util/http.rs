@@ -54,6 +54,13 @@ impl<'a> RequestBuilder<'a> {
         self.headers.push(HttpHeader { name, value });
         self
     }
+
+    pub fn header_opt(&mut self, name: &'a str, value: Option<&'a str>) -> &mut RequestBuilder<'a> {
+        if let Some(v) = value {
+            self.headers.push(HttpHeader { name, value: v });
+        }
+        self
+    }
 }
 
 impl<'a> RequestBuilder<'a> {
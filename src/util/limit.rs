@@ -0,0 +1,104 @@
+use std::net::IpAddr;
+use std::time;
+
+use crate::util::FHashMap;
+
+/// Number of tracked addresses before idle entries are pruned
+const MAX_TRACKED: usize = 1024;
+
+/// Per IP limiter for incoming connections, used to mitigate
+/// accept floods on publicly exposed listeners.
+pub struct AcceptLimiter {
+    rate: u32,
+    max_pending: usize,
+    buckets: FHashMap<IpAddr, Bucket>,
+}
+
+/// Token bucket refilled at `rate` tokens per second,
+/// holding at most `rate` tokens.
+struct Bucket {
+    tokens: f64,
+    updated: time::Instant,
+}
+
+impl AcceptLimiter {
+    pub fn new(rate: u32, max_pending: usize) -> AcceptLimiter {
+        AcceptLimiter {
+            rate,
+            max_pending,
+            buckets: FHashMap::default(),
+        }
+    }
+
+    /// Returns whether or not a new connection from ip should be accepted,
+    /// given the number of connections from it that are still pending.
+    /// A rate or pending limit of 0 disables the respective check.
+    pub fn allow(&mut self, ip: IpAddr, pending: usize) -> Result<(), &'static str> {
+        if self.max_pending != 0 && pending >= self.max_pending {
+            return Err("too many pending connections");
+        }
+        if self.rate == 0 {
+            return Ok(());
+        }
+
+        let now = time::Instant::now();
+        if self.buckets.len() >= MAX_TRACKED {
+            self.prune(now);
+        }
+        let rate = f64::from(self.rate);
+        let bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: rate,
+            updated: now,
+        });
+        bucket.refill(rate, now);
+        if bucket.tokens < 1.0 {
+            return Err("connection rate exceeded");
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// Drops buckets which have fully refilled, as they're indistinguishable
+    /// from a fresh entry.
+    fn prune(&mut self, now: time::Instant) {
+        let rate = f64::from(self.rate);
+        self.buckets.retain(|_, b| {
+            b.refill(rate, now);
+            b.tokens < rate
+        });
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: f64, now: time::Instant) {
+        let elapsed = now.duration_since(self.updated);
+        let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + secs * rate).min(rate);
+        self.updated = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AcceptLimiter;
+
+    #[test]
+    fn test_rate() {
+        let mut l = AcceptLimiter::new(3, 0);
+        let ip = "1.2.3.4".parse().unwrap();
+        for _ in 0..3 {
+            assert!(l.allow(ip, 0).is_ok());
+        }
+        assert!(l.allow(ip, 0).is_err());
+        // Other addresses are unaffected
+        assert!(l.allow("1.2.3.5".parse().unwrap(), 0).is_ok());
+    }
+
+    #[test]
+    fn test_pending() {
+        let mut l = AcceptLimiter::new(0, 2);
+        let ip = "1.2.3.4".parse().unwrap();
+        assert!(l.allow(ip, 1).is_ok());
+        assert!(l.allow(ip, 2).is_err());
+    }
+}
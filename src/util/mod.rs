@@ -1,22 +1,23 @@
 pub mod http;
 mod io;
+pub mod limit;
 pub mod native;
 
 use std::collections::{HashMap, HashSet};
 use std::fmt::Write as FWrite;
 use std::hash::BuildHasherDefault;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
 
 use byteorder::{BigEndian, ByteOrder};
 use metrohash::MetroHash;
 use rand::distributions::Alphanumeric;
 use rand::{self, Rng};
 use sha1::{Digest, Sha1};
+use url::{Host, Url};
 
 pub type FHashMap<K, V> = fnv::FnvHashMap<K, V>;
 pub type FHashSet<T> = fnv::FnvHashSet<T>;
 pub type UHashMap<T> = FHashMap<usize, T>;
-pub type UHashSet = FHashSet<usize>;
 
 pub type MBuildHasher = BuildHasherDefault<MetroHash>;
 pub type MHashMap<K, V> = HashMap<K, V, MBuildHasher>;
@@ -84,6 +85,15 @@ pub fn trk_rpc_id(torrent: &[u8; 20], url: &str) -> String {
     hash_to_id(&ctx.finalize())
 }
 
+/// Lowercase hex encoding, as output by checksum tools
+pub fn to_hex(b: &[u8]) -> String {
+    let mut s = String::with_capacity(b.len() * 2);
+    for i in b {
+        write!(&mut s, "{:02x}", i).unwrap();
+    }
+    s
+}
+
 pub fn hash_to_id(hash: &[u8]) -> String {
     let mut hash_str = String::new();
     for i in hash {
@@ -136,6 +146,112 @@ pub fn bytes_to_addr(p: &[u8]) -> SocketAddr {
     SocketAddr::V4(SocketAddrV4::new(ip, BigEndian::read_u16(&p[4..])))
 }
 
+/// Parses a compact IPv6 address, 16 bytes of address followed by the port.
+pub fn bytes_to_addr6(p: &[u8]) -> SocketAddr {
+    let mut ip = [0u8; 16];
+    ip.copy_from_slice(&p[..16]);
+    let port = BigEndian::read_u16(&p[16..]);
+    SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(ip), port, 0, 0))
+}
+
+/// Appends the compact form of an address, its 4 or 16 bytes
+/// followed by the port.
+pub fn append_compact(addr: &SocketAddr, buf: &mut Vec<u8>) {
+    match addr.ip() {
+        IpAddr::V4(ip) => buf.extend_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => buf.extend_from_slice(&ip.octets()),
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Normalizes a peer address received from a tracker or another peer,
+/// returning None if it can't be connected to. IPv4 mapped IPv6
+/// addresses become plain IPv4 ones.
+pub fn dialable(addr: SocketAddr) -> Option<SocketAddr> {
+    if addr.port() == 0 {
+        return None;
+    }
+    match addr {
+        SocketAddr::V4(a) if a.ip().is_unspecified() || a.ip().is_multicast() => None,
+        SocketAddr::V4(_) => Some(addr),
+        SocketAddr::V6(a) => {
+            let ip = a.ip();
+            if let Some(v4) = ip.to_ipv4_mapped() {
+                return dialable(SocketAddr::V4(SocketAddrV4::new(v4, a.port())));
+            }
+            // Link local addresses are meaningless without a scope
+            let link_local = ip.segments()[0] & 0xffc0 == 0xfe80;
+            if ip.is_unspecified() || ip.is_multicast() || link_local {
+                None
+            } else {
+                Some(addr)
+            }
+        }
+    }
+}
+
+/// Checks whether a tracker URL learned from a peer is safe to announce
+/// to: it must be http, https or udp and must not point at loopback,
+/// private, link local or otherwise non global hosts.
+pub fn public_tracker(url: &Url) -> bool {
+    if !["http", "https", "udp"].contains(&url.scheme()) || url.port() == Some(0) {
+        return false;
+    }
+    // Hosts of non special schemes like udp aren't parsed as addresses
+    let ip = match url.host() {
+        Some(Host::Domain(d)) => match d.trim_matches(|c| c == '[' || c == ']').parse() {
+            Ok(ip) => ip,
+            Err(_) => {
+                let d = d.trim_end_matches('.').to_ascii_lowercase();
+                return !(d == "localhost" || d.ends_with(".localhost") || d.ends_with(".local"));
+            }
+        },
+        Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
+        Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+        None => return false,
+    };
+    let ip = match ip {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => v4,
+            None => {
+                let seg = ip.segments()[0];
+                let link_local = seg & 0xffc0 == 0xfe80;
+                let unique_local = seg & 0xfe00 == 0xfc00;
+                return !(ip.is_unspecified()
+                    || ip.is_loopback()
+                    || ip.is_multicast()
+                    || link_local
+                    || unique_local);
+            }
+        },
+    };
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_multicast()
+        || ip.is_broadcast())
+}
+
+/// Finds the globally routable IPv6 address outgoing connections use,
+/// if there is one. No packets are sent to determine this.
+pub fn global_ipv6() -> Option<Ipv6Addr> {
+    let sock = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).ok()?;
+    sock.connect(("2001:4860:4860::8888", 53)).ok()?;
+    let ip = match sock.local_addr().ok()? {
+        SocketAddr::V6(a) => *a.ip(),
+        SocketAddr::V4(_) => return None,
+    };
+    let seg = ip.segments()[0];
+    // Global unicast addresses are in 2000::/3
+    if seg & 0xe000 == 0x2000 {
+        Some(ip)
+    } else {
+        None
+    }
+}
+
 pub fn addr_to_bytes(addr: &SocketAddr) -> [u8; 6] {
     let mut data = [0u8; 6];
     match *addr {
@@ -175,4 +291,52 @@ mod test {
         let s = hash_to_id(&hash);
         assert_eq!(id_to_hash(&s).unwrap(), hash);
     }
+
+    #[test]
+    fn test_addr6() {
+        let mut data = [0u8; 18];
+        data[15] = 1;
+        data[16..].copy_from_slice(&6881u16.to_be_bytes());
+        assert_eq!(bytes_to_addr6(&data), "[::1]:6881".parse().unwrap());
+    }
+
+    #[test]
+    fn test_dialable() {
+        let d = |s: &str| dialable(s.parse().unwrap()).map(|a| a.to_string());
+        assert_eq!(d("1.2.3.4:6881"), Some("1.2.3.4:6881".to_owned()));
+        assert_eq!(d("1.2.3.4:0"), None);
+        assert_eq!(d("0.0.0.0:6881"), None);
+        assert_eq!(
+            d("[2001:db8::1]:6881"),
+            Some("[2001:db8::1]:6881".to_owned())
+        );
+        assert_eq!(d("[::ffff:1.2.3.4]:6881"), Some("1.2.3.4:6881".to_owned()));
+        assert_eq!(d("[fe80::1]:6881"), None);
+        assert_eq!(d("[::]:6881"), None);
+    }
+
+    #[test]
+    fn test_public_tracker() {
+        let p = |s: &str| public_tracker(&Url::parse(s).unwrap());
+        assert!(p("http://tracker.example.org/announce"));
+        assert!(p("https://1.2.3.4:443/announce"));
+        assert!(p("udp://[2001:db8::1]:6969"));
+        assert!(!p("ftp://tracker.example.org/announce"));
+        assert!(!p("udp://tracker.example.org:0"));
+        assert!(!p("http://localhost:8080/announce"));
+        assert!(!p("http://printer.local/announce"));
+        assert!(!p("http://127.0.0.1/announce"));
+        assert!(!p("http://10.0.0.1/announce"));
+        assert!(!p("http://192.168.1.1/announce"));
+        assert!(!p("http://172.16.0.1/announce"));
+        assert!(!p("http://169.254.1.1/announce"));
+        assert!(!p("http://0.0.0.0/announce"));
+        assert!(!p("udp://224.0.0.1:6969"));
+        assert!(!p("udp://192.168.1.1:6969"));
+        assert!(!p("udp://[::1]:6969"));
+        assert!(!p("http://[::1]/announce"));
+        assert!(!p("http://[fe80::1]/announce"));
+        assert!(!p("http://[fd00::1]/announce"));
+        assert!(!p("http://[::ffff:192.168.1.1]/announce"));
+    }
 }
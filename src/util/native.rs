@@ -1,9 +1,16 @@
+use std::cell::Cell;
+use std::ffi::CString;
 use std::fs::File;
-use std::io;
+use std::ops::{Deref, DerefMut};
 use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Once;
+use std::{io, mem, ptr, slice};
 
 use nix::errno::Errno;
+use nix::libc;
 
 use crate::util::io::io_err;
 
@@ -16,9 +23,42 @@ mod sys {
     }
 }
 
+/// Sets the TCP keepalive probe interval and count, where supported.
+#[cfg(target_os = "linux")]
+pub fn set_keepalive_params<S: AsRawFd>(sock: &S, interval: u32, count: u32) -> io::Result<()> {
+    use nix::libc::{self, c_int, c_void, socklen_t};
+    use std::convert::TryFrom;
+    use std::mem;
+
+    for &(opt, val) in &[(libc::TCP_KEEPINTVL, interval), (libc::TCP_KEEPCNT, count)] {
+        let val = c_int::try_from(val).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "keepalive value too large")
+        })?;
+        let res = unsafe {
+            libc::setsockopt(
+                sock.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                opt,
+                &val as *const c_int as *const c_void,
+                mem::size_of::<c_int>() as socklen_t,
+            )
+        };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_keepalive_params<S: AsRawFd>(_: &S, _: u32, _: u32) -> io::Result<()> {
+    Ok(())
+}
+
 pub fn is_sparse(f: &File) -> io::Result<bool> {
     let stat = f.metadata()?;
-    Ok(stat.blocks() * stat.blksize() < stat.size())
+    // st_blocks counts 512 byte units, whatever the block size
+    Ok(stat.blocks() * 512 < stat.size())
 }
 
 pub fn fallocate(f: &File, len: u64) -> io::Result<bool> {
@@ -32,7 +72,7 @@ pub fn fallocate(f: &File, len: u64) -> io::Result<bool> {
                     return Ok(false);
                 }
                 Errno::ENOSPC => {
-                    return io_err("Out of disk space!");
+                    return Err(io::Error::from_raw_os_error(libc::ENOSPC));
                 }
                 Errno::EINTR => {
                     continue;
@@ -45,3 +85,220 @@ pub fn fallocate(f: &File, len: u64) -> io::Result<bool> {
         }
     }
 }
+
+/// Writes a range of a file out to disk and evicts it from the page
+/// cache, so that reading it hits the disk.
+#[cfg(target_os = "linux")]
+pub fn drop_cache(f: &File, offset: u64, len: u64) -> io::Result<()> {
+    let fd = f.as_raw_fd();
+    let (offset, len) = (offset as libc::off64_t, len as libc::off64_t);
+    let flags = libc::SYNC_FILE_RANGE_WAIT_BEFORE
+        | libc::SYNC_FILE_RANGE_WRITE
+        | libc::SYNC_FILE_RANGE_WAIT_AFTER;
+    if unsafe { libc::sync_file_range(fd, offset, len, flags) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    match unsafe { libc::posix_fadvise(fd, offset, len, libc::POSIX_FADV_DONTNEED) } {
+        0 => Ok(()),
+        e => Err(io::Error::from_raw_os_error(e)),
+    }
+}
+
+/// Writes a file out to disk, the page cache can't be bypassed here.
+#[cfg(not(target_os = "linux"))]
+pub fn drop_cache(f: &File, _: u64, _: u64) -> io::Result<()> {
+    f.sync_data()
+}
+
+/// A shared memory map of the start of a file. Writes through it are
+/// only allowed if the file was opened for writing. Accessing pages
+/// past the end of a file truncated since raises SIGBUS, so accesses are
+/// made through read and write, which fail rather than crash if so.
+pub struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+    writable: bool,
+}
+
+impl Mmap {
+    pub fn map(f: &File, len: u64, writable: bool) -> io::Result<Mmap> {
+        let prot = if writable {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        };
+        let len = len as usize;
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                prot,
+                libc::MAP_SHARED,
+                f.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mmap { ptr, len, writable })
+    }
+
+    pub fn writable(&self) -> bool {
+        self.writable
+    }
+
+    /// Copies the range at offset into buf.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> io::Result<()> {
+        let len = buf.len();
+        guarded(|| buf.copy_from_slice(&self[offset..offset + len]))
+    }
+
+    /// Copies buf into the range at offset.
+    pub fn write(&mut self, offset: usize, buf: &[u8]) -> io::Result<()> {
+        guarded(|| self[offset..offset + buf.len()].copy_from_slice(buf))
+    }
+
+    /// Writes a range of the map out to disk, waiting for it to complete.
+    pub fn sync(&self, offset: usize, len: usize) -> io::Result<()> {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let start = offset - offset % page;
+        let addr = unsafe { (self.ptr as *mut u8).add(start) } as *mut libc::c_void;
+        if unsafe { libc::msync(addr, len + offset - start, libc::MS_SYNC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl DerefMut for Mmap {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        assert!(self.writable, "write to a read-only map");
+        unsafe { slice::from_raw_parts_mut(self.ptr as *mut u8, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+thread_local! {
+    /// Whether the thread is accessing a map, and whether that faulted
+    static MAP_ACCESS: Cell<(bool, bool)> = const { Cell::new((false, false)) };
+}
+
+static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+static mut PREV_SIGBUS: mem::MaybeUninit<libc::sigaction> = mem::MaybeUninit::uninit();
+static SIGBUS_GUARD: Once = Once::new();
+
+/// Runs a map access, failing if it touched pages no longer backed by the file.
+/// Those are replaced with zeroed ones so the access can carry on, leaving
+/// the map unusable.
+fn guarded<F: FnOnce()>(access: F) -> io::Result<()> {
+    SIGBUS_GUARD.call_once(|| unsafe {
+        PAGE_SIZE.store(
+            libc::sysconf(libc::_SC_PAGESIZE) as usize,
+            Ordering::Relaxed,
+        );
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = on_sigbus as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(
+            libc::SIGBUS,
+            &action,
+            (*ptr::addr_of_mut!(PREV_SIGBUS)).as_mut_ptr(),
+        );
+    });
+    MAP_ACCESS.with(|a| a.set((true, false)));
+    access();
+    let (_, faulted) = MAP_ACCESS.with(|a| a.replace((false, false)));
+    if faulted {
+        return io_err("Mapped file was truncated!");
+    }
+    Ok(())
+}
+
+extern "C" fn on_sigbus(sig: libc::c_int, info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+    let (accessing, _) = MAP_ACCESS.with(|a| a.get());
+    if accessing {
+        let page = PAGE_SIZE.load(Ordering::Relaxed);
+        let addr = unsafe { (*info).si_addr() } as usize;
+        let res = unsafe {
+            libc::mmap(
+                (addr - addr % page) as *mut libc::c_void,
+                page,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED,
+                -1,
+                0,
+            )
+        };
+        if res != libc::MAP_FAILED {
+            MAP_ACCESS.with(|a| a.set((true, true)));
+            return;
+        }
+    }
+    // Faults elsewhere are left to whatever handled them before
+    unsafe {
+        let prev = (*ptr::addr_of!(PREV_SIGBUS)).assume_init_ref();
+        if prev.sa_flags & libc::SA_SIGINFO != 0 {
+            let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+                mem::transmute(prev.sa_sigaction);
+            handler(sig, info, ctx);
+        } else if prev.sa_sigaction == libc::SIG_DFL || prev.sa_sigaction == libc::SIG_IGN {
+            // Returning retries the access, which then kills us
+            libc::sigaction(libc::SIGBUS, prev, ptr::null_mut());
+        } else {
+            let handler: extern "C" fn(libc::c_int) = mem::transmute(prev.sa_sigaction);
+            handler(sig);
+        }
+    }
+}
+
+/// Changes the owner and/or group of a file.
+pub fn chown(path: &Path, uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+    use nix::unistd::{self, Gid, Uid};
+
+    unistd::chown(path, uid.map(Uid::from_raw), gid.map(Gid::from_raw)).map_err(io::Error::other)
+}
+
+/// Resolves a user name or numeric id to a uid.
+pub fn user_id(name: &str) -> io::Result<u32> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    let name = CString::new(name).or_else(|_| io_err("Invalid user name!"))?;
+    let pw = unsafe { libc::getpwnam(name.as_ptr()) };
+    if pw.is_null() {
+        io_err("Unknown user!")
+    } else {
+        Ok(unsafe { (*pw).pw_uid })
+    }
+}
+
+/// Resolves a group name or numeric id to a gid.
+pub fn group_id(name: &str) -> io::Result<u32> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    let name = CString::new(name).or_else(|_| io_err("Invalid group name!"))?;
+    let gr = unsafe { libc::getgrnam(name.as_ptr()) };
+    if gr.is_null() {
+        io_err("Unknown group!")
+    } else {
+        Ok(unsafe { (*gr).gr_gid })
+    }
+}
@@ -39,36 +39,63 @@ impl SStream {
         SStream::new(conn, host)
     }
 
+    /// Creates a TLS client stream which uses config rather than
+    /// the default client configuration.
+    pub fn new_v4_tls(host: String, config: &Arc<rustls::ClientConfig>) -> io::Result<SStream> {
+        let conn = TcpBuilder::new_v4()?.to_tcp_stream()?;
+        SStream::new_tls(conn, host, config)
+    }
+
+    /// IPv6 equivalent of new_v4_tls.
+    pub fn new_v6_tls(host: String, config: &Arc<rustls::ClientConfig>) -> io::Result<SStream> {
+        let conn = TcpBuilder::new_v6()?.to_tcp_stream()?;
+        SStream::new_tls(conn, host, config)
+    }
+
+    /// Client configuration trusting the bundled webpki roots.
+    pub fn client_config() -> rustls::ClientConfig {
+        let mut config = rustls::ClientConfig::new();
+        config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        config
+    }
+
     fn new(conn: TcpStream, host: Option<String>) -> io::Result<SStream> {
+        match host {
+            Some(h) => SStream::new_tls(conn, h, &Arc::new(SStream::client_config())),
+            None => {
+                conn.set_nonblocking(true)?;
+                let fd = conn.as_raw_fd();
+                Ok(SStream {
+                    conn: SConn::Plain(conn),
+                    fd,
+                })
+            }
+        }
+    }
+
+    fn new_tls(
+        conn: TcpStream,
+        h: String,
+        config: &Arc<rustls::ClientConfig>,
+    ) -> io::Result<SStream> {
         conn.set_nonblocking(true)?;
         let fd = conn.as_raw_fd();
-        let sock = match host {
-            Some(h) => {
-                let mut config = rustls::ClientConfig::new();
-                config
-                    .root_store
-                    .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
-                let dns_name = match webpki::DNSNameRef::try_from_ascii_str(&h) {
-                    Ok(name) => name,
-                    Err(_) => {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "invalid host string used",
-                        ))
-                    }
-                };
-                let session = rustls::ClientSession::new(&Arc::new(config), dns_name);
-                SStream {
-                    conn: SConn::SSLC { conn, session },
-                    fd,
-                }
+        let dns_name = match webpki::DNSNameRef::try_from_ascii_str(&h) {
+            Ok(name) => name,
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid host string used",
+                ))
             }
-            None => SStream {
-                conn: SConn::Plain(conn),
-                fd,
-            },
         };
-        Ok(sock)
+        let session = rustls::ClientSession::new(config, dns_name);
+        Ok(SStream {
+            conn: SConn::SSLC { conn, session },
+            fd,
+        })
     }
 
     pub fn connect(&mut self, addr: SocketAddr) -> io::Result<()> {
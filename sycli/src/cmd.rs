@@ -56,6 +56,8 @@ fn add_file(
         path: dir.as_ref().map(|d| format!("{}", d)),
         start,
         import,
+        category: None,
+        dry_run: false,
     };
     let token = if let SMessage::TransferOffer { token, .. } = c.rr(msg)? {
         token
@@ -98,6 +100,8 @@ fn add_magnet(
         uri: magnet.as_str().to_owned(),
         path: dir.as_ref().map(|d| format!("{}", d)),
         start,
+        category: None,
+        dry_run: false,
     };
     match c.rr(msg)? {
         SMessage::ResourcesExtant { ids, .. } => {
@@ -332,19 +336,20 @@ pub fn list(mut c: Client, kind: &str, crit: Vec<Criterion>, output: &str) -> Re
     Ok(())
 }
 
-pub fn pause(mut c: Client, torrents: Vec<&str>) -> Result<()> {
+pub fn pause(mut c: Client, torrents: Vec<&str>, soft: bool) -> Result<()> {
     for torrent in torrents {
-        pause_torrent(&mut c, torrent)?;
+        pause_torrent(&mut c, torrent, soft)?;
     }
     Ok(())
 }
 
-fn pause_torrent(c: &mut Client, torrent: &str) -> Result<()> {
+fn pause_torrent(c: &mut Client, torrent: &str, soft: bool) -> Result<()> {
     let resources = search_torrent_name(c, torrent)?;
     if resources.len() == 1 {
         let msg = CMessage::PauseTorrent {
             serial: c.next_serial(),
             id: resources[0].id().to_owned(),
+            soft,
         };
         c.send(msg)?;
     } else if resources.is_empty() {
@@ -688,6 +693,40 @@ pub fn set_torrent_pri(mut c: Client, id: &str, pri: &str) -> Result<()> {
     Ok(())
 }
 
+pub fn set_torrent_dht(mut c: Client, id: &str, dht: bool) -> Result<()> {
+    let torrent = search_torrent_name(&mut c, id)?;
+    if torrent.len() != 1 {
+        bail!("Could not find appropriate torrent!");
+    }
+    let update = CMessage::UpdateResource {
+        serial: c.next_serial(),
+        resource: CResourceUpdate {
+            id: torrent[0].id().to_owned(),
+            dht: Some(dht),
+            ..Default::default()
+        },
+    };
+    c.send(update)?;
+    Ok(())
+}
+
+pub fn set_torrent_first_last(mut c: Client, id: &str, first_last: bool) -> Result<()> {
+    let torrent = search_torrent_name(&mut c, id)?;
+    if torrent.len() != 1 {
+        bail!("Could not find appropriate torrent!");
+    }
+    let update = CMessage::UpdateResource {
+        serial: c.next_serial(),
+        resource: CResourceUpdate {
+            id: torrent[0].id().to_owned(),
+            first_last_pieces: Some(first_last),
+            ..Default::default()
+        },
+    };
+    c.send(update)?;
+    Ok(())
+}
+
 pub fn set_file_pri(mut c: Client, id: &str, pri: &str) -> Result<()> {
     let p: u8 = pri.parse().chain_err(|| ErrorKind::Parse)?;
     let update = CMessage::UpdateResource {
@@ -745,6 +784,22 @@ fn print_torrent_res(c: &mut Client, id: &str, kind: ResourceKind, output: &str)
     Ok(())
 }
 
+pub fn shutdown(mut c: Client, restart: bool) -> Result<()> {
+    let msg = CMessage::Shutdown {
+        serial: c.next_serial(),
+        restart,
+    };
+    match c.rr(msg)? {
+        SMessage::RequestApplied { .. } => Ok(()),
+        SMessage::PermissionDenied(message::Error { reason, .. }) => {
+            bail!("{}", reason);
+        }
+        _ => {
+            bail!("Failed to receive shutdown confirmation from synapse!");
+        }
+    }
+}
+
 pub fn status(mut c: Client) -> Result<()> {
     match search(&mut c, ResourceKind::Server, vec![])?.pop() {
         Some(Resource::Server(s)) => {
@@ -763,6 +818,18 @@ pub fn status(mut c: Client) -> Result<()> {
                 fmt_bytes(s.transferred_up as f64),
                 fmt_bytes(s.transferred_down as f64),
             );
+            let reads = s.read_cache_hits + s.read_cache_misses;
+            if reads != 0 {
+                println!(
+                    "Read cache: {}, {:.1}% of {} block reads hit",
+                    fmt_bytes(s.read_cache_size as f64),
+                    100. * s.read_cache_hits as f64 / reads as f64,
+                    reads
+                );
+            }
+            if let (Some(version), Some(url)) = (s.update_version, s.update_url) {
+                println!("Update available: {} ({})", version, url);
+            }
         }
         _ => {
             bail!("synapse server incorrectly reported server status!");
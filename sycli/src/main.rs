@@ -187,6 +187,12 @@ fn main() {
                         .short("t")
                         .long("torrents")
                         .index(1),
+                )
+                .arg(
+                    Arg::with_name("soft")
+                        .help("Stay connected to peers for a few minutes so resuming is instant.")
+                        .short("s")
+                        .long("soft"),
                 ),
             SubCommand::with_name("resume")
                 .about("Resumes the given torrents.")
@@ -199,6 +205,14 @@ fn main() {
                         .long("torrents")
                         .index(1),
                 ),
+            SubCommand::with_name("shutdown")
+                .about("Shuts the server down cleanly")
+                .arg(
+                    Arg::with_name("restart")
+                        .help("Exit with the restart code, for a supervisor to restart on.")
+                        .short("r")
+                        .long("restart"),
+                ),
             SubCommand::with_name("status").about("Server status"),
             SubCommand::with_name("watch")
                 .about("Watches the specified resource, printing out updates.")
@@ -324,6 +338,24 @@ fn main() {
                                 .index(1)
                                 .required(true),
                         ),
+                    SubCommand::with_name("dht")
+                        .about("Enable or disable DHT use for a torrent")
+                        .arg(
+                            Arg::with_name("state")
+                                .help("whether the DHT should be used")
+                                .possible_values(&["on", "off"])
+                                .index(1)
+                                .required(true),
+                        ),
+                    SubCommand::with_name("first-last")
+                        .about("Download the first and last pieces of each file first")
+                        .arg(
+                            Arg::with_name("state")
+                                .help("whether they should be downloaded first")
+                                .possible_values(&["on", "off"])
+                                .index(1)
+                                .required(true),
+                        ),
                     SubCommand::with_name("trackers").about("Prints a torrent's trackers"),
                     SubCommand::with_name("peers").about("Prints a torrent's peers"),
                     SubCommand::with_name("tags").about("Prints a torrent's tags"),
@@ -488,7 +520,11 @@ fn main() {
         }
         "pause" => {
             let args = matches.subcommand_matches("pause").unwrap();
-            let res = cmd::pause(client, args.values_of("torrents").unwrap().collect());
+            let res = cmd::pause(
+                client,
+                args.values_of("torrents").unwrap().collect(),
+                args.is_present("soft"),
+            );
             if let Err(e) = res {
                 eprintln!("Failed to pause torrents: {}", e.display_chain());
                 process::exit(1);
@@ -502,6 +538,13 @@ fn main() {
                 process::exit(1);
             }
         }
+        "shutdown" => {
+            let args = matches.subcommand_matches("shutdown").unwrap();
+            if let Err(e) = cmd::shutdown(client, args.is_present("restart")) {
+                eprintln!("Failed to shut down server: {}", e.display_chain());
+                process::exit(1);
+            }
+        }
         "status" => {
             if let Err(e) = cmd::status(client) {
                 eprintln!("Failed to get server status: {}", e.display_chain());
@@ -661,6 +704,31 @@ fn main() {
                         process::exit(1);
                     }
                 }
+                "dht" => {
+                    let state = subcmd
+                        .subcommand_matches("dht")
+                        .unwrap()
+                        .value_of("state")
+                        .unwrap();
+                    if let Err(e) = cmd::set_torrent_dht(client, id, state == "on") {
+                        eprintln!("Failed to set torrent DHT use: {}", e.display_chain());
+                        process::exit(1);
+                    }
+                }
+                "first-last" => {
+                    let state = subcmd
+                        .subcommand_matches("first-last")
+                        .unwrap()
+                        .value_of("state")
+                        .unwrap();
+                    if let Err(e) = cmd::set_torrent_first_last(client, id, state == "on") {
+                        eprintln!(
+                            "Failed to set first and last piece priority: {}",
+                            e.display_chain()
+                        );
+                        process::exit(1);
+                    }
+                }
                 "files" => {
                     if let Err(e) = cmd::get_files(client, id, output) {
                         eprintln!("Failed to get torrent files: {}", e.display_chain());